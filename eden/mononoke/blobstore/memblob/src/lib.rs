@@ -121,6 +121,14 @@ impl Blobstore for EagerMemblob {
 
         future::ok(inner.get(&key).map(|blob_ref| blob_ref.clone().into())).boxed()
     }
+
+    fn unlink(
+        &self,
+        _ctx: CoreContext,
+        key: String,
+    ) -> BoxFuture<'static, Result<Option<()>, Error>> {
+        EagerMemblob::unlink(self, key)
+    }
 }
 
 impl BlobstoreWithLink for EagerMemblob {
@@ -166,6 +174,14 @@ impl Blobstore for LazyMemblob {
         })
         .boxed()
     }
+
+    fn unlink(
+        &self,
+        _ctx: CoreContext,
+        key: String,
+    ) -> BoxFuture<'static, Result<Option<()>, Error>> {
+        LazyMemblob::unlink(self, key)
+    }
 }
 
 impl BlobstoreWithLink for LazyMemblob {
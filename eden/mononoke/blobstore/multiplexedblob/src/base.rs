@@ -5,28 +5,35 @@
  * GNU General Public License version 2.
  */
 
-use anyhow::Error;
+use crate::checksum::{add_checksum, has_valid_checksum, strip_checksum_if};
+use crate::compression::{compress, decompress_if};
+
+use anyhow::{format_err, Error};
 use blobstore::{Blobstore, BlobstoreGetData};
 use blobstore_stats::{record_get_stats, record_put_stats, OperationType};
 use blobstore_sync_queue::OperationKey;
 use cloned::cloned;
 use context::{CoreContext, PerfCounterType};
 use futures::{
-    future::{join_all, select, BoxFuture, Either as FutureEither, FutureExt},
-    stream::{FuturesUnordered, StreamExt, TryStreamExt},
+    channel::oneshot,
+    future::{self, join_all, select, BoxFuture, Either as FutureEither, FutureExt, TryFutureExt},
+    stream::{self, FuturesUnordered, Stream, StreamExt, TryStreamExt},
 };
 use futures_stats::TimedFutureExt;
 use itertools::{Either, Itertools};
 use metaconfig_types::{BlobstoreId, MultiplexId};
 use mononoke_types::BlobstoreBytes;
 use scuba::ScubaSampleBuilder;
+use serde::Serialize;
+use slog::{debug, warn};
 use std::{
     borrow::Borrow,
-    collections::{HashMap, HashSet},
+    collections::{hash_map::DefaultHasher, HashMap, HashSet},
     fmt,
     future::Future,
+    hash::{Hash, Hasher},
     iter::Iterator,
-    num::NonZeroU64,
+    num::{NonZeroU64, NonZeroUsize},
     sync::{
         atomic::{AtomicUsize, Ordering},
         Arc,
@@ -35,13 +42,18 @@ use std::{
 };
 use thiserror::Error;
 use time_ext::DurationExt;
-use tokio::time::timeout;
+use tokio::{sync::Semaphore, time::timeout};
 
-const REQUEST_TIMEOUT: Duration = Duration::from_secs(600);
+pub const DEFAULT_REQUEST_TIMEOUT: Duration = Duration::from_secs(600);
+pub(crate) const MULTIPLEX_ID: &str = "multiplex_id";
+const OPERATION_KEY: &str = "operation_key";
+const PUT_BATCH_CONCURRENCY: usize = 10;
+const AUDIT_CONCURRENCY: usize = 10;
 
 type BlobstoresWithEntry = HashSet<BlobstoreId>;
 type BlobstoresReturnedNone = HashSet<BlobstoreId>;
 type BlobstoresReturnedError = HashMap<BlobstoreId, Error>;
+type BlobstoresByValueHash = HashMap<u64, BlobstoresWithEntry>;
 
 #[derive(Error, Debug, Clone)]
 pub enum ErrorKind {
@@ -49,16 +61,160 @@ pub enum ErrorKind {
     SomeFailedOthersNone(Arc<BlobstoresReturnedError>),
     #[error("All blobstores failed: {0:?}")]
     AllFailed(Arc<BlobstoresReturnedError>),
+    #[error("Some blobstores failed to unlink: {0:?}")]
+    SomeUnlinksFailed(Arc<BlobstoresReturnedError>),
+    #[error("Not written, as a put to a primary blobstore failed")]
+    PrimaryPutFailed,
+    #[error("Blobstore {0:?} acknowledged the put, but did not return the written value")]
+    VerifyAfterWriteFailed(BlobstoreId),
+    #[error("Not written, as the put has a nil operation key")]
+    NilOperationKey,
+    #[error("Blobstore {0:?} returned a value that doesn't match its checksum")]
+    ChecksumMismatch(BlobstoreId),
+    #[error("Failed to decode the value read from the blobstores: {0:?}")]
+    DecodeFailed(Arc<Error>),
+    #[error("All blobstores failed to put: {0:?}")]
+    AllPutsFailed(Arc<BlobstoresReturnedError>),
+    #[error("Only {0} of the {1} blobstores required by the write quorum were written: {2:?}")]
+    WriteQuorumNotReached(usize, usize, Arc<BlobstoresReturnedError>),
     // Errors below this point are from ScrubBlobstore only. If they include an
     // Option<BlobstoreBytes>, this implies that this error is recoverable
     #[error(
-        "Different blobstores have different values for this item: {0:?} differ, {1:?} do not have"
+        "Different blobstores have different values for this item: {0:?} differ, {1:?} do not have, grouped by value hash: {2:?}"
     )]
-    ValueMismatch(Arc<BlobstoresWithEntry>, Arc<BlobstoresReturnedNone>),
+    ValueMismatch(
+        Arc<BlobstoresWithEntry>,
+        Arc<BlobstoresReturnedNone>,
+        Arc<BlobstoresByValueHash>,
+    ),
     #[error("Some blobstores missing this item: {0:?}")]
     SomeMissingItem(Arc<BlobstoresReturnedNone>, Option<BlobstoreGetData>),
 }
 
+/// How `scrub_get_with_resolution` deals with blobstores returning different values.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MismatchResolution {
+    /// Fail with `ErrorKind::ValueMismatch`
+    Error,
+    /// Return the value held by the most blobstores, failing if there is a tie
+    MajorityWins,
+    /// Return the value held by the given blobstore, failing if it doesn't have one
+    PreferStore(BlobstoreId),
+}
+
+/// How `get` spreads its reads over the blobstores, see `with_read_strategy`.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum ReadStrategy {
+    /// Query all blobstores at once
+    Concurrent,
+    /// Query the first blobstore alone, and only query the rest if it doesn't return a
+    /// value within `first_timeout`
+    PreferredWithFallback { first_timeout: Duration },
+}
+
+impl Default for ReadStrategy {
+    fn default() -> Self {
+        ReadStrategy::Concurrent
+    }
+}
+
+/// How `put_with_operation_key` deals with a nil `OperationKey`, e.g. from legacy callers.
+#[derive(Clone, Copy, Debug, Eq, PartialEq, Serialize)]
+pub enum NilOperationKeyHandling {
+    /// Fail with `ErrorKind::NilOperationKey`
+    Reject,
+    /// Log a warning and record the put under a newly generated key, so that it isn't
+    /// correlated with any other put
+    Uncorrelated,
+}
+
+/// Outcome of a successful `put_with_report`.
+#[derive(Clone, Debug)]
+pub struct PutReport {
+    pub operation_key: OperationKey,
+    /// The `write_order` of each blobstore that completed its write before the put
+    /// returned, in completion order. Blobstores still in flight are not included.
+    pub write_orders: Vec<(BlobstoreId, usize)>,
+}
+
+/// Outcome of `unlink_with_report` for each blobstore.
+#[derive(Debug, Default)]
+pub struct UnlinkReport {
+    /// Blobstores that had the key and removed it
+    pub unlinked: HashSet<BlobstoreId>,
+    /// Blobstores that didn't have the key
+    pub missing: HashSet<BlobstoreId>,
+    /// Blobstores that returned an error, which may or may not still have the key
+    pub failed: HashMap<BlobstoreId, Error>,
+}
+
+/// Outcome of `scrub_report` for a single key: the blobstores that don't agree with the others.
+#[derive(Debug, Default)]
+pub struct ScrubReport {
+    /// Blobstores that returned a value, if not all of them returned the same one
+    pub mismatched: HashSet<BlobstoreId>,
+    /// Blobstores that didn't have the key, while others did
+    pub missing: HashSet<BlobstoreId>,
+    /// Blobstores that returned an error
+    pub failed: HashSet<BlobstoreId>,
+}
+
+impl ScrubReport {
+    pub fn is_consistent(&self) -> bool {
+        self.mismatched.is_empty() && self.missing.is_empty() && self.failed.is_empty()
+    }
+}
+
+/// Number of keys of an `AuditReport` each kind of inconsistency was found for in a blobstore.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct BlobstoreAuditCounts {
+    pub mismatched: usize,
+    pub missing: usize,
+    pub failed: usize,
+}
+
+/// The `ScrubReport`s of a sample of keys, folded by `audit_sample`. A key with several
+/// kinds of inconsistency is counted for each of them.
+#[derive(Clone, Debug, Default, PartialEq)]
+pub struct AuditReport {
+    pub keys: usize,
+    pub consistent: usize,
+    pub mismatched: usize,
+    pub missing: usize,
+    pub failed: usize,
+    pub per_blobstore: HashMap<BlobstoreId, BlobstoreAuditCounts>,
+}
+
+impl AuditReport {
+    fn add(&mut self, report: &ScrubReport) {
+        self.keys += 1;
+        if report.is_consistent() {
+            self.consistent += 1;
+        }
+        if !report.mismatched.is_empty() {
+            self.mismatched += 1;
+        }
+        if !report.missing.is_empty() {
+            self.missing += 1;
+        }
+        if !report.failed.is_empty() {
+            self.failed += 1;
+        }
+        for blobstore_id in &report.mismatched {
+            self.per_blobstore
+                .entry(*blobstore_id)
+                .or_default()
+                .mismatched += 1;
+        }
+        for blobstore_id in &report.missing {
+            self.per_blobstore.entry(*blobstore_id).or_default().missing += 1;
+        }
+        for blobstore_id in &report.failed {
+            self.per_blobstore.entry(*blobstore_id).or_default().failed += 1;
+        }
+    }
+}
+
 /// This handler is called on each successful put to underlying blobstore,
 /// for put to be considered successful this handler must return success.
 /// It will be used to keep self-healing table up to date.
@@ -70,51 +226,830 @@ pub trait MultiplexedBlobstorePutHandler: Send + Sync {
         multiplex_id: MultiplexId,
         operation_key: &'out OperationKey,
         key: &'out str,
+        blob_size: u64,
     ) -> BoxFuture<'out, Result<(), Error>>;
 }
 
+/// A step of a multiplexed get or put, as reported to a `MultiplexRecorder`.
+#[derive(Clone, Debug, Eq, PartialEq)]
+pub enum MultiplexEvent {
+    /// A request was sent to the blobstore
+    StoreContacted(BlobstoreId),
+    /// The blobstore returned a value for a get, or acknowledged a put
+    StoreReturnedValue(BlobstoreId),
+    /// The blobstore didn't have the key
+    StoreReturnedNone(BlobstoreId),
+    /// The blobstore failed or timed out
+    StoreErrored(BlobstoreId),
+    /// The operation had the answers it needed to succeed
+    QuorumReached,
+    /// A shadow blobstore failed or timed out. This doesn't affect the operation
+    ShadowErrored(BlobstoreId),
+    /// A shadow blobstore returned a different value than the multiplex for a get
+    ShadowMismatch(BlobstoreId),
+}
+
+/// Observes the decisions the multiplexer makes, so that e.g. tests can check which
+/// blobstores were contacted and in what order.
+pub trait MultiplexRecorder: Send + Sync {
+    fn record(&self, event: MultiplexEvent);
+}
+
+fn record_event(recorder: &Option<Arc<dyn MultiplexRecorder>>, event: MultiplexEvent) {
+    if let Some(recorder) = recorder {
+        recorder.record(event);
+    }
+}
+
+/// Read-only snapshot of a multiplex's configuration, for diagnostics
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct MultiplexDescription {
+    pub multiplex_id: MultiplexId,
+    pub blobstores: Vec<BlobstoreDescription>,
+    pub shadow_blobstores: Vec<BlobstoreDescription>,
+    pub strict_handler: bool,
+    pub verify_after_write: bool,
+    pub skip_handlers_on_success: bool,
+    pub compress: bool,
+    pub checksum: bool,
+    pub nil_operation_key: NilOperationKeyHandling,
+    /// Number of blobstores `get` queries before waiting for the grace period, if staged
+    pub get_first_stage: Option<usize>,
+    pub get_grace_period_ms: u64,
+    pub write_quorum: usize,
+}
+
+#[derive(Clone, Debug, PartialEq, Serialize)]
+pub struct BlobstoreDescription {
+    pub blobstore_id: BlobstoreId,
+    /// Whether `is_present` asks this blobstore before the others
+    pub local: bool,
+    /// Whether `put` writes this blobstore before the others
+    pub primary: bool,
+    /// `Debug` output of the underlying blobstore
+    pub blobstore: String,
+}
+
+#[derive(Clone)]
 pub struct MultiplexedBlobstoreBase {
     multiplex_id: MultiplexId,
     blobstores: Arc<[(BlobstoreId, Arc<dyn Blobstore>)]>,
+    shadow_blobstores: Arc<[(BlobstoreId, Arc<dyn Blobstore>)]>,
     handler: Arc<dyn MultiplexedBlobstorePutHandler>,
     scuba: ScubaSampleBuilder,
     scuba_sample_rate: NonZeroU64,
+    strict_handler: bool,
+    get_first_stage: usize,
+    get_grace_period: Duration,
+    local_blobstores: Arc<HashSet<BlobstoreId>>,
+    primary_blobstores: Arc<HashSet<BlobstoreId>>,
+    verify_after_write: bool,
+    skip_handlers_on_success: bool,
+    nil_operation_key: NilOperationKeyHandling,
+    compress: bool,
+    checksum: bool,
+    recorder: Option<Arc<dyn MultiplexRecorder>>,
+    request_timeout: Duration,
+    write_quorum: NonZeroUsize,
 }
 
 impl MultiplexedBlobstoreBase {
+    /// Like `new_with_request_timeout`, with the `DEFAULT_REQUEST_TIMEOUT`.
     pub fn new(
+        multiplex_id: MultiplexId,
+        blobstores: Vec<(BlobstoreId, Arc<dyn Blobstore>)>,
+        handler: Arc<dyn MultiplexedBlobstorePutHandler>,
+        scuba: ScubaSampleBuilder,
+        scuba_sample_rate: NonZeroU64,
+    ) -> Self {
+        Self::new_with_request_timeout(
+            multiplex_id,
+            blobstores,
+            handler,
+            scuba,
+            scuba_sample_rate,
+            DEFAULT_REQUEST_TIMEOUT,
+        )
+    }
+
+    /// Each request to an underlying blobstore fails if it doesn't complete within
+    /// `request_timeout`, so that a slow blobstore counts as failed rather than holding up
+    /// operations that the others could satisfy.
+    pub fn new_with_request_timeout(
         multiplex_id: MultiplexId,
         blobstores: Vec<(BlobstoreId, Arc<dyn Blobstore>)>,
         handler: Arc<dyn MultiplexedBlobstorePutHandler>,
         mut scuba: ScubaSampleBuilder,
         scuba_sample_rate: NonZeroU64,
+        request_timeout: Duration,
     ) -> Self {
         scuba.add_common_server_data();
+        scuba.add(MULTIPLEX_ID, multiplex_id);
 
         Self {
             multiplex_id,
             blobstores: blobstores.into(),
+            shadow_blobstores: Vec::new().into(),
             handler,
             scuba,
             scuba_sample_rate,
+            strict_handler: false,
+            get_first_stage: usize::MAX,
+            get_grace_period: Duration::from_secs(0),
+            local_blobstores: Arc::new(HashSet::new()),
+            primary_blobstores: Arc::new(HashSet::new()),
+            verify_after_write: false,
+            skip_handlers_on_success: false,
+            nil_operation_key: NilOperationKeyHandling::Uncorrelated,
+            compress: false,
+            checksum: false,
+            recorder: None,
+            request_timeout,
+            write_quorum: NonZeroUsize::new(1).unwrap(),
+        }
+    }
+
+    pub(crate) fn request_timeout(&self) -> Duration {
+        self.request_timeout
+    }
+
+    /// In strict mode `put` only succeeds once a put handler has completed, rather than as
+    /// soon as all blobstores have been written with handlers still in flight. This makes
+    /// sure the self-healing record exists before returning, at the cost of latency.
+    pub fn with_strict_handler(self, strict_handler: bool) -> Self {
+        Self {
+            strict_handler,
+            ..self
+        }
+    }
+
+    /// Make `get` query only the first `first_stage` blobstores, and only query the rest if
+    /// none of those returned a value within `grace_period` (or all of them answered without
+    /// one). This reduces read amplification for multiplexes where blobs are almost always
+    /// present. By default all blobstores are queried at once.
+    pub fn with_staged_get(self, first_stage: usize, grace_period: Duration) -> Self {
+        Self {
+            get_first_stage: first_stage,
+            get_grace_period: grace_period,
+            ..self
+        }
+    }
+
+    /// Set how `get` spreads its reads, e.g. to read from a fast local blobstore, listed
+    /// first, without also using the bandwidth of the remote ones. This is a shorthand for
+    /// the common configurations of `with_staged_get`, which it overrides.
+    pub fn with_read_strategy(self, read_strategy: ReadStrategy) -> Self {
+        match read_strategy {
+            ReadStrategy::Concurrent => self.with_staged_get(usize::MAX, Duration::from_secs(0)),
+            ReadStrategy::PreferredWithFallback { first_timeout } => {
+                self.with_staged_get(1, first_timeout)
+            }
+        }
+    }
+
+    /// Tag `local_blobstores` as being in the same region as this multiplex. `is_present`
+    /// then asks only those first, and only queries the remote ones if none of the local
+    /// ones has the blob. With no local blobstores, all are queried at once.
+    pub fn with_local_blobstores(self, local_blobstores: HashSet<BlobstoreId>) -> Self {
+        Self {
+            local_blobstores: Arc::new(local_blobstores),
+            ..self
+        }
+    }
+
+    /// Tag `primary_blobstores` as having to be written before the others. `put` then only
+    /// issues puts to the secondary blobstores once all primary ones succeeded, and fails
+    /// without writing the secondaries if any primary put fails. This guarantees e.g. that a
+    /// replica never has a blob the primary lacks, at the cost of latency. With no primary
+    /// blobstores, all are written at once.
+    pub fn with_primary_blobstores(self, primary_blobstores: HashSet<BlobstoreId>) -> Self {
+        Self {
+            primary_blobstores: Arc::new(primary_blobstores),
+            ..self
+        }
+    }
+
+    /// With `verify_after_write`, each put to an underlying blobstore is followed by a get
+    /// from the same blobstore, and only counts as successful if that returns the written
+    /// value. This catches backends that acknowledge writes that aren't durable, but roughly
+    /// doubles the cost of writes.
+    pub fn with_verify_after_write(self, verify_after_write: bool) -> Self {
+        Self {
+            verify_after_write,
+            ..self
+        }
+    }
+
+    /// With `skip_handlers_on_success`, put handlers are held back until some blobstore
+    /// fails its put, and dropped if all of them succeed, as then there is nothing to heal.
+    /// This reduces churn on e.g. the sync queue, but `put` can then only return once every
+    /// blobstore either completed or failed its write.
+    pub fn with_skip_handlers_on_success(self, skip_handlers_on_success: bool) -> Self {
+        Self {
+            skip_handlers_on_success,
+            ..self
+        }
+    }
+
+    /// Choose whether puts with a nil operation key are rejected, or recorded as uncorrelated
+    /// puts (the default). Correlating all nil-keyed puts with each other would make healing
+    /// treat unrelated writes as a single operation.
+    pub fn with_nil_operation_key(self, nil_operation_key: NilOperationKeyHandling) -> Self {
+        Self {
+            nil_operation_key,
+            ..self
+        }
+    }
+
+    /// With `compress`, values are compressed once before being written to all blobstores,
+    /// and decompressed on get. Compressed blobs carry a header, so that blobs written
+    /// without compression still read back unchanged. Keep this on for as long as compressed
+    /// blobs may be read.
+    pub fn with_compression(self, compress: bool) -> Self {
+        Self { compress, ..self }
+    }
+
+    /// With `checksum`, values get a footer with a checksum of what is written, and reads
+    /// check it, so that blobs that rotted in storage are detected even if the blobstore
+    /// returns them without error. A blobstore returning a value that doesn't match its
+    /// checksum counts as failed for that read. Blobs written without a checksum still read
+    /// back unchanged. Keep this on for as long as checksummed blobs may be read.
+    pub fn with_checksum(self, checksum: bool) -> Self {
+        Self { checksum, ..self }
+    }
+
+    /// Undo the compression and checksum of a value read from the underlying blobstores, if
+    /// enabled.
+    pub(crate) fn decode_value(
+        &self,
+        value: Option<BlobstoreGetData>,
+    ) -> Result<Option<BlobstoreGetData>, Error> {
+        decompress_if(self.compress, strip_checksum_if(self.checksum, value))
+    }
+
+    /// Like `decode_value`, but for the result of a scrub, including the value carried by a
+    /// recoverable `ErrorKind::SomeMissingItem`.
+    fn decode_scrub_result(
+        &self,
+        result: Result<Option<BlobstoreGetData>, ErrorKind>,
+    ) -> Result<Option<BlobstoreGetData>, ErrorKind> {
+        let decode = |value| {
+            self.decode_value(value)
+                .map_err(|error| ErrorKind::DecodeFailed(Arc::new(error)))
+        };
+        match result {
+            Ok(value) => decode(value),
+            Err(ErrorKind::SomeMissingItem(missing, value)) => {
+                Err(ErrorKind::SomeMissingItem(missing, decode(value)?))
+            }
+            Err(error) => Err(error),
+        }
+    }
+
+    /// Mirror writes to `shadow_blobstores` and compare their reads against the multiplex,
+    /// to qualify new blobstores without risk. Shadow blobstores are written and read in the
+    /// background, are not counted towards any put or get outcome, and get no put handler
+    /// calls. Their failures and mismatches are only logged and reported to the recorder.
+    pub fn with_shadow_blobstores(
+        self,
+        shadow_blobstores: Vec<(BlobstoreId, Arc<dyn Blobstore>)>,
+    ) -> Self {
+        Self {
+            shadow_blobstores: shadow_blobstores.into(),
+            ..self
+        }
+    }
+
+    /// Make `put` only succeed once `write_quorum` blobstores were written and had their put
+    /// handler complete, rather than just one, so that an acknowledged write is in several
+    /// blobstores. This holds even if every blobstore was written, except with
+    /// `skip_handlers_on_success`, where no handler runs then. The remaining puts still
+    /// complete in the background. If the quorum can't be reached, `put` fails with
+    /// `ErrorKind::WriteQuorumNotReached`, or with `ErrorKind::AllPutsFailed` if no blobstore
+    /// was written with its handler completing. Fails if there are fewer blobstores than
+    /// `write_quorum`, as no put could then succeed.
+    pub fn with_write_quorum(self, write_quorum: NonZeroUsize) -> Result<Self, Error> {
+        if write_quorum.get() > self.blobstores.len() {
+            return Err(format_err!(
+                "Write quorum of {} is more than the {} blobstores of the multiplex",
+                write_quorum,
+                self.blobstores.len()
+            ));
+        }
+        Ok(Self {
+            write_quorum,
+            ..self
+        })
+    }
+
+    /// Report each step of gets and puts to `recorder`.
+    pub fn with_recorder(self, recorder: Arc<dyn MultiplexRecorder>) -> Self {
+        Self {
+            recorder: Some(recorder),
+            ..self
+        }
+    }
+
+    /// Describe the configuration of this multiplex, e.g. to let admin endpoints expose it.
+    pub fn describe(&self) -> MultiplexDescription {
+        MultiplexDescription {
+            multiplex_id: self.multiplex_id,
+            blobstores: self
+                .blobstores
+                .iter()
+                .map(|(blobstore_id, blobstore)| BlobstoreDescription {
+                    blobstore_id: *blobstore_id,
+                    local: self.local_blobstores.contains(blobstore_id),
+                    primary: self.primary_blobstores.contains(blobstore_id),
+                    blobstore: format!("{:?}", blobstore),
+                })
+                .collect(),
+            shadow_blobstores: self
+                .shadow_blobstores
+                .iter()
+                .map(|(blobstore_id, blobstore)| BlobstoreDescription {
+                    blobstore_id: *blobstore_id,
+                    local: false,
+                    primary: false,
+                    blobstore: format!("{:?}", blobstore),
+                })
+                .collect(),
+            strict_handler: self.strict_handler,
+            verify_after_write: self.verify_after_write,
+            skip_handlers_on_success: self.skip_handlers_on_success,
+            compress: self.compress,
+            checksum: self.checksum,
+            nil_operation_key: self.nil_operation_key,
+            get_first_stage: if self.get_first_stage < self.blobstores.len() {
+                Some(self.get_first_stage)
+            } else {
+                None
+            },
+            get_grace_period_ms: self.get_grace_period.as_millis() as u64,
+            write_quorum: self.write_quorum.get(),
+        }
+    }
+
+    /// Like `put`, but reports the `write_order` each blobstore was assigned, so that
+    /// callers can tell which blobstores complete their writes first or last.
+    pub fn put_with_report(
+        &self,
+        ctx: CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> BoxFuture<'static, Result<PutReport, Error>> {
+        self.put_with_operation_key(ctx, key, value, OperationKey::gen())
+    }
+
+    /// Put all of `items` under a single newly generated `OperationKey`, so that the sync
+    /// queue records them as one operation. At most `PUT_BATCH_CONCURRENCY` puts run at once.
+    pub async fn put_batch(
+        &self,
+        ctx: CoreContext,
+        items: Vec<(String, BlobstoreBytes)>,
+    ) -> Result<(), Error> {
+        let operation_key = OperationKey::gen();
+        stream::iter(items)
+            .map(|(key, value)| {
+                self.put_with_operation_key(ctx.clone(), key, value, operation_key.clone())
+            })
+            .buffer_unordered(PUT_BATCH_CONCURRENCY)
+            .try_for_each(|_| future::ok(()))
+            .await
+    }
+
+    /// Like `put_with_report`, but records the put under `operation_key` rather than a newly
+    /// generated one, so that callers can group related puts under a single operation.
+    pub fn put_with_operation_key(
+        &self,
+        ctx: CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        operation_key: OperationKey,
+    ) -> BoxFuture<'static, Result<PutReport, Error>> {
+        let operation_key = if operation_key.is_null() {
+            match self.nil_operation_key {
+                NilOperationKeyHandling::Reject => {
+                    return future::err(ErrorKind::NilOperationKey.into()).boxed();
+                }
+                NilOperationKeyHandling::Uncorrelated => {
+                    let operation_key = OperationKey::gen();
+                    warn!(
+                        ctx.logger(),
+                        "Nil operation key for put of {}, recording it under {} instead",
+                        key,
+                        operation_key.0
+                    );
+                    operation_key
+                }
+            }
+        } else {
+            operation_key
+        };
+        let value = if self.compress {
+            match compress(&value) {
+                Ok(value) => value,
+                Err(e) => return future::err(e).boxed(),
+            }
+        } else {
+            value
+        };
+        let value = if self.checksum {
+            add_checksum(&value)
+        } else {
+            value
+        };
+        let shadow_blobstores = self.shadow_blobstores.clone();
+        let shadow_value = value.clone();
+        let write_order = Arc::new(AtomicUsize::new(0));
+        let strict_handler = self.strict_handler;
+        let verify_after_write = self.verify_after_write;
+        let request_timeout = self.request_timeout;
+        let write_quorum = self.write_quorum.get();
+        let mut defer_handlers = self.skip_handlers_on_success;
+        let recorder = self.recorder.clone();
+        let mut scuba = self.scuba.clone();
+        scuba.add(OPERATION_KEY, operation_key.0.to_string());
+
+        // Each primary put reports whether it succeeded, and secondary puts wait until all
+        // primaries reported success
+        let mut primary_senders = HashMap::new();
+        let mut primary_receivers = Vec::new();
+        for (blobstore_id, _) in self.blobstores.iter() {
+            if self.primary_blobstores.contains(blobstore_id) {
+                let (sender, receiver) = oneshot::channel();
+                primary_senders.insert(*blobstore_id, sender);
+                primary_receivers.push(receiver);
+            }
+        }
+        let is_ordered = !primary_receivers.is_empty();
+        let primaries_done = join_all(primary_receivers)
+            .map(|results| results.into_iter().all(|result| result.unwrap_or(false)))
+            .boxed()
+            .shared();
+
+        let mut puts: FuturesUnordered<_> = self
+            .blobstores
+            .iter()
+            .cloned()
+            .map({
+                |(blobstore_id, blobstore)| {
+                    let primary_sender = primary_senders.remove(&blobstore_id);
+                    let wait_for_primaries = if is_ordered && primary_sender.is_none() {
+                        Some(primaries_done.clone())
+                    } else {
+                        None
+                    };
+                    cloned!(
+                        self.handler,
+                        self.multiplex_id,
+                        recorder,
+                        scuba,
+                        ctx,
+                        write_order,
+                        key,
+                        value,
+                        operation_key
+                    );
+                    async move {
+                        let blob_size = value.len() as u64;
+                        let expected = if verify_after_write {
+                            Some(value.clone())
+                        } else {
+                            None
+                        };
+                        if let Some(wait_for_primaries) = wait_for_primaries {
+                            if !wait_for_primaries.await {
+                                return Err((
+                                    blobstore_id,
+                                    Error::from(ErrorKind::PrimaryPutFailed),
+                                ));
+                            }
+                        }
+                        record_event(&recorder, MultiplexEvent::StoreContacted(blobstore_id));
+                        let result = inner_put(
+                            &ctx,
+                            scuba,
+                            write_order.as_ref(),
+                            blobstore_id,
+                            blobstore.as_ref(),
+                            key.clone(),
+                            value,
+                            request_timeout,
+                        )
+                        .await;
+                        let result = match (result, expected) {
+                            (Ok(put), Some(expected)) => verify_put(
+                                &ctx,
+                                blobstore_id,
+                                blobstore.as_ref(),
+                                key.clone(),
+                                &expected,
+                                request_timeout,
+                            )
+                            .await
+                            .map(|()| put),
+                            (result, _) => result,
+                        };
+                        record_event(
+                            &recorder,
+                            match result {
+                                Ok(_) => MultiplexEvent::StoreReturnedValue(blobstore_id),
+                                Err(_) => MultiplexEvent::StoreErrored(blobstore_id),
+                            },
+                        );
+                        if let Some(primary_sender) = primary_sender {
+                            let _ = primary_sender.send(result.is_ok());
+                        }
+                        let (_, order) = result.map_err(|error| (blobstore_id, error))?;
+                        // Return the on_put handler
+                        let handler = async move {
+                            handler
+                                .on_put(
+                                    &ctx,
+                                    blobstore_id,
+                                    multiplex_id,
+                                    &operation_key,
+                                    &key,
+                                    blob_size,
+                                )
+                                .await
+                                .map_err(|error| (blobstore_id, error))
+                        };
+                        Ok((blobstore_id, order, handler))
+                    }
+                }
+            })
+            .collect();
+
+        async move {
+            spawn_shadow_puts(
+                &ctx,
+                &shadow_blobstores,
+                &key,
+                &shadow_value,
+                request_timeout,
+                &recorder,
+            );
+            let (stats, result) = {
+                let ctx = &ctx;
+                async move {
+                    ctx.perf_counters()
+                        .increment_counter(PerfCounterType::BlobPuts);
+
+                    let mut errors = HashMap::new();
+                    let mut written = 0;
+                    let mut handlers = FuturesUnordered::new();
+                    let mut deferred_handlers = Vec::new();
+                    let mut write_orders = Vec::new();
+
+                    while let Some(result) = select_next(&mut puts, &mut handlers).await {
+                        use Either::*;
+                        match result {
+                            Left(Ok((blobstore_id, order, handler))) => {
+                                write_orders.push((blobstore_id, order));
+                                if defer_handlers {
+                                    deferred_handlers.push(handler);
+                                } else {
+                                    handlers.push(handler);
+                                }
+                                if puts.is_empty() && errors.is_empty() {
+                                    // All puts have succeeded, so there is nothing to heal
+                                    // and the deferred handlers need not run
+                                    if defer_handlers {
+                                        return Ok(write_orders);
+                                    }
+                                    // No errors - we're done, unless we have to wait for a
+                                    // handler, or for a quorum of them
+                                    if !strict_handler && write_quorum == 1 {
+                                        // Spawn off the handlers to ensure that all writes are logged.
+                                        spawn_stream_completion(
+                                            ctx.clone(),
+                                            handlers.map_err(|(_, error)| error),
+                                        );
+                                        return Ok(write_orders);
+                                    }
+                                }
+                            }
+                            Left(Err((blobstore_id, e))) => {
+                                errors.insert(blobstore_id, e);
+                                // A blobstore is missing the blob, so the successful writes
+                                // must be recorded after all
+                                if defer_handlers {
+                                    defer_handlers = false;
+                                    handlers.extend(deferred_handlers.drain(..));
+                                }
+                            }
+                            Right(Ok(())) => {
+                                written += 1;
+                                if written < write_quorum {
+                                    continue;
+                                }
+                                // Enough handlers were successful. Spawn off remaining puts and
+                                // handler writes, then done
+                                spawn_stream_completion(
+                                    ctx.clone(),
+                                    puts.and_then(|(_, _, handler)| handler)
+                                        .map_err(|(_, error)| error),
+                                );
+                                spawn_stream_completion(
+                                    ctx.clone(),
+                                    handlers.map_err(|(_, error)| error),
+                                );
+                                return Ok(write_orders);
+                            }
+                            Right(Err((blobstore_id, e))) => {
+                                errors.insert(blobstore_id, e);
+                            }
+                        }
+                    }
+                    if written == 0 {
+                        Err(ErrorKind::AllPutsFailed(Arc::new(errors)).into())
+                    } else {
+                        Err(ErrorKind::WriteQuorumNotReached(
+                            written,
+                            write_quorum,
+                            Arc::new(errors),
+                        )
+                        .into())
+                    }
+                }
+                .timed()
+                .await
+            };
+
+            ctx.perf_counters().set_max_counter(
+                PerfCounterType::BlobPutsMaxLatency,
+                stats.completion_time.as_millis_unchecked() as i64,
+            );
+            if result.is_ok() {
+                record_event(&recorder, MultiplexEvent::QuorumReached);
+            }
+            result.map(|write_orders| PutReport {
+                operation_key,
+                write_orders,
+            })
         }
+        .boxed()
     }
 
+    /// Remove `key` from all blobstores, reporting which of them removed it, didn't have it,
+    /// or failed, so that callers can confirm complete removal or retry the failures.
+    pub async fn unlink_with_report(&self, ctx: &CoreContext, key: &str) -> UnlinkReport {
+        let results = join_all(self.blobstores.iter().map(|(blobstore_id, blobstore)| {
+            let blobstore_id = *blobstore_id;
+            blobstore
+                .unlink(ctx.clone(), key.to_string())
+                .map(move |result| (blobstore_id, result))
+        }))
+        .await;
+
+        let mut report = UnlinkReport::default();
+        for (blobstore_id, result) in results {
+            match result {
+                Ok(Some(())) => {
+                    report.unlinked.insert(blobstore_id);
+                }
+                Ok(None) => {
+                    report.missing.insert(blobstore_id);
+                }
+                Err(error) => {
+                    report.failed.insert(blobstore_id, error);
+                }
+            }
+        }
+        report
+    }
+
+    /// Fetch `key` from all blobstores and check that they agree. If `limiter` is given, each
+    /// request to an underlying blobstore holds one of its permits, so that a semaphore shared
+    /// between concurrent scrubs caps the total load they put on storage.
     pub async fn scrub_get(
         &self,
         ctx: &CoreContext,
         key: &String,
+        limiter: Option<Arc<Semaphore>>,
+    ) -> Result<Option<BlobstoreGetData>, ErrorKind> {
+        let result = self.scrub_get_raw(ctx, key, limiter).await;
+        self.decode_scrub_result(result)
+    }
+
+    /// Like `scrub_get`, but returns values as they are stored, e.g. to copy them to the
+    /// blobstores that miss them.
+    pub(crate) async fn scrub_get_raw(
+        &self,
+        ctx: &CoreContext,
+        key: &String,
+        limiter: Option<Arc<Semaphore>>,
+    ) -> Result<Option<BlobstoreGetData>, ErrorKind> {
+        self.scrub_get_impl(ctx, key, limiter, MismatchResolution::Error, None)
+            .await
+    }
+
+    /// Like `scrub_get`, but tolerates blobstores that don't have the value yet, e.g. because
+    /// of replication lag: if at least `min_agreeing` blobstores return the same value and
+    /// the others return None, the value is returned as healthy, leaving the missing copies
+    /// to be repaired from the sync queue. Blobstores that return different values are still
+    /// an `ErrorKind::ValueMismatch`, and with fewer agreeing blobstores this fails with
+    /// `ErrorKind::SomeMissingItem` like `scrub_get`.
+    pub async fn scrub_get_with_quorum(
+        &self,
+        ctx: &CoreContext,
+        key: &String,
+        min_agreeing: NonZeroUsize,
+    ) -> Result<Option<BlobstoreGetData>, ErrorKind> {
+        let result = self
+            .scrub_get_impl(
+                ctx,
+                key,
+                None,
+                MismatchResolution::Error,
+                Some(min_agreeing),
+            )
+            .await;
+        self.decode_scrub_result(result)
+    }
+
+    /// Like `scrub_get`, but reports which blobstores disagree instead of returning the value.
+    pub async fn scrub_report(&self, ctx: &CoreContext, key: &String) -> ScrubReport {
+        let mut report = ScrubReport::default();
+        match self.scrub_get_raw(ctx, key, None).await {
+            Ok(_) => {}
+            Err(ErrorKind::ValueMismatch(mismatched, missing, _)) => {
+                report.mismatched = mismatched.as_ref().clone();
+                report.missing = missing.as_ref().clone();
+            }
+            Err(ErrorKind::SomeMissingItem(missing, _)) => {
+                report.missing = missing.as_ref().clone();
+            }
+            Err(ErrorKind::SomeFailedOthersNone(errors)) | Err(ErrorKind::AllFailed(errors)) => {
+                report.failed = errors.keys().cloned().collect();
+            }
+            Err(_) => {
+                report.failed = self.blobstores.iter().map(|(id, _)| *id).collect();
+            }
+        }
+        report
+    }
+
+    /// Run `scrub_report` over a sample of `keys`, and fold the results into a snapshot of
+    /// the consistency of the whole multiplex, e.g. for periodic health audits. At most
+    /// `AUDIT_CONCURRENCY` keys are scrubbed at once.
+    pub async fn audit_sample(&self, ctx: &CoreContext, keys: Vec<String>) -> AuditReport {
+        let mut reports = stream::iter(keys)
+            .map(|key| async move { self.scrub_report(ctx, &key).await })
+            .buffer_unordered(AUDIT_CONCURRENCY);
+        let mut audit = AuditReport::default();
+        while let Some(report) = reports.next().await {
+            audit.add(&report);
+        }
+        audit
+    }
+
+    /// Like `scrub_get`, but picks a value according to `resolution` if blobstores disagree,
+    /// e.g. to keep reads available during a known divergence.
+    pub async fn scrub_get_with_resolution(
+        &self,
+        ctx: &CoreContext,
+        key: &String,
+        resolution: MismatchResolution,
+    ) -> Result<Option<BlobstoreGetData>, ErrorKind> {
+        let result = self.scrub_get_impl(ctx, key, None, resolution, None).await;
+        self.decode_scrub_result(result)
+    }
+
+    async fn scrub_get_impl(
+        &self,
+        ctx: &CoreContext,
+        key: &String,
+        limiter: Option<Arc<Semaphore>>,
+        resolution: MismatchResolution,
+        min_agreeing: Option<NonZeroUsize>,
     ) -> Result<Option<BlobstoreGetData>, ErrorKind> {
         let mut scuba = self.scuba.clone();
         scuba.sampled(self.scuba_sample_rate);
 
-        let results = join_all(multiplexed_get(
-            ctx,
-            self.blobstores.as_ref(),
-            key,
-            OperationType::ScrubGet,
-            scuba,
-        ))
+        let results = join_all(
+            multiplexed_get(
+                ctx,
+                self.blobstores.as_ref(),
+                key,
+                OperationType::ScrubGet,
+                scuba,
+                self.request_timeout,
+                self.checksum,
+            )
+            .map(|get| {
+                cloned!(limiter);
+                async move {
+                    let _permit = match limiter.as_ref() {
+                        Some(limiter) => Some(limiter.acquire().await),
+                        None => None,
+                    };
+                    get.await
+                }
+            }),
+        )
         .await;
 
         let (successes, errors): (HashMap<_, _>, HashMap<_, _>) =
@@ -129,46 +1064,191 @@ impl MultiplexedBlobstoreBase {
 
         let mut best_value = None;
         let mut missing = HashSet::new();
-        let mut answered = HashSet::new();
+        let mut answered = HashMap::new();
         let mut all_same = true;
 
         for (blobstore_id, value) in successes.into_iter() {
-            if value.is_none() {
-                missing.insert(blobstore_id);
-            } else {
-                answered.insert(blobstore_id);
-                if best_value.is_none() {
-                    best_value = value;
-                } else if value.as_ref().map(BlobstoreGetData::as_bytes)
-                    != best_value.as_ref().map(BlobstoreGetData::as_bytes)
-                {
-                    all_same = false;
+            match value {
+                None => {
+                    missing.insert(blobstore_id);
+                }
+                Some(value) => {
+                    if best_value.is_none() {
+                        best_value = Some(value.clone());
+                    } else if Some(value.as_bytes())
+                        != best_value.as_ref().map(BlobstoreGetData::as_bytes)
+                    {
+                        all_same = false;
+                    }
+                    answered.insert(blobstore_id, value);
+                }
+            }
+        }
+
+        if !all_same {
+            match resolve_mismatch(&answered, resolution) {
+                Some(value) => best_value = Some(value),
+                None => {
+                    let mut by_hash = BlobstoresByValueHash::new();
+                    for (blobstore_id, value) in answered.iter() {
+                        by_hash
+                            .entry(value_hash(value))
+                            .or_default()
+                            .insert(*blobstore_id);
+                    }
+                    warn!(
+                        ctx.logger(),
+                        "scrub: blobstores have different values for {}, grouped by value hash: {:?}",
+                        key,
+                        by_hash
+                    );
+                    return Err(ErrorKind::ValueMismatch(
+                        Arc::new(answered.keys().cloned().collect()),
+                        Arc::new(missing),
+                        Arc::new(by_hash),
+                    ));
                 }
             }
         }
 
-        match (all_same, best_value.is_some(), missing.is_empty()) {
-            (false, _, _) => Err(ErrorKind::ValueMismatch(
-                Arc::new(answered),
-                Arc::new(missing),
-            )),
-            (true, false, _) => {
+        match (best_value.is_some(), missing.is_empty()) {
+            (false, _) => {
                 if errors.is_empty() {
                     Ok(None)
                 } else {
                     Err(ErrorKind::SomeFailedOthersNone(errors.into()))
                 }
             }
-            (true, true, false) => Err(ErrorKind::SomeMissingItem(Arc::new(missing), best_value)),
-            (true, true, true) => Ok(best_value),
+            (true, false) => match min_agreeing {
+                // Values only differ here if `all_same` is false, and then the mismatch
+                // was resolved rather than agreed on
+                Some(min_agreeing) if all_same && answered.len() >= min_agreeing.get() => {
+                    Ok(best_value)
+                }
+                _ => Err(ErrorKind::SomeMissingItem(Arc::new(missing), best_value)),
+            },
+            (true, true) => Ok(best_value),
+        }
+    }
+
+    /// Like `get`, but waits for every blobstore to answer and logs each one's outcome
+    /// (value hash, None or error) at debug level before returning the value that arrived
+    /// first. Useful for diagnosing why a read returned what it did.
+    pub async fn get_debug(
+        &self,
+        ctx: &CoreContext,
+        key: &String,
+    ) -> Result<Option<BlobstoreGetData>, ErrorKind> {
+        let mut scuba = self.scuba.clone();
+        scuba.sampled(self.scuba_sample_rate);
+
+        let mut requests: FuturesUnordered<_> = multiplexed_get(
+            ctx,
+            self.blobstores.as_ref(),
+            key,
+            OperationType::Get,
+            scuba,
+            self.request_timeout,
+            self.checksum,
+        )
+        .collect();
+
+        let mut winner = None;
+        let mut errors = HashMap::new();
+        while let Some((blobstore_id, result)) = requests.next().await {
+            match result {
+                Ok(Some(value)) => {
+                    debug!(
+                        ctx.logger(),
+                        "get_debug: blobstore_id {:?} returned value with hash {:016x} for {}",
+                        blobstore_id,
+                        value_hash(&value),
+                        key
+                    );
+                    if winner.is_none() {
+                        winner = Some(value);
+                    }
+                }
+                Ok(None) => {
+                    debug!(
+                        ctx.logger(),
+                        "get_debug: blobstore_id {:?} returned None for {}", blobstore_id, key
+                    );
+                }
+                Err(error) => {
+                    debug!(
+                        ctx.logger(),
+                        "get_debug: blobstore_id {:?} failed for {}: {}", blobstore_id, key, error
+                    );
+                    errors.insert(blobstore_id, error);
+                }
+            }
+        }
+
+        match winner {
+            Some(mut value) => {
+                value.remove_ctime();
+                self.decode_value(Some(value))
+                    .map_err(|error| ErrorKind::DecodeFailed(Arc::new(error)))
+            }
+            None if errors.is_empty() => Ok(None),
+            None if errors.len() == self.blobstores.len() => {
+                Err(ErrorKind::AllFailed(Arc::new(errors)))
+            }
+            None => Err(ErrorKind::SomeFailedOthersNone(Arc::new(errors))),
         }
     }
 }
 
-fn remap_timeout_result<O>(
+fn resolve_mismatch(
+    answered: &HashMap<BlobstoreId, BlobstoreGetData>,
+    resolution: MismatchResolution,
+) -> Option<BlobstoreGetData> {
+    match resolution {
+        MismatchResolution::Error => None,
+        MismatchResolution::PreferStore(blobstore_id) => answered.get(&blobstore_id).cloned(),
+        MismatchResolution::MajorityWins => {
+            // Group byte-identical values, counting how many blobstores hold each
+            let mut groups: Vec<(&BlobstoreGetData, usize)> = Vec::new();
+            for value in answered.values() {
+                match groups
+                    .iter_mut()
+                    .find(|(group, _)| group.as_bytes() == value.as_bytes())
+                {
+                    Some((_, count)) => *count += 1,
+                    None => groups.push((value, 1)),
+                }
+            }
+            groups.sort_by(|(_, a), (_, b)| b.cmp(a));
+            match groups.as_slice() {
+                [(winner, _)] => Some((*winner).clone()),
+                [(winner, first), (_, second), ..] if first > second => Some((*winner).clone()),
+                _ => None,
+            }
+        }
+    }
+}
+
+fn value_hash(value: &BlobstoreGetData) -> u64 {
+    let mut hasher = DefaultHasher::new();
+    value.as_raw_bytes().hash(&mut hasher);
+    hasher.finish()
+}
+
+pub(crate) fn remap_timeout_result<O>(
+    ctx: &CoreContext,
+    blobstore_id: BlobstoreId,
+    key: &str,
+    request_timeout: Duration,
     timeout_or_result: Result<Result<O, Error>, tokio::time::Elapsed>,
 ) -> Result<O, Error> {
-    timeout_or_result.unwrap_or_else(|_| Err(Error::msg("blobstore operation timeout")))
+    timeout_or_result.unwrap_or_else(|_| {
+        warn!(
+            ctx.logger(),
+            "blobstore_id {:?} timed out after {:?} for {}", blobstore_id, request_timeout, key
+        );
+        Err(Error::msg("blobstore operation timeout"))
+    })
 }
 
 pub async fn inner_put(
@@ -179,15 +1259,17 @@ pub async fn inner_put(
     blobstore: &dyn Blobstore,
     key: String,
     value: BlobstoreBytes,
-) -> Result<BlobstoreId, Error> {
+    request_timeout: Duration,
+) -> Result<(BlobstoreId, usize), Error> {
     let size = value.len();
     let (stats, timeout_or_res) = timeout(
-        REQUEST_TIMEOUT,
+        request_timeout,
         blobstore.put(ctx.clone(), key.clone(), value),
     )
     .timed()
     .await;
-    let result = remap_timeout_result(timeout_or_res);
+    let result = remap_timeout_result(ctx, blobstore_id, &key, request_timeout, timeout_or_res);
+    let order = write_order.fetch_add(1, Ordering::Relaxed) + 1;
     record_put_stats(
         &mut scuba,
         stats,
@@ -197,9 +1279,103 @@ pub async fn inner_put(
         OperationType::Put,
         size,
         Some(blobstore_id),
-        Some(write_order.fetch_add(1, Ordering::Relaxed) + 1),
+        Some(order),
     );
-    result.map(|()| blobstore_id)
+    result.map(|()| (blobstore_id, order))
+}
+
+/// Read `key` back from `blobstore` after a put, checking that it returns `expected`.
+async fn verify_put(
+    ctx: &CoreContext,
+    blobstore_id: BlobstoreId,
+    blobstore: &dyn Blobstore,
+    key: String,
+    expected: &BlobstoreBytes,
+    request_timeout: Duration,
+) -> Result<(), Error> {
+    let timeout_or_res = timeout(request_timeout, blobstore.get(ctx.clone(), key.clone())).await;
+    let value = remap_timeout_result(ctx, blobstore_id, &key, request_timeout, timeout_or_res)?;
+    match value {
+        Some(value) if value.as_bytes() == expected => Ok(()),
+        _ => Err(ErrorKind::VerifyAfterWriteFailed(blobstore_id).into()),
+    }
+}
+
+/// Write `value` to each of the shadow blobstores in the background. Failures are only
+/// logged and recorded.
+fn spawn_shadow_puts(
+    ctx: &CoreContext,
+    shadow_blobstores: &[(BlobstoreId, Arc<dyn Blobstore>)],
+    key: &str,
+    value: &BlobstoreBytes,
+    request_timeout: Duration,
+    recorder: &Option<Arc<dyn MultiplexRecorder>>,
+) {
+    for (blobstore_id, blobstore) in shadow_blobstores.iter().cloned() {
+        cloned!(ctx, value, recorder);
+        let key = key.to_string();
+        tokio::spawn(async move {
+            let timeout_or_res = timeout(
+                request_timeout,
+                blobstore.put(ctx.clone(), key.clone(), value),
+            )
+            .await;
+            if let Err(error) =
+                remap_timeout_result(&ctx, blobstore_id, &key, request_timeout, timeout_or_res)
+            {
+                record_event(&recorder, MultiplexEvent::ShadowErrored(blobstore_id));
+                warn!(
+                    ctx.logger(),
+                    "Shadow put of {} to blobstore_id {:?} failed: {:?}", key, blobstore_id, error
+                );
+            }
+        });
+    }
+}
+
+/// Fetch `key` from each of the shadow blobstores in the background, and compare what they
+/// return with `expected`, the raw value returned by the multiplex. Failures and mismatches
+/// are only logged and recorded.
+fn spawn_shadow_gets(
+    ctx: &CoreContext,
+    shadow_blobstores: &[(BlobstoreId, Arc<dyn Blobstore>)],
+    key: &str,
+    expected: Option<BlobstoreBytes>,
+    request_timeout: Duration,
+    recorder: &Option<Arc<dyn MultiplexRecorder>>,
+) {
+    for (blobstore_id, blobstore) in shadow_blobstores.iter().cloned() {
+        cloned!(ctx, expected, recorder);
+        let key = key.to_string();
+        tokio::spawn(async move {
+            let timeout_or_res =
+                timeout(request_timeout, blobstore.get(ctx.clone(), key.clone())).await;
+            match remap_timeout_result(&ctx, blobstore_id, &key, request_timeout, timeout_or_res) {
+                Ok(value) => {
+                    let value = value.map(|value| value.into_bytes());
+                    if value != expected {
+                        record_event(&recorder, MultiplexEvent::ShadowMismatch(blobstore_id));
+                        warn!(
+                            ctx.logger(),
+                            "Shadow blobstore_id {:?} returned a different value for {}",
+                            blobstore_id,
+                            key
+                        );
+                    }
+                }
+                Err(error) => {
+                    record_event(&recorder, MultiplexEvent::ShadowErrored(blobstore_id));
+                    warn!(
+                        ctx.logger(),
+                        "Shadow get of {} from blobstore_id {:?} failed: {:?}",
+                        key,
+                        blobstore_id,
+                        error
+                    );
+                }
+            }
+        });
+    }
 }
 
 // Workaround for Blobstore returning a static lifetime future
@@ -208,6 +1384,11 @@ async fn blobstore_get(
     blobstores: Arc<[(BlobstoreId, Arc<dyn Blobstore>)]>,
     key: String,
     scuba: ScubaSampleBuilder,
+    first_stage: usize,
+    grace_period: Duration,
+    request_timeout: Duration,
+    checksum: bool,
+    recorder: Option<Arc<dyn MultiplexRecorder>>,
 ) -> Result<Option<BlobstoreGetData>, Error> {
     let is_logged = scuba.sampling().is_logged();
     let blobstores_count = blobstores.len();
@@ -219,17 +1400,66 @@ async fn blobstore_get(
             ctx.perf_counters()
                 .increment_counter(PerfCounterType::BlobGets);
 
+            let (first, mut rest) = blobstores.split_at(first_stage.min(blobstores_count));
+            for (blobstore_id, _) in first {
+                record_event(&recorder, MultiplexEvent::StoreContacted(*blobstore_id));
+            }
             let mut requests: FuturesUnordered<_> = multiplexed_get(
                 ctx.clone(),
-                blobstores.as_ref(),
+                first,
                 &key,
                 OperationType::Get,
-                scuba,
+                scuba.clone(),
+                request_timeout,
+                checksum,
             )
             .collect();
-            while let Some(result) = requests.next().await {
+            let grace = tokio::time::delay_for(grace_period);
+            futures::pin_mut!(grace);
+
+            loop {
+                let result = if rest.is_empty() {
+                    requests.next().await
+                } else {
+                    match select(requests.next(), grace.as_mut()).await {
+                        FutureEither::Left((Some(result), _)) => Some(result),
+                        // Either the first stage found nothing, or it is taking too long:
+                        // query the remaining blobstores as well
+                        FutureEither::Left((None, _)) | FutureEither::Right(_) => {
+                            for (blobstore_id, _) in rest {
+                                record_event(
+                                    &recorder,
+                                    MultiplexEvent::StoreContacted(*blobstore_id),
+                                );
+                            }
+                            requests.extend(multiplexed_get(
+                                ctx.clone(),
+                                rest,
+                                &key,
+                                OperationType::Get,
+                                scuba.clone(),
+                                request_timeout,
+                                checksum,
+                            ));
+                            rest = &[];
+                            continue;
+                        }
+                    }
+                };
+                let result = match result {
+                    Some(result) => result,
+                    None => break,
+                };
                 match result {
-                    (_, Ok(Some(mut value))) => {
+                    (blobstore_id, Ok(Some(mut value))) => {
+                        record_event(&recorder, MultiplexEvent::StoreReturnedValue(blobstore_id));
+                        record_event(&recorder, MultiplexEvent::QuorumReached);
+                        // Which replica served the (last) get, for latency debugging. This is
+                        // offset by one, as a counter of 0 is not logged and means no winner.
+                        ctx.perf_counters().set_counter(
+                            PerfCounterType::BlobGetsWinner,
+                            blobstore_id.id() as i64 + 1,
+                        );
                         if is_logged {
                             // Allow the other requests to complete so that we can record some
                             // metrics for the blobstore.
@@ -240,14 +1470,18 @@ async fn blobstore_get(
                         return Ok(Some(value));
                     }
                     (blobstore_id, Err(error)) => {
+                        record_event(&recorder, MultiplexEvent::StoreErrored(blobstore_id));
                         errors.insert(blobstore_id, error);
                     }
-                    (_, Ok(None)) => (),
+                    (blobstore_id, Ok(None)) => {
+                        record_event(&recorder, MultiplexEvent::StoreReturnedNone(blobstore_id));
+                    }
                 }
             }
 
             if errors.is_empty() {
                 // All blobstores must have returned None, as Some would have triggered a return,
+                record_event(&recorder, MultiplexEvent::QuorumReached);
                 Ok(None)
             } else {
                 if errors.len() == blobstores_count {
@@ -268,8 +1502,20 @@ async fn blobstore_get(
     Ok(result?)
 }
 
-fn spawn_stream_completion(s: impl StreamExt + Send + 'static) {
-    tokio::spawn(s.for_each(|_| async {}));
+/// Run `s` to completion in the background, logging its errors as nobody is waiting for them.
+fn spawn_stream_completion<T>(
+    ctx: CoreContext,
+    s: impl Stream<Item = Result<T, Error>> + Send + 'static,
+) {
+    tokio::spawn(s.for_each(move |result| {
+        if let Err(error) = result {
+            warn!(
+                ctx.logger(),
+                "Background multiplexed put or put handler failed: {:?}", error
+            );
+        }
+        async {}
+    }));
 }
 
 async fn select_next<F1: Future, F2: Future>(
@@ -306,9 +1552,41 @@ impl Blobstore for MultiplexedBlobstoreBase {
     ) -> BoxFuture<'static, Result<Option<BlobstoreGetData>, Error>> {
         let mut scuba = self.scuba.clone();
         let blobstores = self.blobstores.clone();
+        let first_stage = self.get_first_stage;
+        let grace_period = self.get_grace_period;
+        let request_timeout = self.request_timeout;
+        let shadow_blobstores = self.shadow_blobstores.clone();
+        let recorder = self.recorder.clone();
+        let compress = self.compress;
+        let checksum = self.checksum;
         scuba.sampled(self.scuba_sample_rate);
 
-        async move { blobstore_get(ctx, blobstores, key, scuba).await }.boxed()
+        async move {
+            let value = blobstore_get(
+                ctx.clone(),
+                blobstores,
+                key.clone(),
+                scuba,
+                first_stage,
+                grace_period,
+                request_timeout,
+                checksum,
+                recorder.clone(),
+            )
+            .await?;
+            if !shadow_blobstores.is_empty() {
+                spawn_shadow_gets(
+                    &ctx,
+                    &shadow_blobstores,
+                    &key,
+                    value.as_ref().map(|value| value.as_bytes().clone()),
+                    request_timeout,
+                    &recorder,
+                );
+            }
+            decompress_if(compress, strip_checksum_if(checksum, value))
+        }
+        .boxed()
     }
 
     fn put(
@@ -317,110 +1595,19 @@ impl Blobstore for MultiplexedBlobstoreBase {
         key: String,
         value: BlobstoreBytes,
     ) -> BoxFuture<'static, Result<(), Error>> {
-        let write_order = Arc::new(AtomicUsize::new(0));
-        let operation_key = OperationKey::gen();
-
-        let mut puts: FuturesUnordered<_> = self
-            .blobstores
-            .iter()
-            .cloned()
-            .map({
-                |(blobstore_id, blobstore)| {
-                    cloned!(
-                        self.handler,
-                        self.multiplex_id,
-                        self.scuba,
-                        ctx,
-                        write_order,
-                        key,
-                        value,
-                        operation_key
-                    );
-                    async move {
-                        inner_put(
-                            &ctx,
-                            scuba,
-                            write_order.as_ref(),
-                            blobstore_id,
-                            blobstore.as_ref(),
-                            key.clone(),
-                            value,
-                        )
-                        .await?;
-                        // Return the on_put handler
-                        Ok(async move {
-                            handler
-                                .on_put(&ctx, blobstore_id, multiplex_id, &operation_key, &key)
-                                .await
-                        })
-                    }
-                }
-            })
-            .collect();
-
-        async move {
-            let (stats, result) = {
-                let ctx = &ctx;
-                async move {
-                    ctx.perf_counters()
-                        .increment_counter(PerfCounterType::BlobPuts);
-
-                    // TODO: Gather all the errors for presentation to the user in a failure case
-                    let mut last_err = None;
-                    let mut handlers = FuturesUnordered::new();
-
-                    while let Some(result) = select_next(&mut puts, &mut handlers).await {
-                        use Either::*;
-                        match result {
-                            Left(Ok(handler)) => {
-                                handlers.push(handler);
-                                // All puts have succeeded, no errors - we're done
-                                if puts.is_empty() && last_err.is_none() {
-                                    // Spawn off the handlers to ensure that all writes are logged.
-                                    spawn_stream_completion(handlers);
-                                    return Ok(());
-                                }
-                            }
-                            Left(Err(e)) => last_err = Some(e),
-                            Right(Ok(())) => {
-                                // A handler was successful. Spawn off remaining puts and handler
-                                // writes, then done
-                                spawn_stream_completion(puts.and_then(|handler| handler));
-                                spawn_stream_completion(handlers);
-                                return Ok(());
-                            }
-                            Right(Err(e)) => last_err = Some(e),
-                        }
-                    }
-                    // Unwrap is safe here, because the only way to get here is if there's an Error above
-                    Err(last_err.unwrap())
-                }
-                .timed()
-                .await
-            };
-
-            ctx.perf_counters().set_max_counter(
-                PerfCounterType::BlobPutsMaxLatency,
-                stats.completion_time.as_millis_unchecked() as i64,
-            );
-            result
-        }
-        .boxed()
+        self.put_with_report(ctx, key, value).map_ok(|_| ()).boxed()
     }
 
     fn is_present(&self, ctx: CoreContext, key: String) -> BoxFuture<'static, Result<bool, Error>> {
         let blobstores_count = self.blobstores.len();
-
-        let mut requests: FuturesUnordered<_> = self
-            .blobstores
-            .iter()
-            .cloned()
-            .map(|(blobstore_id, blobstore)| {
-                let ctx = ctx.clone();
-                let key = key.clone();
-                async move { (blobstore_id, blobstore.is_present(ctx, key).await) }
-            })
-            .collect();
+        let local_blobstores = self.local_blobstores.clone();
+        let (local, remote): (Vec<_>, Vec<_>) =
+            self.blobstores
+                .iter()
+                .cloned()
+                .partition(|(blobstore_id, _)| {
+                    local_blobstores.is_empty() || local_blobstores.contains(blobstore_id)
+                });
 
         async move {
             let (stats, result) = {
@@ -429,15 +1616,9 @@ impl Blobstore for MultiplexedBlobstoreBase {
                     let mut errors = HashMap::new();
                     ctx.perf_counters()
                         .increment_counter(PerfCounterType::BlobPresenceChecks);
-                    while let Some(result) = requests.next().await {
-                        match result {
-                            (_, Ok(true)) => {
-                                return Ok(true);
-                            }
-                            (blobstore_id, Err(error)) => {
-                                errors.insert(blobstore_id, error);
-                            }
-                            (_, Ok(false)) => (),
+                    for stage in vec![local, remote] {
+                        if is_present_in(ctx, stage, &key, &mut errors).await {
+                            return Ok(true);
                         }
                     }
                     if errors.is_empty() {
@@ -461,6 +1642,56 @@ impl Blobstore for MultiplexedBlobstoreBase {
         }
         .boxed()
     }
+
+    fn unlink(
+        &self,
+        ctx: CoreContext,
+        key: String,
+    ) -> BoxFuture<'static, Result<Option<()>, Error>> {
+        let this = self.clone();
+        async move {
+            let report = this.unlink_with_report(&ctx, &key).await;
+            if !report.failed.is_empty() {
+                return Err(ErrorKind::SomeUnlinksFailed(Arc::new(report.failed)).into());
+            }
+            if report.unlinked.is_empty() {
+                Ok(None)
+            } else {
+                Ok(Some(()))
+            }
+        }
+        .boxed()
+    }
+}
+
+/// Ask all of `blobstores` at once whether `key` is present, returning as soon as one of them
+/// has it. Errors are added to `errors`.
+async fn is_present_in(
+    ctx: &CoreContext,
+    blobstores: Vec<(BlobstoreId, Arc<dyn Blobstore>)>,
+    key: &str,
+    errors: &mut HashMap<BlobstoreId, Error>,
+) -> bool {
+    let mut requests: FuturesUnordered<_> = blobstores
+        .into_iter()
+        .map(|(blobstore_id, blobstore)| {
+            let ctx = ctx.clone();
+            let key = key.to_string();
+            async move { (blobstore_id, blobstore.is_present(ctx, key).await) }
+        })
+        .collect();
+    while let Some(result) = requests.next().await {
+        match result {
+            (_, Ok(true)) => {
+                return true;
+            }
+            (blobstore_id, Err(error)) => {
+                errors.insert(blobstore_id, error);
+            }
+            (_, Ok(false)) => (),
+        }
+    }
+    false
 }
 
 impl fmt::Debug for MultiplexedBlobstoreBase {
@@ -483,14 +1714,30 @@ async fn multiplexed_get_one(
     key: String,
     operation: OperationType,
     mut scuba: ScubaSampleBuilder,
+    request_timeout: Duration,
+    checksum: bool,
 ) -> (BlobstoreId, Result<Option<BlobstoreGetData>, Error>) {
     let (stats, timeout_or_res) = timeout(
-        REQUEST_TIMEOUT,
+        request_timeout,
         blobstore.get(ctx.borrow().clone(), key.clone()),
     )
     .timed()
     .await;
-    let result = remap_timeout_result(timeout_or_res);
+    let result = remap_timeout_result(
+        ctx.borrow(),
+        blobstore_id,
+        &key,
+        request_timeout,
+        timeout_or_res,
+    );
+    // The checksum is checked here rather than when decoding, so that a corrupt value only
+    // fails this blobstore, and the others can still answer
+    let result = match result {
+        Ok(Some(value)) if checksum && !has_valid_checksum(&value) => {
+            Err(ErrorKind::ChecksumMismatch(blobstore_id).into())
+        }
+        result => result,
+    };
     record_get_stats(
         &mut scuba,
         stats,
@@ -509,6 +1756,8 @@ fn multiplexed_get<'fut: 'iter, 'iter>(
     key: &'iter String,
     operation: OperationType,
     scuba: ScubaSampleBuilder,
+    request_timeout: Duration,
+    checksum: bool,
 ) -> impl Iterator<
     Item = impl Future<Output = (BlobstoreId, Result<Option<BlobstoreGetData>, Error>)> + 'fut,
 > + 'iter {
@@ -520,6 +1769,8 @@ fn multiplexed_get<'fut: 'iter, 'iter>(
             key.clone(),
             operation,
             scuba.clone(),
+            request_timeout,
+            checksum,
         )
     })
 }
@@ -0,0 +1,89 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use blobstore::BlobstoreGetData;
+use mononoke_types::{hash::Context, BlobstoreBytes};
+
+/// Marks blobs that end in a checksum footer written by the multiplexer: the content, then
+/// its hash, then this marker. Blobs without it were written without a checksum and are
+/// returned as they are.
+const CHECKSUM_MARKER: &[u8] = b"\0mplxc1\0";
+const CHECKSUM_LEN: usize = 32;
+
+fn checksum(content: &[u8]) -> Vec<u8> {
+    let mut context = Context::new(b"multiplexedblob");
+    context.update(content);
+    context.finish().as_ref().to_vec()
+}
+
+/// Split a blob into its content and checksum, if it has a checksum footer.
+fn split_footer(bytes: &[u8]) -> Option<(&[u8], &[u8])> {
+    if !bytes.ends_with(CHECKSUM_MARKER) || bytes.len() < CHECKSUM_LEN + CHECKSUM_MARKER.len() {
+        return None;
+    }
+    let bytes = &bytes[..bytes.len() - CHECKSUM_MARKER.len()];
+    Some(bytes.split_at(bytes.len() - CHECKSUM_LEN))
+}
+
+/// Whether `bytes` ends in something that is one byte away from the checksum marker, e.g. a
+/// marker with a rotted byte or of an unknown version. Such a footer can't be told apart
+/// from a damaged one, so it must not be taken for a blob written without a checksum.
+fn has_damaged_marker(bytes: &[u8]) -> bool {
+    if bytes.len() < CHECKSUM_LEN + CHECKSUM_MARKER.len() {
+        return false;
+    }
+    let marker = &bytes[bytes.len() - CHECKSUM_MARKER.len()..];
+    marker
+        .iter()
+        .zip(CHECKSUM_MARKER)
+        .filter(|(actual, expected)| actual != expected)
+        .count()
+        == 1
+}
+
+/// Append a checksum footer to `value`.
+pub(crate) fn add_checksum(value: &BlobstoreBytes) -> BlobstoreBytes {
+    let content = value.as_bytes();
+    let mut bytes = Vec::with_capacity(content.len() + CHECKSUM_LEN + CHECKSUM_MARKER.len());
+    bytes.extend_from_slice(content);
+    bytes.extend_from_slice(&checksum(content));
+    bytes.extend_from_slice(CHECKSUM_MARKER);
+    BlobstoreBytes::from_bytes(bytes)
+}
+
+/// Whether `value` matches its checksum footer. Values without a footer can't be checked,
+/// and are taken to be fine, unless their footer looks like a damaged or unknown marker.
+pub(crate) fn has_valid_checksum(value: &BlobstoreGetData) -> bool {
+    let bytes = value.as_raw_bytes();
+    match split_footer(bytes) {
+        Some((content, expected)) => checksum(content) == expected,
+        None => !has_damaged_marker(bytes),
+    }
+}
+
+/// Like `strip_checksum`, but only if `checksummed` is set, e.g. because checksums are
+/// enabled.
+pub(crate) fn strip_checksum_if(
+    checksummed: bool,
+    value: Option<BlobstoreGetData>,
+) -> Option<BlobstoreGetData> {
+    match value {
+        Some(value) if checksummed => Some(strip_checksum(value)),
+        value => value,
+    }
+}
+
+/// Undo `add_checksum`, passing through values that were stored without one. This doesn't
+/// check the checksum, see `has_valid_checksum`.
+pub(crate) fn strip_checksum(value: BlobstoreGetData) -> BlobstoreGetData {
+    let content_len = match split_footer(value.as_raw_bytes()) {
+        Some((content, _)) => content.len(),
+        None => return value,
+    };
+    let content = value.as_raw_bytes().slice(..content_len);
+    BlobstoreGetData::new(value.as_meta().clone(), BlobstoreBytes::from_bytes(content))
+}
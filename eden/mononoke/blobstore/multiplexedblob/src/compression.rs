@@ -0,0 +1,50 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use anyhow::Error;
+use blobstore::BlobstoreGetData;
+use mononoke_types::BlobstoreBytes;
+use std::io::Cursor;
+
+/// Marks blobs that were compressed by the multiplexer. Blobs without it were written
+/// uncompressed and are returned as they are.
+const COMPRESSED_HEADER: &[u8] = b"\0mplxz1\0";
+
+/// Compress `value` with zstd, prefixed with `COMPRESSED_HEADER`.
+pub(crate) fn compress(value: &BlobstoreBytes) -> Result<BlobstoreBytes, Error> {
+    let mut compressed = COMPRESSED_HEADER.to_vec();
+    zstd::stream::copy_encode(
+        Cursor::new(value.as_bytes()),
+        &mut compressed,
+        0, /* use default */
+    )?;
+    Ok(BlobstoreBytes::from_bytes(compressed))
+}
+
+/// Like `decompress`, but only if `compressed` is set, e.g. because compression is enabled.
+pub(crate) fn decompress_if(
+    compressed: bool,
+    value: Option<BlobstoreGetData>,
+) -> Result<Option<BlobstoreGetData>, Error> {
+    match value {
+        Some(value) if compressed => decompress(value).map(Some),
+        value => Ok(value),
+    }
+}
+
+/// Undo `compress`, passing through values that were stored uncompressed.
+pub(crate) fn decompress(value: BlobstoreGetData) -> Result<BlobstoreGetData, Error> {
+    let bytes = value.as_raw_bytes();
+    if !bytes.starts_with(COMPRESSED_HEADER) {
+        return Ok(value);
+    }
+    let decompressed = zstd::decode_all(Cursor::new(&bytes[COMPRESSED_HEADER.len()..]))?;
+    Ok(BlobstoreGetData::new(
+        value.as_meta().clone(),
+        BlobstoreBytes::from_bytes(decompressed),
+    ))
+}
@@ -8,6 +8,8 @@
 #![deny(warnings)]
 
 pub mod base;
+mod checksum;
+mod compression;
 pub mod queue;
 pub mod scrub;
 
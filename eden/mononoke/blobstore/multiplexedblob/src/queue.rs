@@ -5,7 +5,11 @@
  * GNU General Public License version 2.
  */
 
-use crate::base::{ErrorKind, MultiplexedBlobstoreBase, MultiplexedBlobstorePutHandler};
+use crate::base::{
+    AuditReport, ErrorKind, MultiplexDescription, MultiplexRecorder, MultiplexedBlobstoreBase,
+    MultiplexedBlobstorePutHandler, NilOperationKeyHandling, PutReport, ReadStrategy, UnlinkReport,
+    DEFAULT_REQUEST_TIMEOUT,
+};
 use anyhow::Error;
 use blobstore::{Blobstore, BlobstoreGetData};
 use blobstore_sync_queue::{BlobstoreSyncQueue, BlobstoreSyncQueueEntry, OperationKey};
@@ -15,9 +19,11 @@ use futures::future::{BoxFuture, FutureExt};
 use metaconfig_types::{BlobstoreId, MultiplexId};
 use mononoke_types::{BlobstoreBytes, DateTime};
 use scuba::ScubaSampleBuilder;
+use std::collections::HashSet;
 use std::fmt;
-use std::num::NonZeroU64;
+use std::num::{NonZeroU64, NonZeroUsize};
 use std::sync::Arc;
+use std::time::Duration;
 
 #[derive(Clone)]
 pub struct MultiplexedBlobstore {
@@ -32,21 +38,244 @@ impl MultiplexedBlobstore {
         queue: Arc<dyn BlobstoreSyncQueue>,
         scuba: ScubaSampleBuilder,
         scuba_sample_rate: NonZeroU64,
+    ) -> Self {
+        Self::new_with_request_timeout(
+            multiplex_id,
+            blobstores,
+            queue,
+            scuba,
+            scuba_sample_rate,
+            DEFAULT_REQUEST_TIMEOUT,
+        )
+    }
+
+    /// See `MultiplexedBlobstoreBase::new_with_request_timeout`.
+    pub fn new_with_request_timeout(
+        multiplex_id: MultiplexId,
+        blobstores: Vec<(BlobstoreId, Arc<dyn Blobstore>)>,
+        queue: Arc<dyn BlobstoreSyncQueue>,
+        scuba: ScubaSampleBuilder,
+        scuba_sample_rate: NonZeroU64,
+        request_timeout: Duration,
     ) -> Self {
         let put_handler = Arc::new(QueueBlobstorePutHandler {
             queue: queue.clone(),
         });
         Self {
-            blobstore: Arc::new(MultiplexedBlobstoreBase::new(
+            blobstore: Arc::new(MultiplexedBlobstoreBase::new_with_request_timeout(
                 multiplex_id,
                 blobstores,
                 put_handler,
                 scuba,
                 scuba_sample_rate,
+                request_timeout,
             )),
             queue,
         }
     }
+
+    /// See `MultiplexedBlobstoreBase::with_strict_handler`.
+    pub fn with_strict_handler(self, strict_handler: bool) -> Self {
+        Self {
+            blobstore: Arc::new(
+                self.blobstore
+                    .as_ref()
+                    .clone()
+                    .with_strict_handler(strict_handler),
+            ),
+            ..self
+        }
+    }
+
+    /// See `MultiplexedBlobstoreBase::with_staged_get`.
+    pub fn with_staged_get(self, first_stage: usize, grace_period: Duration) -> Self {
+        Self {
+            blobstore: Arc::new(
+                self.blobstore
+                    .as_ref()
+                    .clone()
+                    .with_staged_get(first_stage, grace_period),
+            ),
+            ..self
+        }
+    }
+
+    /// See `MultiplexedBlobstoreBase::with_read_strategy`.
+    pub fn with_read_strategy(self, read_strategy: ReadStrategy) -> Self {
+        Self {
+            blobstore: Arc::new(
+                self.blobstore
+                    .as_ref()
+                    .clone()
+                    .with_read_strategy(read_strategy),
+            ),
+            ..self
+        }
+    }
+
+    /// See `MultiplexedBlobstoreBase::with_local_blobstores`.
+    pub fn with_local_blobstores(self, local_blobstores: HashSet<BlobstoreId>) -> Self {
+        Self {
+            blobstore: Arc::new(
+                self.blobstore
+                    .as_ref()
+                    .clone()
+                    .with_local_blobstores(local_blobstores),
+            ),
+            ..self
+        }
+    }
+
+    /// See `MultiplexedBlobstoreBase::with_primary_blobstores`.
+    pub fn with_primary_blobstores(self, primary_blobstores: HashSet<BlobstoreId>) -> Self {
+        Self {
+            blobstore: Arc::new(
+                self.blobstore
+                    .as_ref()
+                    .clone()
+                    .with_primary_blobstores(primary_blobstores),
+            ),
+            ..self
+        }
+    }
+
+    /// See `MultiplexedBlobstoreBase::with_verify_after_write`.
+    pub fn with_verify_after_write(self, verify_after_write: bool) -> Self {
+        Self {
+            blobstore: Arc::new(
+                self.blobstore
+                    .as_ref()
+                    .clone()
+                    .with_verify_after_write(verify_after_write),
+            ),
+            ..self
+        }
+    }
+
+    /// See `MultiplexedBlobstoreBase::with_skip_handlers_on_success`.
+    pub fn with_skip_handlers_on_success(self, skip_handlers_on_success: bool) -> Self {
+        Self {
+            blobstore: Arc::new(
+                self.blobstore
+                    .as_ref()
+                    .clone()
+                    .with_skip_handlers_on_success(skip_handlers_on_success),
+            ),
+            ..self
+        }
+    }
+
+    /// See `MultiplexedBlobstoreBase::with_nil_operation_key`.
+    pub fn with_nil_operation_key(self, nil_operation_key: NilOperationKeyHandling) -> Self {
+        Self {
+            blobstore: Arc::new(
+                self.blobstore
+                    .as_ref()
+                    .clone()
+                    .with_nil_operation_key(nil_operation_key),
+            ),
+            ..self
+        }
+    }
+
+    /// See `MultiplexedBlobstoreBase::with_compression`.
+    pub fn with_compression(self, compress: bool) -> Self {
+        Self {
+            blobstore: Arc::new(self.blobstore.as_ref().clone().with_compression(compress)),
+            ..self
+        }
+    }
+
+    /// See `MultiplexedBlobstoreBase::with_checksum`.
+    pub fn with_checksum(self, checksum: bool) -> Self {
+        Self {
+            blobstore: Arc::new(self.blobstore.as_ref().clone().with_checksum(checksum)),
+            ..self
+        }
+    }
+
+    /// See `MultiplexedBlobstoreBase::with_shadow_blobstores`.
+    pub fn with_shadow_blobstores(
+        self,
+        shadow_blobstores: Vec<(BlobstoreId, Arc<dyn Blobstore>)>,
+    ) -> Self {
+        Self {
+            blobstore: Arc::new(
+                self.blobstore
+                    .as_ref()
+                    .clone()
+                    .with_shadow_blobstores(shadow_blobstores),
+            ),
+            ..self
+        }
+    }
+
+    /// See `MultiplexedBlobstoreBase::with_write_quorum`.
+    pub fn with_write_quorum(self, write_quorum: NonZeroUsize) -> Result<Self, Error> {
+        Ok(Self {
+            blobstore: Arc::new(
+                self.blobstore
+                    .as_ref()
+                    .clone()
+                    .with_write_quorum(write_quorum)?,
+            ),
+            ..self
+        })
+    }
+
+    /// See `MultiplexedBlobstoreBase::with_recorder`.
+    pub fn with_recorder(self, recorder: Arc<dyn MultiplexRecorder>) -> Self {
+        Self {
+            blobstore: Arc::new(self.blobstore.as_ref().clone().with_recorder(recorder)),
+            ..self
+        }
+    }
+
+    /// See `MultiplexedBlobstoreBase::describe`.
+    pub fn describe(&self) -> MultiplexDescription {
+        self.blobstore.describe()
+    }
+
+    /// See `MultiplexedBlobstoreBase::put_with_report`.
+    pub fn put_with_report(
+        &self,
+        ctx: CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> BoxFuture<'static, Result<PutReport, Error>> {
+        self.blobstore.put_with_report(ctx, key, value)
+    }
+
+    /// See `MultiplexedBlobstoreBase::unlink_with_report`.
+    pub async fn unlink_with_report(&self, ctx: &CoreContext, key: &str) -> UnlinkReport {
+        self.blobstore.unlink_with_report(ctx, key).await
+    }
+
+    /// See `MultiplexedBlobstoreBase::audit_sample`.
+    pub async fn audit_sample(&self, ctx: &CoreContext, keys: Vec<String>) -> AuditReport {
+        self.blobstore.audit_sample(ctx, keys).await
+    }
+
+    /// See `MultiplexedBlobstoreBase::put_batch`.
+    pub async fn put_batch(
+        &self,
+        ctx: CoreContext,
+        items: Vec<(String, BlobstoreBytes)>,
+    ) -> Result<(), Error> {
+        self.blobstore.put_batch(ctx, items).await
+    }
+
+    /// See `MultiplexedBlobstoreBase::put_with_operation_key`.
+    pub fn put_with_operation_key(
+        &self,
+        ctx: CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+        operation_key: OperationKey,
+    ) -> BoxFuture<'static, Result<PutReport, Error>> {
+        self.blobstore
+            .put_with_operation_key(ctx, key, value, operation_key)
+    }
 }
 
 impl fmt::Debug for MultiplexedBlobstore {
@@ -69,6 +298,7 @@ impl MultiplexedBlobstorePutHandler for QueueBlobstorePutHandler {
         multiplex_id: MultiplexId,
         operation_key: &'out OperationKey,
         key: &'out str,
+        blob_size: u64,
     ) -> BoxFuture<'out, Result<(), Error>> {
         self.queue.add(
             ctx.clone(),
@@ -78,7 +308,8 @@ impl MultiplexedBlobstorePutHandler for QueueBlobstorePutHandler {
                 multiplex_id,
                 DateTime::now(),
                 operation_key.clone(),
-            ),
+            )
+            .with_blob_size(blob_size),
         )
     }
 }
@@ -149,4 +380,12 @@ impl Blobstore for MultiplexedBlobstore {
         }
         .boxed()
     }
+
+    fn unlink(
+        &self,
+        ctx: CoreContext,
+        key: String,
+    ) -> BoxFuture<'static, Result<Option<()>, Error>> {
+        self.blobstore.unlink(ctx, key)
+    }
 }
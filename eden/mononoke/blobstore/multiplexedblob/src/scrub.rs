@@ -6,7 +6,7 @@
  */
 
 use crate::{
-    base::{inner_put, ErrorKind, MultiplexedBlobstoreBase},
+    base::{inner_put, ErrorKind, MultiplexedBlobstoreBase, MULTIPLEX_ID},
     queue::MultiplexedBlobstore,
 };
 
@@ -27,6 +27,8 @@ use std::collections::HashMap;
 use std::fmt;
 use std::num::NonZeroU64;
 use std::sync::{atomic::AtomicUsize, Arc};
+use std::time::Duration;
+use tokio::sync::Semaphore;
 
 pub trait ScrubHandler: Send + Sync {
     /// Called when one of the inner stores required repair.
@@ -83,6 +85,7 @@ pub struct ScrubBlobstore {
     scuba: ScubaSampleBuilder,
     scrub_stores: Arc<HashMap<BlobstoreId, Arc<dyn Blobstore>>>,
     queue: Arc<dyn BlobstoreSyncQueue>,
+    scrub_limiter: Option<Arc<Semaphore>>,
 }
 
 impl ScrubBlobstore {
@@ -90,7 +93,7 @@ impl ScrubBlobstore {
         multiplex_id: MultiplexId,
         blobstores: Vec<(BlobstoreId, Arc<dyn Blobstore>)>,
         queue: Arc<dyn BlobstoreSyncQueue>,
-        scuba: ScubaSampleBuilder,
+        mut scuba: ScubaSampleBuilder,
         scuba_sample_rate: NonZeroU64,
         scrub_handler: Arc<dyn ScrubHandler>,
         scrub_action: ScrubAction,
@@ -102,6 +105,8 @@ impl ScrubBlobstore {
             scuba.clone(),
             scuba_sample_rate,
         );
+        // This copy logs repairs, the inner blobstore adds the id to its own copy
+        scuba.add(MULTIPLEX_ID, multiplex_id);
         Self {
             inner,
             scrub_handler,
@@ -113,6 +118,24 @@ impl ScrubBlobstore {
                     .collect::<HashMap<BlobstoreId, Arc<dyn Blobstore>>>(),
             ),
             queue,
+            scrub_limiter: None,
+        }
+    }
+
+    /// Share `scrub_limiter` between all gets, so that at most as many requests as it has
+    /// permits are in flight to the underlying blobstores at once.
+    pub fn with_scrub_limiter(self, scrub_limiter: Arc<Semaphore>) -> Self {
+        Self {
+            scrub_limiter: Some(scrub_limiter),
+            ..self
+        }
+    }
+
+    /// See `MultiplexedBlobstoreBase::with_strict_handler`.
+    pub fn with_strict_handler(self, strict_handler: bool) -> Self {
+        Self {
+            inner: self.inner.with_strict_handler(strict_handler),
+            ..self
         }
     }
 }
@@ -134,6 +157,7 @@ async fn put_and_mark_repaired(
     store: &dyn Blobstore,
     key: &String,
     value: &BlobstoreGetData,
+    request_timeout: Duration,
     scrub_handler: &dyn ScrubHandler,
 ) {
     let res = inner_put(
@@ -144,6 +168,7 @@ async fn put_and_mark_repaired(
         store,
         key.clone(),
         value.as_bytes().clone(),
+        request_timeout,
     )
     .await;
     scrub_handler.on_repair(&ctx, id, &key, res.is_ok(), value.as_meta());
@@ -159,8 +184,12 @@ async fn blobstore_get(
     scrub_handler: &dyn ScrubHandler,
     scrub_action: ScrubAction,
     scuba: ScubaSampleBuilder,
+    scrub_limiter: Option<Arc<Semaphore>>,
 ) -> Result<Option<BlobstoreGetData>, Error> {
-    match inner_blobstore.scrub_get(ctx, &key).await {
+    match inner_blobstore
+        .scrub_get_raw(ctx, &key, scrub_limiter)
+        .await
+    {
         Ok(value) => return Ok(value),
         Err(error) => match error {
             ErrorKind::SomeFailedOthersNone(_) => {
@@ -212,6 +241,7 @@ async fn blobstore_get(
                                 store,
                                 &key,
                                 &value,
+                                inner_blobstore.request_timeout(),
                                 scrub_handler,
                             )
                         })
@@ -239,11 +269,13 @@ impl Blobstore for ScrubBlobstore {
             self.scuba,
             self.scrub_action,
             self.queue,
+            self.scrub_limiter,
         );
         let inner_blobstore = self.inner.blobstore.clone();
 
         async move {
-            blobstore_get(
+            // Repairs copy values as they are stored, so only decode what is returned
+            let value = blobstore_get(
                 inner_blobstore.as_ref(),
                 &ctx,
                 key,
@@ -252,8 +284,10 @@ impl Blobstore for ScrubBlobstore {
                 scrub_handler.as_ref(),
                 scrub_action,
                 scuba,
+                scrub_limiter,
             )
-            .await
+            .await?;
+            inner_blobstore.decode_value(value)
         }
         .boxed()
     }
@@ -270,4 +304,12 @@ impl Blobstore for ScrubBlobstore {
     fn is_present(&self, ctx: CoreContext, key: String) -> BoxFuture<'static, Result<bool, Error>> {
         self.inner.is_present(ctx, key)
     }
+
+    fn unlink(
+        &self,
+        ctx: CoreContext,
+        key: String,
+    ) -> BoxFuture<'static, Result<Option<()>, Error>> {
+        self.inner.unlink(ctx, key)
+    }
 }
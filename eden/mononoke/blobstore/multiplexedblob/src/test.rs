@@ -6,14 +6,24 @@
  */
 
 use std::{
-    collections::{HashMap, VecDeque},
+    collections::{HashMap, HashSet, VecDeque},
     fmt,
     future::Future,
+    num::NonZeroUsize,
     pin::Pin,
-    sync::{Arc, Mutex},
+    sync::{
+        atomic::{AtomicUsize, Ordering},
+        Arc, Mutex,
+    },
+    time::Duration,
 };
 
-use crate::base::{MultiplexedBlobstoreBase, MultiplexedBlobstorePutHandler};
+use crate::base::{
+    remap_timeout_result, AuditReport, BlobstoreAuditCounts, BlobstoreDescription, ErrorKind,
+    MismatchResolution, MultiplexDescription, MultiplexEvent, MultiplexRecorder,
+    MultiplexedBlobstoreBase, MultiplexedBlobstorePutHandler, NilOperationKeyHandling,
+    ReadStrategy, UnlinkReport,
+};
 use crate::queue::MultiplexedBlobstore;
 use crate::scrub::{LoggingScrubHandler, ScrubBlobstore, ScrubHandler};
 use anyhow::{bail, Error};
@@ -23,11 +33,11 @@ use blobstore_sync_queue::{
 };
 use bytes::Bytes;
 use cloned::cloned;
-use context::CoreContext;
+use context::{CoreContext, PerfCounterType};
 use fbinit::FacebookInit;
 use futures::{
     channel::oneshot,
-    future::{BoxFuture, FutureExt, TryFutureExt},
+    future::{join_all, BoxFuture, FutureExt, TryFutureExt},
     task::{Context, Poll},
 };
 use lock_ext::LockExt;
@@ -37,7 +47,10 @@ use mononoke_types::{BlobstoreBytes, DateTime};
 use nonzero_ext::nonzero;
 use readonlyblob::ReadOnlyBlobstore;
 use scuba::ScubaSampleBuilder;
+use slog::{o, Drain, Logger, Never, OwnedKVList, Record};
 use sql_construct::SqlConstruct;
+use tempdir::TempDir;
+use tokio::sync::Semaphore;
 
 pub struct Tickable<T> {
     pub storage: Arc<Mutex<HashMap<String, T>>>,
@@ -130,6 +143,7 @@ impl MultiplexedBlobstorePutHandler for Tickable<BlobstoreId> {
         _multiplex_id: MultiplexId,
         _operation_key: &OperationKey,
         key: &str,
+        _blob_size: u64,
     ) -> BoxFuture<Result<(), Error>> {
         let storage = self.storage.clone();
         let key = key.to_string();
@@ -166,6 +180,7 @@ impl MultiplexedBlobstorePutHandler for LogHandler {
         _multiplex_id: MultiplexId,
         _operation_key: &OperationKey,
         key: &str,
+        _blob_size: u64,
     ) -> BoxFuture<Result<(), Error>> {
         self.log
             .with(move |log| log.push((blobstore_id, key.to_string())));
@@ -173,6 +188,172 @@ impl MultiplexedBlobstorePutHandler for LogHandler {
     }
 }
 
+/// Recorder that keeps every event, in the order they happened
+#[derive(Default)]
+struct VecRecorder {
+    events: Mutex<Vec<MultiplexEvent>>,
+}
+
+impl VecRecorder {
+    fn take(&self) -> Vec<MultiplexEvent> {
+        self.events.with(|events| events.drain(..).collect())
+    }
+}
+
+impl MultiplexRecorder for VecRecorder {
+    fn record(&self, event: MultiplexEvent) {
+        self.events.with(|events| events.push(event));
+    }
+}
+
+/// Blobstore that records the highest number of gets that were in flight at once
+#[derive(Debug, Default)]
+struct ConcurrencyTracking {
+    in_flight: Arc<AtomicUsize>,
+    max_in_flight: Arc<AtomicUsize>,
+}
+
+impl Blobstore for ConcurrencyTracking {
+    fn get(
+        &self,
+        _ctx: CoreContext,
+        _key: String,
+    ) -> BoxFuture<'static, Result<Option<BlobstoreGetData>, Error>> {
+        cloned!(self.in_flight, self.max_in_flight);
+        async move {
+            let current = in_flight.fetch_add(1, Ordering::SeqCst) + 1;
+            max_in_flight.fetch_max(current, Ordering::SeqCst);
+            tokio::time::delay_for(Duration::from_millis(10)).await;
+            in_flight.fetch_sub(1, Ordering::SeqCst);
+            Ok(None)
+        }
+        .boxed()
+    }
+
+    fn put(
+        &self,
+        _ctx: CoreContext,
+        _key: String,
+        _value: BlobstoreBytes,
+    ) -> BoxFuture<'static, Result<(), Error>> {
+        async { Ok(()) }.boxed()
+    }
+}
+
+/// Blobstore that delays every operation on `inner` by `delay`, and fails all operations after
+/// the first `fail_after`, to simulate slow or failing stores.
+#[derive(Debug)]
+struct SlowBlobstore<B> {
+    inner: B,
+    delay: Duration,
+    fail_after: Option<usize>,
+    ops: AtomicUsize,
+}
+
+impl<B> SlowBlobstore<B> {
+    fn new(inner: B, delay: Duration) -> Self {
+        Self {
+            inner,
+            delay,
+            fail_after: None,
+            ops: AtomicUsize::new(0),
+        }
+    }
+
+    fn with_fail_after(self, fail_after: usize) -> Self {
+        Self {
+            fail_after: Some(fail_after),
+            ..self
+        }
+    }
+
+    /// Wait for `fut` after the delay, or fail instead if too many operations were made.
+    fn delayed<O: Send + 'static>(
+        &self,
+        fut: BoxFuture<'static, Result<O, Error>>,
+    ) -> BoxFuture<'static, Result<O, Error>> {
+        let op = self.ops.fetch_add(1, Ordering::SeqCst);
+        let fail = self.fail_after.map_or(false, |fail_after| op >= fail_after);
+        let delay = self.delay;
+        async move {
+            tokio::time::delay_for(delay).await;
+            if fail {
+                bail!("SlowBlobstore: failing operation {}", op);
+            }
+            fut.await
+        }
+        .boxed()
+    }
+}
+
+impl<B: Blobstore> Blobstore for SlowBlobstore<B> {
+    fn get(
+        &self,
+        ctx: CoreContext,
+        key: String,
+    ) -> BoxFuture<'static, Result<Option<BlobstoreGetData>, Error>> {
+        self.delayed(self.inner.get(ctx, key))
+    }
+
+    fn put(
+        &self,
+        ctx: CoreContext,
+        key: String,
+        value: BlobstoreBytes,
+    ) -> BoxFuture<'static, Result<(), Error>> {
+        self.delayed(self.inner.put(ctx, key, value))
+    }
+
+    fn is_present(&self, ctx: CoreContext, key: String) -> BoxFuture<'static, Result<bool, Error>> {
+        self.delayed(self.inner.is_present(ctx, key))
+    }
+}
+
+/// Blobstore that acknowledges all writes, but always reads back `value`
+#[derive(Debug)]
+struct LyingBlobstore {
+    value: BlobstoreBytes,
+}
+
+impl Blobstore for LyingBlobstore {
+    fn get(
+        &self,
+        _ctx: CoreContext,
+        _key: String,
+    ) -> BoxFuture<'static, Result<Option<BlobstoreGetData>, Error>> {
+        let value = self.value.clone();
+        async move { Ok(Some(value.into())) }.boxed()
+    }
+
+    fn put(
+        &self,
+        _ctx: CoreContext,
+        _key: String,
+        _value: BlobstoreBytes,
+    ) -> BoxFuture<'static, Result<(), Error>> {
+        async { Ok(()) }.boxed()
+    }
+}
+
+#[derive(Clone, Default)]
+struct CapturingDrain(Arc<Mutex<Vec<String>>>);
+
+impl Drain for CapturingDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, _values: &OwnedKVList) -> Result<(), Never> {
+        self.0.with(|msgs| msgs.push(format!("{}", record.msg())));
+        Ok(())
+    }
+}
+
+impl CapturingDrain {
+    fn messages(&self) -> Vec<String> {
+        self.0.with(|msgs| msgs.clone())
+    }
+}
+
 fn make_value(value: &str) -> BlobstoreBytes {
     BlobstoreBytes::from_bytes(Bytes::copy_from_slice(value.as_bytes()))
 }
@@ -233,6 +414,7 @@ async fn scrub_blobstore_fetch_none(fb: FacebookInit) -> Result<(), Error> {
         timestamp: DateTime::now(),
         id: None,
         operation_key: OperationKey::gen(),
+        blob_size: None,
     };
     queue.add(ctx.clone(), entry).await?;
 
@@ -507,6 +689,41 @@ async fn multiplexed_operation_keys(fb: FacebookInit) -> Result<(), Error> {
     Ok(())
 }
 
+#[fbinit::test]
+async fn skip_handlers_on_success(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = Arc::new(SqlBlobstoreSyncQueue::with_sqlite_in_memory()?);
+    let multiplex = |bs1: Arc<dyn Blobstore>| {
+        MultiplexedBlobstore::new(
+            MultiplexId::new(1),
+            vec![
+                (BlobstoreId::new(0), Arc::new(LazyMemblob::new())),
+                (BlobstoreId::new(1), bs1),
+            ],
+            queue.clone(),
+            ScubaSampleBuilder::with_discard(),
+            nonzero!(1u64),
+        )
+        .with_skip_handlers_on_success(true)
+    };
+
+    // All blobstores have the blob, so nothing is queued
+    let bs = multiplex(Arc::new(LazyMemblob::new()));
+    let k0 = String::from("k0");
+    bs.put(ctx.clone(), k0.clone(), make_value("v0")).await?;
+    assert!(queue.get(ctx.clone(), k0).await?.is_empty());
+
+    // One blobstore failed, so the other one is queued for healing
+    let bs = multiplex(Arc::new(ReadOnlyBlobstore::new(LazyMemblob::new())));
+    let k1 = String::from("k1");
+    bs.put(ctx.clone(), k1.clone(), make_value("v1")).await?;
+    match queue.get(ctx.clone(), k1).await?.as_slice() {
+        [entry] => assert_eq!(entry.blobstore_id, BlobstoreId::new(0)),
+        entries => panic!("one entry expected, got {:?}", entries),
+    }
+    Ok(())
+}
+
 #[fbinit::test]
 async fn scrubbed(fb: FacebookInit) {
     let ctx = CoreContext::test_mock(fb);
@@ -762,3 +979,1484 @@ async fn queue_waits(fb: FacebookInit) {
         clear();
     }
 }
+
+#[fbinit::test]
+async fn get_debug_logs_all_outcomes(fb: FacebookInit) {
+    let bs0 = Arc::new(Tickable::new());
+    let bs1 = Arc::new(Tickable::new());
+    let bs2 = Arc::new(Tickable::new());
+    let log = Arc::new(LogHandler::new());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs0.clone()),
+            (BlobstoreId::new(1), bs1.clone()),
+            (BlobstoreId::new(2), bs2.clone()),
+        ],
+        log,
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    );
+    let drain = CapturingDrain::default();
+    let ctx = CoreContext::new_with_logger(fb, Logger::root(drain.clone().fuse(), o!()));
+
+    let k = String::from("k");
+    let v = make_value("v");
+    bs0.storage.with(|s| s.insert(k.clone(), v.clone()));
+
+    let mut fut = bs.get_debug(&ctx, &k).map_err(|_| ()).boxed();
+    assert_eq!(PollOnce::new(Pin::new(&mut fut)).await, Poll::Pending);
+
+    // A value has arrived, but get_debug keeps waiting for the other stores
+    bs0.tick(None);
+    assert_eq!(PollOnce::new(Pin::new(&mut fut)).await, Poll::Pending);
+    bs1.tick(None);
+    assert_eq!(PollOnce::new(Pin::new(&mut fut)).await, Poll::Pending);
+    bs2.tick(Some("oops"));
+
+    assert_eq!(fut.await, Ok(Some(v.into())));
+
+    let messages = drain.messages();
+    assert_eq!(messages.len(), 3);
+    assert!(messages
+        .iter()
+        .any(|m| m.starts_with("get_debug: blobstore_id BlobstoreId(0) returned value with hash")));
+    assert!(messages
+        .iter()
+        .any(|m| m == "get_debug: blobstore_id BlobstoreId(1) returned None for k"));
+    assert!(messages
+        .iter()
+        .any(|m| m == "get_debug: blobstore_id BlobstoreId(2) failed for k: oops"));
+}
+
+#[fbinit::test]
+async fn strict_handler_waits(fb: FacebookInit) {
+    let bs0 = Arc::new(Tickable::new());
+    let bs1 = Arc::new(Tickable::new());
+    let log = Arc::new(Tickable::new());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs0.clone()),
+            (BlobstoreId::new(1), bs1.clone()),
+        ],
+        log.clone(),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    )
+    .with_strict_handler(true);
+    let ctx = CoreContext::test_mock(fb);
+
+    let k = String::from("k");
+    let v = make_value("v");
+
+    let mut fut = bs.put(ctx, k.clone(), v).map_err(|_| ()).boxed();
+    assert_eq!(PollOnce::new(Pin::new(&mut fut)).await, Poll::Pending);
+
+    // All blobstores succeeded, but no handler has completed yet
+    bs0.tick(None);
+    bs1.tick(None);
+    assert_eq!(PollOnce::new(Pin::new(&mut fut)).await, Poll::Pending);
+    assert!(log.storage.with(|s| s.is_empty()));
+
+    log.tick(None);
+    assert_eq!(PollOnce::new(Pin::new(&mut fut)).await, Poll::Ready(Ok(())));
+    assert!(log.storage.with(|s| s.get(&k).is_some()));
+}
+
+#[fbinit::test]
+async fn write_quorum(fb: FacebookInit) {
+    let bs0 = Arc::new(Tickable::new());
+    let bs1 = Arc::new(Tickable::new());
+    let bs2 = Arc::new(Tickable::new());
+    let log = Arc::new(Tickable::new());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs0.clone()),
+            (BlobstoreId::new(1), bs1.clone()),
+            (BlobstoreId::new(2), bs2.clone()),
+        ],
+        log.clone(),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    )
+    .with_write_quorum(NonZeroUsize::new(2).unwrap())
+    .unwrap();
+    let ctx = CoreContext::test_mock(fb);
+
+    // A quorum larger than the multiplex could never be reached
+    assert!(bs
+        .clone()
+        .with_write_quorum(NonZeroUsize::new(4).unwrap())
+        .is_err());
+
+    // One blobstore and its handler aren't enough
+    let k0 = String::from("k0");
+    let mut fut = bs.put(ctx.clone(), k0.clone(), make_value("v0")).boxed();
+    assert!(PollOnce::new(Pin::new(&mut fut)).await.is_pending());
+    bs0.tick(None);
+    assert!(PollOnce::new(Pin::new(&mut fut)).await.is_pending());
+    log.tick(None);
+    assert!(PollOnce::new(Pin::new(&mut fut)).await.is_pending());
+    assert_eq!(log.storage.with(|s| s.len()), 1);
+
+    // But a second one is, even though the third failed
+    bs1.tick(Some("bs1 failed"));
+    bs2.tick(None);
+    assert!(PollOnce::new(Pin::new(&mut fut)).await.is_pending());
+    log.tick(None);
+    match PollOnce::new(Pin::new(&mut fut)).await {
+        Poll::Ready(Ok(())) => {}
+        other => panic!("put should have succeeded, got {:?}", other),
+    }
+    assert!(bs0.storage.with(|s| s.contains_key(&k0)));
+    assert!(bs2.storage.with(|s| s.contains_key(&k0)));
+
+    // If too many blobstores fail, the put fails with all of their errors
+    let k1 = String::from("k1");
+    let mut fut = bs.put(ctx.clone(), k1.clone(), make_value("v1")).boxed();
+    assert!(PollOnce::new(Pin::new(&mut fut)).await.is_pending());
+    bs0.tick(None);
+    assert!(PollOnce::new(Pin::new(&mut fut)).await.is_pending());
+    log.tick(None);
+    assert!(PollOnce::new(Pin::new(&mut fut)).await.is_pending());
+    bs1.tick(Some("bs1 failed"));
+    bs2.tick(Some("bs2 failed"));
+    match PollOnce::new(Pin::new(&mut fut)).await {
+        Poll::Ready(Err(err)) => match err.downcast_ref::<ErrorKind>() {
+            Some(ErrorKind::WriteQuorumNotReached(written, quorum, errors)) => {
+                assert_eq!(*written, 1);
+                assert_eq!(*quorum, 2);
+                let failed: HashSet<_> = errors.keys().cloned().collect();
+                let expected: HashSet<_> = vec![BlobstoreId::new(1), BlobstoreId::new(2)]
+                    .into_iter()
+                    .collect();
+                assert_eq!(failed, expected);
+            }
+            _ => panic!("unexpected error: {:?}", err),
+        },
+        other => panic!("put should have failed, got {:?}", other),
+    }
+
+    // Even once every blobstore was written, the put waits for the quorum of handlers, and
+    // fails if they all fail
+    let k2 = String::from("k2");
+    let mut fut = bs.put(ctx.clone(), k2.clone(), make_value("v2")).boxed();
+    assert!(PollOnce::new(Pin::new(&mut fut)).await.is_pending());
+    bs0.tick(None);
+    bs1.tick(None);
+    bs2.tick(None);
+    assert!(PollOnce::new(Pin::new(&mut fut)).await.is_pending());
+    log.tick(Some("log failed"));
+    match PollOnce::new(Pin::new(&mut fut)).await {
+        Poll::Ready(Err(err)) => match err.downcast_ref::<ErrorKind>() {
+            Some(ErrorKind::AllPutsFailed(errors)) => assert_eq!(errors.len(), 3),
+            _ => panic!("unexpected error: {:?}", err),
+        },
+        other => panic!("put should have failed, got {:?}", other),
+    }
+    assert!(log.storage.with(|s| !s.contains_key(&k2)));
+}
+
+#[fbinit::test]
+async fn scrub_get_limiter(fb: FacebookInit) {
+    let tracker = Arc::new(ConcurrencyTracking::default());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), tracker.clone()),
+            (BlobstoreId::new(1), tracker.clone()),
+        ],
+        Arc::new(LogHandler::new()),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    );
+    let ctx = CoreContext::test_mock(fb);
+    let keys: Vec<_> = (0..4).map(|i| format!("k{}", i)).collect();
+
+    // Unlimited scrubs hit the backend concurrently
+    let results = join_all(keys.iter().map(|k| bs.scrub_get(&ctx, k, None))).await;
+    assert!(results.iter().all(|r| r.as_ref().unwrap().is_none()));
+    assert!(tracker.max_in_flight.load(Ordering::SeqCst) > 1);
+
+    // A shared semaphore with one permit serializes all backend access
+    tracker.max_in_flight.store(0, Ordering::SeqCst);
+    let limiter = Arc::new(Semaphore::new(1));
+    let results = join_all(
+        keys.iter()
+            .map(|k| bs.scrub_get(&ctx, k, Some(limiter.clone()))),
+    )
+    .await;
+    assert!(results.iter().all(|r| r.as_ref().unwrap().is_none()));
+    assert_eq!(tracker.max_in_flight.load(Ordering::SeqCst), 1);
+}
+
+#[fbinit::test]
+async fn scrub_get_with_resolution(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let bs0 = Arc::new(LazyMemblob::new());
+    let bs1 = Arc::new(LazyMemblob::new());
+    let bs2 = Arc::new(LazyMemblob::new());
+    let bs3 = Arc::new(LazyMemblob::new());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs0.clone()),
+            (BlobstoreId::new(1), bs1.clone()),
+            (BlobstoreId::new(2), bs2.clone()),
+            (BlobstoreId::new(3), bs3.clone()),
+        ],
+        Arc::new(LogHandler::new()),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    );
+
+    let v_major = make_value("major");
+    let v_minor = make_value("minor");
+
+    // Majority: 0, 1 and 2 agree, 3 differs
+    let k = String::from("majority");
+    bs0.put(ctx.clone(), k.clone(), v_major.clone()).await?;
+    bs1.put(ctx.clone(), k.clone(), v_major.clone()).await?;
+    bs2.put(ctx.clone(), k.clone(), v_major.clone()).await?;
+    bs3.put(ctx.clone(), k.clone(), v_minor.clone()).await?;
+
+    match bs.scrub_get(&ctx, &k, None).await {
+        Err(ErrorKind::ValueMismatch(..)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    let value = bs
+        .scrub_get_with_resolution(&ctx, &k, MismatchResolution::MajorityWins)
+        .await?;
+    assert_eq!(value.map(|v| v.into_bytes()), Some(v_major.clone()));
+    let value = bs
+        .scrub_get_with_resolution(
+            &ctx,
+            &k,
+            MismatchResolution::PreferStore(BlobstoreId::new(3)),
+        )
+        .await?;
+    assert_eq!(value.map(|v| v.into_bytes()), Some(v_minor.clone()));
+
+    // Tie: 0 and 1 agree, 2 and 3 agree on something else
+    let k = String::from("tie");
+    bs0.put(ctx.clone(), k.clone(), v_major.clone()).await?;
+    bs1.put(ctx.clone(), k.clone(), v_major.clone()).await?;
+    bs2.put(ctx.clone(), k.clone(), v_minor.clone()).await?;
+    bs3.put(ctx.clone(), k.clone(), v_minor.clone()).await?;
+
+    match bs
+        .scrub_get_with_resolution(&ctx, &k, MismatchResolution::MajorityWins)
+        .await
+    {
+        Err(ErrorKind::ValueMismatch(answered, missing, _)) => {
+            assert_eq!(answered.len(), 4);
+            assert!(missing.is_empty());
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+    Ok(())
+}
+
+#[fbinit::test]
+async fn scrub_get_with_quorum(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let bs0 = Arc::new(LazyMemblob::new());
+    let bs1 = Arc::new(LazyMemblob::new());
+    let bs2 = Arc::new(LazyMemblob::new());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs0.clone()),
+            (BlobstoreId::new(1), bs1.clone()),
+            (BlobstoreId::new(2), bs2.clone()),
+        ],
+        Arc::new(LogHandler::new()),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    );
+
+    // 0 and 1 agree, 2 doesn't have the value yet
+    let k = String::from("lagging");
+    let v = make_value("v");
+    bs0.put(ctx.clone(), k.clone(), v.clone()).await?;
+    bs1.put(ctx.clone(), k.clone(), v.clone()).await?;
+
+    match bs.scrub_get(&ctx, &k, None).await {
+        Err(ErrorKind::SomeMissingItem(missing, _)) => {
+            assert_eq!(
+                *missing,
+                vec![BlobstoreId::new(2)]
+                    .into_iter()
+                    .collect::<HashSet<_>>()
+            );
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+    let value = bs.scrub_get_with_quorum(&ctx, &k, nonzero!(2usize)).await?;
+    assert_eq!(value.map(|v| v.into_bytes()), Some(v.clone()));
+    match bs.scrub_get_with_quorum(&ctx, &k, nonzero!(3usize)).await {
+        Err(ErrorKind::SomeMissingItem(..)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+
+    // Disagreeing blobstores are a mismatch, however many agree
+    let k = String::from("mismatch");
+    bs0.put(ctx.clone(), k.clone(), v.clone()).await?;
+    bs1.put(ctx.clone(), k.clone(), make_value("other")).await?;
+    match bs.scrub_get_with_quorum(&ctx, &k, nonzero!(1usize)).await {
+        Err(ErrorKind::ValueMismatch(..)) => {}
+        other => panic!("unexpected result {:?}", other),
+    }
+    Ok(())
+}
+
+#[fbinit::test]
+async fn put_with_report_write_orders(fb: FacebookInit) {
+    let bs0 = Arc::new(Tickable::new());
+    let bs1 = Arc::new(Tickable::new());
+    let bs2 = Arc::new(Tickable::new());
+    // Handlers never complete, so the put only returns once every blobstore has been written
+    let log = Arc::new(Tickable::new());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs0.clone()),
+            (BlobstoreId::new(1), bs1.clone()),
+            (BlobstoreId::new(2), bs2.clone()),
+        ],
+        log.clone(),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    );
+    let ctx = CoreContext::test_mock(fb);
+
+    let mut fut = bs
+        .put_with_report(ctx, String::from("k"), make_value("v"))
+        .map_err(|_| ())
+        .boxed();
+    assert!(PollOnce::new(Pin::new(&mut fut)).await.is_pending());
+
+    bs1.tick(None);
+    assert!(PollOnce::new(Pin::new(&mut fut)).await.is_pending());
+    bs2.tick(None);
+    assert!(PollOnce::new(Pin::new(&mut fut)).await.is_pending());
+    bs0.tick(None);
+    let report = fut.await.unwrap();
+
+    let stores: Vec<_> = report.write_orders.iter().map(|(id, _)| *id).collect();
+    assert_eq!(
+        stores,
+        vec![
+            BlobstoreId::new(1),
+            BlobstoreId::new(2),
+            BlobstoreId::new(0)
+        ]
+    );
+    let orders: Vec<_> = report
+        .write_orders
+        .iter()
+        .map(|(_, order)| *order)
+        .collect();
+    assert_eq!(orders, vec![1, 2, 3]);
+}
+
+#[fbinit::test]
+async fn scrub_get_value_mismatch_groups(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let bs0 = Arc::new(LazyMemblob::new());
+    let bs1 = Arc::new(LazyMemblob::new());
+    let bs2 = Arc::new(LazyMemblob::new());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs0.clone()),
+            (BlobstoreId::new(1), bs1.clone()),
+            (BlobstoreId::new(2), bs2.clone()),
+        ],
+        Arc::new(LogHandler::new()),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    );
+
+    let k = String::from("k");
+    bs0.put(ctx.clone(), k.clone(), make_value("v1")).await?;
+    bs1.put(ctx.clone(), k.clone(), make_value("v1")).await?;
+    bs2.put(ctx.clone(), k.clone(), make_value("v2")).await?;
+
+    match bs.scrub_get(&ctx, &k, None).await {
+        Err(ErrorKind::ValueMismatch(_, _, by_hash)) => {
+            let mut groups: Vec<Vec<BlobstoreId>> = by_hash
+                .values()
+                .map(|ids| {
+                    let mut ids: Vec<_> = ids.iter().cloned().collect();
+                    ids.sort();
+                    ids
+                })
+                .collect();
+            groups.sort();
+            assert_eq!(
+                groups,
+                vec![
+                    vec![BlobstoreId::new(0), BlobstoreId::new(1)],
+                    vec![BlobstoreId::new(2)],
+                ]
+            );
+        }
+        other => panic!("unexpected result {:?}", other),
+    }
+    Ok(())
+}
+
+#[fbinit::test]
+async fn audit_sample(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let bs0 = Arc::new(LazyMemblob::new());
+    let bs1 = Arc::new(LazyMemblob::new());
+    let bs2 = Arc::new(LazyMemblob::new());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs0.clone()),
+            (BlobstoreId::new(1), bs1.clone()),
+            (BlobstoreId::new(2), bs2.clone()),
+        ],
+        Arc::new(LogHandler::new()),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    );
+
+    // k0 and k1 are in all blobstores, and k2 in none, which is consistent too
+    for k in vec!["k0", "k1"] {
+        bs.put(ctx.clone(), k.to_string(), make_value(k)).await?;
+    }
+    // A key blobstore 2 has a different value for
+    let k3 = String::from("k3");
+    bs0.put(ctx.clone(), k3.clone(), make_value("v1")).await?;
+    bs1.put(ctx.clone(), k3.clone(), make_value("v1")).await?;
+    bs2.put(ctx.clone(), k3.clone(), make_value("v2")).await?;
+
+    let keys = vec!["k0", "k1", "k2", "k3"]
+        .into_iter()
+        .map(String::from)
+        .collect();
+    let report = bs.audit_sample(&ctx, keys).await;
+    let mismatched = BlobstoreAuditCounts {
+        mismatched: 1,
+        ..Default::default()
+    };
+    assert_eq!(
+        report,
+        AuditReport {
+            keys: 4,
+            consistent: 3,
+            mismatched: 1,
+            missing: 0,
+            failed: 0,
+            per_blobstore: vec![
+                (BlobstoreId::new(0), mismatched.clone()),
+                (BlobstoreId::new(1), mismatched.clone()),
+                (BlobstoreId::new(2), mismatched),
+            ]
+            .into_iter()
+            .collect(),
+        }
+    );
+
+    // The per key report has the details
+    let report = bs.scrub_report(&ctx, &k3).await;
+    assert!(!report.is_consistent());
+    assert_eq!(report.mismatched.len(), 3);
+    assert!(bs
+        .scrub_report(&ctx, &"k0".to_string())
+        .await
+        .is_consistent());
+    Ok(())
+}
+
+#[fbinit::test]
+async fn staged_get(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let bs0 = Arc::new(LazyMemblob::new());
+    let bs1 = Arc::new(Tickable::new());
+    let bs2 = Arc::new(LazyMemblob::new());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs0.clone()),
+            (BlobstoreId::new(1), bs1.clone()),
+            (BlobstoreId::new(2), bs2.clone()),
+        ],
+        Arc::new(LogHandler::new()),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    )
+    .with_staged_get(1, Duration::from_secs(60));
+
+    // The first stage has the value, so the other blobstores are never asked
+    let k0 = String::from("k0");
+    let v0 = make_value("v0");
+    bs0.put(ctx.clone(), k0.clone(), v0.clone()).await?;
+    let value = bs.get(ctx.clone(), k0).await?;
+    assert_eq!(value.map(|v| v.into_bytes()), Some(v0));
+    assert!(bs1.queue.with(|q| q.is_empty()));
+
+    // The first stage doesn't have the value, so the rest are queried without waiting
+    // for the grace period
+    let k1 = String::from("k1");
+    let v1 = make_value("v1");
+    bs2.put(ctx.clone(), k1.clone(), v1.clone()).await?;
+    let value = bs.get(ctx.clone(), k1).await?;
+    assert_eq!(value.map(|v| v.into_bytes()), Some(v1));
+    assert_eq!(bs1.queue.with(|q| q.len()), 1);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn read_strategy_preferred_with_fallback(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let bs0 = Arc::new(Tickable::new());
+    let bs1 = Arc::new(Tickable::new());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs0.clone()),
+            (BlobstoreId::new(1), bs1.clone()),
+        ],
+        Arc::new(LogHandler::new()),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    )
+    .with_read_strategy(ReadStrategy::PreferredWithFallback {
+        first_timeout: Duration::from_millis(50),
+    });
+
+    // The preferred blobstore is tried alone, and answers in time
+    let k0 = String::from("k0");
+    let v0 = make_value("v0");
+    bs0.storage.with(|s| s.insert(k0.clone(), v0.clone()));
+    let mut fut = bs.get(ctx.clone(), k0);
+    assert!(PollOnce::new(Pin::new(&mut fut)).await.is_pending());
+    assert_eq!(bs0.queue.with(|q| q.len()), 1);
+    assert!(bs1.queue.with(|q| q.is_empty()));
+    bs0.tick(None);
+    assert_eq!(fut.await?.map(|v| v.into_bytes()), Some(v0));
+    assert!(bs1.queue.with(|q| q.is_empty()));
+
+    // The preferred blobstore doesn't answer in time, so the rest are queried
+    let k1 = String::from("k1");
+    let v1 = make_value("v1");
+    bs1.storage.with(|s| s.insert(k1.clone(), v1.clone()));
+    let mut fut = bs.get(ctx.clone(), k1);
+    assert!(PollOnce::new(Pin::new(&mut fut)).await.is_pending());
+    assert!(bs1.queue.with(|q| q.is_empty()));
+    tokio::time::delay_for(Duration::from_millis(100)).await;
+    assert!(PollOnce::new(Pin::new(&mut fut)).await.is_pending());
+    assert_eq!(bs1.queue.with(|q| q.len()), 1);
+    bs1.tick(None);
+    assert_eq!(fut.await?.map(|v| v.into_bytes()), Some(v1));
+    Ok(())
+}
+
+#[fbinit::test]
+async fn is_present_prefers_local(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let bs0 = Arc::new(LazyMemblob::new());
+    let bs1 = Arc::new(Tickable::new());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs0.clone()),
+            (BlobstoreId::new(1), bs1.clone()),
+        ],
+        Arc::new(LogHandler::new()),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    )
+    .with_local_blobstores(vec![BlobstoreId::new(0)].into_iter().collect());
+
+    // The local blobstore has the blob, so the remote one is never asked
+    let k0 = String::from("k0");
+    bs0.put(ctx.clone(), k0.clone(), make_value("v0")).await?;
+    assert!(bs.is_present(ctx.clone(), k0).await?);
+    assert!(bs1.queue.with(|q| q.is_empty()));
+
+    // The local blobstore doesn't have it, so the remote one is asked
+    let k1 = String::from("k1");
+    bs1.storage.with(|s| {
+        s.insert(k1.clone(), make_value("v1"));
+    });
+    let mut fut = bs.is_present(ctx.clone(), k1);
+    assert!(PollOnce::new(Pin::new(&mut fut)).await.is_pending());
+    assert_eq!(bs1.queue.with(|q| q.len()), 1);
+    bs1.tick(None);
+    assert!(fut.await?);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn ordered_put(fb: FacebookInit) {
+    let bs0 = Arc::new(Tickable::new());
+    let bs1 = Arc::new(Tickable::new());
+    let log = Arc::new(Tickable::new());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs0.clone()),
+            (BlobstoreId::new(1), bs1.clone()),
+        ],
+        log.clone(),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    )
+    .with_strict_handler(true)
+    .with_primary_blobstores(vec![BlobstoreId::new(0)].into_iter().collect());
+    let ctx = CoreContext::test_mock(fb);
+
+    // The secondary put is only issued once the primary one completed
+    let k0 = String::from("k0");
+    let mut fut = bs.put(ctx.clone(), k0.clone(), make_value("v0")).boxed();
+    assert!(PollOnce::new(Pin::new(&mut fut)).await.is_pending());
+    assert_eq!(bs0.queue.with(|q| q.len()), 1);
+    assert!(bs1.queue.with(|q| q.is_empty()));
+
+    bs0.tick(None);
+    assert!(PollOnce::new(Pin::new(&mut fut)).await.is_pending());
+    assert!(bs0.storage.with(|s| s.contains_key(&k0)));
+    assert_eq!(bs1.queue.with(|q| q.len()), 1);
+
+    bs1.tick(None);
+    log.tick(None);
+    assert!(fut.await.is_ok());
+    assert!(bs1.storage.with(|s| s.contains_key(&k0)));
+
+    // If the primary put fails, the secondary is never written
+    let k1 = String::from("k1");
+    let mut fut = bs.put(ctx.clone(), k1.clone(), make_value("v1")).boxed();
+    assert!(PollOnce::new(Pin::new(&mut fut)).await.is_pending());
+    bs0.tick(Some("bs0 failed"));
+    assert!(fut.await.is_err());
+    assert!(bs1.queue.with(|q| q.is_empty()));
+    assert!(!bs1.storage.with(|s| s.contains_key(&k1)));
+}
+
+#[fbinit::test]
+async fn verify_after_write(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let multiplex = |blobstore: Arc<dyn Blobstore>| {
+        MultiplexedBlobstoreBase::new(
+            MultiplexId::new(1),
+            vec![(BlobstoreId::new(0), blobstore)],
+            Arc::new(LogHandler::new()),
+            ScubaSampleBuilder::with_discard(),
+            nonzero!(1u64),
+        )
+    };
+    let lying = Arc::new(LyingBlobstore {
+        value: make_value("wrong"),
+    });
+
+    // Without verification, the acknowledged write counts as successful
+    let bs = multiplex(lying.clone());
+    bs.put(ctx.clone(), "k".to_string(), make_value("v"))
+        .await?;
+
+    // With verification, the mismatch on read back fails the put
+    let bs = multiplex(lying).with_verify_after_write(true);
+    let err = bs
+        .put(ctx.clone(), "k".to_string(), make_value("v"))
+        .await
+        .unwrap_err();
+    match err.downcast_ref::<ErrorKind>() {
+        Some(ErrorKind::AllPutsFailed(errors)) => {
+            match errors[&BlobstoreId::new(0)].downcast_ref::<ErrorKind>() {
+                Some(ErrorKind::VerifyAfterWriteFailed(id)) => {
+                    assert_eq!(*id, BlobstoreId::new(0))
+                }
+                _ => panic!("unexpected error: {:?}", err),
+            }
+        }
+        _ => panic!("unexpected error: {:?}", err),
+    }
+
+    // A blobstore that stores the value passes verification
+    let bs = multiplex(Arc::new(LazyMemblob::new())).with_verify_after_write(true);
+    bs.put(ctx.clone(), "k".to_string(), make_value("v"))
+        .await?;
+    Ok(())
+}
+
+#[fbinit::test]
+async fn all_puts_failed(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (
+                BlobstoreId::new(0),
+                Arc::new(ReadOnlyBlobstore::new(LazyMemblob::new())),
+            ),
+            (
+                BlobstoreId::new(1),
+                Arc::new(
+                    SlowBlobstore::new(LazyMemblob::new(), Duration::from_millis(0))
+                        .with_fail_after(0),
+                ),
+            ),
+        ],
+        Arc::new(LogHandler::new()),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    );
+
+    // The error has the reason each blobstore failed for
+    let err = bs
+        .put(ctx.clone(), "k".to_string(), make_value("v"))
+        .await
+        .unwrap_err();
+    match err.downcast_ref::<ErrorKind>() {
+        Some(ErrorKind::AllPutsFailed(errors)) => assert_eq!(errors.len(), 2),
+        _ => panic!("unexpected error: {:?}", err),
+    }
+    let message = err.to_string();
+    assert!(message.contains("BlobstoreId(0)"), "{}", message);
+    assert!(message.contains("BlobstoreId(1)"), "{}", message);
+    assert!(
+        message.contains("SlowBlobstore: failing operation 0"),
+        "{}",
+        message
+    );
+    Ok(())
+}
+
+#[fbinit::test]
+async fn timeout_is_logged(fb: FacebookInit) {
+    let drain = CapturingDrain::default();
+    let ctx = CoreContext::new_with_logger(fb, Logger::root(drain.clone().fuse(), o!()));
+
+    let timed_out = tokio::time::timeout(
+        Duration::from_millis(1),
+        futures::future::pending::<Result<(), Error>>(),
+    )
+    .await;
+    assert!(remap_timeout_result(
+        &ctx,
+        BlobstoreId::new(3),
+        "k",
+        Duration::from_millis(1),
+        timed_out
+    )
+    .is_err());
+
+    let messages = drain.messages();
+    assert_eq!(messages.len(), 1);
+    assert!(messages[0].contains("BlobstoreId(3) timed out after 1ms"));
+    assert!(messages[0].ends_with("for k"));
+
+    // Operations that complete in time aren't logged
+    let completed = tokio::time::timeout(Duration::from_secs(1), async { Ok(()) }).await;
+    assert!(remap_timeout_result(
+        &ctx,
+        BlobstoreId::new(3),
+        "k",
+        Duration::from_secs(1),
+        completed
+    )
+    .is_ok());
+    assert_eq!(drain.messages().len(), 1);
+}
+
+#[fbinit::test]
+async fn compression(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let bs0 = Arc::new(LazyMemblob::new());
+    let bs1 = Arc::new(LazyMemblob::new());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs0.clone()),
+            (BlobstoreId::new(1), bs1.clone()),
+        ],
+        Arc::new(LogHandler::new()),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    )
+    .with_compression(true);
+
+    let k0 = String::from("k0");
+    let v0 = make_value(&"compressible".repeat(100));
+    bs.put(ctx.clone(), k0.clone(), v0.clone()).await?;
+    for store in &[&bs0, &bs1] {
+        let stored = store
+            .get(ctx.clone(), k0.clone())
+            .await?
+            .expect("stored value");
+        assert!(stored.as_bytes().len() < v0.len());
+    }
+    let value = bs.get(ctx.clone(), k0).await?.expect("value");
+    assert_eq!(value.as_bytes(), &v0);
+
+    // Blobs written before compression was enabled read back unchanged
+    let k1 = String::from("k1");
+    let v1 = make_value("legacy");
+    bs0.put(ctx.clone(), k1.clone(), v1.clone()).await?;
+    bs1.put(ctx.clone(), k1.clone(), v1.clone()).await?;
+    let value = bs.get(ctx.clone(), k1).await?.expect("value");
+    assert_eq!(value.as_bytes(), &v1);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn checksum(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let bs0 = Arc::new(LazyMemblob::new());
+    let bs1 = Arc::new(LazyMemblob::new());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs0.clone()),
+            (BlobstoreId::new(1), bs1.clone()),
+        ],
+        Arc::new(LogHandler::new()),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    )
+    .with_checksum(true);
+
+    let k0 = String::from("k0");
+    let v0 = make_value("v0");
+    bs.put(ctx.clone(), k0.clone(), v0.clone()).await?;
+    let stored = bs0
+        .get(ctx.clone(), k0.clone())
+        .await?
+        .expect("stored value");
+    assert!(stored.as_bytes().len() > v0.len());
+    let value = bs.get(ctx.clone(), k0.clone()).await?.expect("value");
+    assert_eq!(value.as_bytes(), &v0);
+
+    // Scrubs and debug reads return the value without its footer too
+    let value = bs.scrub_get(&ctx, &k0, None).await?.expect("value");
+    assert_eq!(value.as_bytes(), &v0);
+    let value = bs
+        .scrub_get_with_quorum(&ctx, &k0, nonzero!(2usize))
+        .await?
+        .expect("value");
+    assert_eq!(value.as_bytes(), &v0);
+    let value = bs
+        .scrub_get_with_resolution(&ctx, &k0, MismatchResolution::MajorityWins)
+        .await?
+        .expect("value");
+    assert_eq!(value.as_bytes(), &v0);
+    let value = bs.get_debug(&ctx, &k0).await?.expect("value");
+    assert_eq!(value.as_bytes(), &v0);
+
+    // A damaged marker is not mistaken for a blob written without a checksum
+    let mut damaged_marker = stored.as_raw_bytes().to_vec();
+    let last = damaged_marker.len() - 2;
+    damaged_marker[last] ^= 0xff;
+    let damaged_marker = BlobstoreBytes::from_bytes(damaged_marker);
+    bs0.put(ctx.clone(), k0.clone(), damaged_marker.clone())
+        .await?;
+    bs1.put(ctx.clone(), k0.clone(), damaged_marker).await?;
+    match bs.get(ctx.clone(), k0.clone()).await {
+        Err(error) => match error.downcast_ref::<ErrorKind>() {
+            Some(ErrorKind::AllFailed(errors)) => assert_eq!(errors.len(), 2),
+            other => panic!("unexpected error {:?}", other),
+        },
+        Ok(value) => panic!("unexpected value {:?}", value),
+    }
+    bs1.put(ctx.clone(), k0.clone(), stored.clone().into_bytes())
+        .await?;
+
+    // A blobstore that returns corrupted bytes counts as failed, so the value comes from
+    // the other one
+    let mut corrupted = stored.into_raw_bytes().to_vec();
+    corrupted[0] ^= 0xff;
+    let corrupted = BlobstoreBytes::from_bytes(corrupted);
+    bs0.put(ctx.clone(), k0.clone(), corrupted.clone()).await?;
+    let value = bs.get(ctx.clone(), k0.clone()).await?.expect("value");
+    assert_eq!(value.as_bytes(), &v0);
+
+    // If all of them are corrupted, the get fails
+    bs1.put(ctx.clone(), k0.clone(), corrupted).await?;
+    match bs.get(ctx.clone(), k0).await {
+        Err(error) => match error.downcast_ref::<ErrorKind>() {
+            Some(ErrorKind::AllFailed(errors)) => {
+                assert_eq!(errors.len(), 2);
+                match errors[&BlobstoreId::new(0)].downcast_ref::<ErrorKind>() {
+                    Some(ErrorKind::ChecksumMismatch(id)) => {
+                        assert_eq!(*id, BlobstoreId::new(0))
+                    }
+                    other => panic!("unexpected error {:?}", other),
+                }
+            }
+            other => panic!("unexpected error {:?}", other),
+        },
+        Ok(value) => panic!("unexpected value {:?}", value),
+    }
+
+    // Blobs written before checksums were enabled read back unchanged
+    let k1 = String::from("k1");
+    let v1 = make_value("legacy");
+    bs0.put(ctx.clone(), k1.clone(), v1.clone()).await?;
+    bs1.put(ctx.clone(), k1.clone(), v1.clone()).await?;
+    let value = bs.get(ctx.clone(), k1).await?.expect("value");
+    assert_eq!(value.as_bytes(), &v1);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn recorded_get(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let bs0 = Arc::new(LazyMemblob::new());
+    let bs1 = Arc::new(LazyMemblob::new());
+    let recorder = Arc::new(VecRecorder::default());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs0.clone()),
+            (BlobstoreId::new(1), bs1.clone()),
+        ],
+        Arc::new(LogHandler::new()),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    )
+    .with_staged_get(1, Duration::from_secs(60))
+    .with_recorder(recorder.clone());
+
+    let k0 = String::from("k0");
+    bs1.put(ctx.clone(), k0.clone(), make_value("v0")).await?;
+    assert!(bs.get(ctx.clone(), k0).await?.is_some());
+
+    // The first stage doesn't have it, so the second one is asked without waiting
+    assert_eq!(
+        recorder.take(),
+        vec![
+            MultiplexEvent::StoreContacted(BlobstoreId::new(0)),
+            MultiplexEvent::StoreReturnedNone(BlobstoreId::new(0)),
+            MultiplexEvent::StoreContacted(BlobstoreId::new(1)),
+            MultiplexEvent::StoreReturnedValue(BlobstoreId::new(1)),
+            MultiplexEvent::QuorumReached,
+        ]
+    );
+    Ok(())
+}
+
+#[fbinit::test]
+async fn shadow_blobstores(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let bs0 = Arc::new(LazyMemblob::new());
+    let bs1 = Arc::new(LazyMemblob::new());
+    // Puts to the failing shadow fail, and it never has any value
+    let failing_shadow = Arc::new(ReadOnlyBlobstore::new(LazyMemblob::new()));
+    let shadow = Arc::new(LazyMemblob::new());
+    let recorder = Arc::new(VecRecorder::default());
+    let log = Arc::new(LogHandler::new());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs0.clone()),
+            (BlobstoreId::new(1), bs1.clone()),
+        ],
+        log.clone(),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    )
+    .with_shadow_blobstores(vec![
+        (BlobstoreId::new(2), failing_shadow.clone()),
+        (BlobstoreId::new(3), shadow.clone()),
+    ])
+    .with_recorder(recorder.clone());
+
+    // Waits until `event` was recorded, returning everything recorded so far
+    let wait_for = |event: MultiplexEvent| {
+        let recorder = recorder.clone();
+        async move {
+            let mut events = vec![];
+            while !events.contains(&event) {
+                events.extend(recorder.take());
+                tokio::task::yield_now().await;
+            }
+            events
+        }
+    };
+
+    let k0 = String::from("k0");
+    let v0 = make_value("v0");
+    bs.put(ctx.clone(), k0.clone(), v0.clone()).await?;
+    let events = wait_for(MultiplexEvent::ShadowErrored(BlobstoreId::new(2))).await;
+    assert!(!events.contains(&MultiplexEvent::ShadowErrored(BlobstoreId::new(3))));
+    let shadow_value = loop {
+        if let Some(value) = shadow.get(ctx.clone(), k0.clone()).await? {
+            break value;
+        }
+        tokio::task::yield_now().await;
+    };
+    assert_eq!(shadow_value.as_bytes(), &v0);
+
+    let value = bs.get(ctx.clone(), k0.clone()).await?.expect("value");
+    assert_eq!(value.as_bytes(), &v0);
+    wait_for(MultiplexEvent::ShadowMismatch(BlobstoreId::new(2))).await;
+
+    // Shadow puts are not passed to the put handler, so they are never healed
+    assert!(log.log.with(|log| log
+        .iter()
+        .all(|(blobstore_id, _)| *blobstore_id == BlobstoreId::new(0)
+            || *blobstore_id == BlobstoreId::new(1))));
+    Ok(())
+}
+
+#[fbinit::test]
+async fn describe(_fb: FacebookInit) -> Result<(), Error> {
+    let bs0 = Arc::new(LazyMemblob::new());
+    let bs1 = Arc::new(LazyMemblob::new());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs0.clone()),
+            (BlobstoreId::new(1), bs1.clone()),
+        ],
+        Arc::new(LogHandler::new()),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    )
+    .with_staged_get(1, Duration::from_millis(50))
+    .with_local_blobstores(vec![BlobstoreId::new(1)].into_iter().collect());
+
+    let description = bs.describe();
+    assert_eq!(
+        description,
+        MultiplexDescription {
+            multiplex_id: MultiplexId::new(1),
+            blobstores: vec![
+                BlobstoreDescription {
+                    blobstore_id: BlobstoreId::new(0),
+                    local: false,
+                    primary: false,
+                    blobstore: format!("{:?}", bs0),
+                },
+                BlobstoreDescription {
+                    blobstore_id: BlobstoreId::new(1),
+                    local: true,
+                    primary: false,
+                    blobstore: format!("{:?}", bs1),
+                },
+            ],
+            shadow_blobstores: vec![],
+            strict_handler: false,
+            verify_after_write: false,
+            skip_handlers_on_success: false,
+            compress: false,
+            checksum: false,
+            nil_operation_key: NilOperationKeyHandling::Uncorrelated,
+            get_first_stage: Some(1),
+            get_grace_period_ms: 50,
+            write_quorum: 1,
+        }
+    );
+
+    let json = serde_json::to_value(&description)?;
+    assert_eq!(json["multiplex_id"], 1);
+    assert_eq!(json["blobstores"][1]["blobstore_id"], 1);
+    assert_eq!(json["blobstores"][1]["local"], true);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn put_with_operation_key(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = Arc::new(SqlBlobstoreSyncQueue::with_sqlite_in_memory()?);
+    let bs = MultiplexedBlobstore::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), Arc::new(LazyMemblob::new())),
+            (BlobstoreId::new(1), Arc::new(LazyMemblob::new())),
+        ],
+        queue.clone(),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    );
+
+    let operation_key = OperationKey::gen();
+    for key in &["k0", "k1"] {
+        let report = bs
+            .put_with_operation_key(
+                ctx.clone(),
+                key.to_string(),
+                make_value(key),
+                operation_key.clone(),
+            )
+            .await?;
+        assert_eq!(report.operation_key, operation_key);
+    }
+
+    for key in &["k0", "k1"] {
+        // Handlers may still be running in the background
+        let entries = loop {
+            let entries = queue.get(ctx.clone(), key.to_string()).await?;
+            if entries.len() == 2 {
+                break entries;
+            }
+            tokio::task::yield_now().await;
+        };
+        assert!(entries.iter().all(|e| e.operation_key == operation_key));
+    }
+    Ok(())
+}
+
+#[fbinit::test]
+async fn put_batch(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = Arc::new(SqlBlobstoreSyncQueue::with_sqlite_in_memory()?);
+    let bs = MultiplexedBlobstore::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), Arc::new(LazyMemblob::new())),
+            (BlobstoreId::new(1), Arc::new(LazyMemblob::new())),
+        ],
+        queue.clone(),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    );
+
+    let keys = (0..20).map(|i| format!("k{}", i)).collect::<Vec<_>>();
+    bs.put_batch(
+        ctx.clone(),
+        keys.iter()
+            .map(|key| (key.clone(), make_value(key)))
+            .collect(),
+    )
+    .await?;
+
+    let mut operation_keys = HashSet::new();
+    for key in &keys {
+        // Handlers may still be running in the background
+        let entries = loop {
+            let entries = queue.get(ctx.clone(), key.clone()).await?;
+            if entries.len() == 2 {
+                break entries;
+            }
+            tokio::task::yield_now().await;
+        };
+        operation_keys.extend(entries.into_iter().map(|e| e.operation_key));
+        let value = bs.get(ctx.clone(), key.clone()).await?.expect("value");
+        assert_eq!(value.as_bytes(), &make_value(key));
+    }
+    assert_eq!(operation_keys.len(), 1);
+    assert!(!operation_keys.into_iter().next().unwrap().is_null());
+    Ok(())
+}
+
+#[fbinit::test]
+async fn nil_operation_key(fb: FacebookInit) -> Result<(), Error> {
+    let drain = CapturingDrain::default();
+    let ctx = CoreContext::new_with_logger(fb, Logger::root(drain.clone().fuse(), o!()));
+    let queue = Arc::new(SqlBlobstoreSyncQueue::with_sqlite_in_memory()?);
+    let bs0 = Arc::new(LazyMemblob::new());
+    let bs = MultiplexedBlobstore::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs0.clone()),
+            (BlobstoreId::new(1), Arc::new(LazyMemblob::new())),
+        ],
+        queue.clone(),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    )
+    .with_strict_handler(true);
+    let nil_key = OperationKey(uuid::Uuid::nil());
+
+    // By default, the put is recorded under a fresh key and a warning is logged
+    let k0 = String::from("k0");
+    let report = bs
+        .put_with_operation_key(ctx.clone(), k0.clone(), make_value("v0"), nil_key.clone())
+        .await?;
+    assert!(!report.operation_key.is_null());
+    let messages = drain.messages();
+    assert_eq!(messages.len(), 1);
+    assert!(messages[0].contains("Nil operation key for put of k0"));
+    let entries = queue.get(ctx.clone(), k0).await?;
+    assert!(!entries.is_empty());
+    assert!(entries
+        .iter()
+        .all(|e| e.operation_key == report.operation_key));
+
+    // When rejecting, nothing is written
+    let bs = bs.with_nil_operation_key(NilOperationKeyHandling::Reject);
+    let k1 = String::from("k1");
+    let err = bs
+        .put_with_operation_key(ctx.clone(), k1.clone(), make_value("v1"), nil_key)
+        .await
+        .expect_err("put with a nil operation key should fail");
+    match err.downcast_ref::<ErrorKind>() {
+        Some(ErrorKind::NilOperationKey) => {}
+        _ => panic!("unexpected error: {:?}", err),
+    }
+    assert!(bs0.get(ctx.clone(), k1.clone()).await?.is_none());
+    assert!(queue.get(ctx.clone(), k1).await?.is_empty());
+    Ok(())
+}
+
+#[fbinit::test]
+async fn unlink_with_report(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let bs0 = Arc::new(LazyMemblob::new());
+    let bs1 = Arc::new(LazyMemblob::new());
+    // Tickable doesn't support unlink, so it always fails
+    let bs2 = Arc::new(Tickable::<BlobstoreBytes>::new());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs0.clone()),
+            (BlobstoreId::new(1), bs1.clone()),
+            (BlobstoreId::new(2), bs2.clone()),
+        ],
+        Arc::new(LogHandler::new()),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    );
+
+    let k0 = String::from("k0");
+    bs0.put(ctx.clone(), k0.clone(), make_value("v0")).await?;
+
+    let UnlinkReport {
+        unlinked,
+        missing,
+        failed,
+    } = bs.unlink_with_report(&ctx, &k0).await;
+    assert_eq!(
+        unlinked,
+        vec![BlobstoreId::new(0)]
+            .into_iter()
+            .collect::<HashSet<_>>()
+    );
+    assert_eq!(
+        missing,
+        vec![BlobstoreId::new(1)]
+            .into_iter()
+            .collect::<HashSet<_>>()
+    );
+    assert_eq!(
+        failed.keys().collect::<Vec<_>>(),
+        vec![&BlobstoreId::new(2)]
+    );
+    assert_eq!(bs0.get(ctx.clone(), k0.clone()).await?, None);
+
+    // The convenience method fails, as one of the blobstores did
+    match bs.unlink(ctx.clone(), k0).await {
+        Err(error) => match error.downcast_ref::<ErrorKind>() {
+            Some(ErrorKind::SomeUnlinksFailed(_)) => {}
+            _ => panic!("unexpected error: {:?}", error),
+        },
+        Ok(_) => panic!("unlink should have failed"),
+    }
+    Ok(())
+}
+
+#[fbinit::test]
+async fn unlink_through_queue(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = Arc::new(SqlBlobstoreSyncQueue::with_sqlite_in_memory()?);
+    let bs0 = Arc::new(LazyMemblob::new());
+    let bs1 = Arc::new(LazyMemblob::new());
+    let bs = MultiplexedBlobstore::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs0.clone()),
+            (BlobstoreId::new(1), bs1.clone()),
+        ],
+        queue.clone(),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    );
+
+    // Blobstores that don't have the key don't fail the unlink
+    let k0 = String::from("k0");
+    bs0.put(ctx.clone(), k0.clone(), make_value("v0")).await?;
+    assert_eq!(bs.unlink(ctx.clone(), k0.clone()).await?, Some(()));
+    assert_eq!(bs0.get(ctx.clone(), k0.clone()).await?, None);
+    assert_eq!(bs.unlink(ctx.clone(), k0.clone()).await?, None);
+
+    // And nothing is queued for healing, which would bring the key back
+    assert!(queue.get(ctx.clone(), k0).await?.is_empty());
+    Ok(())
+}
+
+#[fbinit::test]
+async fn slow_blobstore(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let k0 = String::from("k0");
+    let v0 = make_value("v0");
+    let make_bs = |delay, fail_after| {
+        let blobstore = SlowBlobstore::new(LazyMemblob::new(), delay);
+        let blobstore = match fail_after {
+            Some(fail_after) => blobstore.with_fail_after(fail_after),
+            None => blobstore,
+        };
+        Arc::new(blobstore)
+    };
+
+    // A get from slow stores only doesn't finish in time
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), make_bs(Duration::from_secs(60), None)),
+            (BlobstoreId::new(1), make_bs(Duration::from_secs(60), None)),
+        ],
+        Arc::new(LogHandler::new()),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    );
+    let get = tokio::time::timeout(Duration::from_millis(100), bs.get(ctx.clone(), k0.clone()));
+    assert!(get.await.is_err());
+
+    // But a fast store answers on their behalf
+    let fast = make_bs(Duration::from_millis(0), None);
+    fast.put(ctx.clone(), k0.clone(), v0.clone()).await?;
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), make_bs(Duration::from_secs(60), None)),
+            (BlobstoreId::new(1), fast),
+        ],
+        Arc::new(LogHandler::new()),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    );
+    let get = tokio::time::timeout(Duration::from_secs(10), bs.get(ctx.clone(), k0.clone()));
+    assert_eq!(get.await??.map(|v| v.into_bytes()), Some(v0.clone()));
+
+    // Stores can be made to fail after a number of operations
+    let failing = make_bs(Duration::from_millis(0), Some(1));
+    failing.put(ctx.clone(), k0.clone(), v0).await?;
+    assert!(failing.get(ctx.clone(), k0).await.is_err());
+    Ok(())
+}
+
+#[fbinit::test]
+async fn request_timeout(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let k0 = String::from("k0");
+    let v0 = make_value("v0");
+    let fast = Arc::new(LazyMemblob::new());
+    let bs = MultiplexedBlobstoreBase::new_with_request_timeout(
+        MultiplexId::new(1),
+        vec![
+            (
+                BlobstoreId::new(0),
+                Arc::new(SlowBlobstore::new(
+                    LazyMemblob::new(),
+                    Duration::from_secs(60),
+                )),
+            ),
+            (BlobstoreId::new(1), fast.clone()),
+        ],
+        Arc::new(LogHandler::new()),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+        Duration::from_millis(100),
+    );
+
+    // The slow store times out, and counts as failed, rather than blocking the get
+    let get = tokio::time::timeout(Duration::from_secs(10), bs.get(ctx.clone(), k0.clone()));
+    match get.await? {
+        Err(error) => match error.downcast_ref() {
+            Some(ErrorKind::SomeFailedOthersNone(errors)) => {
+                assert_eq!(
+                    errors.keys().collect::<Vec<_>>(),
+                    vec![&BlobstoreId::new(0)]
+                );
+            }
+            _ => panic!("unexpected error: {:?}", error),
+        },
+        Ok(value) => panic!("get should have failed, got {:?}", value),
+    }
+
+    // While the fast store still satisfies the get
+    fast.put(ctx.clone(), k0.clone(), v0.clone()).await?;
+    let get = tokio::time::timeout(Duration::from_secs(10), bs.get(ctx.clone(), k0.clone()));
+    assert_eq!(get.await??.map(|v| v.into_bytes()), Some(v0));
+    Ok(())
+}
+
+#[fbinit::test]
+async fn scuba_multiplex_id(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let dir = TempDir::new("scuba_multiplex_id")?;
+    let log_file = dir.path().join("scuba.json");
+    let bs0 = Arc::new(LazyMemblob::new());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(42),
+        vec![(BlobstoreId::new(0), bs0)],
+        Arc::new(LogHandler::new()),
+        ScubaSampleBuilder::with_discard().with_log_file(&log_file)?,
+        nonzero!(1u64),
+    );
+
+    let k0 = String::from("k0");
+    bs.put(ctx.clone(), k0.clone(), make_value("v0")).await?;
+    bs.get(ctx.clone(), k0).await?;
+
+    let samples = std::fs::read_to_string(&log_file)?
+        .lines()
+        .map(serde_json::from_str::<serde_json::Value>)
+        .collect::<Result<Vec<_>, _>>()?;
+    assert!(!samples.is_empty());
+    for sample in samples {
+        assert_eq!(sample["int"]["multiplex_id"], 42);
+    }
+    Ok(())
+}
+
+#[fbinit::test]
+async fn get_records_winner(fb: FacebookInit) {
+    let bs1 = Arc::new(Tickable::new());
+    let bs2 = Arc::new(Tickable::new());
+    let bs = MultiplexedBlobstoreBase::new(
+        MultiplexId::new(1),
+        vec![
+            (BlobstoreId::new(0), bs1.clone()),
+            (BlobstoreId::new(1), bs2.clone()),
+        ],
+        Arc::new(LogHandler::new()),
+        ScubaSampleBuilder::with_discard(),
+        nonzero!(1u64),
+    );
+    let ctx = CoreContext::test_mock(fb);
+    let k = String::from("k");
+    let v = make_value("v");
+    bs2.storage.with(|s| s.insert(k.clone(), v.clone()));
+
+    // Nothing won yet
+    assert_eq!(
+        ctx.perf_counters()
+            .get_counter(PerfCounterType::BlobGetsWinner),
+        0
+    );
+
+    // Only bs2 has the value, so it wins even though bs1 answers first
+    let mut get_fut = bs.get(ctx.clone(), k.clone()).map_err(|_| ()).boxed();
+    assert_eq!(PollOnce::new(Pin::new(&mut get_fut)).await, Poll::Pending);
+    bs1.tick(None);
+    assert_eq!(PollOnce::new(Pin::new(&mut get_fut)).await, Poll::Pending);
+    bs2.tick(None);
+    assert_eq!(get_fut.await.unwrap(), Some(v.clone().into()));
+    assert_eq!(
+        ctx.perf_counters()
+            .get_counter(PerfCounterType::BlobGetsWinner),
+        2
+    );
+
+    // Once both have it, whichever answers first wins, even blobstore 0
+    bs1.storage.with(|s| s.insert(k.clone(), v.clone()));
+    let mut get_fut = bs.get(ctx.clone(), k).map_err(|_| ()).boxed();
+    assert_eq!(PollOnce::new(Pin::new(&mut get_fut)).await, Poll::Pending);
+    bs1.tick(None);
+    assert_eq!(get_fut.await.unwrap(), Some(v.into()));
+    assert_eq!(
+        ctx.perf_counters()
+            .get_counter(PerfCounterType::BlobGetsWinner),
+        1
+    );
+}
@@ -320,6 +320,17 @@ pub trait Blobstore: fmt::Debug + Send + Sync + 'static {
         }
         .boxed()
     }
+
+    /// Remove `key` from the blob store, returning `None` if it wasn't there. Blob stores
+    /// are append-only in normal operation, so this is only meant for tools like redaction and
+    /// GC, and most implementations don't support it.
+    fn unlink(
+        &self,
+        _ctx: CoreContext,
+        _key: String,
+    ) -> BoxFuture<'static, Result<Option<()>, Error>> {
+        async { Err(ErrorKind::UnlinkNotSupported.into()) }.boxed()
+    }
 }
 
 /// Mixin trait for blobstores that support the `link()` operation
@@ -12,5 +12,8 @@ CREATE TABLE `blobstore_sync_queue` (
   `add_timestamp` BIGINT NOT NULL,
   `multiplex_id` INTEGER NOT NULL,
   `original_timestamp` BIGINT NOT NULL DEFAULT 0,
-  `operation_key` BINARY(16) NOT NULL DEFAULT X'00000000000000000000000000000000'
+  `operation_key` BINARY(16) NOT NULL DEFAULT X'00000000000000000000000000000000',
+  `claimed_by` varchar NULL,
+  `lease_expiry` BIGINT NULL,
+  `blob_size` BIGINT NULL
 );
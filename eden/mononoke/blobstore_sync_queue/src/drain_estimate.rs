@@ -0,0 +1,56 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+//! Forecast when the sync queue will be empty, from how its size changed between two samples.
+
+use mononoke_types::DateTime;
+
+/// Number of entries in the queue at a point in time.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub struct QueueSize {
+    pub at: DateTime,
+    pub count: u64,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq)]
+pub struct DrainEstimate {
+    /// How fast the queue shrank between the samples
+    pub entries_per_sec: f64,
+    /// When the queue will be empty if it keeps shrinking at that rate
+    pub empty_at: DateTime,
+}
+
+/// Estimate when the queue will be empty, given its size at two points in time. Returns `None`
+/// if it isn't shrinking, as it would then never be empty, or if `later` isn't later than
+/// `earlier`.
+pub fn estimate_drain(earlier: QueueSize, later: QueueSize) -> Option<DrainEstimate> {
+    let elapsed_secs = later.at.timestamp_secs() - earlier.at.timestamp_secs();
+    if elapsed_secs <= 0 {
+        return None;
+    }
+    let entries_per_sec = (earlier.count as f64 - later.count as f64) / elapsed_secs as f64;
+    if later.count == 0 {
+        return Some(DrainEstimate {
+            entries_per_sec: entries_per_sec.max(0.0),
+            empty_at: later.at,
+        });
+    }
+    if entries_per_sec <= 0.0 {
+        return None;
+    }
+
+    let remaining_secs = (later.count as f64 / entries_per_sec).ceil() as i64;
+    let empty_at = DateTime::from_timestamp(
+        later.at.timestamp_secs() + remaining_secs,
+        later.at.tz_offset_secs(),
+    )
+    .ok()?;
+    Some(DrainEstimate {
+        entries_per_sec,
+        empty_at,
+    })
+}
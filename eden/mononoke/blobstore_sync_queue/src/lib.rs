@@ -7,6 +7,8 @@
 
 #![deny(warnings)]
 
+mod drain_estimate;
+
 use anyhow::{format_err, Error};
 use auto_impl::auto_impl;
 use cloned::cloned;
@@ -15,10 +17,12 @@ use futures::{
     channel::{mpsc, oneshot},
     compat::Future01CompatExt,
     future::{self, BoxFuture, FutureExt, Shared, TryFutureExt},
-    stream::StreamExt,
+    sink::SinkExt,
+    stream::{self, Stream, StreamExt, TryStreamExt},
 };
 use metaconfig_types::{BlobstoreId, MultiplexId};
 use mononoke_types::{DateTime, Timestamp};
+use slog::warn;
 use sql::mysql_async::{
     prelude::{ConvIr, FromValue},
     FromValueError, Value,
@@ -27,15 +31,25 @@ use sql::{queries, Connection};
 pub use sql_construct::SqlConstruct;
 pub use sql_ext::SqlConnections;
 use stats::prelude::*;
+use std::collections::HashMap;
+use std::future::Future;
 use std::iter::IntoIterator;
-use std::sync::Arc;
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
+use thiserror::Error as DeriveError;
 use uuid::Uuid;
 
+pub use crate::drain_estimate::{estimate_drain, DrainEstimate, QueueSize};
+
 define_stats! {
     prefix = "mononoke.blobstore_sync_queue";
     adds: timeseries(Rate, Sum),
     iters: timeseries(Rate, Sum),
+    iter_replica_fallbacks: timeseries(Rate, Sum),
+    counts: timeseries(Rate, Sum),
     dels: timeseries(Rate, Sum),
+    unpersisted_adds: timeseries(Rate, Sum),
 }
 
 // Identifier for given blobstore operation to faciliate correlating same operation
@@ -83,6 +97,52 @@ impl FromValue for OperationKey {
     type Intermediate = OperationKey;
 }
 
+/// Error returned by `add_many` when entries could not be inserted into the queue.
+#[derive(Clone, Debug, DeriveError)]
+pub enum QueueError {
+    /// The insert failed for a transient reason (e.g. deadlock, lock wait timeout or lost
+    /// connection), so retrying it may succeed.
+    #[error("failed to insert (retryable): {0}")]
+    Retryable(String),
+    /// The insert can't succeed as is (e.g. it violates a constraint).
+    #[error("failed to insert: {0}")]
+    Permanent(String),
+}
+
+impl QueueError {
+    pub fn is_retryable(&self) -> bool {
+        match self {
+            QueueError::Retryable(_) => true,
+            QueueError::Permanent(_) => false,
+        }
+    }
+
+    /// Classify a MySQL server or client error code.
+    pub fn from_mysql_code(code: u16, message: String) -> Self {
+        match code {
+            // ER_LOCK_WAIT_TIMEOUT, ER_LOCK_DEADLOCK
+            1205 | 1213 => QueueError::Retryable(message),
+            // CR_SERVER_GONE_ERROR, CR_SERVER_LOST
+            2006 | 2013 => QueueError::Retryable(message),
+            _ => QueueError::Permanent(message),
+        }
+    }
+
+    fn from_insert_error(err: Error) -> Self {
+        let message = format!("{}", err);
+        if let Some(server_error) = err.downcast_ref::<sql::error::ServerError>() {
+            return QueueError::from_mysql_code(server_error.code, message);
+        }
+        if let Some(io_error) = err.downcast_ref::<std::io::Error>() {
+            use std::io::ErrorKind::*;
+            if let ConnectionReset | ConnectionAborted | BrokenPipe | TimedOut = io_error.kind() {
+                return QueueError::Retryable(message);
+            }
+        }
+        QueueError::Permanent(message)
+    }
+}
+
 #[derive(Clone, Debug, Eq, PartialEq, Hash)]
 pub struct BlobstoreSyncQueueEntry {
     pub blobstore_key: String,
@@ -91,6 +151,7 @@ pub struct BlobstoreSyncQueueEntry {
     pub timestamp: DateTime,
     pub id: Option<u64>,
     pub operation_key: OperationKey,
+    pub blob_size: Option<u64>,
 }
 
 impl BlobstoreSyncQueueEntry {
@@ -108,6 +169,16 @@ impl BlobstoreSyncQueueEntry {
             timestamp,
             operation_key,
             id: None,
+            blob_size: None,
+        }
+    }
+
+    /// Record the size of the blob this entry refers to, so that it's counted by
+    /// `pending_bytes`.
+    pub fn with_blob_size(self, blob_size: u64) -> Self {
+        Self {
+            blob_size: Some(blob_size),
+            ..self
         }
     }
 }
@@ -130,7 +201,7 @@ pub trait BlobstoreSyncQueue: Send + Sync {
 
     /// Returns list of entries that consist of two groups of entries:
     /// 1. Group with at most `limit` entries that are older than `older_than` and
-    ///    optionally sql like `key_like`
+    ///    optionally sql like `key_like`, taking the oldest operations first
     /// 2. Group of entries whose `blobstore_key` can be found in group (1)
     ///
     /// As a result the caller gets a reasonably limited slice of BlobstoreSyncQueue entries that
@@ -145,6 +216,24 @@ pub trait BlobstoreSyncQueue: Send + Sync {
         limit: usize,
     ) -> BoxFuture<'static, Result<Vec<BlobstoreSyncQueueEntry>, Error>>;
 
+    /// Returns the number of operations for `multiplex_id` with entries older than
+    /// `older_than`, i.e. how many operations `iter` would go through to drain the queue.
+    /// This is meant for monitoring queue depth without fetching the entries.
+    fn count(
+        &self,
+        ctx: CoreContext,
+        multiplex_id: MultiplexId,
+        older_than: DateTime,
+    ) -> BoxFuture<'static, Result<u64, Error>>;
+
+    /// Returns the `limit` oldest entries across all multiplexes, oldest first, e.g. to find
+    /// the most lagging blobstores without going through each multiplex.
+    fn oldest_entries_global(
+        &self,
+        ctx: CoreContext,
+        limit: usize,
+    ) -> BoxFuture<'static, Result<Vec<BlobstoreSyncQueueEntry>, Error>>;
+
     fn del(
         &self,
         ctx: CoreContext,
@@ -156,16 +245,49 @@ pub trait BlobstoreSyncQueue: Send + Sync {
         ctx: CoreContext,
         key: String,
     ) -> BoxFuture<'static, Result<Vec<BlobstoreSyncQueueEntry>, Error>>;
+
+    /// Like `get`, but for many keys at once. Keys without entries are absent from the
+    /// returned map.
+    fn get_many(
+        &self,
+        ctx: CoreContext,
+        keys: Vec<String>,
+    ) -> BoxFuture<'static, Result<HashMap<String, Vec<BlobstoreSyncQueueEntry>>, Error>>;
+
+    /// Atomically marks up to `limit` entries older than `older_than` as owned by
+    /// `claimant_id` and returns them. Entries `claimant_id` already owns are claimed again,
+    /// which renews their lease. Entries claimed by somebody else are skipped until their
+    /// lease expires, so that multiple healers don't redundantly process the same entries.
+    fn claim(
+        &self,
+        ctx: CoreContext,
+        multiplex_id: MultiplexId,
+        older_than: DateTime,
+        limit: usize,
+        claimant_id: String,
+    ) -> BoxFuture<'static, Result<Vec<BlobstoreSyncQueueEntry>, Error>>;
 }
 
+type WriteRequest = (
+    oneshot::Sender<Result<(), QueueError>>,
+    BlobstoreSyncQueueEntry,
+);
+
+/// Entries are added by a background writer, which keeps going until the last handle to the
+/// queue is dropped and then writes whatever is still buffered. Use `close` to wait for that.
 #[derive(Clone)]
 pub struct SqlBlobstoreSyncQueue {
     write_connection: Arc<Connection>,
     read_connection: Connection,
     read_master_connection: Connection,
-    write_sender:
-        Arc<mpsc::UnboundedSender<(oneshot::Sender<Result<(), Error>>, BlobstoreSyncQueueEntry)>>,
+    write_sender: Arc<mpsc::Sender<WriteRequest>>,
     ensure_worker_scheduled: Shared<BoxFuture<'static, ()>>,
+    worker_done: Shared<BoxFuture<'static, usize>>,
+    iter_replica_fallback: bool,
+    claim_lease_duration: Duration,
+    add_rate_limiter: Option<Arc<AddRateLimiter>>,
+    write_buffer: usize,
+    max_write_latency: Option<Duration>,
 }
 
 queries! {
@@ -175,9 +297,10 @@ queries! {
         multiplex_id: MultiplexId,
         timestamp: Timestamp,
         operation_key: OperationKey,
+        blob_size: Option<u64>,
     )) {
         none,
-        "INSERT INTO blobstore_sync_queue (blobstore_key, blobstore_id, multiplex_id, add_timestamp, operation_key)
+        "INSERT INTO blobstore_sync_queue (blobstore_key, blobstore_id, multiplex_id, add_timestamp, operation_key, blob_size)
          VALUES {values}"
     }
 
@@ -186,6 +309,21 @@ queries! {
         "DELETE FROM blobstore_sync_queue WHERE id in {ids}"
     }
 
+    write UpdateAddTimestamp(add_timestamp: Timestamp, >list ids: u64) {
+        none,
+        "UPDATE blobstore_sync_queue SET add_timestamp = {add_timestamp} WHERE id IN {ids}"
+    }
+
+    write DeleteByOperationKeys(>list operation_keys: OperationKey) {
+        none,
+        "DELETE FROM blobstore_sync_queue WHERE operation_key in {operation_keys}"
+    }
+
+    write DeleteByOperationKeysInMultiplex(multiplex_id: MultiplexId, >list operation_keys: OperationKey) {
+        none,
+        "DELETE FROM blobstore_sync_queue WHERE operation_key IN {operation_keys} AND multiplex_id = {multiplex_id}"
+    }
+
     read GetRangeOfEntries(multiplex_id: MultiplexId, older_than: Timestamp, limit: usize) -> (
         String,
         BlobstoreId,
@@ -197,15 +335,23 @@ queries! {
         "SELECT blobstore_key, blobstore_id, multiplex_id, add_timestamp, blobstore_sync_queue.operation_key, id
          FROM blobstore_sync_queue
          JOIN (
-               SELECT DISTINCT operation_key
+               SELECT operation_key
                FROM blobstore_sync_queue
                WHERE add_timestamp <= {older_than} AND multiplex_id = {multiplex_id}
+               GROUP BY operation_key
+               ORDER BY MIN(add_timestamp) ASC
                LIMIT {limit}
          ) b
          ON blobstore_sync_queue.operation_key = b.operation_key AND multiplex_id = {multiplex_id}
          "
     }
 
+    read CountOperations(multiplex_id: MultiplexId, older_than: Timestamp) -> (u64) {
+        "SELECT COUNT(DISTINCT operation_key)
+         FROM blobstore_sync_queue
+         WHERE add_timestamp <= {older_than} AND multiplex_id = {multiplex_id}"
+    }
+
     read GetRangeOfEntriesLike(blobstore_key_like: String, multiplex_id: MultiplexId, older_than: Timestamp, limit: usize) -> (
         String,
         BlobstoreId,
@@ -217,15 +363,103 @@ queries! {
         "SELECT blobstore_key, blobstore_id, multiplex_id, add_timestamp, blobstore_sync_queue.operation_key, id
          FROM blobstore_sync_queue
          JOIN (
-               SELECT DISTINCT operation_key
+               SELECT operation_key
                FROM blobstore_sync_queue
                WHERE blobstore_key LIKE {blobstore_key_like} AND add_timestamp <= {older_than} AND multiplex_id = {multiplex_id}
+               GROUP BY operation_key
+               ORDER BY MIN(add_timestamp) ASC
                LIMIT {limit}
          ) b
          ON blobstore_sync_queue.operation_key = b.operation_key AND multiplex_id = {multiplex_id}
          "
     }
 
+    read GetFlatRangeOfEntries(multiplex_id: MultiplexId, older_than: Timestamp, limit: usize) -> (
+        String,
+        BlobstoreId,
+        MultiplexId,
+        Timestamp,
+        OperationKey,
+        u64,
+    ) {
+        "SELECT blobstore_key, blobstore_id, multiplex_id, add_timestamp, operation_key, id
+         FROM blobstore_sync_queue
+         WHERE add_timestamp <= {older_than} AND multiplex_id = {multiplex_id}
+         ORDER BY add_timestamp ASC
+         LIMIT {limit}"
+    }
+
+    read GetOldestEntries(limit: usize) -> (
+        String,
+        BlobstoreId,
+        MultiplexId,
+        Timestamp,
+        OperationKey,
+        u64,
+    ) {
+        "SELECT blobstore_key, blobstore_id, multiplex_id, add_timestamp, operation_key, id
+         FROM blobstore_sync_queue
+         ORDER BY add_timestamp ASC, id ASC
+         LIMIT {limit}"
+    }
+
+    read GetFilteredEntries(
+        multiplex_id: MultiplexId,
+        blobstore_id: Option<BlobstoreId>,
+        blobstore_key_like: Option<String>,
+        from: Option<Timestamp>,
+        to: Option<Timestamp>,
+        limit: usize,
+    ) -> (
+        String,
+        BlobstoreId,
+        MultiplexId,
+        Timestamp,
+        OperationKey,
+        u64,
+    ) {
+        "SELECT blobstore_key, blobstore_id, multiplex_id, add_timestamp, operation_key, id
+         FROM blobstore_sync_queue
+         WHERE multiplex_id = {multiplex_id}
+           AND ({blobstore_id} IS NULL OR blobstore_id = {blobstore_id})
+           AND ({blobstore_key_like} IS NULL OR blobstore_key LIKE {blobstore_key_like})
+           AND ({from} IS NULL OR add_timestamp >= {from})
+           AND ({to} IS NULL OR add_timestamp <= {to})
+         ORDER BY id ASC
+         LIMIT {limit}"
+    }
+
+    read CountFilteredEntries(
+        multiplex_id: MultiplexId,
+        blobstore_id: Option<BlobstoreId>,
+        blobstore_key_like: Option<String>,
+        from: Option<Timestamp>,
+        to: Option<Timestamp>,
+    ) -> (u64) {
+        "SELECT COUNT(*)
+         FROM blobstore_sync_queue
+         WHERE multiplex_id = {multiplex_id}
+           AND ({blobstore_id} IS NULL OR blobstore_id = {blobstore_id})
+           AND ({blobstore_key_like} IS NULL OR blobstore_key LIKE {blobstore_key_like})
+           AND ({from} IS NULL OR add_timestamp >= {from})
+           AND ({to} IS NULL OR add_timestamp <= {to})"
+    }
+
+    read GetEntriesAfterId(multiplex_id: MultiplexId, older_than: Timestamp, after_id: u64, limit: usize) -> (
+        String,
+        BlobstoreId,
+        MultiplexId,
+        Timestamp,
+        OperationKey,
+        u64,
+    ) {
+        "SELECT blobstore_key, blobstore_id, multiplex_id, add_timestamp, operation_key, id
+         FROM blobstore_sync_queue
+         WHERE multiplex_id = {multiplex_id} AND add_timestamp <= {older_than} AND id > {after_id}
+         ORDER BY id ASC
+         LIMIT {limit}"
+    }
+
     read GetByKey(key: String) -> (
         String,
         BlobstoreId,
@@ -238,6 +472,145 @@ queries! {
          FROM blobstore_sync_queue
          WHERE blobstore_key = {key}"
     }
+
+    read GetByKeys(>list keys: String) -> (
+        String,
+        BlobstoreId,
+        MultiplexId,
+        Timestamp,
+        OperationKey,
+        u64,
+    ) {
+        "SELECT blobstore_key, blobstore_id, multiplex_id, add_timestamp, operation_key, id
+         FROM blobstore_sync_queue
+         WHERE blobstore_key IN {keys}"
+    }
+
+    write ClaimEntries(
+        multiplex_id: MultiplexId,
+        older_than: Timestamp,
+        limit: usize,
+        claimant_id: String,
+        now: Timestamp,
+        lease_expiry: Timestamp,
+    ) {
+        none,
+        mysql(
+            "UPDATE blobstore_sync_queue
+             SET claimed_by = {claimant_id}, lease_expiry = {lease_expiry}
+             WHERE multiplex_id = {multiplex_id} AND add_timestamp <= {older_than}
+               AND (claimed_by IS NULL OR lease_expiry < {now} OR claimed_by = {claimant_id})
+             ORDER BY id
+             LIMIT {limit}"
+        )
+        sqlite(
+            "UPDATE blobstore_sync_queue
+             SET claimed_by = {claimant_id}, lease_expiry = {lease_expiry}
+             WHERE id IN (
+                 SELECT id
+                 FROM blobstore_sync_queue
+                 WHERE multiplex_id = {multiplex_id} AND add_timestamp <= {older_than}
+                   AND (claimed_by IS NULL OR lease_expiry < {now} OR claimed_by = {claimant_id})
+                 ORDER BY id
+                 LIMIT {limit}
+             )"
+        )
+    }
+
+    read GetPendingBytes(multiplex_id: MultiplexId) -> (Option<u64>) {
+        "SELECT SUM(blob_size)
+         FROM blobstore_sync_queue
+         WHERE multiplex_id = {multiplex_id}"
+    }
+
+    write ReassignMultiplex(from: MultiplexId, to: MultiplexId, limit: usize) {
+        none,
+        mysql(
+            "UPDATE blobstore_sync_queue
+             SET multiplex_id = {to}
+             WHERE multiplex_id = {from}
+             LIMIT {limit}"
+        )
+        sqlite(
+            "UPDATE blobstore_sync_queue
+             SET multiplex_id = {to}
+             WHERE id IN (
+                 SELECT id
+                 FROM blobstore_sync_queue
+                 WHERE multiplex_id = {from}
+                 LIMIT {limit}
+             )"
+        )
+    }
+
+    write ReassignBlobstoreId(multiplex_id: MultiplexId, from: BlobstoreId, to: BlobstoreId, limit: usize) {
+        none,
+        mysql(
+            "UPDATE blobstore_sync_queue
+             SET blobstore_id = {to}
+             WHERE multiplex_id = {multiplex_id} AND blobstore_id = {from}
+             LIMIT {limit}"
+        )
+        sqlite(
+            "UPDATE blobstore_sync_queue
+             SET blobstore_id = {to}
+             WHERE id IN (
+                 SELECT id
+                 FROM blobstore_sync_queue
+                 WHERE multiplex_id = {multiplex_id} AND blobstore_id = {from}
+                 LIMIT {limit}
+             )"
+        )
+    }
+
+    read GetOperationsMissingStores(multiplex_id: MultiplexId, expected_store_count: u64, limit: usize) -> (OperationKey) {
+        "SELECT operation_key
+         FROM blobstore_sync_queue
+         WHERE multiplex_id = {multiplex_id}
+         GROUP BY operation_key
+         HAVING COUNT(DISTINCT blobstore_id) < {expected_store_count}
+         ORDER BY MIN(id) ASC
+         LIMIT {limit}"
+    }
+
+    read GetDuplicateEntries(multiplex_id: MultiplexId, limit: usize) -> (u64) {
+        "SELECT id
+         FROM blobstore_sync_queue q
+         WHERE multiplex_id = {multiplex_id}
+           AND EXISTS (
+               SELECT 1
+               FROM blobstore_sync_queue o
+               WHERE o.multiplex_id = q.multiplex_id
+                 AND o.blobstore_key = q.blobstore_key
+                 AND o.blobstore_id = q.blobstore_id
+                 AND o.operation_key = q.operation_key
+                 AND (o.add_timestamp < q.add_timestamp
+                      OR (o.add_timestamp = q.add_timestamp AND o.id < q.id))
+           )
+         ORDER BY id ASC
+         LIMIT {limit}"
+    }
+
+    read Probe() -> (u64) {
+        "SELECT 1"
+    }
+
+    read GetClaimedEntries(
+        multiplex_id: MultiplexId,
+        claimant_id: String,
+        lease_expiry: Timestamp,
+    ) -> (
+        String,
+        BlobstoreId,
+        MultiplexId,
+        Timestamp,
+        OperationKey,
+        u64,
+    ) {
+        "SELECT blobstore_key, blobstore_id, multiplex_id, add_timestamp, operation_key, id
+         FROM blobstore_sync_queue
+         WHERE multiplex_id = {multiplex_id} AND claimed_by = {claimant_id} AND lease_expiry = {lease_expiry}"
+    }
 }
 
 impl SqlConstruct for SqlBlobstoreSyncQueue {
@@ -247,54 +620,747 @@ impl SqlConstruct for SqlBlobstoreSyncQueue {
 
     fn from_sql_connections(connections: SqlConnections) -> Self {
         let write_connection = Arc::new(connections.write_connection);
-        type ChannelType = (oneshot::Sender<Result<(), Error>>, BlobstoreSyncQueueEntry);
-        let (sender, receiver): (mpsc::UnboundedSender<ChannelType>, _) = mpsc::unbounded();
+        let (write_sender, ensure_worker_scheduled, worker_done) =
+            start_writer(write_connection.clone(), WRITE_BUFFER_SIZE, None);
+
+        Self {
+            write_connection,
+            read_connection: connections.read_connection,
+            read_master_connection: connections.read_master_connection,
+            write_sender,
+            ensure_worker_scheduled,
+            worker_done,
+            iter_replica_fallback: false,
+            claim_lease_duration: DEFAULT_CLAIM_LEASE_DURATION,
+            add_rate_limiter: None,
+            write_buffer: WRITE_BUFFER_SIZE,
+            max_write_latency: None,
+        }
+    }
+}
+
+impl SqlBlobstoreSyncQueue {
+    /// Make `iter` fall back to the replica read connection when reading from master fails.
+    /// Entries returned this way may be stale, so this trades correctness for availability
+    /// (e.g. during a master failover) and is disabled by default.
+    pub fn with_iter_replica_fallback(self, iter_replica_fallback: bool) -> Self {
+        Self {
+            iter_replica_fallback,
+            ..self
+        }
+    }
+
+    /// Set for how long entries returned by `claim` stay owned by their claimant.
+    pub fn with_claim_lease_duration(self, claim_lease_duration: Duration) -> Self {
+        Self {
+            claim_lease_duration,
+            ..self
+        }
+    }
+
+    /// Cap how many entries per second `add_many` hands to the writer. Callers exceeding the
+    /// rate wait for capacity, which protects the database during write storms at the cost
+    /// of extra put latency. Unlimited by default.
+    pub fn with_add_rate_limit(self, entries_per_second: NonZeroU32) -> Self {
+        Self {
+            add_rate_limiter: Some(Arc::new(AddRateLimiter::new(entries_per_second))),
+            ..self
+        }
+    }
+
+    /// Bound how many entries can wait to be written, and how many are written in one batch,
+    /// instead of `WRITE_BUFFER_SIZE`. Once the buffer is full, `add_many` waits for the
+    /// writer to catch up, so that a slow database applies backpressure to callers rather
+    /// than the buffer growing without limit. This replaces the writer, so it must be called
+    /// before adding entries.
+    pub fn with_write_buffer(self, write_buffer: usize) -> Self {
+        Self {
+            write_buffer,
+            ..self
+        }
+        .restart_writer()
+    }
+
+    /// By default, the writer writes whatever entries are waiting as soon as it gets to
+    /// them, in batches of up to the write buffer. With a max write latency, while entries
+    /// keep arriving the writer waits for more of them to fill a batch, but for no longer
+    /// than `max_write_latency` after its first entry, which bounds how long `add` takes. A
+    /// batch is still written as soon as no more entries are waiting, so there is no added
+    /// delay for a lone entry. This replaces the writer, so it must be called before adding
+    /// entries.
+    pub fn with_max_write_latency(self, max_write_latency: Duration) -> Self {
+        Self {
+            max_write_latency: Some(max_write_latency),
+            ..self
+        }
+        .restart_writer()
+    }
+
+    fn restart_writer(self) -> Self {
+        let (write_sender, ensure_worker_scheduled, worker_done) = start_writer(
+            self.write_connection.clone(),
+            self.write_buffer,
+            self.max_write_latency,
+        );
+        Self {
+            write_sender,
+            ensure_worker_scheduled,
+            worker_done,
+            ..self
+        }
+    }
+
+    /// Stop accepting new entries, for all handles to this queue, and wait until the entries
+    /// already added have been written. Entries that fail to be written while nobody is
+    /// waiting for them any more are logged, as they would otherwise be lost silently.
+    pub async fn close(&self, ctx: &CoreContext) {
+        // Closing any sender closes the channel for all of them
+        (*self.write_sender).clone().close_channel();
+        // The writer has to run to notice that the channel is closed
+        self.ensure_worker_scheduled.clone().await;
+        let unpersisted = self.worker_done.clone().await;
+        if unpersisted > 0 {
+            warn!(
+                ctx.logger(),
+                "{} sync queue entries could not be written after being abandoned", unpersisted
+            );
+        }
+    }
+
+    /// Issue a trivial query on each connection, so that they are established before real
+    /// traffic arrives rather than adding latency to the first queue operations. Services
+    /// can call this during startup.
+    pub async fn warmup(&self, _ctx: &CoreContext) -> Result<(), Error> {
+        future::try_join3(
+            Probe::query(&self.write_connection).compat(),
+            Probe::query(&self.read_connection).compat(),
+            Probe::query(&self.read_master_connection).compat(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Move up to `limit` entries from multiplex `from` to multiplex `to`, returning how many
+    /// were moved. Used when reconfiguring multiplexes, so that pending heals aren't dropped;
+    /// call repeatedly until it returns 0 to move everything in bounded transactions.
+    pub async fn reassign_multiplex(
+        &self,
+        _ctx: CoreContext,
+        from: MultiplexId,
+        to: MultiplexId,
+        limit: usize,
+    ) -> Result<u64, Error> {
+        let result = ReassignMultiplex::query(&self.write_connection, &from, &to, &limit)
+            .compat()
+            .await?;
+        Ok(result.affected_rows())
+    }
+
+    /// Re-point up to `limit` entries of `multiplex_id` from blobstore `from` to blobstore
+    /// `to`, returning how many were changed. Used when replacing a blobstore, so that its
+    /// pending heals go to the replacement; like `reassign_multiplex`, call repeatedly until
+    /// it returns 0.
+    pub async fn reassign_blobstore_id(
+        &self,
+        _ctx: CoreContext,
+        multiplex_id: MultiplexId,
+        from: BlobstoreId,
+        to: BlobstoreId,
+        limit: usize,
+    ) -> Result<u64, Error> {
+        let result =
+            ReassignBlobstoreId::query(&self.write_connection, &multiplex_id, &from, &to, &limit)
+                .compat()
+                .await?;
+        Ok(result.affected_rows())
+    }
+
+    /// Move the `add_timestamp` of `entries` back to `timestamp`, or to the Unix epoch if
+    /// `None`, returning how many entries were changed. This is an operational override
+    /// to make the healer pick up recently added entries on its next pass, rather than
+    /// waiting until they are older than its `older_than` cutoff.
+    pub async fn expedite(
+        &self,
+        _ctx: CoreContext,
+        entries: Vec<BlobstoreSyncQueueEntry>,
+        timestamp: Option<DateTime>,
+    ) -> Result<u64, Error> {
+        let timestamp =
+            timestamp.map_or_else(|| Timestamp::from_timestamp_nanos(0), Timestamp::from);
+        let ids: Vec<u64> = entries
+            .into_iter()
+            .map(|entry| {
+                entry.id.ok_or_else(|| {
+                    format_err!(
+                        "BlobstoreSyncQueueEntry must contain `id` to be able to expedite it"
+                    )
+                })
+            })
+            .collect::<Result<_, _>>()?;
+
+        let mut expedited = 0;
+        for chunk in ids.chunks(10_000) {
+            let result = UpdateAddTimestamp::query(&self.write_connection, &timestamp, chunk)
+                .compat()
+                .await?;
+            expedited += result.affected_rows();
+        }
+        Ok(expedited)
+    }
+
+    /// Delete all entries of the given operations, returning how many entries were deleted.
+    /// Each statement deletes every entry of up to `chunk` operations, so that an operation
+    /// is never left half-deleted if a later chunk fails, unlike with `del`. Fails without
+    /// deleting anything if one of the keys is nil.
+    pub async fn del_many_operations(
+        &self,
+        _ctx: CoreContext,
+        keys: Vec<OperationKey>,
+        chunk: usize,
+    ) -> Result<u64, Error> {
+        if chunk == 0 {
+            return Err(format_err!(
+                "del_many_operations needs a chunk size above 0"
+            ));
+        }
+        ensure_not_null(&keys)?;
+        let mut deleted = 0;
+        for chunk in keys.chunks(chunk) {
+            let deletion_result = DeleteByOperationKeys::query(&self.write_connection, chunk)
+                .compat()
+                .await?;
+            STATS::dels.add_value(deletion_result.affected_rows() as i64);
+            deleted += deletion_result.affected_rows();
+        }
+        Ok(deleted)
+    }
+
+    /// Delete the entries of `multiplex_id` for the given operations, returning how many
+    /// entries were deleted. Unlike `del`, this doesn't need the entries' ids, so a caller
+    /// that knows an operation completed in all blobstores doesn't have to fetch it first.
+    /// Fails without deleting anything if one of the keys is nil.
+    pub async fn del_by_operation_key(
+        &self,
+        _ctx: CoreContext,
+        multiplex_id: MultiplexId,
+        keys: Vec<OperationKey>,
+    ) -> Result<u64, Error> {
+        ensure_not_null(&keys)?;
+        let mut deleted = 0;
+        for chunk in keys.chunks(10_000) {
+            let deletion_result = DeleteByOperationKeysInMultiplex::query(
+                &self.write_connection,
+                &multiplex_id,
+                chunk,
+            )
+            .compat()
+            .await?;
+            STATS::dels.add_value(deletion_result.affected_rows() as i64);
+            deleted += deletion_result.affected_rows();
+        }
+        Ok(deleted)
+    }
+
+    /// Delete up to `limit` redundant entries of `multiplex_id`, i.e. entries for the same
+    /// blobstore key, blobstore and operation as an older entry, returning how many were
+    /// deleted. Only the oldest entry of each is kept, so the queue still records the same
+    /// writes; like `reassign_multiplex`, call repeatedly until it returns 0.
+    pub async fn compact(
+        &self,
+        _ctx: CoreContext,
+        multiplex_id: MultiplexId,
+        limit: usize,
+    ) -> Result<u64, Error> {
+        let rows = GetDuplicateEntries::query(&self.read_master_connection, &multiplex_id, &limit)
+            .compat()
+            .await?;
+        let ids: Vec<u64> = rows.into_iter().map(|(id,)| id).collect();
+        if ids.is_empty() {
+            return Ok(0);
+        }
+        let result = DeleteEntries::query(&self.write_connection, &ids[..])
+            .compat()
+            .await?;
+        STATS::dels.add_value(result.affected_rows() as i64);
+        Ok(result.affected_rows())
+    }
+
+    /// Return at most `limit` operation keys of `multiplex_id`, oldest first, that have
+    /// entries for fewer than `expected_store_count` distinct blobstores. As each blobstore
+    /// written by a multiplexed put gets an entry, these are the incomplete writes that
+    /// still need healing.
+    pub async fn operations_missing_stores(
+        &self,
+        _ctx: CoreContext,
+        multiplex_id: MultiplexId,
+        expected_store_count: u64,
+        limit: usize,
+    ) -> Result<Vec<OperationKey>, Error> {
+        let rows = GetOperationsMissingStores::query(
+            &self.read_connection,
+            &multiplex_id,
+            &expected_store_count,
+            &limit,
+        )
+        .compat()
+        .await?;
+        Ok(rows
+            .into_iter()
+            .map(|(operation_key,)| operation_key)
+            .collect())
+    }
+
+    /// Return at most `limit` entries for `multiplex_id` older than `older_than`, oldest
+    /// first. Unlike `iter`, this doesn't also fetch the other entries of each operation, so
+    /// it's cheaper, but related entries are not guaranteed to be returned together. Meant
+    /// for inspecting the queue rather than processing it.
+    pub async fn iter_flat(
+        &self,
+        _ctx: CoreContext,
+        multiplex_id: MultiplexId,
+        older_than: DateTime,
+        limit: usize,
+    ) -> Result<Vec<BlobstoreSyncQueueEntry>, Error> {
+        let rows = GetFlatRangeOfEntries::query(
+            &self.read_connection,
+            &multiplex_id,
+            &older_than.into(),
+            &limit,
+        )
+        .compat()
+        .await?;
+        Ok(rows.into_iter().map(entry_from_row).collect())
+    }
 
-        let ensure_worker_scheduled = {
-            cloned!(write_connection);
+    /// Stream all entries for `multiplex_id` older than `older_than`, in id order. Entries are
+    /// fetched `batch_size` at a time, walking the queue by id, so that memory stays bounded
+    /// and entries are returned once even if the queue changes in the meantime.
+    pub fn iter_all(
+        &self,
+        _ctx: CoreContext,
+        multiplex_id: MultiplexId,
+        older_than: DateTime,
+        batch_size: usize,
+    ) -> impl Stream<Item = Result<BlobstoreSyncQueueEntry, Error>> + 'static {
+        let connection = self.read_connection.clone();
+        stream::try_unfold(Some(0), move |after_id| {
+            cloned!(connection);
             async move {
-                let batch_writes = receiver.ready_chunks(WRITE_BUFFER_SIZE).for_each({
-                    move |batch| {
-                        cloned!(write_connection);
-                        async move {
-                            let (senders, entries): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
-
-                            match insert_entries(write_connection.as_ref(), entries).await {
-                                Ok(()) => {
-                                    for sender in senders {
-                                        // Ignoring the error, because receiver might have gone
-                                        let _ = sender.send(Ok(()));
-                                    }
-                                }
-                                Err(err) => {
-                                    let s = format!("failed to insert {}", err);
-                                    for sender in senders {
-                                        // Ignoring the error, because receiver might have gone
-                                        let _ = sender.send(Err(Error::msg(s.clone())));
-                                    }
-                                }
-                            }
-                        }
-                    }
-                });
+                let after_id = match after_id {
+                    Some(after_id) => after_id,
+                    None => return Ok::<_, Error>(None),
+                };
+                let rows = GetEntriesAfterId::query(
+                    &connection,
+                    &multiplex_id,
+                    &older_than.into(),
+                    &after_id,
+                    &batch_size,
+                )
+                .compat()
+                .await?;
+                STATS::iters.add_value(1);
+                let entries: Vec<_> = rows.into_iter().map(entry_from_row).collect();
+                // A short batch means we've reached the end, so don't query again
+                let next = if entries.len() < batch_size {
+                    None
+                } else {
+                    entries.last().and_then(|entry| entry.id)
+                };
+                if entries.is_empty() {
+                    Ok(None)
+                } else {
+                    Ok(Some((entries, next)))
+                }
+            }
+        })
+        .map_ok(|entries| stream::iter(entries.into_iter().map(Ok)))
+        .try_flatten()
+    }
+
+    /// Return at most `limit` entries for `multiplex_id`, oldest first, keeping only those
+    /// matching all of the given filters: entries for `blobstore_id`, with a key matching the
+    /// sql like `key_like`, and added between `from` and `to` (inclusive). Filters that are
+    /// `None` match every entry.
+    pub async fn iter_filtered(
+        &self,
+        _ctx: CoreContext,
+        multiplex_id: MultiplexId,
+        blobstore_id: Option<BlobstoreId>,
+        key_like: Option<String>,
+        from: Option<DateTime>,
+        to: Option<DateTime>,
+        limit: usize,
+    ) -> Result<Vec<BlobstoreSyncQueueEntry>, Error> {
+        let rows = GetFilteredEntries::query(
+            &self.read_connection,
+            &multiplex_id,
+            &blobstore_id,
+            &key_like,
+            &from.map(Timestamp::from),
+            &to.map(Timestamp::from),
+            &limit,
+        )
+        .compat()
+        .await?;
+        Ok(rows.into_iter().map(entry_from_row).collect())
+    }
+
+    /// Count the entries `iter_filtered` would return with an unbounded limit, without
+    /// fetching them. The filters are the same, so the queries' predicates must stay in sync.
+    pub async fn count_filtered(
+        &self,
+        _ctx: CoreContext,
+        multiplex_id: MultiplexId,
+        blobstore_id: Option<BlobstoreId>,
+        key_like: Option<String>,
+        from: Option<DateTime>,
+        to: Option<DateTime>,
+    ) -> Result<u64, Error> {
+        let rows = CountFilteredEntries::query(
+            &self.read_connection,
+            &multiplex_id,
+            &blobstore_id,
+            &key_like,
+            &from.map(Timestamp::from),
+            &to.map(Timestamp::from),
+        )
+        .compat()
+        .await?;
+        Ok(rows.into_iter().next().map_or(0, |(count,)| count))
+    }
+
+    /// Sample how many entries are currently queued for `multiplex_id`, e.g. to
+    /// `estimate_drain` from two samples taken some time apart.
+    pub async fn sample_size(
+        &self,
+        ctx: CoreContext,
+        multiplex_id: MultiplexId,
+    ) -> Result<QueueSize, Error> {
+        let at = DateTime::now();
+        let count = self
+            .count_filtered(ctx, multiplex_id, None, None, None, None)
+            .await?;
+        Ok(QueueSize { at, count })
+    }
+
+    /// Pass all entries for `multiplex_id` older than `older_than` to `process`, in batches of
+    /// at most `batch` entries, and delete the entries it returns. Returns how many entries
+    /// were deleted.
+    pub async fn drain<F, Fut>(
+        &self,
+        ctx: CoreContext,
+        multiplex_id: MultiplexId,
+        older_than: DateTime,
+        batch: usize,
+        process: F,
+    ) -> Result<usize, Error>
+    where
+        F: FnMut(Vec<BlobstoreSyncQueueEntry>) -> Fut,
+        Fut: Future<Output = Result<Vec<BlobstoreSyncQueueEntry>, Error>>,
+    {
+        let deleted = self
+            .drain_impl(ctx, multiplex_id, older_than, batch, process, false)
+            .await?;
+        Ok(deleted.len())
+    }
 
-                tokio::spawn(batch_writes);
+    /// Like `drain`, but only returns the entries that would be deleted, without deleting
+    /// them. `process` is still called, so it must not have side effects of its own.
+    pub async fn drain_plan<F, Fut>(
+        &self,
+        ctx: CoreContext,
+        multiplex_id: MultiplexId,
+        older_than: DateTime,
+        batch: usize,
+        process: F,
+    ) -> Result<Vec<BlobstoreSyncQueueEntry>, Error>
+    where
+        F: FnMut(Vec<BlobstoreSyncQueueEntry>) -> Fut,
+        Fut: Future<Output = Result<Vec<BlobstoreSyncQueueEntry>, Error>>,
+    {
+        self.drain_impl(ctx, multiplex_id, older_than, batch, process, true)
+            .await
+    }
+
+    async fn drain_impl<F, Fut>(
+        &self,
+        ctx: CoreContext,
+        multiplex_id: MultiplexId,
+        older_than: DateTime,
+        batch: usize,
+        mut process: F,
+        dry_run: bool,
+    ) -> Result<Vec<BlobstoreSyncQueueEntry>, Error>
+    where
+        F: FnMut(Vec<BlobstoreSyncQueueEntry>) -> Fut,
+        Fut: Future<Output = Result<Vec<BlobstoreSyncQueueEntry>, Error>>,
+    {
+        // Walk the queue by id, so that entries `process` keeps aren't returned again, and
+        // so that a dry run makes progress without deleting anything.
+        let mut after_id = 0;
+        let mut deleted = vec![];
+        loop {
+            let rows = GetEntriesAfterId::query(
+                &self.read_master_connection,
+                &multiplex_id,
+                &older_than.into(),
+                &after_id,
+                &batch,
+            )
+            .compat()
+            .await?;
+            let entries: Vec<_> = rows.into_iter().map(entry_from_row).collect();
+            after_id = match entries.last().and_then(|entry| entry.id) {
+                Some(id) => id,
+                None => break,
+            };
+
+            let to_delete = process(entries).await?;
+            if !dry_run {
+                self.del(ctx.clone(), to_delete.clone()).await?;
+            }
+            deleted.extend(to_delete);
+        }
+        Ok(deleted)
+    }
+
+    /// Find the operations queued for `multiplex_id` whose keys are in none of the
+    /// blobstores, according to `check`, which returns whether a key is present in any of
+    /// them. Such entries can never be healed (e.g. because the blob was redacted), so they
+    /// should be deleted rather than retried.
+    pub async fn find_orphaned<F, Fut>(
+        &self,
+        _ctx: CoreContext,
+        multiplex_id: MultiplexId,
+        check: F,
+    ) -> Result<Vec<OperationKey>, Error>
+    where
+        F: Fn(String) -> Fut,
+        Fut: Future<Output = Result<bool, Error>>,
+    {
+        let now = DateTime::now();
+        let mut after_id = 0;
+        let mut operations: Vec<(OperationKey, Vec<String>)> = vec![];
+        let mut operation_idx = HashMap::new();
+        loop {
+            let rows = GetEntriesAfterId::query(
+                &self.read_master_connection,
+                &multiplex_id,
+                &now.into(),
+                &after_id,
+                &FIND_ORPHANED_BATCH_SIZE,
+            )
+            .compat()
+            .await?;
+            let entries: Vec<_> = rows.into_iter().map(entry_from_row).collect();
+            after_id = match entries.last().and_then(|entry| entry.id) {
+                Some(id) => id,
+                None => break,
+            };
+
+            for entry in entries {
+                let idx = *operation_idx
+                    .entry(entry.operation_key.clone())
+                    .or_insert_with(|| {
+                        operations.push((entry.operation_key.clone(), vec![]));
+                        operations.len() - 1
+                    });
+                let keys = &mut operations[idx].1;
+                if !keys.contains(&entry.blobstore_key) {
+                    keys.push(entry.blobstore_key);
+                }
             }
         }
-        .boxed()
-        .shared();
 
+        // Operations usually share keys with others, so only check each key once
+        let mut present: HashMap<String, bool> = HashMap::new();
+        let mut orphaned = vec![];
+        for (operation_key, keys) in operations {
+            let mut any_present = false;
+            for key in keys {
+                let is_present = match present.get(&key) {
+                    Some(is_present) => *is_present,
+                    None => {
+                        let is_present = check(key.clone()).await?;
+                        present.insert(key, is_present);
+                        is_present
+                    }
+                };
+                if is_present {
+                    any_present = true;
+                    break;
+                }
+            }
+            if !any_present {
+                orphaned.push(operation_key);
+            }
+        }
+        Ok(orphaned)
+    }
+
+    /// Total size of the blobs referenced by entries queued for `multiplex_id`, i.e. how
+    /// many bytes are waiting to be healed. Entries added without a size (see
+    /// `BlobstoreSyncQueueEntry::with_blob_size`) are not counted.
+    pub async fn pending_bytes(
+        &self,
+        _ctx: CoreContext,
+        multiplex_id: MultiplexId,
+    ) -> Result<u64, Error> {
+        let rows = GetPendingBytes::query(&self.read_master_connection, &multiplex_id)
+            .compat()
+            .await?;
+        Ok(rows
+            .into_iter()
+            .next()
+            .and_then(|(bytes,)| bytes)
+            .unwrap_or(0))
+    }
+}
+
+/// Token bucket holding at most one second worth of entries. Reservations are allowed to
+/// drive it into debt, so that later callers queue up behind earlier ones.
+struct AddRateLimiter {
+    entries_per_second: f64,
+    // Available tokens and when they were last refilled
+    state: Mutex<(f64, Instant)>,
+}
+
+impl AddRateLimiter {
+    fn new(entries_per_second: NonZeroU32) -> Self {
         Self {
-            write_connection,
-            read_connection: connections.read_connection,
-            read_master_connection: connections.read_master_connection,
-            write_sender: Arc::new(sender),
-            ensure_worker_scheduled,
+            entries_per_second: entries_per_second.get() as f64,
+            state: Mutex::new((0.0, Instant::now())),
+        }
+    }
+
+    /// Take `count` tokens, returning how long to wait until they are actually available.
+    fn reserve(&self, count: usize) -> Duration {
+        let mut state = self.state.lock().expect("lock poisoned");
+        let (tokens, refilled_at) = &mut *state;
+        let now = Instant::now();
+        *tokens = (*tokens
+            + now.duration_since(*refilled_at).as_secs_f64() * self.entries_per_second)
+            .min(self.entries_per_second);
+        *refilled_at = now;
+        *tokens -= count as f64;
+        if *tokens >= 0.0 {
+            Duration::from_secs(0)
+        } else {
+            Duration::from_secs_f64(-*tokens / self.entries_per_second)
         }
     }
 }
 
 const WRITE_BUFFER_SIZE: usize = 5000;
+const GET_MANY_CHUNK_SIZE: usize = 1000;
+const FIND_ORPHANED_BATCH_SIZE: usize = 1000;
+const DEFAULT_CLAIM_LEASE_DURATION: Duration = Duration::from_secs(600);
+
+/// Start the background writer, which inserts entries sent to the returned sender in
+/// batches. At most about `write_buffer` entries wait in the channel, after which senders
+/// wait for the writer to catch up. Batches are at most `write_buffer` entries long, and with
+/// `max_write_latency`, written no later than that after their first entry. Returns the
+/// sender, a future that schedules the writer, and one that resolves once it's done, to the
+/// number of entries it lost.
+fn start_writer(
+    write_connection: Arc<Connection>,
+    write_buffer: usize,
+    max_write_latency: Option<Duration>,
+) -> (
+    Arc<mpsc::Sender<WriteRequest>>,
+    Shared<BoxFuture<'static, ()>>,
+    Shared<BoxFuture<'static, usize>>,
+) {
+    let (sender, receiver) = mpsc::channel::<WriteRequest>(write_buffer);
+    // Resolves to the number of entries that failed to be written after whoever added
+    // them stopped waiting, i.e. that nobody else knows were lost
+    let (done_sender, done_receiver) = oneshot::channel();
+
+    let ensure_worker_scheduled = async move {
+        let batches = match max_write_latency {
+            Some(max_write_latency) => {
+                timed_chunks(receiver, write_buffer.max(1), max_write_latency).boxed()
+            }
+            None => receiver.ready_chunks(write_buffer.max(1)).boxed(),
+        };
+        let batch_writes = batches.fold(0, {
+            move |unpersisted, batch| {
+                cloned!(write_connection);
+                async move {
+                    let (senders, entries): (Vec<_>, Vec<_>) = batch.into_iter().unzip();
+
+                    match insert_entries(write_connection.as_ref(), entries).await {
+                        Ok(()) => {
+                            for sender in senders {
+                                // Ignoring the error, because receiver might have gone
+                                let _ = sender.send(Ok(()));
+                            }
+                            unpersisted
+                        }
+                        Err(err) => {
+                            let err = QueueError::from_insert_error(err);
+                            let lost = senders
+                                .into_iter()
+                                .map(|sender| sender.send(Err(err.clone())))
+                                .filter(Result::is_err)
+                                .count();
+                            STATS::unpersisted_adds.add_value(lost as i64);
+                            unpersisted + lost
+                        }
+                    }
+                }
+            }
+        });
+
+        tokio::spawn(async move {
+            let unpersisted = batch_writes.await;
+            let _ = done_sender.send(unpersisted);
+        });
+    }
+    .boxed()
+    .shared();
+    let worker_done = done_receiver.map(|res| res.unwrap_or(0)).boxed().shared();
+
+    (Arc::new(sender), ensure_worker_scheduled, worker_done)
+}
+
+/// Like `ready_chunks`, group the items of `stream` into batches of up to `max_size` items.
+/// A batch is emitted as soon as nothing else is ready, but while items keep arriving, it
+/// waits for more of them until `max_latency` after its first.
+fn timed_chunks<S>(
+    stream: S,
+    max_size: usize,
+    max_latency: Duration,
+) -> impl Stream<Item = Vec<S::Item>>
+where
+    S: Stream + Unpin,
+{
+    stream::unfold(stream, move |mut stream| async move {
+        let mut batch = vec![stream.next().await?];
+        let deadline = tokio::time::Instant::now() + max_latency;
+        loop {
+            let taken = batch.len();
+            while batch.len() < max_size {
+                match stream.next().now_or_never() {
+                    Some(Some(item)) => batch.push(item),
+                    // Nothing else is waiting, or the stream ended
+                    Some(None) | None => break,
+                }
+            }
+            // Don't hold back a batch nobody else is adding to
+            if batch.len() == taken || batch.len() >= max_size {
+                break;
+            }
+            match tokio::time::timeout_at(deadline, stream.next()).await {
+                Ok(Some(item)) => batch.push(item),
+                // The stream ended, or the batch has waited long enough
+                Ok(None) | Err(_) => break,
+            }
+        }
+        Some((batch, stream))
+    })
+}
 
 async fn insert_entries(
     write_connection: &Connection,
@@ -309,16 +1375,24 @@ async fn insert_entries(
                 timestamp,
                 multiplex_id,
                 operation_key,
+                blob_size,
                 ..
             } = entry;
             let t: Timestamp = timestamp.into();
-            (blobstore_key, blobstore_id, multiplex_id, t, operation_key)
+            (
+                blobstore_key,
+                blobstore_id,
+                multiplex_id,
+                t,
+                operation_key,
+                blob_size,
+            )
         })
         .collect();
 
     let entries_ref: Vec<_> = entries
         .iter()
-        .map(|(b, c, d, e, f)| (b, c, d, e, f)) // &(a, b, ...) into (&a, &b, ...)
+        .map(|(b, c, d, e, f, g)| (b, c, d, e, f, g)) // &(a, b, ...) into (&a, &b, ...)
         .collect();
 
     InsertEntry::query(write_connection, entries_ref.as_ref())
@@ -327,13 +1401,78 @@ async fn insert_entries(
     Ok(())
 }
 
+type EntryRow = (
+    String,
+    BlobstoreId,
+    MultiplexId,
+    Timestamp,
+    OperationKey,
+    u64,
+);
+
+/// Fail if one of `keys` is nil. Legacy entries all have the nil operation key, so deleting
+/// by it would delete the entries of every operation written before operation keys.
+fn ensure_not_null(keys: &[OperationKey]) -> Result<(), Error> {
+    if keys.iter().any(OperationKey::is_null) {
+        return Err(format_err!(
+            "Can't delete by the nil operation key, it is shared by all legacy entries"
+        ));
+    }
+    Ok(())
+}
+
+fn entry_from_row(row: EntryRow) -> BlobstoreSyncQueueEntry {
+    let (blobstore_key, blobstore_id, multiplex_id, timestamp, operation_key, id) = row;
+    BlobstoreSyncQueueEntry {
+        blobstore_key,
+        blobstore_id,
+        multiplex_id,
+        timestamp: timestamp.into(),
+        operation_key,
+        id: Some(id),
+        blob_size: None,
+    }
+}
+
+async fn get_range_of_entries(
+    connection: Connection,
+    key_like: Option<String>,
+    multiplex_id: MultiplexId,
+    older_than: DateTime,
+    limit: usize,
+) -> Result<Vec<BlobstoreSyncQueueEntry>, Error> {
+    let rows = match key_like {
+        Some(sql_like) => {
+            GetRangeOfEntriesLike::query(
+                &connection,
+                &sql_like,
+                &multiplex_id,
+                &older_than.into(),
+                &limit,
+            )
+            .compat()
+            .await?
+        }
+        None => {
+            GetRangeOfEntries::query(&connection, &multiplex_id, &older_than.into(), &limit)
+                .compat()
+                .await?
+        }
+    };
+    Ok(rows.into_iter().map(entry_from_row).collect())
+}
+
 impl BlobstoreSyncQueue for SqlBlobstoreSyncQueue {
     fn add_many(
         &self,
         _ctx: CoreContext,
         entries: Box<dyn Iterator<Item = BlobstoreSyncQueueEntry> + Send>,
     ) -> BoxFuture<'static, Result<(), Error>> {
-        cloned!(self.write_sender, self.ensure_worker_scheduled);
+        cloned!(
+            self.write_sender,
+            self.ensure_worker_scheduled,
+            self.add_rate_limiter
+        );
         async move {
             ensure_worker_scheduled.await;
             let (senders_entries, receivers): (Vec<_>, Vec<_>) = entries
@@ -343,19 +1482,29 @@ impl BlobstoreSyncQueue for SqlBlobstoreSyncQueue {
                 })
                 .unzip();
 
+            if let Some(limiter) = add_rate_limiter {
+                let wait = limiter.reserve(senders_entries.len());
+                if wait > Duration::from_secs(0) {
+                    tokio::time::delay_for(wait).await;
+                }
+            }
+
             STATS::adds.add_value(senders_entries.len() as i64);
-            senders_entries
-                .into_iter()
-                .map(|(send, entry)| write_sender.unbounded_send((send, entry)))
-                .collect::<Result<_, _>>()?;
+            // Waits for room in the buffer if the writer is behind
+            let mut write_sender = (*write_sender).clone();
+            for (send, entry) in senders_entries {
+                write_sender.send((send, entry)).await?;
+            }
             let results = future::try_join_all(receivers)
                 .map_err(|errs| format_err!("failed to receive result {:?}", errs))
                 .await?;
-            let errs: Vec<_> = results.into_iter().filter_map(|r| r.err()).collect();
-            if errs.len() > 0 {
-                Err(format_err!("failed to receive result {:?}", errs))
-            } else {
-                Ok(())
+            // Entries may have been inserted in different batches, so report a permanent
+            // failure if there is one, as retrying won't help with that.
+            let mut errs: Vec<_> = results.into_iter().filter_map(|r| r.err()).collect();
+            errs.sort_by_key(QueueError::is_retryable);
+            match errs.into_iter().next() {
+                Some(err) => Err(err.into()),
+                None => Ok(()),
             }
         }
         .boxed()
@@ -363,50 +1512,79 @@ impl BlobstoreSyncQueue for SqlBlobstoreSyncQueue {
 
     fn iter(
         &self,
-        _ctx: CoreContext,
+        ctx: CoreContext,
         key_like: Option<String>,
         multiplex_id: MultiplexId,
         older_than: DateTime,
         limit: usize,
     ) -> BoxFuture<'static, Result<Vec<BlobstoreSyncQueueEntry>, Error>> {
         STATS::iters.add_value(1);
-        let query = match &key_like {
-            Some(sql_like) => GetRangeOfEntriesLike::query(
-                &self.read_master_connection,
-                &sql_like,
-                &multiplex_id,
-                &older_than.into(),
-                &limit,
-            )
-            .compat()
-            .left_future(),
-            None => GetRangeOfEntries::query(
-                &self.read_master_connection,
-                &multiplex_id,
-                &older_than.into(),
-                &limit,
-            )
-            .compat()
-            .right_future(),
+        let master = get_range_of_entries(
+            self.read_master_connection.clone(),
+            key_like.clone(),
+            multiplex_id,
+            older_than,
+            limit,
+        );
+        let replica = if self.iter_replica_fallback {
+            Some(get_range_of_entries(
+                self.read_connection.clone(),
+                key_like,
+                multiplex_id,
+                older_than,
+                limit,
+            ))
+        } else {
+            None
         };
 
+        async move {
+            match (master.await, replica) {
+                (Ok(entries), _) => Ok(entries),
+                (Err(err), Some(replica)) => {
+                    STATS::iter_replica_fallbacks.add_value(1);
+                    warn!(
+                        ctx.logger(),
+                        "Reading sync queue from master failed, falling back to replica, entries may be stale: {}",
+                        err
+                    );
+                    replica.await
+                }
+                (Err(err), None) => Err(err),
+            }
+        }
+        .boxed()
+    }
+
+    fn count(
+        &self,
+        _ctx: CoreContext,
+        multiplex_id: MultiplexId,
+        older_than: DateTime,
+    ) -> BoxFuture<'static, Result<u64, Error>> {
+        STATS::counts.add_value(1);
+        let query = CountOperations::query(
+            &self.read_master_connection,
+            &multiplex_id,
+            &older_than.into(),
+        )
+        .compat();
         async move {
             let rows = query.await?;
-            Ok(rows
-                .into_iter()
-                .map(
-                    |(blobstore_key, blobstore_id, multiplex_id, timestamp, operation_key, id)| {
-                        BlobstoreSyncQueueEntry {
-                            blobstore_key,
-                            blobstore_id,
-                            multiplex_id,
-                            timestamp: timestamp.into(),
-                            operation_key,
-                            id: Some(id),
-                        }
-                    },
-                )
-                .collect())
+            Ok(rows.into_iter().next().map_or(0, |(count,)| count))
+        }
+        .boxed()
+    }
+
+    fn oldest_entries_global(
+        &self,
+        _ctx: CoreContext,
+        limit: usize,
+    ) -> BoxFuture<'static, Result<Vec<BlobstoreSyncQueueEntry>, Error>> {
+        let query = GetOldestEntries::query(&self.read_master_connection, &limit).compat();
+        async move {
+            let rows = query.await?;
+            Ok(rows.into_iter().map(entry_from_row).collect())
         }
         .boxed()
     }
@@ -449,21 +1627,73 @@ impl BlobstoreSyncQueue for SqlBlobstoreSyncQueue {
         let query = GetByKey::query(&self.read_master_connection, &key).compat();
         async move {
             let rows = query.await?;
-            Ok(rows
-                .into_iter()
-                .map(
-                    |(blobstore_key, blobstore_id, multiplex_id, timestamp, operation_key, id)| {
-                        BlobstoreSyncQueueEntry {
-                            blobstore_key,
-                            blobstore_id,
-                            multiplex_id,
-                            timestamp: timestamp.into(),
-                            operation_key,
-                            id: Some(id),
-                        }
-                    },
-                )
-                .collect())
+            Ok(rows.into_iter().map(entry_from_row).collect())
+        }
+        .boxed()
+    }
+
+    fn get_many(
+        &self,
+        _ctx: CoreContext,
+        keys: Vec<String>,
+    ) -> BoxFuture<'static, Result<HashMap<String, Vec<BlobstoreSyncQueueEntry>>, Error>> {
+        cloned!(self.read_master_connection);
+
+        async move {
+            let mut entries_by_key: HashMap<String, Vec<BlobstoreSyncQueueEntry>> = HashMap::new();
+            for chunk in keys.chunks(GET_MANY_CHUNK_SIZE) {
+                let rows = GetByKeys::query(&read_master_connection, chunk)
+                    .compat()
+                    .await?;
+                for entry in rows.into_iter().map(entry_from_row) {
+                    entries_by_key
+                        .entry(entry.blobstore_key.clone())
+                        .or_default()
+                        .push(entry);
+                }
+            }
+            Ok(entries_by_key)
+        }
+        .boxed()
+    }
+
+    fn claim(
+        &self,
+        _ctx: CoreContext,
+        multiplex_id: MultiplexId,
+        older_than: DateTime,
+        limit: usize,
+        claimant_id: String,
+    ) -> BoxFuture<'static, Result<Vec<BlobstoreSyncQueueEntry>, Error>> {
+        cloned!(self.write_connection, self.read_master_connection);
+        let claim_lease_duration = self.claim_lease_duration;
+
+        async move {
+            let now = Timestamp::now();
+            let lease_expiry = Timestamp::from_timestamp_nanos(
+                now.timestamp_nanos() + claim_lease_duration.as_nanos() as i64,
+            );
+            ClaimEntries::query(
+                &write_connection,
+                &multiplex_id,
+                &older_than.into(),
+                &limit,
+                &claimant_id,
+                &now,
+                &lease_expiry,
+            )
+            .compat()
+            .await?;
+            // Only the entries claimed by this call have its lease expiry
+            let rows = GetClaimedEntries::query(
+                &read_master_connection,
+                &multiplex_id,
+                &claimant_id,
+                &lease_expiry,
+            )
+            .compat()
+            .await?;
+            Ok(rows.into_iter().map(entry_from_row).collect())
         }
         .boxed()
     }
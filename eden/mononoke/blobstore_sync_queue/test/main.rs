@@ -9,17 +9,46 @@
 
 #![deny(warnings)]
 
-use anyhow::Error;
+use anyhow::{format_err, Error};
 use blobstore_sync_queue::{
-    BlobstoreSyncQueue, BlobstoreSyncQueueEntry, OperationKey, SqlBlobstoreSyncQueue,
+    estimate_drain, BlobstoreSyncQueue, BlobstoreSyncQueueEntry, OperationKey, QueueError,
+    QueueSize, SqlBlobstoreSyncQueue,
 };
 use context::CoreContext;
 use fbinit::FacebookInit;
+use futures::{future, TryStreamExt};
 use metaconfig_types::{BlobstoreId, MultiplexId};
 use mononoke_types::DateTime;
+use slog::{Drain, Logger, Never, OwnedKVList, Record};
+use sql::Connection;
 use sql_construct::SqlConstruct;
+use sql_ext::{open_sqlite_in_memory, SqlConnections};
+use std::collections::{HashMap, HashSet};
+use std::num::NonZeroU32;
+use std::sync::{Arc, Mutex};
+use std::time::{Duration, Instant};
 use uuid::Uuid;
 
+/// Drain that keeps the messages of all log records, so tests can assert on them.
+#[derive(Clone, Default)]
+struct CapturingDrain(Arc<Mutex<Vec<String>>>);
+
+impl Drain for CapturingDrain {
+    type Ok = ();
+    type Err = Never;
+
+    fn log(&self, record: &Record, _values: &OwnedKVList) -> Result<(), Never> {
+        self.0.lock().unwrap().push(format!("{}", record.msg()));
+        Ok(())
+    }
+}
+
+impl CapturingDrain {
+    fn messages(&self) -> Vec<String> {
+        self.0.lock().unwrap().clone()
+    }
+}
+
 #[fbinit::test]
 async fn test_simple(fb: FacebookInit) -> Result<(), Error> {
     let ctx = CoreContext::test_mock(fb);
@@ -110,3 +139,1468 @@ async fn test_simple(fb: FacebookInit) -> Result<(), Error> {
     assert_eq!(entries.len(), 0);
     Ok(())
 }
+
+#[fbinit::test]
+async fn test_iter_replica_fallback(fb: FacebookInit) -> Result<(), Error> {
+    let drain = CapturingDrain::default();
+    let ctx = CoreContext::new_with_logger(fb, Logger::root(drain.clone(), slog::o!()));
+
+    // The master has no schema, so every read from it fails.
+    let master = Connection::with_sqlite(open_sqlite_in_memory()?);
+    let replica = open_sqlite_in_memory()?;
+    replica.execute_batch(SqlBlobstoreSyncQueue::CREATION_QUERY)?;
+    let replica = Connection::with_sqlite(replica);
+
+    let connections = SqlConnections {
+        write_connection: replica.clone(),
+        read_connection: replica,
+        read_master_connection: master,
+    };
+    let mp = MultiplexId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z").unwrap();
+    let entry = BlobstoreSyncQueueEntry::new(
+        "key0".to_string(),
+        BlobstoreId::new(0),
+        mp,
+        t0,
+        OperationKey::gen(),
+    );
+
+    let queue = SqlBlobstoreSyncQueue::from_sql_connections(connections.clone());
+    queue.add(ctx.clone(), entry.clone()).await?;
+    queue
+        .iter(ctx.clone(), None, mp, t0, 100)
+        .await
+        .expect_err("Iterating over a failing master should have failed");
+    assert!(drain.messages().is_empty());
+
+    let queue =
+        SqlBlobstoreSyncQueue::from_sql_connections(connections).with_iter_replica_fallback(true);
+    let entries = queue
+        .iter(ctx.clone(), None, mp, t0, 100)
+        .await
+        .expect("Iterating with replica fallback failed");
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].blobstore_key, entry.blobstore_key);
+    assert_eq!(entries[0].operation_key, entry.operation_key);
+
+    let messages = drain.messages();
+    assert_eq!(messages.len(), 1);
+    assert!(messages[0].contains("may be stale"));
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_claim(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    let mp = MultiplexId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z").unwrap();
+
+    let entries: Vec<_> = (0..5)
+        .map(|i| {
+            BlobstoreSyncQueueEntry::new(
+                format!("key{}", i),
+                BlobstoreId::new(0),
+                mp,
+                t0,
+                OperationKey::gen(),
+            )
+        })
+        .collect();
+    queue
+        .add_many(ctx.clone(), Box::new(entries.into_iter()))
+        .await?;
+
+    let claimed0 = queue
+        .claim(ctx.clone(), mp, t0, 3, "healer0".to_string())
+        .await?;
+    let claimed1 = queue
+        .claim(ctx.clone(), mp, t0, 3, "healer1".to_string())
+        .await?;
+    assert_eq!(claimed0.len(), 3);
+    assert_eq!(claimed1.len(), 2);
+
+    let ids0: HashSet<_> = claimed0.iter().map(|e| e.id).collect();
+    let ids1: HashSet<_> = claimed1.iter().map(|e| e.id).collect();
+    assert!(ids0.is_disjoint(&ids1));
+
+    // Nothing left to claim, but the claimant renews what it owns.
+    let claimed1 = queue
+        .claim(ctx.clone(), mp, t0, 3, "healer1".to_string())
+        .await?;
+    assert_eq!(claimed1.len(), 2);
+    let claimed2 = queue
+        .claim(ctx.clone(), mp, t0, 3, "healer2".to_string())
+        .await?;
+    assert!(claimed2.is_empty());
+
+    // Only the entries renewed by this claim are returned, not everything the claimant owns
+    let renewed0 = queue
+        .claim(ctx.clone(), mp, t0, 1, "healer0".to_string())
+        .await?;
+    assert_eq!(renewed0.len(), 1);
+    assert!(ids0.contains(&renewed0[0].id));
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_claim_renews_lease(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let lease = Duration::from_secs(1);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?.with_claim_lease_duration(lease);
+    let mp = MultiplexId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z").unwrap();
+    let entry = BlobstoreSyncQueueEntry::new(
+        "key0".to_string(),
+        BlobstoreId::new(0),
+        mp,
+        t0,
+        OperationKey::gen(),
+    );
+    queue.add(ctx.clone(), entry).await?;
+
+    let claimed = queue
+        .claim(ctx.clone(), mp, t0, 1, "healer0".to_string())
+        .await?;
+    assert_eq!(claimed.len(), 1);
+
+    // Claiming again before the lease expires renews it, so that it outlives the first one
+    tokio::time::delay_for(lease * 3 / 5).await;
+    let renewed = queue
+        .claim(ctx.clone(), mp, t0, 1, "healer0".to_string())
+        .await?;
+    assert_eq!(renewed.len(), 1);
+    assert_eq!(renewed[0].id, claimed[0].id);
+    tokio::time::delay_for(lease * 3 / 5).await;
+    assert!(queue
+        .claim(ctx.clone(), mp, t0, 1, "healer1".to_string())
+        .await?
+        .is_empty());
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_claim_expired_lease(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?
+        .with_claim_lease_duration(Duration::from_secs(0));
+    let mp = MultiplexId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z").unwrap();
+    let entry = BlobstoreSyncQueueEntry::new(
+        "key0".to_string(),
+        BlobstoreId::new(0),
+        mp,
+        t0,
+        OperationKey::gen(),
+    );
+    queue.add(ctx.clone(), entry).await?;
+
+    let claimed0 = queue
+        .claim(ctx.clone(), mp, t0, 1, "healer0".to_string())
+        .await?;
+    assert_eq!(claimed0.len(), 1);
+
+    // The lease has expired by now, so another healer can take the entry over.
+    let claimed1 = queue
+        .claim(ctx.clone(), mp, t0, 1, "healer1".to_string())
+        .await?;
+    assert_eq!(claimed1.len(), 1);
+    assert_eq!(claimed0[0].id, claimed1[0].id);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_add_rate_limit(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let rate = 20;
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?
+        .with_add_rate_limit(NonZeroU32::new(rate).unwrap());
+    let mp = MultiplexId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z").unwrap();
+
+    let count = 2 * rate as usize;
+    let start = Instant::now();
+    let adds = (0..count).map(|i| {
+        let entry = BlobstoreSyncQueueEntry::new(
+            format!("key{}", i),
+            BlobstoreId::new(0),
+            mp,
+            t0,
+            OperationKey::gen(),
+        );
+        queue.add(ctx.clone(), entry)
+    });
+    future::try_join_all(adds).await?;
+    let elapsed = start.elapsed();
+
+    assert!(count as f64 / elapsed.as_secs_f64() <= rate as f64);
+    assert_eq!(queue.iter(ctx, None, mp, t0, count).await?.len(), count);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_reassign_multiplex(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    let mp_old = MultiplexId::new(1);
+    let mp_new = MultiplexId::new(2);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z").unwrap();
+
+    let entries: Vec<_> = (0..3)
+        .map(|i| {
+            BlobstoreSyncQueueEntry::new(
+                format!("key{}", i),
+                BlobstoreId::new(0),
+                mp_old,
+                t0,
+                OperationKey::gen(),
+            )
+        })
+        .collect();
+    queue
+        .add_many(ctx.clone(), Box::new(entries.into_iter()))
+        .await?;
+
+    // Move in chunks of 2
+    assert_eq!(
+        queue
+            .reassign_multiplex(ctx.clone(), mp_old, mp_new, 2)
+            .await?,
+        2
+    );
+    assert_eq!(
+        queue
+            .reassign_multiplex(ctx.clone(), mp_old, mp_new, 2)
+            .await?,
+        1
+    );
+    assert_eq!(
+        queue
+            .reassign_multiplex(ctx.clone(), mp_old, mp_new, 2)
+            .await?,
+        0
+    );
+
+    assert!(queue
+        .iter(ctx.clone(), None, mp_old, t0, 10)
+        .await?
+        .is_empty());
+    let moved = queue.iter(ctx.clone(), None, mp_new, t0, 10).await?;
+    assert_eq!(moved.len(), 3);
+    assert!(moved.iter().all(|e| e.multiplex_id == mp_new));
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_reassign_blobstore_id(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    let mp = MultiplexId::new(1);
+    let other_mp = MultiplexId::new(2);
+    let bs_old = BlobstoreId::new(0);
+    let bs_new = BlobstoreId::new(3);
+    let bs_other = BlobstoreId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z").unwrap();
+
+    let entry = |key: &str, blobstore_id, multiplex_id| {
+        BlobstoreSyncQueueEntry::new(
+            key.to_string(),
+            blobstore_id,
+            multiplex_id,
+            t0,
+            OperationKey::gen(),
+        )
+    };
+    let entries = vec![
+        entry("key0", bs_old, mp),
+        entry("key1", bs_old, mp),
+        entry("key2", bs_old, mp),
+        entry("key3", bs_other, mp),
+        // Same blobstore id, but in another multiplex
+        entry("key4", bs_old, other_mp),
+    ];
+    queue
+        .add_many(ctx.clone(), Box::new(entries.into_iter()))
+        .await?;
+
+    assert_eq!(
+        queue
+            .reassign_blobstore_id(ctx.clone(), mp, bs_old, bs_new, 2)
+            .await?,
+        2
+    );
+    assert_eq!(
+        queue
+            .reassign_blobstore_id(ctx.clone(), mp, bs_old, bs_new, 2)
+            .await?,
+        1
+    );
+    assert_eq!(
+        queue
+            .reassign_blobstore_id(ctx.clone(), mp, bs_old, bs_new, 2)
+            .await?,
+        0
+    );
+
+    let mut blobstore_ids: Vec<_> = queue
+        .iter(ctx.clone(), None, mp, t0, 10)
+        .await?
+        .into_iter()
+        .map(|e| (e.blobstore_key, e.blobstore_id))
+        .collect();
+    blobstore_ids.sort();
+    assert_eq!(
+        blobstore_ids,
+        vec![
+            ("key0".to_string(), bs_new),
+            ("key1".to_string(), bs_new),
+            ("key2".to_string(), bs_new),
+            ("key3".to_string(), bs_other),
+        ]
+    );
+    let untouched = queue.iter(ctx.clone(), None, other_mp, t0, 10).await?;
+    assert_eq!(untouched.len(), 1);
+    assert_eq!(untouched[0].blobstore_id, bs_old);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_get_many(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    let mp = MultiplexId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z").unwrap();
+
+    let entries = vec![
+        ("key0", BlobstoreId::new(0)),
+        ("key0", BlobstoreId::new(1)),
+        ("key1", BlobstoreId::new(0)),
+        ("key2", BlobstoreId::new(2)),
+    ]
+    .into_iter()
+    .map(|(key, blobstore_id)| {
+        BlobstoreSyncQueueEntry::new(key.to_string(), blobstore_id, mp, t0, OperationKey::gen())
+    })
+    .collect::<Vec<_>>();
+    queue
+        .add_many(ctx.clone(), Box::new(entries.into_iter()))
+        .await?;
+
+    let keys = vec!["key0", "key1", "missing"]
+        .into_iter()
+        .map(String::from)
+        .collect();
+    let by_key: HashMap<String, HashSet<BlobstoreId>> = queue
+        .get_many(ctx.clone(), keys)
+        .await?
+        .into_iter()
+        .map(|(key, entries)| {
+            assert!(entries.iter().all(|e| e.blobstore_key == key));
+            (key, entries.into_iter().map(|e| e.blobstore_id).collect())
+        })
+        .collect();
+
+    let expected: HashMap<String, HashSet<BlobstoreId>> = vec![
+        (
+            "key0".to_string(),
+            vec![BlobstoreId::new(0), BlobstoreId::new(1)]
+                .into_iter()
+                .collect(),
+        ),
+        (
+            "key1".to_string(),
+            vec![BlobstoreId::new(0)].into_iter().collect(),
+        ),
+    ]
+    .into_iter()
+    .collect();
+    assert_eq!(by_key, expected);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_iter_oldest_first(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    let mp = MultiplexId::new(1);
+    let timestamps = vec![
+        DateTime::from_rfc3339("2018-11-29T12:03:00.00Z")?,
+        DateTime::from_rfc3339("2018-11-29T12:02:00.00Z")?,
+        DateTime::from_rfc3339("2018-11-29T12:01:00.00Z")?,
+        DateTime::from_rfc3339("2018-11-29T12:00:00.00Z")?,
+    ];
+
+    // Insert the newest operations first, so that insertion order doesn't match age
+    for (i, timestamp) in timestamps.iter().enumerate() {
+        let entry = BlobstoreSyncQueueEntry::new(
+            format!("key{}", i),
+            BlobstoreId::new(0),
+            mp,
+            *timestamp,
+            OperationKey::gen(),
+        );
+        queue.add(ctx.clone(), entry).await?;
+    }
+
+    let entries = queue.iter(ctx.clone(), None, mp, timestamps[0], 2).await?;
+    let keys: HashSet<_> = entries.into_iter().map(|e| e.blobstore_key).collect();
+    let expected: HashSet<_> = vec!["key2".to_string(), "key3".to_string()]
+        .into_iter()
+        .collect();
+    assert_eq!(keys, expected);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_pending_bytes(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    let mp0 = MultiplexId::new(0);
+    let mp1 = MultiplexId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z").unwrap();
+
+    assert_eq!(queue.pending_bytes(ctx.clone(), mp0).await?, 0);
+
+    let entries = vec![
+        ("key0", mp0, Some(10)),
+        ("key1", mp0, Some(32)),
+        ("key2", mp0, None),
+        ("key3", mp1, Some(100)),
+    ]
+    .into_iter()
+    .map(|(key, multiplex_id, blob_size)| {
+        let entry = BlobstoreSyncQueueEntry::new(
+            key.to_string(),
+            BlobstoreId::new(0),
+            multiplex_id,
+            t0,
+            OperationKey::gen(),
+        );
+        match blob_size {
+            Some(blob_size) => entry.with_blob_size(blob_size),
+            None => entry,
+        }
+    })
+    .collect::<Vec<_>>();
+    queue
+        .add_many(ctx.clone(), Box::new(entries.into_iter()))
+        .await?;
+
+    assert_eq!(queue.pending_bytes(ctx.clone(), mp0).await?, 42);
+    assert_eq!(queue.pending_bytes(ctx.clone(), mp1).await?, 100);
+    Ok(())
+}
+
+#[test]
+fn test_queue_error_classification() {
+    let retryable = |code| QueueError::from_mysql_code(code, String::new()).is_retryable();
+    // Deadlock and lock wait timeout
+    assert!(retryable(1213));
+    assert!(retryable(1205));
+    // Server gone away and lost connection
+    assert!(retryable(2006));
+    assert!(retryable(2013));
+    // Duplicate entry and foreign key violations
+    assert!(!retryable(1062));
+    assert!(!retryable(1452));
+}
+
+#[fbinit::test]
+async fn test_iter_filtered(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    let mp = MultiplexId::new(1);
+    let bs0 = BlobstoreId::new(0);
+    let bs1 = BlobstoreId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z").unwrap();
+    let t1 = DateTime::from_rfc3339("2018-11-29T12:01:00.00Z").unwrap();
+    let t2 = DateTime::from_rfc3339("2018-11-29T12:02:00.00Z").unwrap();
+
+    let entries = vec![
+        ("foo0", bs0, mp, t0),
+        ("foo1", bs1, mp, t1),
+        ("bar0", bs0, mp, t2),
+        ("foo2", bs0, MultiplexId::new(2), t1),
+    ]
+    .into_iter()
+    .map(|(key, blobstore_id, multiplex_id, t)| {
+        BlobstoreSyncQueueEntry::new(
+            key.to_string(),
+            blobstore_id,
+            multiplex_id,
+            t,
+            OperationKey::gen(),
+        )
+    })
+    .collect::<Vec<_>>();
+    queue
+        .add_many(ctx.clone(), Box::new(entries.into_iter()))
+        .await?;
+
+    let keys = |entries: Vec<BlobstoreSyncQueueEntry>| -> Vec<String> {
+        entries.into_iter().map(|e| e.blobstore_key).collect()
+    };
+
+    let all = queue
+        .iter_filtered(ctx.clone(), mp, None, None, None, None, 10)
+        .await?;
+    assert_eq!(keys(all), vec!["foo0", "foo1", "bar0"]);
+
+    let limited = queue
+        .iter_filtered(ctx.clone(), mp, None, None, None, None, 2)
+        .await?;
+    assert_eq!(keys(limited), vec!["foo0", "foo1"]);
+
+    let by_store = queue
+        .iter_filtered(ctx.clone(), mp, Some(bs0), None, None, None, 10)
+        .await?;
+    assert_eq!(keys(by_store), vec!["foo0", "bar0"]);
+
+    let by_store_and_key = queue
+        .iter_filtered(
+            ctx.clone(),
+            mp,
+            Some(bs0),
+            Some("foo%".to_string()),
+            None,
+            None,
+            10,
+        )
+        .await?;
+    assert_eq!(keys(by_store_and_key), vec!["foo0"]);
+
+    let by_range = queue
+        .iter_filtered(ctx.clone(), mp, None, None, Some(t1), Some(t2), 10)
+        .await?;
+    assert_eq!(keys(by_range), vec!["foo1", "bar0"]);
+
+    let by_key_and_range = queue
+        .iter_filtered(
+            ctx.clone(),
+            mp,
+            None,
+            Some("foo%".to_string()),
+            Some(t1),
+            None,
+            10,
+        )
+        .await?;
+    assert_eq!(keys(by_key_and_range), vec!["foo1"]);
+
+    let by_everything = queue
+        .iter_filtered(
+            ctx.clone(),
+            mp,
+            Some(bs1),
+            Some("bar%".to_string()),
+            Some(t0),
+            Some(t2),
+            10,
+        )
+        .await?;
+    assert!(by_everything.is_empty());
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_drain_plan(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    let mp = MultiplexId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z").unwrap();
+    let t1 = DateTime::from_rfc3339("2018-11-29T12:01:00.00Z").unwrap();
+    let t2 = DateTime::from_rfc3339("2018-11-29T12:02:00.00Z").unwrap();
+
+    let entries = vec![("del0", t0), ("keep0", t0), ("del1", t0), ("del2", t2)]
+        .into_iter()
+        .map(|(key, t)| {
+            BlobstoreSyncQueueEntry::new(
+                key.to_string(),
+                BlobstoreId::new(0),
+                mp,
+                t,
+                OperationKey::gen(),
+            )
+        })
+        .collect::<Vec<_>>();
+    queue
+        .add_many(ctx.clone(), Box::new(entries.into_iter()))
+        .await?;
+
+    let process = |entries: Vec<BlobstoreSyncQueueEntry>| async move {
+        Ok::<_, Error>(
+            entries
+                .into_iter()
+                .filter(|e| e.blobstore_key.starts_with("del"))
+                .collect::<Vec<_>>(),
+        )
+    };
+    let keys = |entries: Vec<BlobstoreSyncQueueEntry>| -> Vec<String> {
+        entries.into_iter().map(|e| e.blobstore_key).collect()
+    };
+
+    let plan = queue.drain_plan(ctx.clone(), mp, t1, 1, process).await?;
+    assert_eq!(keys(plan), vec!["del0", "del1"]);
+
+    // Nothing was actually deleted
+    let remaining = queue
+        .iter_filtered(ctx.clone(), mp, None, None, None, None, 10)
+        .await?;
+    assert_eq!(remaining.len(), 4);
+
+    // And draining for real deletes what the plan said
+    assert_eq!(queue.drain(ctx.clone(), mp, t1, 1, process).await?, 2);
+    let remaining = queue
+        .iter_filtered(ctx.clone(), mp, None, None, None, None, 10)
+        .await?;
+    assert_eq!(keys(remaining), vec!["keep0", "del2"]);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_close_flushes_abandoned_adds(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    // Shares the connections, and the writer, with `queue`
+    let other = queue.clone();
+    let mp = MultiplexId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z")?;
+    let key = String::from("key");
+    let entry = BlobstoreSyncQueueEntry::new(
+        key.clone(),
+        BlobstoreId::new(0),
+        mp,
+        t0,
+        OperationKey::gen(),
+    );
+
+    // Hand the entry to the writer, then give up on waiting for it and drop the queue
+    let mut add = queue.add(ctx.clone(), entry);
+    assert!(futures::poll!(&mut add).is_pending());
+    drop(add);
+    drop(queue);
+
+    other.close(&ctx).await;
+    assert_eq!(other.get(ctx.clone(), key.clone()).await?.len(), 1);
+
+    // Once closed, the queue doesn't accept new entries
+    let entry = BlobstoreSyncQueueEntry::new(key, BlobstoreId::new(1), mp, t0, OperationKey::gen());
+    assert!(other.add(ctx.clone(), entry).await.is_err());
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_count_filtered(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    let mp = MultiplexId::new(1);
+    let bs0 = BlobstoreId::new(0);
+    let bs1 = BlobstoreId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z")?;
+    let t1 = DateTime::from_rfc3339("2018-11-29T12:01:00.00Z")?;
+
+    let entries = (0..20)
+        .map(|i| {
+            BlobstoreSyncQueueEntry::new(
+                format!("{}{}", if i % 3 == 0 { "foo" } else { "bar" }, i),
+                if i % 2 == 0 { bs0 } else { bs1 },
+                if i % 5 == 0 { MultiplexId::new(2) } else { mp },
+                if i < 10 { t0 } else { t1 },
+                OperationKey::gen(),
+            )
+        })
+        .collect::<Vec<_>>();
+    queue
+        .add_many(ctx.clone(), Box::new(entries.into_iter()))
+        .await?;
+
+    let filters = vec![
+        (None, None, None, None),
+        (Some(bs0), None, None, None),
+        (None, Some("foo%".to_string()), None, None),
+        (None, None, Some(t1), None),
+        (None, None, None, Some(t0)),
+        (Some(bs1), Some("bar%".to_string()), Some(t0), Some(t1)),
+        (Some(BlobstoreId::new(3)), None, None, None),
+    ];
+    for (blobstore_id, key_like, from, to) in filters {
+        let entries = queue
+            .iter_filtered(
+                ctx.clone(),
+                mp,
+                blobstore_id,
+                key_like.clone(),
+                from,
+                to,
+                1000,
+            )
+            .await?;
+        let count = queue
+            .count_filtered(ctx.clone(), mp, blobstore_id, key_like, from, to)
+            .await?;
+        assert_eq!(count, entries.len() as u64);
+    }
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_find_orphaned(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    let mp = MultiplexId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z")?;
+    let op_present = OperationKey::gen();
+    let op_orphaned = OperationKey::gen();
+    let op_other_mp = OperationKey::gen();
+
+    let entries = vec![
+        ("present", BlobstoreId::new(0), mp, op_present.clone()),
+        ("present", BlobstoreId::new(1), mp, op_present.clone()),
+        ("redacted", BlobstoreId::new(0), mp, op_orphaned.clone()),
+        ("redacted", BlobstoreId::new(1), mp, op_orphaned.clone()),
+        (
+            "redacted",
+            BlobstoreId::new(0),
+            MultiplexId::new(2),
+            op_other_mp,
+        ),
+    ]
+    .into_iter()
+    .map(|(key, blobstore_id, multiplex_id, op)| {
+        BlobstoreSyncQueueEntry::new(key.to_string(), blobstore_id, multiplex_id, t0, op)
+    })
+    .collect::<Vec<_>>();
+    queue
+        .add_many(ctx.clone(), Box::new(entries.into_iter()))
+        .await?;
+
+    let checked = Arc::new(Mutex::new(vec![]));
+    let orphaned = queue
+        .find_orphaned(ctx.clone(), mp, |key| {
+            checked.lock().unwrap().push(key.clone());
+            async move { Ok(key == "present") }
+        })
+        .await?;
+    assert_eq!(orphaned, vec![op_orphaned]);
+
+    // Each key is only checked once
+    let mut checked = checked.lock().unwrap().clone();
+    checked.sort();
+    assert_eq!(checked, vec!["present", "redacted"]);
+    Ok(())
+}
+
+#[test]
+fn test_estimate_drain() -> Result<(), Error> {
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z")?;
+    let t1 = DateTime::from_rfc3339("2018-11-29T12:01:00.00Z")?;
+    let size = |at, count| QueueSize { at, count };
+
+    // Draining 60 entries a minute, so the remaining 120 take two more minutes
+    let estimate = estimate_drain(size(t0, 180), size(t1, 120)).unwrap();
+    assert_eq!(estimate.entries_per_sec, 1.0);
+    assert_eq!(
+        estimate.empty_at,
+        DateTime::from_rfc3339("2018-11-29T12:03:00.00Z")?
+    );
+
+    // Already empty
+    let estimate = estimate_drain(size(t0, 10), size(t1, 0)).unwrap();
+    assert_eq!(estimate.empty_at, t1);
+
+    // Growing or steady queues never drain
+    assert_eq!(estimate_drain(size(t0, 100), size(t1, 120)), None);
+    assert_eq!(estimate_drain(size(t0, 100), size(t1, 100)), None);
+    // Nor can anything be said without time passing
+    assert_eq!(estimate_drain(size(t0, 100), size(t0, 50)), None);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_sample_size(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    let mp = MultiplexId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z")?;
+    let entries = (0..3)
+        .map(|i| {
+            BlobstoreSyncQueueEntry::new(
+                format!("key{}", i),
+                BlobstoreId::new(0),
+                mp,
+                t0,
+                OperationKey::gen(),
+            )
+        })
+        .collect::<Vec<_>>();
+    queue
+        .add_many(ctx.clone(), Box::new(entries.into_iter()))
+        .await?;
+
+    assert_eq!(queue.sample_size(ctx.clone(), mp).await?.count, 3);
+    assert_eq!(
+        queue
+            .sample_size(ctx.clone(), MultiplexId::new(2))
+            .await?
+            .count,
+        0
+    );
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_warmup(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    queue.warmup(&ctx).await?;
+
+    // The queue is still usable afterwards
+    let mp = MultiplexId::new(1);
+    let entry = BlobstoreSyncQueueEntry::new(
+        "key".to_string(),
+        BlobstoreId::new(0),
+        mp,
+        DateTime::now(),
+        OperationKey::gen(),
+    );
+    queue.add(ctx.clone(), entry).await?;
+    assert_eq!(queue.get(ctx.clone(), "key".to_string()).await?.len(), 1);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_operations_missing_stores(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    let mp = MultiplexId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z")?;
+    let complete = OperationKey::gen();
+    let incomplete = OperationKey::gen();
+
+    let entry = |key: &str, blobstore_id, operation_key: &OperationKey| {
+        BlobstoreSyncQueueEntry::new(
+            key.to_string(),
+            BlobstoreId::new(blobstore_id),
+            mp,
+            t0,
+            operation_key.clone(),
+        )
+    };
+    let entries = vec![
+        entry("key0", 0, &complete),
+        entry("key0", 1, &complete),
+        entry("key0", 2, &complete),
+        entry("key1", 0, &incomplete),
+        entry("key1", 2, &incomplete),
+        // Another multiplex's entries are never returned
+        BlobstoreSyncQueueEntry::new(
+            "key2".to_string(),
+            BlobstoreId::new(0),
+            MultiplexId::new(2),
+            t0,
+            OperationKey::gen(),
+        ),
+    ];
+    queue
+        .add_many(ctx.clone(), Box::new(entries.into_iter()))
+        .await?;
+
+    assert_eq!(
+        queue
+            .operations_missing_stores(ctx.clone(), mp, 3, 10)
+            .await?,
+        vec![incomplete]
+    );
+    assert!(queue
+        .operations_missing_stores(ctx.clone(), mp, 2, 10)
+        .await?
+        .is_empty());
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_del_many_operations(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    let mp = MultiplexId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z")?;
+    let operation_keys: Vec<_> = (0..5).map(|_| OperationKey::gen()).collect();
+
+    let mut entries = Vec::new();
+    for (i, operation_key) in operation_keys.iter().enumerate() {
+        for blobstore_id in 0..3 {
+            entries.push(BlobstoreSyncQueueEntry::new(
+                format!("key{}", i),
+                BlobstoreId::new(blobstore_id),
+                mp,
+                t0,
+                operation_key.clone(),
+            ));
+        }
+    }
+    queue
+        .add_many(ctx.clone(), Box::new(entries.into_iter()))
+        .await?;
+
+    // Delete all operations but the last, two at a time
+    let (deleted_keys, kept_keys) = operation_keys.split_at(4);
+    let deleted = queue
+        .del_many_operations(ctx.clone(), deleted_keys.to_vec(), 2)
+        .await?;
+    assert_eq!(deleted, 12);
+
+    for i in 0..4 {
+        assert!(queue
+            .get(ctx.clone(), format!("key{}", i))
+            .await?
+            .is_empty());
+    }
+    let remaining = queue.get(ctx.clone(), "key4".to_string()).await?;
+    assert_eq!(remaining.len(), 3);
+    assert!(remaining
+        .iter()
+        .all(|entry| entry.operation_key == kept_keys[0]));
+
+    assert!(queue
+        .del_many_operations(ctx.clone(), kept_keys.to_vec(), 0)
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_expedite(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    let mp = MultiplexId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z")?;
+    let t1 = DateTime::from_rfc3339("2018-11-29T12:01:00.00Z")?;
+
+    let entry = |key: &str, blobstore_id| {
+        BlobstoreSyncQueueEntry::new(
+            key.to_string(),
+            BlobstoreId::new(blobstore_id),
+            mp,
+            t1,
+            OperationKey::gen(),
+        )
+    };
+    queue
+        .add_many(
+            ctx.clone(),
+            Box::new(vec![entry("key0", 0), entry("key1", 0)].into_iter()),
+        )
+        .await?;
+
+    // Too recent for a healer looking at entries up to t0
+    assert!(queue.iter(ctx.clone(), None, mp, t0, 10).await?.is_empty());
+
+    let key0_entries = queue.get(ctx.clone(), "key0".to_string()).await?;
+    assert_eq!(queue.expedite(ctx.clone(), key0_entries, None).await?, 1);
+
+    let entries = queue.iter(ctx.clone(), None, mp, t0, 10).await?;
+    assert_eq!(entries.len(), 1);
+    assert_eq!(entries[0].blobstore_key, "key0");
+
+    // Entries without an id can't be expedited
+    assert!(queue
+        .expedite(ctx.clone(), vec![entry("key1", 0)], Some(t0))
+        .await
+        .is_err());
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_count(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    let mp = MultiplexId::new(1);
+    let other_mp = MultiplexId::new(2);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z")?;
+    let t1 = DateTime::from_rfc3339("2018-11-29T12:01:00.00Z")?;
+
+    let op0 = OperationKey::gen();
+    let op1 = OperationKey::gen();
+    let op2 = OperationKey::gen();
+    let entries = vec![
+        // Two entries of the same operation count once
+        BlobstoreSyncQueueEntry::new("key0".to_string(), BlobstoreId::new(0), mp, t0, op0.clone()),
+        BlobstoreSyncQueueEntry::new("key0".to_string(), BlobstoreId::new(1), mp, t0, op0),
+        BlobstoreSyncQueueEntry::new("key1".to_string(), BlobstoreId::new(0), mp, t1, op1),
+        BlobstoreSyncQueueEntry::new("key2".to_string(), BlobstoreId::new(0), other_mp, t0, op2),
+    ];
+    queue
+        .add_many(ctx.clone(), Box::new(entries.into_iter()))
+        .await?;
+
+    assert_eq!(queue.count(ctx.clone(), mp, t0).await?, 1);
+    assert_eq!(queue.count(ctx.clone(), mp, t1).await?, 2);
+    assert_eq!(queue.count(ctx.clone(), other_mp, t1).await?, 1);
+    assert_eq!(queue.count(ctx.clone(), MultiplexId::new(3), t1).await?, 0);
+
+    // Matches the number of operations iter goes through
+    let operations: HashSet<_> = queue
+        .iter(ctx.clone(), None, mp, t1, 100)
+        .await?
+        .into_iter()
+        .map(|entry| entry.operation_key)
+        .collect();
+    assert_eq!(operations.len(), 2);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_iter_flat(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    let mp = MultiplexId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z")?;
+    let t1 = DateTime::from_rfc3339("2018-11-29T12:01:00.00Z")?;
+    let t2 = DateTime::from_rfc3339("2018-11-29T12:02:00.00Z")?;
+
+    let op0 = OperationKey::gen();
+    let op1 = OperationKey::gen();
+    let entries = vec![
+        BlobstoreSyncQueueEntry::new("key0".to_string(), BlobstoreId::new(0), mp, t0, op0.clone()),
+        BlobstoreSyncQueueEntry::new("key1".to_string(), BlobstoreId::new(0), mp, t1, op1.clone()),
+        // Same operation as key0, but added later
+        BlobstoreSyncQueueEntry::new("key0".to_string(), BlobstoreId::new(1), mp, t2, op0),
+        BlobstoreSyncQueueEntry::new("key1".to_string(), BlobstoreId::new(1), mp, t2, op1),
+    ];
+    queue
+        .add_many(ctx.clone(), Box::new(entries.into_iter()))
+        .await?;
+
+    // Related entries of key0 are not pulled in, unlike with iter
+    let flat = queue.iter_flat(ctx.clone(), mp, t2, 2).await?;
+    let keys: Vec<_> = flat
+        .iter()
+        .map(|entry| (entry.blobstore_key.as_str(), entry.blobstore_id))
+        .collect();
+    assert_eq!(
+        keys,
+        vec![("key0", BlobstoreId::new(0)), ("key1", BlobstoreId::new(0))]
+    );
+    assert_eq!(queue.iter(ctx.clone(), None, mp, t2, 2).await?.len(), 4);
+
+    assert_eq!(queue.iter_flat(ctx.clone(), mp, t2, 10).await?.len(), 4);
+    assert_eq!(queue.iter_flat(ctx.clone(), mp, t0, 10).await?.len(), 1);
+    assert!(queue
+        .iter_flat(ctx.clone(), MultiplexId::new(2), t2, 10)
+        .await?
+        .is_empty());
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_del_by_operation_key(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    let mp = MultiplexId::new(1);
+    let other_mp = MultiplexId::new(2);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z")?;
+
+    let op0 = OperationKey::gen();
+    let op1 = OperationKey::gen();
+    let entry = |key: &str, blobstore_id, multiplex_id, operation_key: &OperationKey| {
+        BlobstoreSyncQueueEntry::new(
+            key.to_string(),
+            BlobstoreId::new(blobstore_id),
+            multiplex_id,
+            t0,
+            operation_key.clone(),
+        )
+    };
+    let entries = vec![
+        entry("key0", 0, mp, &op0),
+        entry("key0", 1, mp, &op0),
+        entry("key1", 0, mp, &op1),
+        entry("key0", 0, other_mp, &op0),
+        entry("legacy", 0, mp, &OperationKey(Uuid::nil())),
+    ];
+    queue
+        .add_many(ctx.clone(), Box::new(entries.into_iter()))
+        .await?;
+
+    assert_eq!(
+        queue.del_by_operation_key(ctx.clone(), mp, vec![]).await?,
+        0
+    );
+    // The nil key of legacy entries is rejected, and nothing is deleted
+    assert!(queue
+        .del_by_operation_key(
+            ctx.clone(),
+            mp,
+            vec![op1.clone(), OperationKey(Uuid::nil())]
+        )
+        .await
+        .is_err());
+    assert_eq!(queue.get(ctx.clone(), "key1".to_string()).await?.len(), 1);
+    assert_eq!(queue.get(ctx.clone(), "legacy".to_string()).await?.len(), 1);
+    assert_eq!(
+        queue
+            .del_by_operation_key(ctx.clone(), mp, vec![op0.clone()])
+            .await?,
+        2
+    );
+
+    // Entries of other operations and other multiplexes are kept
+    let key0_entries = queue.get(ctx.clone(), "key0".to_string()).await?;
+    assert_eq!(key0_entries.len(), 1);
+    assert_eq!(key0_entries[0].multiplex_id, other_mp);
+    assert_eq!(queue.get(ctx.clone(), "key1".to_string()).await?.len(), 1);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_write_buffer(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?.with_write_buffer(1);
+    let mp = MultiplexId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z")?;
+    let entries = |count| {
+        (0..count)
+            .map(|i| {
+                BlobstoreSyncQueueEntry::new(
+                    format!("key{}", i),
+                    BlobstoreId::new(0),
+                    mp,
+                    t0,
+                    OperationKey::gen(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // Adding more entries than fit in the buffer waits for the writer to drain it
+    queue
+        .add_many(ctx.clone(), Box::new(entries(100).into_iter()))
+        .await?;
+    assert_eq!(
+        queue
+            .count_filtered(ctx.clone(), mp, None, None, None, None)
+            .await?,
+        100
+    );
+
+    // Polled once, the add only gets to hand the entries that fit in the buffer to the
+    // writer, and has to wait for room for the others. Closing the queue in the meantime
+    // makes it fail, whereas an unbounded buffer would have taken all of them at once.
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?.with_write_buffer(1);
+    let mut add = queue.add_many(ctx.clone(), Box::new(entries(100).into_iter()));
+    assert!(futures::poll!(&mut add).is_pending());
+    queue.close(&ctx).await;
+    assert!(add.await.is_err());
+    let written = queue
+        .count_filtered(ctx.clone(), mp, None, None, None, None)
+        .await?;
+    assert!(written > 0 && written < 100, "{} entries written", written);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_iter_all(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    let mp = MultiplexId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z")?;
+    let t1 = DateTime::from_rfc3339("2018-11-29T12:01:00.00Z")?;
+
+    let op = OperationKey::gen();
+    let mut entries: Vec<_> = (0..5)
+        .map(|i| {
+            BlobstoreSyncQueueEntry::new(
+                format!("key{}", i),
+                BlobstoreId::new(0),
+                mp,
+                t0,
+                op.clone(),
+            )
+        })
+        .collect();
+    entries.push(BlobstoreSyncQueueEntry::new(
+        "newer".to_string(),
+        BlobstoreId::new(0),
+        mp,
+        t1,
+        op.clone(),
+    ));
+    entries.push(BlobstoreSyncQueueEntry::new(
+        "other".to_string(),
+        BlobstoreId::new(0),
+        MultiplexId::new(2),
+        t0,
+        op,
+    ));
+    queue
+        .add_many(ctx.clone(), Box::new(entries.into_iter()))
+        .await?;
+
+    // Every entry is returned exactly once, whether or not the batch size divides the number
+    // of entries, and the iteration stops once the queue is exhausted
+    for batch_size in vec![1, 2, 5, 10] {
+        let all: Vec<_> = queue
+            .iter_all(ctx.clone(), mp, t0, batch_size)
+            .try_collect()
+            .await?;
+        let keys: Vec<_> = all
+            .iter()
+            .map(|entry| entry.blobstore_key.as_str())
+            .collect();
+        assert_eq!(keys, vec!["key0", "key1", "key2", "key3", "key4"]);
+        let ids: HashSet<_> = all.iter().map(|entry| entry.id).collect();
+        assert_eq!(ids.len(), 5);
+    }
+
+    let all: Vec<_> = queue.iter_all(ctx.clone(), mp, t1, 2).try_collect().await?;
+    assert_eq!(all.len(), 6);
+
+    let all: Vec<_> = queue
+        .iter_all(ctx.clone(), MultiplexId::new(3), t1, 2)
+        .try_collect()
+        .await?;
+    assert!(all.is_empty());
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_compact(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    let mp = MultiplexId::new(1);
+    let other_mp = MultiplexId::new(2);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z")?;
+    let t1 = DateTime::from_rfc3339("2018-11-29T12:01:00.00Z")?;
+    let t2 = DateTime::from_rfc3339("2018-11-29T12:02:00.00Z")?;
+
+    let op0 = OperationKey::gen();
+    let op1 = OperationKey::gen();
+    let entry = |key: &str, blobstore_id, multiplex_id, t, op: &OperationKey| {
+        BlobstoreSyncQueueEntry::new(
+            key.to_string(),
+            BlobstoreId::new(blobstore_id),
+            multiplex_id,
+            t,
+            op.clone(),
+        )
+    };
+    let entries = vec![
+        // Retries of the same write, added out of order
+        entry("key0", 0, mp, t1, &op0),
+        entry("key0", 0, mp, t0, &op0),
+        entry("key0", 0, mp, t2, &op0),
+        // Duplicates with the same timestamp
+        entry("key0", 1, mp, t0, &op0),
+        entry("key0", 1, mp, t0, &op0),
+        // Different blobstore, key or operation aren't duplicates
+        entry("key0", 2, mp, t0, &op0),
+        entry("key0", 0, mp, t0, &op1),
+        entry("key1", 0, mp, t0, &op0),
+        // Other multiplexes aren't compacted
+        entry("key2", 0, other_mp, t0, &op0),
+        entry("key2", 0, other_mp, t1, &op0),
+    ];
+    queue
+        .add_many(ctx.clone(), Box::new(entries.into_iter()))
+        .await?;
+
+    // Compaction is bounded by the limit, and stops once there are no duplicates left
+    assert_eq!(queue.compact(ctx.clone(), mp, 2).await?, 2);
+    assert_eq!(queue.compact(ctx.clone(), mp, 2).await?, 1);
+    assert_eq!(queue.compact(ctx.clone(), mp, 2).await?, 0);
+
+    // Exactly one entry is left per tuple, and it is the oldest
+    let mut tuples = HashMap::new();
+    for key in vec!["key0", "key1"] {
+        for entry in queue.get(ctx.clone(), key.to_string()).await? {
+            let tuple = (
+                entry.blobstore_key.clone(),
+                entry.blobstore_id,
+                entry.operation_key.clone(),
+            );
+            assert!(tuples.insert(tuple, entry.timestamp).is_none());
+        }
+    }
+    assert_eq!(tuples.len(), 5);
+    assert_eq!(
+        tuples.get(&("key0".to_string(), BlobstoreId::new(0), op0.clone())),
+        Some(&t0)
+    );
+
+    assert_eq!(queue.get(ctx.clone(), "key2".to_string()).await?.len(), 2);
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_oldest_entries_global(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?;
+    let mp = MultiplexId::new(1);
+    let other_mp = MultiplexId::new(2);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z")?;
+    let t1 = DateTime::from_rfc3339("2018-11-29T12:01:00.00Z")?;
+    let t2 = DateTime::from_rfc3339("2018-11-29T12:02:00.00Z")?;
+    let t3 = DateTime::from_rfc3339("2018-11-29T12:03:00.00Z")?;
+
+    let entry = |key: &str, multiplex_id, t| {
+        BlobstoreSyncQueueEntry::new(
+            key.to_string(),
+            BlobstoreId::new(0),
+            multiplex_id,
+            t,
+            OperationKey::gen(),
+        )
+    };
+    let entries = vec![
+        entry("key2", mp, t2),
+        entry("key1", other_mp, t1),
+        entry("key3", other_mp, t3),
+        entry("key0", mp, t0),
+    ];
+    queue
+        .add_many(ctx.clone(), Box::new(entries.into_iter()))
+        .await?;
+
+    // The oldest entries come first, whichever multiplex they are in
+    let oldest: Vec<_> = queue
+        .oldest_entries_global(ctx.clone(), 3)
+        .await?
+        .into_iter()
+        .map(|entry| (entry.blobstore_key, entry.multiplex_id))
+        .collect();
+    assert_eq!(
+        oldest,
+        vec![
+            ("key0".to_string(), mp),
+            ("key1".to_string(), other_mp),
+            ("key2".to_string(), mp),
+        ]
+    );
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_max_write_latency(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    let max_write_latency = Duration::from_secs(10);
+    let mp = MultiplexId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z")?;
+    let entries = |count| {
+        (0..count)
+            .map(|i| {
+                BlobstoreSyncQueueEntry::new(
+                    format!("key{}", i),
+                    BlobstoreId::new(0),
+                    mp,
+                    t0,
+                    OperationKey::gen(),
+                )
+            })
+            .collect::<Vec<_>>()
+    };
+
+    // A single entry is written as soon as the writer gets to it, without waiting for the
+    // rest of a batch
+    let queue =
+        SqlBlobstoreSyncQueue::with_sqlite_in_memory()?.with_max_write_latency(max_write_latency);
+    let start = Instant::now();
+    queue
+        .add_many(ctx.clone(), Box::new(entries(1).into_iter()))
+        .await?;
+    assert!(start.elapsed() < max_write_latency, "{:?}", start.elapsed());
+    assert_eq!(queue.get(ctx.clone(), "key0".to_string()).await?.len(), 1);
+
+    // With a write buffer smaller than the number of entries, they are written in several
+    // full batches, none of which waits for the max latency either
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?
+        .with_write_buffer(2)
+        .with_max_write_latency(max_write_latency);
+    let start = Instant::now();
+    queue
+        .add_many(ctx.clone(), Box::new(entries(5).into_iter()))
+        .await?;
+    assert!(start.elapsed() < max_write_latency, "{:?}", start.elapsed());
+    for i in 0..5 {
+        assert_eq!(queue.get(ctx.clone(), format!("key{}", i)).await?.len(), 1);
+    }
+
+    // A burst of entries that never fills the write buffer waits for more entries to join
+    // its batch, but is written once the max latency is up
+    let max_write_latency = Duration::from_millis(200);
+    let queue = SqlBlobstoreSyncQueue::with_sqlite_in_memory()?
+        .with_write_buffer(100)
+        .with_max_write_latency(max_write_latency);
+    tokio::time::timeout(
+        Duration::from_secs(10),
+        queue.add_many(ctx.clone(), Box::new(entries(5).into_iter())),
+    )
+    .await
+    .map_err(|_| format_err!("batch was not written within the max write latency"))??;
+    for i in 0..5 {
+        assert_eq!(queue.get(ctx.clone(), format!("key{}", i)).await?.len(), 1);
+    }
+    Ok(())
+}
+
+#[fbinit::test]
+async fn test_del_many_operations_failures(fb: FacebookInit) -> Result<(), Error> {
+    let ctx = CoreContext::test_mock(fb);
+    // Deleting the entries of key2 fails, so that the chunk with its operation fails
+    let conn = open_sqlite_in_memory()?;
+    conn.execute_batch(SqlBlobstoreSyncQueue::CREATION_QUERY)?;
+    conn.execute_batch(
+        "CREATE TRIGGER fail_key2 BEFORE DELETE ON blobstore_sync_queue
+         WHEN OLD.blobstore_key = 'key2'
+         BEGIN SELECT RAISE(ABORT, 'injected failure'); END;",
+    )?;
+    let queue = SqlBlobstoreSyncQueue::from_sql_connections(SqlConnections::new_single(
+        Connection::with_sqlite(conn),
+    ));
+    let mp = MultiplexId::new(1);
+    let t0 = DateTime::from_rfc3339("2018-11-29T12:00:00.00Z")?;
+    let operation_keys: Vec<_> = (0..5).map(|_| OperationKey::gen()).collect();
+
+    let mut entries = Vec::new();
+    for (i, operation_key) in operation_keys.iter().enumerate() {
+        for blobstore_id in 0..3 {
+            entries.push(BlobstoreSyncQueueEntry::new(
+                format!("key{}", i),
+                BlobstoreId::new(blobstore_id),
+                mp,
+                t0,
+                operation_key.clone(),
+            ));
+        }
+    }
+    queue
+        .add_many(ctx.clone(), Box::new(entries.into_iter()))
+        .await?;
+
+    // A nil key is rejected before anything is deleted
+    let mut with_nil = operation_keys.clone();
+    with_nil.push(OperationKey(Uuid::nil()));
+    assert!(queue
+        .del_many_operations(ctx.clone(), with_nil, 2)
+        .await
+        .is_err());
+    for i in 0..5 {
+        assert_eq!(queue.get(ctx.clone(), format!("key{}", i)).await?.len(), 3);
+    }
+
+    // The first chunk is deleted, and the failed chunk and the ones after it are kept whole
+    assert!(queue
+        .del_many_operations(ctx.clone(), operation_keys, 2)
+        .await
+        .is_err());
+    for i in 0..2 {
+        assert!(queue
+            .get(ctx.clone(), format!("key{}", i))
+            .await?
+            .is_empty());
+    }
+    for i in 2..5 {
+        assert_eq!(queue.get(ctx.clone(), format!("key{}", i)).await?.len(), 3);
+    }
+    Ok(())
+}
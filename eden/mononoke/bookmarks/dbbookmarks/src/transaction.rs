@@ -164,9 +164,9 @@ struct SqlBookmarksTransactionPayload {
     /// Operations to delete a bookmark with an old id.
     deletes: HashMap<BookmarkName, ChangesetId>,
 
-    /// Log entries to log. Scratch updates and creates are not included in
-    /// the log.
-    log: HashMap<BookmarkName, NewUpdateLogEntry>,
+    /// Log entries to log, in order. Scratch updates and creates are not
+    /// included in the log.
+    log: Vec<(BookmarkName, NewUpdateLogEntry)>,
 }
 
 impl SqlBookmarksTransactionPayload {
@@ -178,7 +178,7 @@ impl SqlBookmarksTransactionPayload {
             updates: HashMap::new(),
             force_deletes: HashSet::new(),
             deletes: HashMap::new(),
-            log: HashMap::new(),
+            log: Vec::new(),
         }
     }
 
@@ -396,10 +396,36 @@ impl BookmarkTransaction for SqlBookmarksTransaction {
             bookmark.clone(),
             (old_cs, new_cs, BookmarkKind::ALL_PUBLISHING),
         );
-        self.payload.log.insert(
+        self.payload.log.push((
             bookmark.clone(),
             NewUpdateLogEntry::new(Some(old_cs), Some(new_cs), reason, bundle_replay)?,
+        ));
+        Ok(())
+    }
+
+    fn update_steps(
+        &mut self,
+        bookmark: &BookmarkName,
+        steps: &[ChangesetId],
+        old_cs: ChangesetId,
+        reason: BookmarkUpdateReason,
+    ) -> Result<()> {
+        let new_cs = *steps
+            .last()
+            .ok_or_else(|| anyhow!("No steps to update {} bookmark", bookmark))?;
+        self.check_not_seen(bookmark)?;
+        self.payload.updates.insert(
+            bookmark.clone(),
+            (old_cs, new_cs, BookmarkKind::ALL_PUBLISHING),
         );
+        let mut from_cs = old_cs;
+        for &to_cs in steps {
+            self.payload.log.push((
+                bookmark.clone(),
+                NewUpdateLogEntry::new(Some(from_cs), Some(to_cs), reason, None)?,
+            ));
+            from_cs = to_cs;
+        }
         Ok(())
     }
 
@@ -415,10 +441,10 @@ impl BookmarkTransaction for SqlBookmarksTransaction {
             bookmark.clone(),
             (new_cs, BookmarkKind::PullDefaultPublishing),
         );
-        self.payload.log.insert(
+        self.payload.log.push((
             bookmark.clone(),
             NewUpdateLogEntry::new(None, Some(new_cs), reason, bundle_replay)?,
-        );
+        ));
         Ok(())
     }
 
@@ -431,10 +457,10 @@ impl BookmarkTransaction for SqlBookmarksTransaction {
     ) -> Result<()> {
         self.check_not_seen(bookmark)?;
         self.payload.force_sets.insert(bookmark.clone(), new_cs);
-        self.payload.log.insert(
+        self.payload.log.push((
             bookmark.clone(),
             NewUpdateLogEntry::new(None, Some(new_cs), reason, bundle_replay)?,
-        );
+        ));
         Ok(())
     }
 
@@ -447,10 +473,10 @@ impl BookmarkTransaction for SqlBookmarksTransaction {
     ) -> Result<()> {
         self.check_not_seen(bookmark)?;
         self.payload.deletes.insert(bookmark.clone(), old_cs);
-        self.payload.log.insert(
+        self.payload.log.push((
             bookmark.clone(),
             NewUpdateLogEntry::new(Some(old_cs), None, reason, bundle_replay)?,
-        );
+        ));
         Ok(())
     }
 
@@ -462,10 +488,10 @@ impl BookmarkTransaction for SqlBookmarksTransaction {
     ) -> Result<()> {
         self.check_not_seen(bookmark)?;
         self.payload.force_deletes.insert(bookmark.clone());
-        self.payload.log.insert(
+        self.payload.log.push((
             bookmark.clone(),
             NewUpdateLogEntry::new(None, None, reason, bundle_replay)?,
-        );
+        ));
         Ok(())
     }
 
@@ -503,10 +529,10 @@ impl BookmarkTransaction for SqlBookmarksTransaction {
         self.payload
             .creates
             .insert(bookmark.clone(), (new_cs, BookmarkKind::Publishing));
-        self.payload.log.insert(
+        self.payload.log.push((
             bookmark.clone(),
             NewUpdateLogEntry::new(None, Some(new_cs), reason, bundle_replay)?,
-        );
+        ));
         Ok(())
     }
 
@@ -370,6 +370,88 @@ fn test_simple_update_bookmark(fb: FacebookInit) {
     })
 }
 
+#[fbinit::test]
+fn test_update_steps(fb: FacebookInit) {
+    async_unit::tokio_unit_test(async move {
+        let ctx = CoreContext::test_mock(fb);
+        let bookmarks = SqlBookmarksBuilder::with_sqlite_in_memory()
+            .unwrap()
+            .with_repo_id(REPO_ZERO);
+        let name_1 = create_bookmark_name("book");
+
+        let mut txn = bookmarks.create_transaction(ctx.clone());
+        txn.create(&name_1, ONES_CSID, BookmarkUpdateReason::TestMove, None)
+            .unwrap();
+        assert!(txn.commit().await.unwrap());
+
+        let mut txn = bookmarks.create_transaction(ctx.clone());
+        txn.update_steps(
+            &name_1,
+            &[TWOS_CSID, THREES_CSID],
+            ONES_CSID,
+            BookmarkUpdateReason::TestMove,
+        )
+        .unwrap();
+        assert!(txn.commit().await.unwrap());
+
+        assert_eq!(
+            bookmarks.get(ctx.clone(), &name_1).await.unwrap(),
+            Some(THREES_CSID)
+        );
+
+        // Each step is logged separately
+        compare_log_entries(
+            bookmarks
+                .read_next_bookmark_log_entries(ctx.clone(), 1, 2, Freshness::MostRecent)
+                .try_collect::<Vec<_>>()
+                .await
+                .unwrap(),
+            vec![
+                BookmarkUpdateLogEntry {
+                    id: 2,
+                    repo_id: REPO_ZERO,
+                    bookmark_name: name_1.clone(),
+                    to_changeset_id: Some(TWOS_CSID),
+                    from_changeset_id: Some(ONES_CSID),
+                    reason: BookmarkUpdateReason::TestMove,
+                    timestamp: Timestamp::now(),
+                    bundle_replay_data: None,
+                },
+                BookmarkUpdateLogEntry {
+                    id: 3,
+                    repo_id: REPO_ZERO,
+                    bookmark_name: name_1.clone(),
+                    to_changeset_id: Some(THREES_CSID),
+                    from_changeset_id: Some(TWOS_CSID),
+                    reason: BookmarkUpdateReason::TestMove,
+                    timestamp: Timestamp::now(),
+                    bundle_replay_data: None,
+                },
+            ],
+        );
+
+        // Nothing is logged if the bookmark isn't where the steps start from
+        let mut txn = bookmarks.create_transaction(ctx.clone());
+        txn.update_steps(
+            &name_1,
+            &[FOURS_CSID, FIVES_CSID],
+            ONES_CSID,
+            BookmarkUpdateReason::TestMove,
+        )
+        .unwrap();
+        assert!(!txn.commit().await.unwrap());
+        assert_eq!(
+            bookmarks.get(ctx.clone(), &name_1).await.unwrap(),
+            Some(THREES_CSID)
+        );
+
+        let mut txn = bookmarks.create_transaction(ctx.clone());
+        assert!(txn
+            .update_steps(&name_1, &[], THREES_CSID, BookmarkUpdateReason::TestMove)
+            .is_err());
+    })
+}
+
 #[fbinit::test]
 fn test_noop_update(fb: FacebookInit) {
     async_unit::tokio_unit_test(async move {
@@ -306,6 +306,18 @@ impl BookmarkTransaction for CachedBookmarksTransaction {
             .update(bookmark, new_cs, old_cs, reason, bundle_replay)
     }
 
+    fn update_steps(
+        &mut self,
+        bookmark: &BookmarkName,
+        steps: &[ChangesetId],
+        old_cs: ChangesetId,
+        reason: BookmarkUpdateReason,
+    ) -> Result<()> {
+        self.dirty = true;
+        self.transaction
+            .update_steps(bookmark, steps, old_cs, reason)
+    }
+
     fn create(
         &mut self,
         bookmark: &BookmarkName,
@@ -541,6 +553,16 @@ mod tests {
             Ok(())
         }
 
+        fn update_steps(
+            &mut self,
+            _bookmark: &BookmarkName,
+            _steps: &[ChangesetId],
+            _old_cs: ChangesetId,
+            _reason: BookmarkUpdateReason,
+        ) -> Result<()> {
+            Ok(())
+        }
+
         fn create(
             &mut self,
             _bookmark: &BookmarkName,
@@ -55,6 +55,18 @@ pub trait BookmarkTransaction: Send + Sync + 'static {
         bundle_replay: Option<&dyn BundleReplay>,
     ) -> Result<()>;
 
+    /// Adds update() operations that move a bookmark from `old_cs` through each of `steps` in
+    /// turn. The bookmark ends up at the last step, and each step is logged as if it were a
+    /// separate update. Bookmark should already exist and point to `old_cs`, otherwise
+    /// committing the transaction will fail.
+    fn update_steps(
+        &mut self,
+        bookmark: &BookmarkName,
+        steps: &[ChangesetId],
+        old_cs: ChangesetId,
+        reason: BookmarkUpdateReason,
+    ) -> Result<()>;
+
     /// Adds create() operation to the transaction set.
     /// Creates a bookmark. BookmarkName should not already exist, otherwise committing the
     /// transaction will fail. The resulting Bookmark will be PullDefault.
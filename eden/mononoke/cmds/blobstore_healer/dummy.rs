@@ -16,6 +16,7 @@ use futures::future::{self, BoxFuture, FutureExt};
 use metaconfig_types::MultiplexId;
 use mononoke_types::{BlobstoreBytes, DateTime};
 use slog::{info, Logger};
+use std::collections::HashMap;
 
 #[derive(Debug)]
 pub struct DummyBlobstore<B> {
@@ -100,6 +101,23 @@ impl<Q: BlobstoreSyncQueue> BlobstoreSyncQueue for DummyBlobstoreSyncQueue<Q> {
             .iter(ctx, key_like, multiplex_id, older_than, limit)
     }
 
+    fn count(
+        &self,
+        ctx: CoreContext,
+        multiplex_id: MultiplexId,
+        older_than: DateTime,
+    ) -> BoxFuture<'static, Result<u64, Error>> {
+        self.inner.count(ctx, multiplex_id, older_than)
+    }
+
+    fn oldest_entries_global(
+        &self,
+        ctx: CoreContext,
+        limit: usize,
+    ) -> BoxFuture<'static, Result<Vec<BlobstoreSyncQueueEntry>, Error>> {
+        self.inner.oldest_entries_global(ctx, limit)
+    }
+
     fn del(
         &self,
         _ctx: CoreContext,
@@ -117,4 +135,27 @@ impl<Q: BlobstoreSyncQueue> BlobstoreSyncQueue for DummyBlobstoreSyncQueue<Q> {
     ) -> BoxFuture<'static, Result<Vec<BlobstoreSyncQueueEntry>, Error>> {
         self.inner.get(ctx, key)
     }
+
+    fn get_many(
+        &self,
+        ctx: CoreContext,
+        keys: Vec<String>,
+    ) -> BoxFuture<'static, Result<HashMap<String, Vec<BlobstoreSyncQueueEntry>>, Error>> {
+        self.inner.get_many(ctx, keys)
+    }
+
+    fn claim(
+        &self,
+        ctx: CoreContext,
+        multiplex_id: MultiplexId,
+        older_than: DateTime,
+        limit: usize,
+        claimant_id: String,
+    ) -> BoxFuture<'static, Result<Vec<BlobstoreSyncQueueEntry>, Error>> {
+        info!(
+            self.logger,
+            "I would have claimed up to {} entries for {}", limit, claimant_id
+        );
+        self.inner.iter(ctx, None, multiplex_id, older_than, limit)
+    }
 }
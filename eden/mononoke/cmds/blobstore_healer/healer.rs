@@ -479,6 +479,7 @@ async fn requeue_partial_heal(
             timestamp,
             operation_key: operation_key.clone(),
             id: None,
+            blob_size: None,
         })
         .collect();
     sync_queue
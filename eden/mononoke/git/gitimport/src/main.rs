@@ -7,9 +7,6 @@
 
 #![deny(warnings)]
 
-mod mem_writes_bonsai_hg_mapping;
-mod mem_writes_changesets;
-
 use anyhow::Error;
 use blobrepo_override::DangerousOverride;
 use blobstore::Blobstore;
@@ -23,15 +20,14 @@ use context::CoreContext;
 use fbinit::FacebookInit;
 use futures::compat::Future01CompatExt;
 use git2::Oid;
-use import_tools::{GitimportPreferences, GitimportTarget};
+use import_tools::{
+    GitimportPreferences, GitimportTarget, MemWritesBonsaiHgMapping, MemWritesChangesets,
+};
 use linked_hash_map::LinkedHashMap;
 use mononoke_types::{BonsaiChangeset, ChangesetId};
 use std::path::Path;
 use std::sync::Arc;
 
-use crate::mem_writes_bonsai_hg_mapping::MemWritesBonsaiHgMapping;
-use crate::mem_writes_changesets::MemWritesChangesets;
-
 // Refactor this a bit. Use a thread pool for git operations. Pass that wherever we use store repo.
 // Transform the walk into a stream of commit + file changes.
 
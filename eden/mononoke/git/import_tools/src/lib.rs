@@ -7,12 +7,16 @@
 
 mod git_pool;
 mod gitimport_objects;
+mod mem_writes_bonsai_hg_mapping;
+mod mem_writes_changesets;
 
 pub use crate::git_pool::GitPool;
 pub use crate::gitimport_objects::{
     CommitMetadata, ExtractedCommit, GitLeaf, GitManifest, GitTree, GitimportPreferences,
     GitimportTarget,
 };
+pub use crate::mem_writes_bonsai_hg_mapping::MemWritesBonsaiHgMapping;
+pub use crate::mem_writes_changesets::MemWritesChangesets;
 use anyhow::{format_err, Context, Error};
 use blobrepo::BlobRepo;
 use blobrepo_hg::{derive_hg_changeset::get_manifest_from_bonsai, BlobRepoHg};
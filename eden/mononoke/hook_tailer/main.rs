@@ -22,9 +22,11 @@ use futures::{
     future,
     stream::{FuturesUnordered, StreamExt, TryStreamExt},
 };
-use mononoke_types::ChangesetId;
+use hooks::HookTimeouts;
+use mononoke_types::{ChangesetId, DateTime};
 use slog::{debug, info, Logger};
 use std::collections::HashSet;
+use std::num::NonZeroUsize;
 use std::time::Duration;
 use time_ext::DurationExt;
 use tokio::{
@@ -32,7 +34,7 @@ use tokio::{
     io::{AsyncBufReadExt, AsyncWriteExt, BufReader},
 };
 
-use tailer::{HookExecutionInstance, Tailer};
+use tailer::{DateRange, HookExecutionInstance, MergeFilter, Tailer};
 
 async fn get_changesets<'a>(
     matches: &'a ArgMatches<'a>,
@@ -83,6 +85,28 @@ fn main(fb: FacebookInit) -> Result<()> {
     )
 }
 
+fn parse_hook_timeouts<'a>(matches: &'a ArgMatches<'a>) -> Result<HookTimeouts> {
+    let default = matches
+        .value_of("hook-timeout-ms")
+        .map(|ms| ms.parse().map(Duration::from_millis))
+        .transpose()?;
+
+    let per_hook = matches
+        .values_of("per-hook-timeout")
+        .into_iter()
+        .flatten()
+        .map(|spec| {
+            let (name, ms) = match spec.rfind('=') {
+                Some(idx) => (&spec[..idx], &spec[idx + 1..]),
+                None => return Err(format_err!("Invalid per-hook timeout: {}", spec)),
+            };
+            Ok((name.to_string(), Duration::from_millis(ms.parse()?)))
+        })
+        .collect::<Result<_>>()?;
+
+    Ok(HookTimeouts { default, per_hook })
+}
+
 async fn run_hook_tailer<'a>(
     fb: FacebookInit,
     ctx: &CoreContext,
@@ -96,7 +120,38 @@ async fn run_hook_tailer<'a>(
     let common_config = cmdlib::args::load_common_config(fb, &matches)?;
     let limit = cmdlib::args::get_usize(&matches, "limit", 1000);
     let concurrency = cmdlib::args::get_usize(&matches, "concurrency", 100);
+    let prefetch_depth = matches
+        .value_of("prefetch-depth")
+        .map(|depth| depth.parse::<NonZeroUsize>())
+        .transpose()?
+        .map_or(concurrency, NonZeroUsize::get);
     let stats_file = matches.value_of("stats-file");
+    let slow_threshold = matches
+        .value_of("slow-threshold-ms")
+        .map(|ms| ms.parse().map(Duration::from_millis))
+        .transpose()?;
+    let hook_timeouts = parse_hook_timeouts(matches)?;
+    let max_file_count = matches
+        .value_of("max-file-count")
+        .map(|count| count.parse())
+        .transpose()?;
+    let merge_filter = if matches.is_present("include-merges") {
+        MergeFilter::OnlyMerges
+    } else if matches.is_present("exclude-merges") {
+        MergeFilter::ExcludeMerges
+    } else {
+        MergeFilter::All
+    };
+    let date_range = DateRange {
+        from: matches
+            .value_of("from-date")
+            .map(DateTime::from_rfc3339)
+            .transpose()?,
+        to: matches
+            .value_of("to-date")
+            .map(DateTime::from_rfc3339)
+            .transpose()?,
+    };
 
     let mut stats_file = match stats_file {
         Some(stats_file) => {
@@ -147,6 +202,12 @@ async fn run_hook_tailer<'a>(
         concurrency,
         exclusions,
         &disabled_hooks,
+        slow_threshold,
+        hook_timeouts,
+        max_file_count,
+        merge_filter,
+        date_range,
+        prefetch_depth,
     )
     .await?;
 
@@ -191,6 +252,7 @@ async fn run_hook_tailer<'a>(
     );
     info!(logger, "Changesets accepted: {}", summary.accepted);
     info!(logger, "Changesets rejected: {}", summary.rejected);
+    info!(logger, "Changesets skipped: {}", summary.skipped);
 
     if summary.rejected > 0 {
         return Err(format_err!("Hook rejections: {}", summary.rejected));
@@ -203,24 +265,24 @@ async fn run_hook_tailer<'a>(
 struct HookExecutionSummary {
     accepted: u64,
     rejected: u64,
+    skipped: u64,
     completion_time: Duration,
     poll_time: Duration,
 }
 
 impl HookExecutionSummary {
     pub fn add_instance(&mut self, instance: &HookExecutionInstance, logger: &Logger) {
-        let mut is_rejected = false;
-
         for outcome in instance.outcomes.iter() {
             if outcome.is_rejection() {
-                is_rejected = true;
                 info!(logger, "{}", outcome);
             } else {
                 debug!(logger, "{}", outcome);
             }
         }
 
-        if is_rejected {
+        if instance.skipped_too_large {
+            self.skipped += 1;
+        } else if !instance.is_accepted() {
             self.rejected += 1;
         } else {
             self.accepted += 1;
@@ -250,6 +312,12 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
                 .help("the number of changesets to run hooks for in parallel")
                 .takes_value(true),
         )
+        .arg(
+            Arg::with_name("prefetch-depth")
+                .long("prefetch-depth")
+                .help("the number of changesets to load ahead of running hooks on them, at least 1. Default: --concurrency")
+                .takes_value(true),
+        )
         .arg(
             Arg::with_name("changeset")
                 .long("changeset")
@@ -290,6 +358,55 @@ fn setup_app<'a, 'b>() -> App<'a, 'b> {
                 .long("stats-file")
                 .takes_value(true)
                 .help("Log hook execution statistics to a file (CSV format)"),
+        )
+        .arg(
+            Arg::with_name("slow-threshold-ms")
+                .long("slow-threshold-ms")
+                .takes_value(true)
+                .help("Warn about changesets whose hooks take longer than this many milliseconds"),
+        )
+        .arg(
+            Arg::with_name("max-file-count")
+                .long("max-file-count")
+                .takes_value(true)
+                .help("Skip running hooks on changesets that change more than this many files"),
+        )
+        .arg(
+            Arg::with_name("include-merges")
+                .long("include-merges")
+                .conflicts_with("exclude-merges")
+                .help("Only run hooks on merge changesets"),
+        )
+        .arg(
+            Arg::with_name("exclude-merges")
+                .long("exclude-merges")
+                .help("Don't run hooks on merge changesets"),
+        )
+        .arg(
+            Arg::with_name("from-date")
+                .long("from-date")
+                .takes_value(true)
+                .help("Only run hooks on changesets authored at or after this RFC 3339 date. Tailing a bookmark stops at the first changeset authored before it"),
+        )
+        .arg(
+            Arg::with_name("to-date")
+                .long("to-date")
+                .takes_value(true)
+                .help("Only run hooks on changesets authored at or before this RFC 3339 date"),
+        )
+        .arg(
+            Arg::with_name("hook-timeout-ms")
+                .long("hook-timeout-ms")
+                .takes_value(true)
+                .help("Fail hooks that take longer than this many milliseconds on a changeset or file"),
+        )
+        .arg(
+            Arg::with_name("per-hook-timeout")
+                .long("per-hook-timeout")
+                .multiple(true)
+                .number_of_values(1)
+                .takes_value(true)
+                .help("Timeout for a single hook, as HOOK_NAME=MILLISECONDS. Overrides --hook-timeout-ms"),
         );
 
     cmdlib::args::add_disabled_hooks_args(app)
@@ -19,17 +19,21 @@ use futures::{
     stream::{self, Stream, StreamExt, TryStreamExt},
 };
 use futures_stats::{FutureStats, TimedFutureExt};
-use hooks::{hook_loader::load_hooks, HookManager, HookOutcome};
+use hooks::{
+    hook_loader::load_hooks, ErrorKind as HookErrorKind, HookManager, HookOutcome, HookTimeouts,
+};
 use hooks_content_stores::blobrepo_text_only_fetcher;
 use metaconfig_types::RepoConfig;
-use mononoke_types::ChangesetId;
+use mononoke_types::{BonsaiChangeset, ChangesetId, DateTime};
 use revset::AncestorsNodeStream;
 use scuba_ext::ScubaSampleBuilder;
-use slog::debug;
+use slog::{debug, warn};
 use std::collections::HashSet;
 use std::iter::IntoIterator;
 use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
+use time_ext::DurationExt;
 use tokio::task;
 
 pub struct HookExecutionInstance {
@@ -37,6 +41,85 @@ pub struct HookExecutionInstance {
     pub file_count: usize,
     pub stats: FutureStats,
     pub outcomes: Vec<HookOutcome>,
+    /// Hooks were not run, because the changeset touches more files than `max_file_count`
+    pub skipped_too_large: bool,
+}
+
+impl HookExecutionInstance {
+    /// Whether all hooks accepted the changeset. Hooks can't be advisory, so any rejection
+    /// counts. Skipped changesets have no outcomes, and so are accepted: check
+    /// `skipped_too_large` to tell them apart.
+    pub fn is_accepted(&self) -> bool {
+        self.outcomes.iter().all(HookOutcome::is_accept)
+    }
+
+    pub fn rejections(&self) -> Vec<&HookOutcome> {
+        self.outcomes
+            .iter()
+            .filter(|outcome| outcome.is_rejection())
+            .collect()
+    }
+}
+
+/// What happened to one of the changesets passed to `Tailer::run_changesets_with_status`.
+pub enum ChangesetStatus {
+    Ran(HookExecutionInstance),
+    /// Hooks were not run, because the changeset is excluded or filtered out
+    Filtered,
+    /// Hooks could not be run, even after retrying
+    Failed(Error),
+}
+
+impl ChangesetStatus {
+    pub fn is_failed(&self) -> bool {
+        match self {
+            Self::Failed(_) => true,
+            Self::Ran(_) | Self::Filtered => false,
+        }
+    }
+}
+
+/// Which changesets to run hooks on, by whether they are merges.
+#[derive(Clone, Copy, Debug, Eq, PartialEq)]
+pub enum MergeFilter {
+    All,
+    OnlyMerges,
+    ExcludeMerges,
+}
+
+impl Default for MergeFilter {
+    fn default() -> Self {
+        Self::All
+    }
+}
+
+impl MergeFilter {
+    pub fn matches(self, parent_count: usize) -> bool {
+        let is_merge = parent_count > 1;
+        match self {
+            Self::All => true,
+            Self::OnlyMerges => is_merge,
+            Self::ExcludeMerges => !is_merge,
+        }
+    }
+}
+
+/// Which changesets to run hooks on, by author date. Both bounds are inclusive.
+#[derive(Clone, Copy, Debug, Default, Eq, PartialEq)]
+pub struct DateRange {
+    pub from: Option<DateTime>,
+    pub to: Option<DateTime>,
+}
+
+impl DateRange {
+    pub fn contains(&self, date: &DateTime) -> bool {
+        !self.is_before(date) && self.to.map_or(true, |to| *date <= to)
+    }
+
+    /// Whether `date` is before the start of the range.
+    pub fn is_before(&self, date: &DateTime) -> bool {
+        self.from.map_or(false, |from| *date < from)
+    }
 }
 
 pub struct Tailer {
@@ -46,6 +129,11 @@ pub struct Tailer {
     bookmark: BookmarkName,
     concurrency: usize,
     excludes: HashSet<ChangesetId>,
+    slow_threshold: Option<Duration>,
+    max_file_count: Option<usize>,
+    merge_filter: MergeFilter,
+    date_range: DateRange,
+    prefetch_depth: usize,
 }
 
 impl Tailer {
@@ -57,6 +145,12 @@ impl Tailer {
         concurrency: usize,
         excludes: HashSet<ChangesetId>,
         disabled_hooks: &HashSet<String>,
+        slow_threshold: Option<Duration>,
+        hook_timeouts: HookTimeouts,
+        max_file_count: Option<usize>,
+        merge_filter: MergeFilter,
+        date_range: DateRange,
+        prefetch_depth: usize,
     ) -> Result<Tailer> {
         let content_fetcher = blobrepo_text_only_fetcher(repo.clone(), config.hook_max_file_size);
 
@@ -69,6 +163,7 @@ impl Tailer {
         .await?;
 
         load_hooks(ctx.fb, &mut hook_manager, config, disabled_hooks)?;
+        hook_manager.set_hook_timeouts(hook_timeouts);
 
         Ok(Tailer {
             ctx,
@@ -77,6 +172,11 @@ impl Tailer {
             bookmark,
             concurrency,
             excludes,
+            slow_threshold,
+            max_file_count,
+            merge_filter,
+            date_range,
+            prefetch_depth,
         })
     }
 
@@ -88,9 +188,61 @@ impl Tailer {
         I: IntoIterator<Item = ChangesetId> + 'a,
     {
         let stream = stream::iter(changesets.into_iter().map(Ok));
-        self.run_on_stream(stream)
+        self.run_on_stream(stream, false)
     }
 
+    /// Run hooks on each of `changesets`, returning their statuses in the same order. Unlike
+    /// `run_changesets`, an error doesn't end the run: the hooks for a changeset are retried
+    /// up to `retries` times if they fail with a transient error (e.g. a hook timeout), and
+    /// the changeset is then marked as failed.
+    pub async fn run_changesets_with_status(
+        &self,
+        changesets: Vec<ChangesetId>,
+        retries: usize,
+    ) -> Vec<(ChangesetId, ChangesetStatus)> {
+        stream::iter(changesets)
+            .map(move |cs_id| async move {
+                let mut attempt = 0;
+                let status = loop {
+                    match self.run_one(cs_id).await {
+                        Ok(Some(instance)) => break ChangesetStatus::Ran(instance),
+                        Ok(None) => break ChangesetStatus::Filtered,
+                        Err(e) if attempt < retries && is_transient(&e) => {
+                            attempt += 1;
+                            warn!(
+                                self.ctx.logger(),
+                                "Retrying hooks for changeset {} ({}/{}): {:?}",
+                                cs_id,
+                                attempt,
+                                retries,
+                                e
+                            );
+                        }
+                        Err(e) => break ChangesetStatus::Failed(e),
+                    }
+                };
+                (cs_id, status)
+            })
+            .buffered(self.concurrency)
+            .collect()
+            .await
+    }
+
+    /// Run hooks on a single changeset, or return `None` if it's excluded or filtered out.
+    async fn run_one(&self, cs_id: ChangesetId) -> Result<Option<HookExecutionInstance>, Error> {
+        if self.excludes.contains(&cs_id) {
+            return Ok(None);
+        }
+        let cs = cs_id.load(self.ctx.clone(), self.repo.blobstore()).await?;
+        if !self.should_run(&cs) {
+            return Ok(None);
+        }
+        self.run_hooks(cs).await.map(Some)
+    }
+
+    /// Run hooks on up to `limit` ancestors of the bookmark, newest first. Traversal stops
+    /// at the first changeset authored before the start of the date range, so older
+    /// changesets are not visited even if they are in the range.
     pub fn run_with_limit<'a>(
         &'a self,
         limit: usize,
@@ -111,14 +263,19 @@ impl Tailer {
             .compat()
             .take(limit);
 
-            Ok(self.run_on_stream(stream))
+            Ok(self.run_on_stream(stream, true))
         }
         .try_flatten_stream()
     }
 
+    /// Changesets are loaded up to `prefetch_depth` at a time, ahead of running hooks on
+    /// up to `concurrency` of them, so that loading and running hooks overlap. With
+    /// `stop_before_range`, the stream ends at the first changeset authored before the
+    /// date range.
     fn run_on_stream<'a, S>(
         &'a self,
         stream: S,
+        stop_before_range: bool,
     ) -> impl Stream<Item = Result<HookExecutionInstance, Error>> + 'a
     where
         S: Stream<Item = Result<ChangesetId, Error>> + 'a,
@@ -127,43 +284,114 @@ impl Tailer {
             .try_filter(move |cs_id| future::ready(!self.excludes.contains(cs_id)))
             .map(move |cs_id| async move {
                 match cs_id {
-                    Ok(cs_id) => {
-                        cloned!(self.ctx, self.repo, self.hook_manager, self.bookmark);
-
-                        let outcomes = task::spawn(async move {
-                            run_hooks_for_changeset(
-                                &ctx,
-                                &repo,
-                                hook_manager.as_ref(),
-                                &bookmark,
-                                cs_id,
-                            )
-                            .await
-                        })
-                        .await??;
-
-                        Ok(outcomes)
+                    Ok(cs_id) => cs_id
+                        .load(self.ctx.clone(), self.repo.blobstore())
+                        .await
+                        .map_err(Error::from),
+                    Err(e) => Err(e),
+                }
+            })
+            .buffered(self.prefetch_depth)
+            .take_while(move |cs| {
+                if let Ok(cs) = cs {
+                    if stop_before_range && self.date_range.is_before(cs.author_date()) {
+                        debug!(
+                            self.ctx.logger(),
+                            "Stopping at changeset {}: authored before the date range",
+                            cs.get_changeset_id()
+                        );
+                        return future::ready(false);
                     }
+                }
+                future::ready(true)
+            })
+            .try_filter(move |cs| future::ready(self.should_run(cs)))
+            .map(move |cs| async move {
+                match cs {
+                    Ok(cs) => self.run_hooks(cs).await,
                     Err(e) => Err(e),
                 }
             })
             .buffered(self.concurrency)
     }
+
+    async fn run_hooks(&self, cs: BonsaiChangeset) -> Result<HookExecutionInstance, Error> {
+        cloned!(self.ctx, self.hook_manager, self.bookmark);
+        let max_file_count = self.max_file_count;
+
+        let outcomes = task::spawn(async move {
+            run_hooks_for_changeset(&ctx, hook_manager.as_ref(), &bookmark, cs, max_file_count)
+                .await
+        })
+        .await??;
+
+        if let Some(slow_threshold) = self.slow_threshold {
+            if outcomes.stats.completion_time > slow_threshold {
+                warn!(
+                    self.ctx.logger(),
+                    "Slow hooks for changeset {}: {}us",
+                    outcomes.cs_id,
+                    outcomes.stats.completion_time.as_micros_unchecked()
+                );
+            }
+        }
+
+        Ok(outcomes)
+    }
+
+    /// Whether to run hooks on a loaded changeset, or skip it because the merge filter or
+    /// date range exclude it.
+    fn should_run(&self, cs: &BonsaiChangeset) -> bool {
+        let cs_id = cs.get_changeset_id();
+        let parent_count = cs.parents().count();
+        if !self.merge_filter.matches(parent_count) {
+            debug!(
+                self.ctx.logger(),
+                "Not running hooks for changeset {}: {} parents", cs_id, parent_count
+            );
+            return false;
+        }
+        if !self.date_range.contains(cs.author_date()) {
+            debug!(
+                self.ctx.logger(),
+                "Not running hooks for changeset {}: authored at {}",
+                cs_id,
+                cs.author_date()
+            );
+            return false;
+        }
+        true
+    }
 }
 
 async fn run_hooks_for_changeset(
     ctx: &CoreContext,
-    repo: &BlobRepo,
     hm: &HookManager,
     bm: &BookmarkName,
-    cs_id: ChangesetId,
+    cs: BonsaiChangeset,
+    max_file_count: Option<usize>,
 ) -> Result<HookExecutionInstance, Error> {
-    let cs = cs_id.load(ctx.clone(), repo.blobstore()).await?;
+    let cs_id = cs.get_changeset_id();
+    let file_count = cs.file_changes_map().len();
+    if let Some(max_file_count) = max_file_count {
+        if file_count > max_file_count {
+            warn!(
+                ctx.logger(),
+                "Skipping hooks for changeset {}: {} files changed", cs_id, file_count
+            );
+            let (stats, ()) = future::ready(()).timed().await;
+            return Ok(HookExecutionInstance {
+                cs_id,
+                file_count,
+                stats,
+                outcomes: vec![],
+                skipped_too_large: true,
+            });
+        }
+    }
 
     debug!(ctx.logger(), "Running hooks for changeset {:?}", cs);
 
-    let file_count = cs.file_changes_map().len();
-
     let (stats, outcomes) = hm
         .run_hooks_for_bookmark(ctx, vec![cs].iter(), bm, None)
         .timed()
@@ -176,11 +404,344 @@ async fn run_hooks_for_changeset(
         file_count,
         stats,
         outcomes,
+        skipped_too_large: false,
     })
 }
 
+/// Whether running hooks might succeed if retried. Hook timeouts and errors from outside of
+/// the hooks (e.g. failing to load the changeset) are transient, other hook errors are not.
+fn is_transient(error: &Error) -> bool {
+    match error.downcast_ref::<HookErrorKind>() {
+        Some(HookErrorKind::HookTimeout(..)) | None => true,
+        Some(_) => false,
+    }
+}
+
 #[derive(Debug, Error)]
 pub enum ErrorKind {
     #[error("No such bookmark '{0}'")]
     NoSuchBookmark(BookmarkName),
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+    use blobrepo_factory::new_memblob_empty;
+    use fbinit::FacebookInit;
+    use hooks::{ChangesetHookExecutionID, FileHookExecutionID, HookExecution, HookRejectionInfo};
+    use mononoke_types::MPath;
+    use tests_utils::{bookmark, CreateCommitContext};
+
+    #[fbinit::compat_test]
+    async fn test_max_file_count(fb: FacebookInit) -> Result<(), Error> {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = new_memblob_empty(None)?;
+        let small = CreateCommitContext::new_root(&ctx, &repo)
+            .add_file("a", "a")
+            .commit()
+            .await?;
+        let large = CreateCommitContext::new(&ctx, &repo, vec![small])
+            .add_file("b", "b")
+            .add_file("c", "c")
+            .add_file("d", "d")
+            .commit()
+            .await?;
+
+        let tailer = Tailer::new(
+            ctx.clone(),
+            repo,
+            RepoConfig::default(),
+            BookmarkName::new("master")?,
+            1,
+            HashSet::new(),
+            &HashSet::new(),
+            None,
+            HookTimeouts::default(),
+            Some(2),
+            MergeFilter::All,
+            DateRange::default(),
+            1,
+        )
+        .await?;
+        let instances: Vec<_> = tailer
+            .run_changesets(vec![small, large])
+            .try_collect()
+            .await?;
+
+        assert_eq!(instances.len(), 2);
+        assert_eq!(instances[0].cs_id, small);
+        assert!(!instances[0].skipped_too_large);
+        assert_eq!(instances[1].cs_id, large);
+        assert_eq!(instances[1].file_count, 3);
+        assert!(instances[1].skipped_too_large);
+        assert!(instances[1].outcomes.is_empty());
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn test_merge_filter(fb: FacebookInit) -> Result<(), Error> {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = new_memblob_empty(None)?;
+        let root = CreateCommitContext::new_root(&ctx, &repo)
+            .add_file("a", "a")
+            .commit()
+            .await?;
+        let left = CreateCommitContext::new(&ctx, &repo, vec![root])
+            .add_file("b", "b")
+            .commit()
+            .await?;
+        let right = CreateCommitContext::new(&ctx, &repo, vec![root])
+            .add_file("c", "c")
+            .commit()
+            .await?;
+        let merge = CreateCommitContext::new(&ctx, &repo, vec![left, right])
+            .commit()
+            .await?;
+
+        for (merge_filter, expected) in vec![
+            (MergeFilter::All, vec![root, left, right, merge]),
+            (MergeFilter::OnlyMerges, vec![merge]),
+            (MergeFilter::ExcludeMerges, vec![root, left, right]),
+        ] {
+            let tailer = Tailer::new(
+                ctx.clone(),
+                repo.clone(),
+                RepoConfig::default(),
+                BookmarkName::new("master")?,
+                1,
+                HashSet::new(),
+                &HashSet::new(),
+                None,
+                HookTimeouts::default(),
+                None,
+                merge_filter,
+                DateRange::default(),
+                1,
+            )
+            .await?;
+            let cs_ids: Vec<_> = tailer
+                .run_changesets(vec![root, left, right, merge])
+                .map_ok(|instance| instance.cs_id)
+                .try_collect()
+                .await?;
+            assert_eq!(cs_ids, expected, "{:?}", merge_filter);
+        }
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn test_prefetch_depth(fb: FacebookInit) -> Result<(), Error> {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = new_memblob_empty(None)?;
+        let mut cs_ids = vec![];
+        let mut parents = vec![];
+        for i in 0..10 {
+            let cs_id = CreateCommitContext::new(&ctx, &repo, parents)
+                .add_file(format!("file{}", i), "content")
+                .commit()
+                .await?;
+            cs_ids.push(cs_id);
+            parents = vec![cs_id];
+        }
+
+        let mut results = vec![];
+        for prefetch_depth in vec![1, 8] {
+            let tailer = Tailer::new(
+                ctx.clone(),
+                repo.clone(),
+                RepoConfig::default(),
+                BookmarkName::new("master")?,
+                2,
+                HashSet::new(),
+                &HashSet::new(),
+                None,
+                HookTimeouts::default(),
+                None,
+                MergeFilter::All,
+                DateRange::default(),
+                prefetch_depth,
+            )
+            .await?;
+            let instances: Vec<_> = tailer
+                .run_changesets(cs_ids.clone())
+                .map_ok(|instance| (instance.cs_id, instance.file_count, instance.is_accepted()))
+                .try_collect()
+                .await?;
+            results.push(instances);
+        }
+
+        assert_eq!(
+            results[0]
+                .iter()
+                .map(|(cs_id, ..)| *cs_id)
+                .collect::<Vec<_>>(),
+            cs_ids
+        );
+        assert_eq!(results[0], results[1]);
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn test_date_range(fb: FacebookInit) -> Result<(), Error> {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = new_memblob_empty(None)?;
+        let date = |day| DateTime::from_rfc3339(&format!("2020-01-{:02}T00:00:00+00:00", day));
+
+        // Newest first: out of the range, in it twice, before it, then in it again
+        let mut cs_ids = vec![];
+        let mut parents = vec![];
+        for (i, day) in vec![10, 1, 12, 14, 20].into_iter().enumerate() {
+            let cs_id = CreateCommitContext::new(&ctx, &repo, parents)
+                .add_file(format!("file{}", i), "content")
+                .set_author_date(date(day)?)
+                .commit()
+                .await?;
+            cs_ids.push(cs_id);
+            parents = vec![cs_id];
+        }
+        bookmark(&ctx, &repo, "master")
+            .set_to(*cs_ids.last().unwrap())
+            .await?;
+
+        let tailer = Tailer::new(
+            ctx.clone(),
+            repo.clone(),
+            RepoConfig::default(),
+            BookmarkName::new("master")?,
+            1,
+            HashSet::new(),
+            &HashSet::new(),
+            None,
+            HookTimeouts::default(),
+            None,
+            MergeFilter::All,
+            DateRange {
+                from: Some(date(5)?),
+                to: Some(date(15)?),
+            },
+            1,
+        )
+        .await?;
+
+        // Traversal stops at the changeset from the 1st, so the one from the 10th is not
+        // processed even though it is in the range
+        let processed: Vec<_> = tailer
+            .run_with_limit(100)
+            .map_ok(|instance| instance.cs_id)
+            .try_collect()
+            .await?;
+        assert_eq!(processed, vec![cs_ids[3], cs_ids[2]]);
+
+        // Explicitly listed changesets are only filtered
+        let processed: Vec<_> = tailer
+            .run_changesets(cs_ids.clone())
+            .map_ok(|instance| instance.cs_id)
+            .try_collect()
+            .await?;
+        assert_eq!(processed, vec![cs_ids[0], cs_ids[2], cs_ids[3]]);
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn test_run_changesets_with_status(fb: FacebookInit) -> Result<(), Error> {
+        let ctx = CoreContext::test_mock(fb);
+        let repo = new_memblob_empty(None)?;
+        let first = CreateCommitContext::new_root(&ctx, &repo)
+            .add_file("a", "a")
+            .commit()
+            .await?;
+        let excluded = CreateCommitContext::new(&ctx, &repo, vec![first])
+            .add_file("b", "b")
+            .commit()
+            .await?;
+        // Not in the repo, so loading it fails
+        let missing = ChangesetId::from_bytes([1; 32])?;
+
+        let tailer = Tailer::new(
+            ctx.clone(),
+            repo,
+            RepoConfig::default(),
+            BookmarkName::new("master")?,
+            2,
+            vec![excluded].into_iter().collect(),
+            &HashSet::new(),
+            None,
+            HookTimeouts::default(),
+            None,
+            MergeFilter::All,
+            DateRange::default(),
+            1,
+        )
+        .await?;
+        let statuses = tailer
+            .run_changesets_with_status(vec![first, missing, excluded], 1)
+            .await;
+
+        assert_eq!(
+            statuses.iter().map(|(cs_id, _)| *cs_id).collect::<Vec<_>>(),
+            vec![first, missing, excluded]
+        );
+        match &statuses[0].1 {
+            ChangesetStatus::Ran(instance) => assert!(instance.is_accepted()),
+            _ => panic!("hooks should have run on {}", first),
+        }
+        assert!(statuses[1].1.is_failed());
+        match &statuses[2].1 {
+            ChangesetStatus::Filtered => {}
+            _ => panic!("{} should have been filtered out", excluded),
+        }
+        Ok(())
+    }
+
+    #[test]
+    fn test_is_accepted() -> Result<(), Error> {
+        let cs_id = ChangesetId::from_bytes([1; 32])?;
+        let instance = |outcomes| HookExecutionInstance {
+            cs_id,
+            file_count: 1,
+            stats: futures::executor::block_on(future::ready(()).timed()).0,
+            outcomes,
+            skipped_too_large: false,
+        };
+        let changeset_hook = |execution| {
+            HookOutcome::ChangesetHook(
+                ChangesetHookExecutionID {
+                    cs_id,
+                    hook_name: "changeset_hook".to_string(),
+                },
+                execution,
+            )
+        };
+        let file_hook = |execution| -> Result<_, Error> {
+            Ok(HookOutcome::FileHook(
+                FileHookExecutionID {
+                    cs_id,
+                    hook_name: "file_hook".to_string(),
+                    path: MPath::new("file")?,
+                },
+                execution,
+            ))
+        };
+
+        let none = instance(vec![]);
+        assert!(none.is_accepted());
+        assert!(none.rejections().is_empty());
+
+        let all_pass = instance(vec![
+            changeset_hook(HookExecution::Accepted),
+            file_hook(HookExecution::Accepted)?,
+        ]);
+        assert!(all_pass.is_accepted());
+        assert!(all_pass.rejections().is_empty());
+
+        let one_rejected = instance(vec![
+            changeset_hook(HookExecution::Accepted),
+            file_hook(HookExecution::Rejected(HookRejectionInfo::new("bad file")))?,
+        ]);
+        assert!(!one_rejected.is_accepted());
+        let rejections = one_rejected.rejections();
+        assert_eq!(rejections.len(), 1);
+        assert_eq!(rejections[0].get_hook_name(), "file_hook");
+        Ok(())
+    }
+}
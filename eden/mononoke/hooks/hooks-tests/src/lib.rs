@@ -18,7 +18,7 @@ use futures::future;
 use futures::stream::{futures_unordered, TryStreamExt};
 use hooks::{
     hook_loader::load_hooks, ChangesetHook, ErrorKind, FileHook, HookExecution, HookManager,
-    HookRejectionInfo,
+    HookRejectionInfo, HookTimeouts,
 };
 use hooks_content_stores::{
     BlobRepoFileContentFetcher, FileContentFetcher, InMemoryFileContentFetcher,
@@ -31,6 +31,7 @@ use regex::Regex;
 use scuba_ext::ScubaSampleBuilder;
 use std::collections::hash_map::Entry;
 use std::collections::{BTreeMap, HashMap, HashSet};
+use std::time::Duration;
 use tests_utils::{create_commit, store_files};
 
 #[derive(Clone, Debug)]
@@ -67,6 +68,29 @@ fn always_rejecting_changeset_hook() -> Box<dyn ChangesetHook> {
     Box::new(FnChangesetHook::new(f))
 }
 
+#[derive(Clone, Debug)]
+struct SleepingChangesetHook {
+    duration: Duration,
+}
+
+#[async_trait]
+impl ChangesetHook for SleepingChangesetHook {
+    async fn run<'this: 'cs, 'ctx: 'this, 'cs, 'fetcher: 'cs>(
+        &'this self,
+        _ctx: &'ctx CoreContext,
+        _bookmark: &BookmarkName,
+        _changeset: &'cs BonsaiChangeset,
+        _content_fetcher: &'fetcher dyn FileContentFetcher,
+    ) -> Result<HookExecution, Error> {
+        tokio::time::delay_for(self.duration).await;
+        Ok(HookExecution::Accepted)
+    }
+}
+
+fn sleeping_changeset_hook(duration: Duration) -> Box<dyn ChangesetHook> {
+    Box::new(SleepingChangesetHook { duration })
+}
+
 #[derive(Clone, Debug)]
 struct FileContentMatchingChangesetHook {
     expected_content: HashMap<MPath, Option<String>>,
@@ -418,6 +442,69 @@ fn test_changeset_hook_mix(fb: FacebookInit) {
     });
 }
 
+#[fbinit::test]
+fn test_changeset_hook_timeouts(fb: FacebookInit) {
+    async_unit::tokio_unit_test(async move {
+        let ctx = CoreContext::test_mock(fb);
+        let bookmarks = hashmap! {
+            "bm1".to_string() => vec!["fast".to_string(), "slow".to_string()]
+        };
+        let mut hook_manager =
+            setup_hook_manager(ctx.fb, bookmarks, hashmap! {}, ContentFetcherType::InMemory).await;
+        hook_manager.register_changeset_hook(
+            "fast",
+            sleeping_changeset_hook(Duration::from_millis(10)),
+            Default::default(),
+        );
+        hook_manager.register_changeset_hook(
+            "slow",
+            sleeping_changeset_hook(Duration::from_millis(500)),
+            Default::default(),
+        );
+        let bookmark = BookmarkName::new("bm1").unwrap();
+
+        // The slow hook is given a generous timeout, so only the default applies to the fast one
+        hook_manager.set_hook_timeouts(HookTimeouts {
+            default: Some(Duration::from_millis(200)),
+            per_hook: hashmap! {
+                "slow".to_string() => Duration::from_secs(10),
+            },
+        });
+        let res = hook_manager
+            .run_hooks_for_bookmark(&ctx, vec![default_changeset()].iter(), &bookmark, None)
+            .await
+            .unwrap();
+        let map: HashMap<String, HookExecution> = res
+            .into_iter()
+            .map(|outcome| (outcome.get_hook_name().to_string(), outcome.into()))
+            .collect();
+        let expected = hashmap! {
+            "fast".to_string() => HookExecution::Accepted,
+            "slow".to_string() => HookExecution::Accepted,
+        };
+        assert_eq!(expected, map);
+
+        // Tightening the slow hook's timeout makes it fail without affecting the fast one
+        hook_manager.set_hook_timeouts(HookTimeouts {
+            default: Some(Duration::from_secs(10)),
+            per_hook: hashmap! {
+                "slow".to_string() => Duration::from_millis(50),
+            },
+        });
+        let err = hook_manager
+            .run_hooks_for_bookmark(&ctx, vec![default_changeset()].iter(), &bookmark, None)
+            .await
+            .expect_err("slow hook should have timed out");
+        match err.downcast_ref::<ErrorKind>() {
+            Some(ErrorKind::HookTimeout(hook_name, timeout)) => {
+                assert_eq!(hook_name, "slow");
+                assert_eq!(*timeout, Duration::from_millis(50));
+            }
+            _ => panic!("unexpected error: {:?}", err),
+        }
+    });
+}
+
 #[fbinit::test]
 fn test_changeset_hook_file_text(fb: FacebookInit) {
     async_unit::tokio_unit_test(async move {
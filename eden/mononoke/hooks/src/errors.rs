@@ -6,6 +6,7 @@
  */
 
 use std::collections::HashSet;
+use std::time::Duration;
 use thiserror::Error;
 
 pub use mercurial_types::HgChangesetId;
@@ -38,4 +39,7 @@ pub enum ErrorKind {
 
     #[error("Disabled hook(s) do(es) not exist: {0:?}")]
     NoSuchHookToDisable(HashSet<String>),
+
+    #[error("Hook '{0}' timed out after {1:?}")]
+    HookTimeout(String, Duration),
 }
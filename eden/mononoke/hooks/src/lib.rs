@@ -45,6 +45,8 @@ use std::collections::HashMap;
 use std::fmt;
 use std::hash::Hash;
 use std::str;
+use std::time::Duration;
+use tokio::time::timeout;
 
 /// Manages hooks and allows them to be installed and uninstalled given a name
 /// Knows how to run hooks
@@ -56,6 +58,22 @@ pub struct HookManager {
     content_fetcher: Box<dyn FileContentFetcher>,
     reviewers_membership: ArcMembershipChecker,
     scuba: ScubaSampleBuilder,
+    timeouts: HookTimeouts,
+}
+
+/// Timeouts for individual hook executions, i.e. a changeset hook on one changeset or a
+/// file hook on one file.
+#[derive(Clone, Debug, Default)]
+pub struct HookTimeouts {
+    /// Timeout for hooks that are not listed in `per_hook`
+    pub default: Option<Duration>,
+    pub per_hook: HashMap<String, Duration>,
+}
+
+impl HookTimeouts {
+    pub fn for_hook(&self, hook_name: &str) -> Option<Duration> {
+        self.per_hook.get(hook_name).cloned().or(self.default)
+    }
 }
 
 impl HookManager {
@@ -88,6 +106,7 @@ impl HookManager {
             content_fetcher,
             reviewers_membership: reviewers_membership.into(),
             scuba,
+            timeouts: HookTimeouts::default(),
         })
     }
 
@@ -122,6 +141,10 @@ impl HookManager {
         }
     }
 
+    pub fn set_hook_timeouts(&mut self, timeouts: HookTimeouts) {
+        self.timeouts = timeouts;
+    }
+
     pub(crate) fn get_reviewers_perm_checker(&self) -> ArcMembershipChecker {
         self.reviewers_membership.clone()
     }
@@ -185,16 +208,30 @@ impl HookManager {
             let mut scuba = scuba.clone();
             scuba.add("hook", hook_name.to_string());
 
+            let hook_timeout = self.timeouts.for_hook(hook_name);
             for future in
                 hook.get_futures(ctx, bookmark, &*self.content_fetcher, hook_name, cs, scuba)
             {
-                futs.push(future);
+                futs.push(run_with_timeout(future, hook_name, hook_timeout));
             }
         }
         futs.try_collect().await
     }
 }
 
+async fn run_with_timeout(
+    future: impl Future<Output = Result<HookOutcome, Error>>,
+    hook_name: &str,
+    hook_timeout: Option<Duration>,
+) -> Result<HookOutcome, Error> {
+    match hook_timeout {
+        Some(hook_timeout) => timeout(hook_timeout, future)
+            .await
+            .map_err(|_| ErrorKind::HookTimeout(hook_name.to_string(), hook_timeout))?,
+        None => future.await,
+    }
+}
+
 fn is_hook_bypassed(
     bypass: Option<&HookBypass>,
     cs_msg: &str,
@@ -10,9 +10,13 @@ use permission_checker::MononokeIdentity;
 use serde::de::{Deserializer, Error};
 use serde::ser::Serializer;
 use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
 use std::convert::TryFrom;
 use std::default::Default;
 use std::str::FromStr;
+use std::time::Duration;
+
+use crate::middleware::LfsMethod;
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
 pub struct RawLimit {
@@ -37,6 +41,24 @@ pub struct RawServerConfig {
     /// time window) will not be consistently-routed. This ensures the full pool of servers can be
     /// used to serve very popular blobs.
     pub object_popularity_threshold: Option<u64>,
+    /// How long to wait for the client's hostname to resolve before running post-request
+    /// callbacks without it. Unbounded if not set.
+    pub hostname_resolution_timeout_ms: Option<u64>,
+    /// Requests that sent or received more than this many bytes are always logged, even if
+    /// they would otherwise not have been (e.g. because they come from a test identity).
+    pub always_log_bytes_threshold: Option<u64>,
+    /// How long requests may take, by method (e.g. "upload" or "batch"). Methods that aren't
+    /// listed have no deadline.
+    pub method_timeouts_ms: Option<HashMap<String, u64>>,
+    /// Requests from clients presenting any of these identities (e.g. health checkers) are
+    /// not logged.
+    pub no_log_client_identities: Option<Vec<String>>,
+    /// Requests taking longer than this are logged as slow, even if they would otherwise
+    /// not have been logged.
+    pub slow_request_threshold_ms: Option<u64>,
+    /// Like `slow_request_threshold_ms`, by method (e.g. "upload" or "batch"). Takes
+    /// precedence over `slow_request_threshold_ms` for the methods that are listed.
+    pub slow_request_thresholds_ms: Option<HashMap<String, u64>>,
 }
 
 #[derive(Debug, Clone)]
@@ -66,6 +88,7 @@ impl TryFrom<&RawLimit> for Limit {
 pub struct ServerConfig {
     pub raw_server_config: RawServerConfig,
     throttle_limits: Vec<Limit>,
+    no_log_client_identities: Vec<MononokeIdentity>,
 }
 
 impl<'de> Deserialize<'de> for ServerConfig {
@@ -85,9 +108,18 @@ impl<'de> Deserialize<'de> for ServerConfig {
             Ok(v) => v,
         };
 
+        let no_log_client_identities = raw_server_config
+            .no_log_client_identities
+            .iter()
+            .flatten()
+            .map(|x| FromStr::from_str(&x))
+            .collect::<Result<Vec<_>, _>>()
+            .map_err(|e: anyhow::Error| D::Error::custom(e.to_string()))?;
+
         Ok(Self {
             raw_server_config,
             throttle_limits,
+            no_log_client_identities,
         })
     }
 }
@@ -111,6 +143,12 @@ impl Default for RawServerConfig {
             enforce_acl_check: false,
             object_popularity_category: None,
             object_popularity_threshold: None,
+            hostname_resolution_timeout_ms: None,
+            always_log_bytes_threshold: None,
+            method_timeouts_ms: None,
+            no_log_client_identities: None,
+            slow_request_threshold_ms: None,
+            slow_request_thresholds_ms: None,
         }
     }
 }
@@ -119,6 +157,7 @@ impl Default for ServerConfig {
         Self {
             raw_server_config: RawServerConfig::default(),
             throttle_limits: vec![],
+            no_log_client_identities: vec![],
         }
     }
 }
@@ -145,6 +184,37 @@ impl ServerConfig {
     pub fn object_popularity_threshold(&self) -> Option<u64> {
         self.raw_server_config.object_popularity_threshold
     }
+    pub fn hostname_resolution_timeout(&self) -> Option<Duration> {
+        self.raw_server_config
+            .hostname_resolution_timeout_ms
+            .map(Duration::from_millis)
+    }
+    pub fn always_log_bytes_threshold(&self) -> Option<u64> {
+        self.raw_server_config.always_log_bytes_threshold
+    }
+    pub fn no_log_client_identities(&self) -> &[MononokeIdentity] {
+        &self.no_log_client_identities
+    }
+    pub fn method_timeout(&self, method: LfsMethod) -> Option<Duration> {
+        self.raw_server_config
+            .method_timeouts_ms
+            .as_ref()?
+            .get(&method.to_string())
+            .copied()
+            .map(Duration::from_millis)
+    }
+    pub fn slow_request_threshold(&self, method: Option<LfsMethod>) -> Option<Duration> {
+        let method_threshold = method.and_then(|method| {
+            self.raw_server_config
+                .slow_request_thresholds_ms
+                .as_ref()?
+                .get(&method.to_string())
+                .copied()
+        });
+        method_threshold
+            .or(self.raw_server_config.slow_request_threshold_ms)
+            .map(Duration::from_millis)
+    }
 }
 
 impl Limit {
@@ -16,6 +16,7 @@ use gotham::state::State;
 use gotham_derive::{StateData, StaticResponseExtender};
 use serde::Deserialize;
 
+use blobstore::{Loadable, LoadableError};
 use filestore::{self, Alias, FetchKey};
 use gotham_ext::{error::HttpError, response::TryIntoResponse};
 use mononoke_types::{hash::Sha256, ContentId};
@@ -25,7 +26,7 @@ use stats::prelude::*;
 use crate::errors::ErrorKind;
 use crate::http::LfsStreamBody;
 use crate::lfs_server_context::RepositoryRequestContext;
-use crate::middleware::LfsMethod;
+use crate::middleware::{LfsMethod, RequestContext};
 
 define_stats! {
     prefix = "mononoke.lfs.download";
@@ -117,7 +118,27 @@ pub async fn download_sha256(state: &mut State) -> Result<impl TryIntoResponse,
         RepositoryRequestContext::instantiate(state, repository.clone(), LfsMethod::DownloadSha256)
             .await?;
 
-    fetch_by_key(ctx, key).await
+    // Translate the sha256 to a content id ourselves (instead of letting the Filestore do it) so
+    // that we can record whether the alias was found.
+    let content_id = match key.load(ctx.ctx.clone(), &ctx.repo.get_blobstore()).await {
+        Ok(content_id) => {
+            state
+                .borrow_mut::<RequestContext>()
+                .set_sha256_alias_hit(true);
+            content_id
+        }
+        Err(LoadableError::Missing(_)) => {
+            state
+                .borrow_mut::<RequestContext>()
+                .set_sha256_alias_hit(false);
+            return Err(HttpError::e404(ErrorKind::ObjectDoesNotExist(key)));
+        }
+        Err(LoadableError::Error(e)) => {
+            return Err(HttpError::e500(e.context(ErrorKind::FilestoreReadFailure)));
+        }
+    };
+
+    fetch_by_key(ctx, FetchKey::Canonical(content_id)).await
 }
 
 #[cfg(test)]
@@ -125,12 +146,94 @@ mod test {
     use super::*;
 
     use anyhow::Error;
+    use blobrepo::BlobRepo;
     use blobrepo_factory::TestRepoBuilder;
+    use bytes::Bytes;
+    use cached_config::ConfigHandle;
+    use context::CoreContext;
     use fbinit::FacebookInit;
+    use filestore::StoreRequest;
+    use futures::compat::Future01CompatExt;
+    use futures_old::stream as stream_old;
     use http::StatusCode;
+    use hyper::{Body, Request};
     use maplit::hashmap;
+    use metaconfig_types::RepoConfig;
     use mononoke_types::typed_hash::MononokeId;
     use mononoke_types_mocks::contentid::ONES_CTID;
+    use mononoke_types_mocks::hash::ONES_SHA256;
+    use permission_checker::PermissionCheckerBuilder;
+    use slog::{o, Discard, Logger};
+    use std::sync::{atomic::AtomicBool, Arc};
+
+    use crate::config::ServerConfig;
+    use crate::lfs_server_context::{LfsServerContext, ServerUris};
+    use crate::middleware::{Middleware, RequestContextMiddleware};
+
+    /// Download `oid` from `repo` like the server would, returning the response status and
+    /// whether the request context recorded an alias hit.
+    async fn download_sha256_from(
+        fb: FacebookInit,
+        repo: BlobRepo,
+        oid: Sha256,
+    ) -> Result<(Result<(), StatusCode>, Option<bool>), Error> {
+        let config_handle = ConfigHandle::<ServerConfig>::default();
+        let mut state = State::from_request(Request::new(Body::empty()), "127.0.0.1:0".parse()?);
+        RequestContextMiddleware::new(fb, Logger::root(Discard, o!()), config_handle.clone())
+            .inbound(&mut state)
+            .await;
+        state.put(LfsServerContext::new(
+            hashmap! {
+                "repo".to_string() => (
+                    repo,
+                    Arc::from(PermissionCheckerBuilder::always_allow()),
+                    RepoConfig::default(),
+                ),
+            },
+            ServerUris::new("http://foo.com/", None)?,
+            false,
+            None,
+            Arc::new(AtomicBool::new(false)),
+            config_handle,
+        )?);
+        state.put(DownloadParamsSha256 {
+            repository: "repo".to_string(),
+            oid: oid.to_string(),
+        });
+
+        let res = download_sha256(&mut state)
+            .await
+            .map(|_| ())
+            .map_err(|err| err.status_code);
+        Ok((res, state.borrow::<RequestContext>().sha256_alias_hit))
+    }
+
+    #[fbinit::compat_test]
+    async fn test_download_sha256_alias_hit(fb: FacebookInit) -> Result<(), Error> {
+        let repo = TestRepoBuilder::new().build()?;
+        let meta = filestore::store(
+            repo.blobstore().clone(),
+            repo.filestore_config(),
+            CoreContext::test_mock(fb),
+            &StoreRequest::new(6),
+            stream_old::once(Ok(Bytes::from("foobar"))),
+        )
+        .compat()
+        .await?;
+
+        // The content was stored with its sha256 alias, so the download finds it
+        assert_eq!(
+            download_sha256_from(fb, repo.clone(), meta.sha256).await?,
+            (Ok(()), Some(true))
+        );
+
+        // Nothing was stored under this sha256, so the alias lookup misses
+        assert_eq!(
+            download_sha256_from(fb, repo, ONES_SHA256).await?,
+            (Err(StatusCode::NOT_FOUND), Some(false))
+        );
+        Ok(())
+    }
 
     #[fbinit::compat_test]
     async fn test_redacted_fetch(fb: FacebookInit) -> Result<(), Error> {
@@ -81,6 +81,10 @@ fn log_request_slog(logger: &Logger, state: &mut State, entry: LogEntry) -> Opti
 
     let ctx = state.try_borrow_mut::<RequestContext>()?;
     let logger = logger.new(o!("request_id" => request_id));
+    let logger = match &ctx.content_category {
+        Some(content_category) => logger.new(o!("content_category" => content_category.clone())),
+        None => logger,
+    };
 
     match entry {
         LogEntry::RequestIn => {
@@ -22,6 +22,8 @@ define_stats! {
     upload_duration: dynamic_histogram("{}.upload_ms", (repo: String); 100, 0, 5000, Average, Sum, Count; P 5; P 25; P 50; P 75; P 95; P 97; P 99),
     download_duration: dynamic_histogram("{}.download_ms", (repo: String); 100, 0, 5000, Average, Sum, Count; P 5; P 25; P 50; P 75; P 95; P 97; P 99),
     download_sha256_duration: dynamic_histogram("{}.download_sha256_ms", (repo: String); 100, 0, 5000, Average, Sum, Count; P 5; P 25; P 50; P 75; P 95; P 97; P 99),
+    sha256_alias_hits: dynamic_timeseries("{}.sha256_alias_hits", (repo: String); Rate, Sum),
+    sha256_alias_misses: dynamic_timeseries("{}.sha256_alias_misses", (repo: String); Rate, Sum),
     batch_duration: dynamic_histogram("{}.batch_ms", (repo: String); 10, 0, 500, Average, Sum, Count; P 5; P 25; P 50; P 75; P 95; P 97; P 99),
     response_bytes_sent: dynamic_histogram("{}.response_bytes_sent", (repo_and_method: String); 1_500_000, 0, 150_000_000, Average, Sum, Count; P 5; P 25; P 50; P 75; P 95; P 97; P 99),
 }
@@ -31,12 +33,21 @@ fn log_stats(state: &mut State, status: StatusCode) -> Option<()> {
     let method = ctx.method?;
     let repo = ctx.repository.clone()?;
     let repo_and_method = format!("{}.{}", &repo, method.to_string());
+    let sha256_alias_hit = ctx.sha256_alias_hit;
 
-    if !ctx.should_log {
-        return None;
+    // Alias lookups are counted for every request, not just the logged ones
+    if let Some(sha256_alias_hit) = sha256_alias_hit {
+        let repo = repo.clone();
+        ctx.add_post_request(move |_, _, _, _| {
+            if sha256_alias_hit {
+                STATS::sha256_alias_hits.add_value(1, (repo,));
+            } else {
+                STATS::sha256_alias_misses.add_value(1, (repo,));
+            }
+        });
     }
 
-    ctx.add_post_request(move |duration, _, response_bytes_sent, _| {
+    ctx.add_logged_post_request(move |duration, _, response_bytes_sent, _| {
         match method {
             LfsMethod::Upload => {
                 STATS::upload_duration.add_value(duration.as_millis_unchecked() as i64, (repo,))
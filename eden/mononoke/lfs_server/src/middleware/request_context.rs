@@ -19,11 +19,13 @@ use gotham_ext::{
     response::ResponseContentLength,
 };
 use hyper::{body::Body, Response};
+use permission_checker::MononokeIdentitySet;
 use scuba::ScubaSampleBuilder;
-use slog::{o, Logger};
+use slog::{o, warn, Logger};
 use std::fmt;
+use std::sync::Arc;
 use std::time::{Duration, Instant};
-use tokio::task;
+use tokio::{task, time::timeout};
 
 use crate::config::ServerConfig;
 
@@ -55,33 +57,68 @@ pub struct RequestContext {
     pub ctx: CoreContext,
     pub repository: Option<String>,
     pub method: Option<LfsMethod>,
+    /// What kind of content this request was for (e.g. media or source archives), if the
+    /// handler could tell. This lets traffic be sliced further than by method.
+    pub content_category: Option<String>,
+    /// For `LfsMethod::DownloadSha256`, whether the sha256 was found in the alias index,
+    /// i.e. could be translated to a content id. This measures how effective the index is.
+    pub sha256_alias_hit: Option<bool>,
+    /// How many bytes of request body the handler received, e.g. for an upload.
+    pub bytes_received: Option<u64>,
     pub error_msg: Option<String>,
     pub headers_duration: Option<Duration>,
     pub should_log: bool,
+    /// When this request should be done by, according to the timeout configured for its
+    /// method. Only known once `set_request` was called.
+    pub deadline: Option<Instant>,
 
+    config: Arc<ServerConfig>,
     checkpoint: Option<Receiver<u64>>,
     start_time: Instant,
     post_request_callbacks: Vec<PostRequestCallback>,
+    logged_post_request_callbacks: Vec<PostRequestCallback>,
 }
 
 impl RequestContext {
-    fn new(ctx: CoreContext, should_log: bool) -> Self {
+    fn new(ctx: CoreContext, should_log: bool, config: Arc<ServerConfig>) -> Self {
         Self {
             ctx,
             repository: None,
             method: None,
+            content_category: None,
+            sha256_alias_hit: None,
+            bytes_received: None,
             error_msg: None,
             headers_duration: None,
             should_log,
+            deadline: None,
+            config,
             start_time: Instant::now(),
             checkpoint: None,
             post_request_callbacks: vec![],
+            logged_post_request_callbacks: vec![],
         }
     }
 
     pub fn set_request(&mut self, repository: String, method: LfsMethod) {
         self.repository = Some(repository);
         self.method = Some(method);
+        self.deadline = self
+            .config
+            .method_timeout(method)
+            .map(|timeout| self.start_time + timeout);
+    }
+
+    pub fn set_content_category(&mut self, content_category: String) {
+        self.content_category = Some(content_category);
+    }
+
+    pub fn set_sha256_alias_hit(&mut self, sha256_alias_hit: bool) {
+        self.sha256_alias_hit = Some(sha256_alias_hit);
+    }
+
+    pub fn set_bytes_received(&mut self, bytes_received: u64) {
+        self.bytes_received = Some(bytes_received);
     }
 
     pub fn set_error_msg(&mut self, error_msg: String) {
@@ -99,6 +136,16 @@ impl RequestContext {
         self.post_request_callbacks.push(Box::new(callback));
     }
 
+    /// Like `add_post_request`, but the callback only runs if this request should be logged.
+    /// This is decided once the number of bytes sent is known, since large transfers in
+    /// either direction are logged even if `should_log` is false.
+    pub fn add_logged_post_request<T>(&mut self, callback: T)
+    where
+        T: FnOnce(&Duration, &Option<String>, Option<u64>, &PerfCounters) + Sync + Send + 'static,
+    {
+        self.logged_post_request_callbacks.push(Box::new(callback));
+    }
+
     pub fn start_time(&self) -> Instant {
         self.start_time
     }
@@ -111,14 +158,25 @@ impl RequestContext {
         sender
     }
 
-    fn dispatch_post_request<H>(self, content_length: Option<u64>, client_hostname: H)
-    where
+    fn dispatch_post_request<H>(
+        self,
+        content_length: Option<u64>,
+        client_hostname: H,
+        hostname_timeout: Option<Duration>,
+        always_log_bytes_threshold: Option<u64>,
+    ) where
         H: Future<Output = Option<String>> + Send + 'static,
     {
         let Self {
             ctx,
+            repository,
+            method,
+            bytes_received,
+            config,
+            should_log,
             start_time,
             post_request_callbacks,
+            logged_post_request_callbacks,
             checkpoint,
             ..
         } = self;
@@ -135,10 +193,46 @@ impl RequestContext {
             // Capture elapsed time before waiting for the client hostname to resolve.
             let elapsed = start_time.elapsed();
 
-            // Resolve client hostname. Querying DNS might be slow.
-            let client_hostname = client_hostname.await;
+            // Slow requests are always logged, so that they can be investigated
+            if let Some(threshold) = config.slow_request_threshold(method) {
+                if elapsed > threshold {
+                    warn!(
+                        ctx.logger(),
+                        "Slow request: repository {}, method {}, {} bytes sent in {:?}",
+                        repository.as_deref().unwrap_or("-"),
+                        method.map_or_else(|| "-".to_string(), |method| method.to_string()),
+                        bytes_sent.map_or_else(|| "-".to_string(), |bytes| bytes.to_string()),
+                        elapsed,
+                    );
+                }
+            }
+
+            // Resolve client hostname. Querying DNS might be slow, so give up after
+            // hostname_timeout rather than holding up the callbacks.
+            let client_hostname = match hostname_timeout {
+                Some(hostname_timeout) => timeout(hostname_timeout, client_hostname)
+                    .await
+                    .unwrap_or(None),
+                None => client_hostname.await,
+            };
 
-            for callback in post_request_callbacks.into_iter() {
+            let is_large = match always_log_bytes_threshold {
+                Some(threshold) => bytes_sent
+                    .into_iter()
+                    .chain(bytes_received)
+                    .any(|bytes| bytes > threshold),
+                None => false,
+            };
+            let logged_post_request_callbacks = if should_log || is_large {
+                logged_post_request_callbacks
+            } else {
+                vec![]
+            };
+
+            for callback in post_request_callbacks
+                .into_iter()
+                .chain(logged_post_request_callbacks)
+            {
                 callback(&elapsed, &client_hostname, bytes_sent, ctx.perf_counters())
             }
         };
@@ -147,6 +241,17 @@ impl RequestContext {
     }
 }
 
+/// Whether the client presents any of the identities that are configured not to be logged.
+fn is_no_log_client(config: &ServerConfig, identities: &Option<MononokeIdentitySet>) -> bool {
+    match identities {
+        Some(identities) => config
+            .no_log_client_identities()
+            .iter()
+            .any(|identity| identities.contains(identity)),
+        None => false,
+    }
+}
+
 #[derive(Clone)]
 pub struct RequestContextMiddleware {
     fb: FacebookInit,
@@ -177,11 +282,15 @@ impl Middleware for RequestContextMiddleware {
         let session = SessionContainer::new_with_defaults(self.fb);
         let ctx = session.new_context(logger, ScubaSampleBuilder::with_discard());
 
+        let config = self.config_handle.get();
         let should_log = ClientIdentity::try_borrow_from(&state)
-            .map(|client_identity| !client_identity.is_proxygen_test_identity())
+            .map(|client_identity| {
+                !client_identity.is_proxygen_test_identity()
+                    && !is_no_log_client(&config, client_identity.identities())
+            })
             .unwrap_or(true);
 
-        state.put(RequestContext::new(ctx, should_log));
+        state.put(RequestContext::new(ctx, should_log, config));
 
         None
     }
@@ -197,7 +306,209 @@ impl Middleware for RequestContextMiddleware {
         };
 
         if let Some(ctx) = state.try_take::<RequestContext>() {
-            ctx.dispatch_post_request(content_length, client_hostname);
+            ctx.dispatch_post_request(
+                content_length,
+                client_hostname,
+                config.hostname_resolution_timeout(),
+                config.always_log_bytes_threshold(),
+            );
         }
     }
 }
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    use anyhow::Error;
+    use hyper::Request;
+    use maplit::hashmap;
+    use permission_checker::MononokeIdentity;
+    use slog::{Drain, Never, OwnedKVList, Record};
+    use std::sync::Mutex;
+    use tempdir::TempDir;
+
+    use crate::config::RawServerConfig;
+    use crate::middleware::ScubaMiddleware;
+
+    #[fbinit::compat_test]
+    async fn test_hostname_timeout(fb: FacebookInit) -> Result<(), Error> {
+        let mut ctx = RequestContext::new(CoreContext::test_mock(fb), true, Default::default());
+        let (sender, receiver) = oneshot::channel();
+        ctx.add_post_request(move |_, client_hostname, _, _| {
+            let _ = sender.send(client_hostname.clone());
+        });
+
+        ctx.dispatch_post_request(
+            None,
+            future::pending(),
+            Some(Duration::from_millis(10)),
+            None,
+        );
+
+        assert_eq!(receiver.await?, None);
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn test_always_log_large_requests(fb: FacebookInit) -> Result<(), Error> {
+        let dispatch = |bytes_sent, bytes_received| {
+            let mut ctx =
+                RequestContext::new(CoreContext::test_mock(fb), false, Default::default());
+            if let Some(bytes_received) = bytes_received {
+                ctx.set_bytes_received(bytes_received);
+            }
+            let (sender, receiver) = oneshot::channel();
+            ctx.add_logged_post_request(move |_, _, bytes_sent, _| {
+                let _ = sender.send(bytes_sent);
+            });
+            ctx.dispatch_post_request(Some(bytes_sent), future::ready(None), None, Some(1000));
+            receiver
+        };
+
+        // Small requests are not logged, so the callback is dropped without running
+        assert!(dispatch(10, None).await.is_err());
+        assert!(dispatch(0, Some(10)).await.is_err());
+        // Large ones are logged regardless of should_log
+        assert_eq!(dispatch(2000, None).await?, Some(2000));
+        // Whichever direction the bytes went in
+        assert_eq!(dispatch(0, Some(2000)).await?, Some(0));
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn test_method_deadline(fb: FacebookInit) -> Result<(), Error> {
+        let mut config = ServerConfig::default();
+        config.raw_server_config.method_timeouts_ms = Some(hashmap! {
+            "upload".to_string() => 60_000,
+            "batch".to_string() => 100,
+        });
+        let config = Arc::new(config);
+
+        for (method, timeout) in vec![
+            (LfsMethod::Upload, Some(Duration::from_millis(60_000))),
+            (LfsMethod::Batch, Some(Duration::from_millis(100))),
+            (LfsMethod::Download, None),
+        ] {
+            let mut ctx = RequestContext::new(CoreContext::test_mock(fb), true, config.clone());
+            assert_eq!(ctx.deadline, None);
+            ctx.set_request("repo".to_string(), method);
+            assert_eq!(
+                ctx.deadline.map(|deadline| deadline - ctx.start_time()),
+                timeout
+            );
+        }
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn test_content_category(fb: FacebookInit) -> Result<(), Error> {
+        let dir = TempDir::new("lfs_server_content_category")?;
+        let log_file = dir.path().join("scuba.json");
+        let scuba =
+            ScubaMiddleware::new(ScubaSampleBuilder::with_discard().with_log_file(&log_file)?);
+        let mut state = State::from_request(Request::new(Body::empty()), "127.0.0.1:0".parse()?);
+        scuba.inbound(&mut state).await;
+
+        let mut ctx = RequestContext::new(CoreContext::test_mock(fb), true, Default::default());
+        assert_eq!(ctx.content_category, None);
+        ctx.set_request("repo".to_string(), LfsMethod::Download);
+        ctx.set_content_category("media".to_string());
+        state.put(ctx);
+
+        // The scuba middleware logs its sample once the request is done
+        scuba
+            .outbound(&mut state, &mut Response::new(Body::empty()))
+            .await;
+        let mut ctx = state.take::<RequestContext>();
+        let (sender, receiver) = oneshot::channel();
+        ctx.add_post_request(move |_, _, _, _| {
+            let _ = sender.send(());
+        });
+        ctx.dispatch_post_request(None, future::ready(None), None, None);
+        receiver.await?;
+
+        let sample: serde_json::Value = serde_json::from_str(&std::fs::read_to_string(&log_file)?)?;
+        assert_eq!(sample["normal"]["content_category"], "media");
+        Ok(())
+    }
+
+    #[derive(Clone, Default)]
+    struct CapturingDrain(Arc<Mutex<Vec<String>>>);
+
+    impl Drain for CapturingDrain {
+        type Ok = ();
+        type Err = Never;
+
+        fn log(&self, record: &Record, _values: &OwnedKVList) -> Result<(), Never> {
+            self.0.lock().unwrap().push(format!("{}", record.msg()));
+            Ok(())
+        }
+    }
+
+    #[fbinit::compat_test]
+    async fn test_slow_request_log(fb: FacebookInit) -> Result<(), Error> {
+        let mut config = ServerConfig::default();
+        config.raw_server_config.slow_request_threshold_ms = Some(60_000);
+        config.raw_server_config.slow_request_thresholds_ms = Some(hashmap! {
+            "upload".to_string() => 10,
+        });
+        let config = Arc::new(config);
+
+        let dispatch = |method| {
+            let drain = CapturingDrain::default();
+            let logger = Logger::root(drain.clone().fuse(), o!());
+            let mut ctx = RequestContext::new(
+                CoreContext::new_with_logger(fb, logger),
+                // Not sampled, but slow requests are logged regardless
+                false,
+                config.clone(),
+            );
+            ctx.set_request("repo".to_string(), method);
+            let (sender, receiver) = oneshot::channel();
+            ctx.add_post_request(move |_, _, _, _| {
+                let _ = sender.send(());
+            });
+            async move {
+                // A deliberately slow request
+                tokio::time::delay_for(Duration::from_millis(50)).await;
+                ctx.dispatch_post_request(Some(123), future::ready(None), None, None);
+                receiver.await?;
+                let messages = drain.0.lock().unwrap().clone();
+                Result::<_, Error>::Ok(messages)
+            }
+        };
+
+        let messages = dispatch(LfsMethod::Upload).await?;
+        assert_eq!(messages.len(), 1);
+        assert!(messages[0].starts_with("Slow request: repository repo, method upload, 123 bytes"));
+
+        // Downloads only count as slow after a minute
+        assert!(dispatch(LfsMethod::Download).await?.is_empty());
+        Ok(())
+    }
+
+    #[test]
+    fn test_no_log_client_identities() -> Result<(), Error> {
+        let raw_config = RawServerConfig {
+            no_log_client_identities: Some(vec!["SERVICE_IDENTITY:health_checker".to_string()]),
+            ..Default::default()
+        };
+        let config: ServerConfig = serde_json::from_value(serde_json::to_value(raw_config)?)?;
+
+        let health_checker: MononokeIdentitySet = vec![
+            MononokeIdentity::new("MACHINE", "host.example.com")?,
+            MononokeIdentity::new("SERVICE_IDENTITY", "health_checker")?,
+        ]
+        .into_iter()
+        .collect();
+        assert!(is_no_log_client(&config, &Some(health_checker)));
+
+        let other: MononokeIdentitySet = vec![MononokeIdentity::new("USER", "health_checker")?]
+            .into_iter()
+            .collect();
+        assert!(!is_no_log_client(&config, &Some(other)));
+        assert!(!is_no_log_client(&config, &None));
+        Ok(())
+    }
+}
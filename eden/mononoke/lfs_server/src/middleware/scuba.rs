@@ -53,6 +53,8 @@ pub enum ScubaKey {
     Repository,
     /// The method this request matched for in our handlers.
     Method,
+    /// The kind of content this request was for, if known.
+    ContentCategory,
     /// If an error was encountered during processing, the error message.
     ErrorMessage,
     /// How long it took to send headers.
@@ -65,6 +67,8 @@ pub enum ScubaKey {
     ResponseBytesSent,
     /// How many bytes were received from the client (should normally equal the content length)
     RequestBytesReceived,
+    /// Whether the sha256 of a download by sha256 was found in the alias index
+    Sha256AliasHit,
     /// The order in which the response to a batch request was produced.
     BatchOrder,
     /// The number of objects in a batch request
@@ -100,6 +104,8 @@ impl AsRef<str> for ScubaKey {
             RequestId => "request_id",
             Repository => "repository",
             Method => "method",
+            ContentCategory => "content_category",
+            Sha256AliasHit => "sha256_alias_hit",
             ErrorMessage => "error_msg",
             HeadersDurationMs => "headers_duration_ms",
             DurationMs => "duration_ms",
@@ -238,6 +244,14 @@ fn log_stats(
         scuba.add(ScubaKey::Method, method.to_string());
     }
 
+    if let Some(content_category) = &ctx.content_category {
+        scuba.add(ScubaKey::ContentCategory, content_category.as_ref());
+    }
+
+    if let Some(sha256_alias_hit) = ctx.sha256_alias_hit {
+        scuba.add(ScubaKey::Sha256AliasHit, sha256_alias_hit);
+    }
+
     if let Some(err_msg) = &ctx.error_msg {
         scuba.add(ScubaKey::ErrorMessage, err_msg.as_ref());
     }
@@ -36,7 +36,7 @@ use mononoke_types::hash::Sha256;
 
 use crate::errors::ErrorKind;
 use crate::lfs_server_context::RepositoryRequestContext;
-use crate::middleware::{LfsMethod, ScubaKey, ScubaMiddlewareState};
+use crate::middleware::{LfsMethod, RequestContext, ScubaKey, ScubaMiddlewareState};
 
 define_stats! {
     prefix ="mononoke.lfs.upload";
@@ -237,13 +237,14 @@ where
     Ok(())
 }
 
+/// Upload `body` both internally and upstream, returning how many bytes were received.
 async fn upload_from_client<S>(
     ctx: &RepositoryRequestContext,
     oid: Sha256,
     size: u64,
     body: S,
     scuba: &mut Option<&mut ScubaMiddlewareState>,
-) -> Result<(), Error>
+) -> Result<u64, Error>
 where
     S: Stream<Item = Result<Bytes, ()>> + Unpin + Send + 'static,
 {
@@ -299,7 +300,7 @@ where
 
     ScubaMiddlewareState::maybe_add(scuba, ScubaKey::RequestBytesReceived, received);
 
-    res.map(|_| ())
+    res.map(|_| received as u64)
 }
 
 async fn sync_internal_and_upstream(
@@ -423,9 +424,13 @@ pub async fn upload(state: &mut State) -> Result<impl TryIntoResponse, HttpError
             // TODO: More appropriate status codes here
             let body = Body::take_from(state).map_err(|_| ());
             let mut scuba = state.try_borrow_mut::<ScubaMiddlewareState>();
-            upload_from_client(&ctx, oid, size, body, &mut scuba)
+            let received = upload_from_client(&ctx, oid, size, body, &mut scuba)
                 .await
                 .map_err(HttpError::e500)?;
+            // Large uploads are always logged, like large downloads
+            state
+                .borrow_mut::<RequestContext>()
+                .set_bytes_received(received);
         }
     }
 
@@ -449,7 +454,10 @@ mod test {
             Sha256::from_str("c3ab8ff13720e8ad9047dd39466b3c8974e592c2fa383d4a3960714caef0c4f2")?;
         let size = 6;
 
-        upload_from_client(&ctx, oid, size, body, &mut None).await?;
+        assert_eq!(
+            upload_from_client(&ctx, oid, size, body, &mut None).await?,
+            size
+        );
 
         Ok(())
     }
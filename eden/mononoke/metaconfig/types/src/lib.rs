@@ -30,7 +30,7 @@ use bookmarks_types::BookmarkName;
 use mononoke_types::{MPath, RepositoryId};
 use regex::Regex;
 use scuba::ScubaValue;
-use serde_derive::Deserialize;
+use serde_derive::{Deserialize, Serialize};
 use sql::mysql_async::{
     from_value_opt,
     prelude::{ConvIr, FromValue},
@@ -523,7 +523,8 @@ pub struct LfsParams {
 }
 
 /// Id used to discriminate diffirent underlying blobstore instances
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd, Deserialize)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd)]
+#[derive(Deserialize, Serialize)]
 pub struct BlobstoreId(u64);
 
 impl BlobstoreId {
@@ -531,6 +532,11 @@ impl BlobstoreId {
     pub fn new(id: u64) -> Self {
         BlobstoreId(id)
     }
+
+    /// The integer the blobstore was constructed from
+    pub fn id(&self) -> u64 {
+        self.0
+    }
 }
 
 impl fmt::Display for BlobstoreId {
@@ -568,7 +574,7 @@ impl From<BlobstoreId> for ScubaValue {
 }
 
 /// Id used to identify storage configuration for a multiplexed blobstore.
-#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd)]
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash, Ord, PartialOrd, Serialize)]
 pub struct MultiplexId(i32);
 
 impl MultiplexId {
@@ -606,6 +612,12 @@ impl FromValue for MultiplexId {
     type Intermediate = MultiplexId;
 }
 
+impl From<MultiplexId> for ScubaValue {
+    fn from(multiplex_id: MultiplexId) -> Self {
+        ScubaValue::from(multiplex_id.0 as i64)
+    }
+}
+
 /// Define storage needed for repo.
 /// Storage consists of a blobstore and some kind of SQL DB for metadata. The configurations
 /// can be broadly classified as "local" and "remote". "Local" is primarily for testing, and is
@@ -9,30 +9,45 @@
 use anyhow::{format_err, Error};
 use blobrepo::{save_bonsai_changesets, BlobRepo};
 use blobrepo_hg::BlobRepoHg;
+use blobrepo_override::DangerousOverride;
+use blobstore::{Blobstore, Loadable};
+use bonsai_hg_mapping::BonsaiHgMapping;
 use bookmarks::{BookmarkName, BookmarkUpdateReason};
-use clap::Arg;
+use bytes::Bytes;
+use cacheblob::{dummy::DummyLease, LeaseOps, MemWritesBlobstore};
+use changesets::Changesets;
+use clap::{App, Arg, ArgMatches};
 use cmdlib::args;
 use cmdlib::helpers::block_execute;
 use context::CoreContext;
 use cross_repo_sync::rewrite_commit;
 use derived_data_utils::derived_data_utils;
 use fbinit::FacebookInit;
+use filestore::StoreRequest;
 use futures::{
     compat::Future01CompatExt,
     future::TryFutureExt,
     stream::{self, StreamExt, TryStreamExt},
 };
-use import_tools::{GitimportPreferences, GitimportTarget};
+use futures_old::stream as old_stream;
+use import_tools::{
+    GitimportPreferences, GitimportTarget, MemWritesBonsaiHgMapping, MemWritesChangesets,
+};
+use manifest::ManifestOps;
 use mercurial_types::{HgChangesetId, MPath};
-use mononoke_types::{BonsaiChangeset, ChangesetId};
-use movers::DefaultAction;
+use metaconfig_types::{DerivedDataConfig, UnodeVersion};
+use mononoke_types::{BonsaiChangeset, BonsaiChangesetMut, ChangesetId, FileChange, FileType};
+use movers::{DefaultAction, Mover};
 use serde::{Deserialize, Serialize};
 use serde_json;
-use slog::info;
-use std::collections::HashMap;
+use slog::{info, warn};
+use std::collections::{HashMap, HashSet};
+use std::future::Future;
 use std::num::NonZeroUsize;
 use std::path::Path;
-use tokio::{process, time};
+use std::str::FromStr;
+use std::sync::Arc;
+use tokio::{fs, process, time};
 use topo_sort::sort_topological;
 
 const ARG_GIT_REPOSITORY_PATH: &str = "git-repository-path";
@@ -44,6 +59,26 @@ const ARG_PHAB_CHECK_DISABLED: &str = "disable-phabricator-check";
 const ARG_X_REPO_CHECK_DISABLED: &str = "disable-x-repo-check";
 const ARG_HG_SYNC_CHECK_DISABLED: &str = "disable-hg-sync-check";
 const ARG_SLEEP_TIME: &str = "sleep-time";
+const ARG_MAPPING_OUTPUT: &str = "mapping-output";
+const ARG_BOOKMARK_RETRIES: &str = "bookmark-retries";
+const ARG_NORMALIZE_EOL: &str = "normalize-eol";
+const ARG_REJECT_NON_UTF8_PATHS: &str = "reject-non-utf8-paths";
+const ARG_REQUIRE_EMPTY_DEST: &str = "require-empty-dest";
+const ARG_PROVENANCE_EXTRA: &str = "provenance-extra";
+const ARG_STEPS_PER_TRANSACTION: &str = "steps-per-transaction";
+const ARG_DERIVE_CONCURRENCY: &str = "derive-concurrency";
+const ARG_IDEMPOTENT: &str = "idempotent";
+const ARG_VERIFY: &str = "verify";
+const ARG_VERIFY_BACKEND: &str = "verify-backend";
+const ARG_STRICT_REWRITE: &str = "strict-rewrite";
+const ARG_EXPECT_DROPPED: &str = "expect-dropped";
+const ARG_GRAFT_ONTO: &str = "graft-onto";
+const ARG_CHECK_TIMEOUT: &str = "check-timeout";
+const ARG_CHECK_RETRIES: &str = "check-retries";
+const ARG_PROCEED_ON_CHECK_TIMEOUT: &str = "proceed-on-check-timeout";
+const ARG_CHECKPOINT_PATH: &str = "checkpoint-path";
+const ARG_DRY_RUN: &str = "dry-run";
+const CHECKPOINT_VERSION: u32 = 1;
 
 #[derive(Deserialize, Clone, Debug)]
 struct GraphqlQueryObj {
@@ -65,12 +100,48 @@ struct GraphqlImportedObj {
 struct GraphqlInputVariables {
     commit: String,
 }
+#[derive(Debug, Serialize)]
+struct ImportedCommit {
+    git_commit: String,
+    bonsai_changeset: String,
+    hg_changeset: String,
+}
+/// Progress of `move_bookmark`, saved so that an interrupted import can be resumed.
+#[derive(Debug, Serialize, Deserialize)]
+struct Checkpoint {
+    version: u32,
+    /// The changeset the bookmark was last moved to
+    last_csid: String,
+    /// Index of `last_csid` in the changesets the bookmark was moved over. With `--idempotent`
+    /// these don't include what a previous run imported, so this is only a hint.
+    index: usize,
+}
 #[derive(Debug)]
 struct CheckerFlags<'a> {
     phab_check_disabled: bool,
     x_repo_check_disabled: bool,
     hg_sync_check_disabled: bool,
     call_sign: Option<&'a str>,
+    timeout_policy: CheckTimeoutPolicy,
+}
+
+/// What `move_bookmark` does when a dependent system check doesn't pass in time.
+#[derive(Clone, Copy, Debug, Default)]
+struct CheckTimeoutPolicy {
+    /// How long to wait for a check to pass, or indefinitely if None
+    timeout: Option<time::Duration>,
+    /// How often to retry a check that timed out. The wait before each retry starts at
+    /// `backoff` and doubles every time.
+    retries: usize,
+    backoff: time::Duration,
+    /// Log a warning and carry on if the check still times out after the last retry,
+    /// instead of failing the import
+    proceed_on_timeout: bool,
+}
+#[derive(Clone, Copy, Debug, Default)]
+struct NormalizationOptions {
+    normalize_eol: bool,
+    reject_non_utf8_paths: bool,
 }
 
 async fn rewrite_file_paths(
@@ -78,19 +149,59 @@ async fn rewrite_file_paths(
     repo: &BlobRepo,
     path: &Path,
     prefix: &str,
-) -> Result<Vec<BonsaiChangeset>, Error> {
+    normalization: &NormalizationOptions,
+    provenance_extras: &[(String, String)],
+    strict_rewrite: Option<&HashSet<String>>,
+    graft_onto: Option<ChangesetId>,
+) -> Result<(Vec<BonsaiChangeset>, HashMap<ChangesetId, String>), Error> {
     let prefs = GitimportPreferences::default();
     let target = GitimportTarget::FullRepo;
     let import_map = import_tools::gitimport(ctx, repo, path, target, prefs).await?;
-    let mut remapped_parents: HashMap<ChangesetId, ChangesetId> = HashMap::new();
     let mover = movers::mover_factory(
         HashMap::new(),
         DefaultAction::PrependPrefix(MPath::new(prefix).unwrap()),
     )?;
+    let changesets = import_map
+        .into_iter()
+        .map(|(id, (bcs_id, bcs))| (id.to_string(), bcs_id, bcs))
+        .collect();
+    rewrite_changesets(
+        ctx,
+        repo,
+        changesets,
+        mover,
+        normalization,
+        provenance_extras,
+        strict_rewrite,
+        graft_onto,
+    )
+    .await
+}
+
+/// Rewrite `changesets` with `mover`, parents first, returning the rewritten changesets and
+/// the source commit of each of them. Changesets that rewrite to nothing (e.g. because the
+/// mover filters out all of their file changes) are dropped. With `strict_rewrite`, that is
+/// an error instead, unless the source commit is in the given set of expected drops.
+/// With `graft_onto`, the root changesets of the import get that changeset as their parent,
+/// so the imported history continues from it instead of starting a new root.
+async fn rewrite_changesets(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    changesets: Vec<(String, ChangesetId, BonsaiChangeset)>,
+    mover: Mover,
+    normalization: &NormalizationOptions,
+    provenance_extras: &[(String, String)],
+    strict_rewrite: Option<&HashSet<String>>,
+    graft_onto: Option<ChangesetId>,
+) -> Result<(Vec<BonsaiChangeset>, HashMap<ChangesetId, String>), Error> {
+    let mut remapped_parents: HashMap<ChangesetId, ChangesetId> = HashMap::new();
     let mut bonsai_changesets = vec![];
+    let mut git_commits = HashMap::new();
 
-    for (_id, (bcs_id, bcs)) in import_map {
-        let bcs_mut = bcs.into_mut();
+    for (id, bcs_id, bcs) in changesets {
+        let is_root = bcs.parents().next().is_none();
+        let mut bcs_mut = bcs.into_mut();
+        normalize_file_changes(ctx, repo, &mut bcs_mut, normalization).await?;
         let rewritten_bcs_opt = rewrite_commit(
             ctx.clone(),
             bcs_mut,
@@ -100,32 +211,249 @@ async fn rewrite_file_paths(
         )
         .await?;
 
-        if let Some(rewritten_bcs_mut) = rewritten_bcs_opt {
-            let rewritten_bcs = rewritten_bcs_mut.freeze()?;
-            remapped_parents.insert(bcs_id, rewritten_bcs.get_changeset_id());
+        match rewritten_bcs_opt {
+            Some(mut rewritten_bcs_mut) => {
+                // The graft is added after rewriting rather than through `remapped_parents`:
+                // it is already in the destination layout, so its files must not be taken
+                // into account when computing implicit deletes for the source commit.
+                if let (true, Some(graft_onto)) = (is_root, graft_onto) {
+                    rewritten_bcs_mut.parents.push(graft_onto);
+                }
+                add_provenance_extras(&mut rewritten_bcs_mut, provenance_extras);
+                let rewritten_bcs = rewritten_bcs_mut.freeze()?;
+                remapped_parents.insert(bcs_id, rewritten_bcs.get_changeset_id());
+                git_commits.insert(rewritten_bcs.get_changeset_id(), id);
+                info!(
+                    ctx.logger(),
+                    "Remapped {:?} => {:?}",
+                    bcs_id,
+                    rewritten_bcs.get_changeset_id(),
+                );
+                bonsai_changesets.push(rewritten_bcs);
+            }
+            None => match strict_rewrite {
+                Some(expected_dropped) if !expected_dropped.contains(&id) => {
+                    return Err(format_err!(
+                        "Changeset {} (from {}) was dropped by the rewrite. Check the mover, or pass --{} {} if this is expected",
+                        bcs_id,
+                        id,
+                        ARG_EXPECT_DROPPED,
+                        id,
+                    ));
+                }
+                _ => {}
+            },
+        }
+    }
+    Ok((bonsai_changesets, git_commits))
+}
+
+/// Import the git repo at `path` into `repo`, rewrite it into `prefix` and sort the result
+/// parents first. With `idempotent`, changesets that a previous run already imported are
+/// left out.
+async fn changesets_to_import(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    path: &Path,
+    prefix: &str,
+    normalization: &NormalizationOptions,
+    provenance_extras: &[(String, String)],
+    strict_rewrite: Option<&HashSet<String>>,
+    graft_onto: Option<ChangesetId>,
+    idempotent: bool,
+    bookmark_suffix: &str,
+) -> Result<(Vec<BonsaiChangeset>, HashMap<ChangesetId, String>), Error> {
+    let (shifted_bcs, git_commits) = rewrite_file_paths(
+        ctx,
+        repo,
+        path,
+        prefix,
+        normalization,
+        provenance_extras,
+        strict_rewrite,
+        graft_onto,
+    )
+    .await?;
+    if shifted_bcs.is_empty() {
+        return Err(format_err!(
+            "No changesets to import from {}",
+            path.display()
+        ));
+    }
+    let mut shifted_bcs = sort_bcs(&shifted_bcs)?;
+    if idempotent {
+        shifted_bcs = skip_imported(ctx, repo, shifted_bcs, bookmark_suffix).await?;
+    }
+    Ok((shifted_bcs, git_commits))
+}
+
+/// Drop the changesets that a previous run of the import already made visible, i.e. the
+/// sorted changesets up to the one its bookmark points to. Rewriting is deterministic, so
+/// those have the same ids again. Changesets that were only saved are kept, so that they
+/// are derived and the bookmark is moved over them.
+async fn skip_imported(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    mut shifted_bcs: Vec<BonsaiChangeset>,
+    bookmark_suffix: &str,
+) -> Result<Vec<BonsaiChangeset>, Error> {
+    let bookmark = BookmarkName::new(format!("repo_import_{}", bookmark_suffix))?;
+    let bookmark_csid = match repo
+        .get_bonsai_bookmark(ctx.clone(), &bookmark)
+        .compat()
+        .await?
+    {
+        Some(bookmark_csid) => bookmark_csid,
+        None => return Ok(shifted_bcs),
+    };
+    match shifted_bcs
+        .iter()
+        .position(|bcs| bcs.get_changeset_id() == bookmark_csid)
+    {
+        Some(index) => {
             info!(
                 ctx.logger(),
-                "Remapped {:?} => {:?}",
-                bcs_id,
-                rewritten_bcs.get_changeset_id(),
+                "Skipping {} changesets already imported up to {:?}",
+                index + 1,
+                bookmark_csid
             );
-            bonsai_changesets.push(rewritten_bcs);
+            Ok(shifted_bcs.split_off(index + 1))
         }
+        None => Err(format_err!(
+            "Bookmark {:?} points to {}, which is not part of this import",
+            bookmark,
+            bookmark_csid
+        )),
+    }
+}
+
+/// Mark an imported commit with where it came from, so that later tooling can identify it.
+fn add_provenance_extras(bcs_mut: &mut BonsaiChangesetMut, provenance_extras: &[(String, String)]) {
+    for (key, value) in provenance_extras {
+        bcs_mut
+            .extra
+            .insert(key.clone(), value.clone().into_bytes());
+    }
+}
+
+fn parse_provenance_extra(arg: &str) -> Result<(String, String), Error> {
+    match arg.find('=') {
+        Some(idx) if idx > 0 => Ok((arg[..idx].to_string(), arg[idx + 1..].to_string())),
+        _ => Err(format_err!(
+            "Invalid provenance extra {:?}, expected key=value",
+            arg
+        )),
+    }
+}
+
+/// Fail if `prefix` already has content in the working copy of `bookmark`, so that an import
+/// doesn't unexpectedly get merged with (or clobber) existing files.
+async fn check_dest_empty(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    bookmark: &BookmarkName,
+    prefix: &str,
+) -> Result<(), Error> {
+    let csid = repo
+        .get_bonsai_bookmark(ctx.clone(), bookmark)
+        .compat()
+        .await?
+        .ok_or_else(|| format_err!("Bookmark {:?} does not exist", bookmark))?;
+    let hg_csid = repo
+        .get_hg_from_bonsai_changeset(ctx.clone(), csid)
+        .compat()
+        .await?;
+    let hg_cs = hg_csid
+        .load(ctx.clone(), &repo.get_blobstore())
+        .compat()
+        .await?;
+    let entry = hg_cs
+        .manifestid()
+        .find_entry(ctx.clone(), repo.get_blobstore(), Some(MPath::new(prefix)?))
+        .compat()
+        .await?;
+    if entry.is_some() {
+        return Err(format_err!(
+            "Destination path {} already has content in {:?}",
+            prefix,
+            bookmark
+        ));
     }
-    save_bonsai_changesets(bonsai_changesets.clone(), ctx.clone(), repo.clone())
+    Ok(())
+}
+
+/// Apply the requested normalizations to the file changes of an imported commit:
+/// convert CRLF line endings of text files to LF, and/or fail on paths that are not UTF-8.
+async fn normalize_file_changes(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    bcs_mut: &mut BonsaiChangesetMut,
+    normalization: &NormalizationOptions,
+) -> Result<(), Error> {
+    for (path, file_change) in bcs_mut.file_changes.iter_mut() {
+        if normalization.reject_non_utf8_paths && std::str::from_utf8(&path.to_vec()).is_err() {
+            return Err(format_err!("Path {} is not valid UTF-8", path));
+        }
+
+        let file_change = match file_change {
+            Some(file_change) if normalization.normalize_eol => file_change,
+            _ => continue,
+        };
+        if file_change.file_type() == FileType::Symlink {
+            continue;
+        }
+        let content =
+            filestore::fetch_concat(&repo.get_blobstore(), ctx.clone(), file_change.content_id())
+                .compat()
+                .await?;
+        // Leave binary files alone, like git's text detection does
+        if content.contains(&0) || !content.windows(2).any(|w| w == b"\r\n") {
+            continue;
+        }
+
+        let normalized = Bytes::from(crlf_to_lf(&content));
+        let meta = filestore::store(
+            repo.get_blobstore(),
+            repo.filestore_config(),
+            ctx.clone(),
+            &StoreRequest::new(normalized.len() as u64),
+            old_stream::once(Ok(normalized)),
+        )
         .compat()
         .await?;
-    Ok(bonsai_changesets)
+        info!(ctx.logger(), "Normalized line endings of {}", path);
+        *file_change = FileChange::new(
+            meta.content_id,
+            file_change.file_type(),
+            meta.total_size,
+            file_change.copy_from().cloned(),
+        );
+    }
+    Ok(())
+}
+
+fn crlf_to_lf(content: &[u8]) -> Vec<u8> {
+    let mut normalized = Vec::with_capacity(content.len());
+    let mut iter = content.iter().peekable();
+    while let Some(&byte) = iter.next() {
+        if byte == b'\r' && iter.peek() == Some(&&b'\n') {
+            continue;
+        }
+        normalized.push(byte);
+    }
+    normalized
 }
 
 async fn derive_bonsais(
     ctx: &CoreContext,
     repo: &BlobRepo,
     shifted_bcs: &[BonsaiChangeset],
+    concurrency: Option<usize>,
 ) -> Result<(), Error> {
     let derived_data_types = &repo.get_derived_data_config().derived_data_types;
 
-    let len = derived_data_types.len();
+    // By default, derive all types at once
+    let concurrency = concurrency.unwrap_or_else(|| derived_data_types.len());
     let mut derived_utils = vec![];
     for ty in derived_data_types {
         let utils = derived_data_utils(repo.clone(), ty)?;
@@ -134,7 +462,7 @@ async fn derive_bonsais(
 
     stream::iter(derived_utils)
         .map(Ok)
-        .try_for_each_concurrent(len, |derived_util| async move {
+        .try_for_each_concurrent(concurrency, |derived_util| async move {
             for bcs in shifted_bcs {
                 let csid = bcs.get_changeset_id();
                 derived_util
@@ -148,6 +476,218 @@ async fn derive_bonsais(
         .await
 }
 
+async fn check_derived(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    csids: Vec<ChangesetId>,
+) -> Result<(), Error> {
+    for ty in &repo.get_derived_data_config().derived_data_types {
+        let utils = derived_data_utils(repo.clone(), ty)?;
+        let pending = utils
+            .pending(ctx.clone(), repo.clone(), csids.clone())
+            .compat()
+            .await?;
+        if !pending.is_empty() {
+            return Err(format_err!(
+                "{} is not derived for changesets {:?}",
+                utils.name(),
+                pending
+            ));
+        }
+    }
+    Ok(())
+}
+
+fn parse_verify_backend(arg: &str) -> Result<UnodeVersion, Error> {
+    match arg {
+        "unodes-v1" => Ok(UnodeVersion::V1),
+        "unodes-v2" => Ok(UnodeVersion::V2),
+        _ => Err(format_err!("Unknown verify backend: {}", arg)),
+    }
+}
+
+// Check that all the imported changesets have their derived data in place. If
+// `backend` is given, unodes are read from that version of the mapping rather
+// than the one configured for the repo.
+async fn verify_import(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    shifted_bcs: &[BonsaiChangeset],
+    backend: Option<UnodeVersion>,
+) -> Result<(), Error> {
+    let repo = match backend {
+        Some(unode_version) => {
+            repo.dangerous_override(|mut derived_data_config: DerivedDataConfig| {
+                derived_data_config.unode_version = unode_version;
+                derived_data_config
+            })
+        }
+        None => repo.clone(),
+    };
+    let csids = shifted_bcs
+        .iter()
+        .map(|bcs| bcs.get_changeset_id())
+        .collect::<Vec<_>>();
+    check_derived(ctx, &repo, csids).await?;
+    info!(
+        ctx.logger(),
+        "Verified derived data for {} imported changesets",
+        shifted_bcs.len()
+    );
+    Ok(())
+}
+
+async fn write_mapping_output(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    shifted_bcs: &[BonsaiChangeset],
+    git_commits: &HashMap<ChangesetId, String>,
+    path: &Path,
+) -> Result<(), Error> {
+    let mut mapping = vec![];
+    for bcs in shifted_bcs {
+        let csid = bcs.get_changeset_id();
+        let git_commit = git_commits
+            .get(&csid)
+            .ok_or_else(|| format_err!("No git commit found for changeset id {}", csid))?;
+        let hg_csid = repo
+            .get_hg_from_bonsai_changeset(ctx.clone(), csid)
+            .compat()
+            .await?;
+        mapping.push(ImportedCommit {
+            git_commit: git_commit.clone(),
+            bonsai_changeset: csid.to_string(),
+            hg_changeset: hg_csid.to_string(),
+        });
+    }
+    fs::write(path, serde_json::to_string_pretty(&mapping)?).await?;
+    info!(
+        ctx.logger(),
+        "Wrote mapping of {} commits to {}",
+        mapping.len(),
+        path.display()
+    );
+    Ok(())
+}
+
+/// Wrap `repo` so that everything written to it, e.g. the contents and changesets stored by
+/// the git import and the rewrite, is only kept in memory, for `--dry-run`.
+fn dry_run_repo(repo: &BlobRepo) -> BlobRepo {
+    repo.dangerous_override(|blobstore| -> Arc<dyn Blobstore> {
+        Arc::new(MemWritesBlobstore::new(blobstore))
+    })
+    .dangerous_override(|changesets| -> Arc<dyn Changesets> {
+        Arc::new(MemWritesChangesets::new(changesets))
+    })
+    .dangerous_override(|bonsai_hg_mapping| -> Arc<dyn BonsaiHgMapping> {
+        Arc::new(MemWritesBonsaiHgMapping::new(bonsai_hg_mapping))
+    })
+    .dangerous_override(|_| Arc::new(DummyLease {}) as Arc<dyn LeaseOps>)
+}
+
+/// Log what importing `shifted_bcs` would do: the changesets with their remapped parents, and
+/// where the bookmark would end up.
+fn log_dry_run(
+    ctx: &CoreContext,
+    shifted_bcs: &[BonsaiChangeset],
+    git_commits: &HashMap<ChangesetId, String>,
+    bookmark_suffix: &str,
+) -> Result<(), Error> {
+    let bookmark = BookmarkName::new(format!("repo_import_{}", bookmark_suffix))?;
+    let last_bcs = match shifted_bcs.last() {
+        Some(last_bcs) => last_bcs,
+        None => {
+            info!(ctx.logger(), "Dry run: nothing new to import");
+            return Ok(());
+        }
+    };
+    for bcs in shifted_bcs {
+        let csid = bcs.get_changeset_id();
+        let git_commit = git_commits.get(&csid).map_or("<unknown>", String::as_str);
+        for parent in bcs.parents() {
+            info!(
+                ctx.logger(),
+                "Dry run: {:?} (from {}) has parent {:?}", csid, git_commit, parent
+            );
+        }
+    }
+    info!(
+        ctx.logger(),
+        "Dry run: would import {} changesets and set bookmark {:?} to point to {:?}",
+        shifted_bcs.len(),
+        bookmark,
+        last_bcs.get_changeset_id()
+    );
+    Ok(())
+}
+
+/// Read the checkpoint at `path`, if there is one, and return the index into `shifted_bcs`
+/// to resume from. Fails if the checkpoint's changeset is not part of this import.
+async fn read_checkpoint(
+    ctx: &CoreContext,
+    path: &Path,
+    shifted_bcs: &[BonsaiChangeset],
+) -> Result<Option<usize>, Error> {
+    let content = match fs::read(path).await {
+        Ok(content) => content,
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => return Ok(None),
+        Err(err) => return Err(err.into()),
+    };
+    let checkpoint: Checkpoint = serde_json::from_slice(&content)?;
+    if checkpoint.version != CHECKPOINT_VERSION {
+        return Err(format_err!(
+            "Checkpoint {} has version {}, expected {}",
+            path.display(),
+            checkpoint.version,
+            CHECKPOINT_VERSION
+        ));
+    }
+    let last_csid = ChangesetId::from_str(&checkpoint.last_csid)?;
+    let index = match shifted_bcs.get(checkpoint.index) {
+        Some(bcs) if bcs.get_changeset_id() == last_csid => Some(checkpoint.index),
+        _ => shifted_bcs
+            .iter()
+            .position(|bcs| bcs.get_changeset_id() == last_csid),
+    };
+    let index = index.ok_or_else(|| {
+        format_err!(
+            "Checkpoint {} does not match this import: changeset {} is not part of it",
+            path.display(),
+            last_csid
+        )
+    })?;
+    info!(
+        ctx.logger(),
+        "Resuming from checkpoint at {} (changeset {} of {})",
+        last_csid,
+        index + 1,
+        shifted_bcs.len()
+    );
+    Ok(Some(index))
+}
+
+async fn write_checkpoint(path: &Path, last_csid: ChangesetId, index: usize) -> Result<(), Error> {
+    let checkpoint = Checkpoint {
+        version: CHECKPOINT_VERSION,
+        last_csid: last_csid.to_string(),
+        index,
+    };
+    // Write to a temporary file first, so a crash never leaves a truncated checkpoint behind
+    let tmp_path = path.with_extension("tmp");
+    fs::write(&tmp_path, serde_json::to_vec(&checkpoint)?).await?;
+    fs::rename(&tmp_path, path).await?;
+    Ok(())
+}
+
+async fn remove_checkpoint(path: &Path) -> Result<(), Error> {
+    match fs::remove_file(path).await {
+        Ok(()) => Ok(()),
+        // An idempotent re-run that finds nothing new never writes a checkpoint
+        Err(err) if err.kind() == std::io::ErrorKind::NotFound => Ok(()),
+        Err(err) => Err(err.into()),
+    }
+}
+
 async fn move_bookmark(
     ctx: &CoreContext,
     repo: &BlobRepo,
@@ -156,8 +696,17 @@ async fn move_bookmark(
     bookmark_suffix: &str,
     checker_flags: &CheckerFlags<'_>,
     sleep_time: u64,
+    bookmark_retries: usize,
+    steps_per_transaction: usize,
+    idempotent: bool,
+    checkpoint_path: Option<&Path>,
+    resume_index: Option<usize>,
 ) -> Result<(), Error> {
     if shifted_bcs.is_empty() {
+        if idempotent {
+            info!(ctx.logger(), "Nothing new to import");
+            return Ok(());
+        }
         return Err(format_err!("There is no bonsai changeset present"));
     }
 
@@ -168,62 +717,286 @@ async fn move_bookmark(
             return Err(format_err!("There is no bonsai changeset present"));
         }
     };
-    let mut old_csid = first_bcs.get_changeset_id();
-    let mut transaction = repo.update_bookmark_transaction(ctx.clone());
-    transaction.create(&bookmark, old_csid, BookmarkUpdateReason::ManualMove, None)?;
-    if !transaction.commit().await? {
-        return Err(format_err!("Logical failure while creating {:?}", bookmark));
-    }
-    info!(
-        ctx.logger(),
-        "Created bookmark {:?} pointing to {}", bookmark, old_csid
-    );
-    for chunk in shifted_bcs.chunks(batch_size) {
-        transaction = repo.update_bookmark_transaction(ctx.clone());
-        let curr_csid = match chunk.last() {
-            Some(bcs) => bcs.get_changeset_id(),
+    // When resuming from a checkpoint, the bookmark points to its changeset, or to a later one
+    // if the previous run died between moving the bookmark and writing the next checkpoint.
+    let resume_index = match resume_index {
+        Some(checkpoint_index) => {
+            let bookmark_csid = repo
+                .get_bonsai_bookmark(ctx.clone(), &bookmark)
+                .compat()
+                .await?;
+            let bookmark_index = bookmark_csid.and_then(|csid| {
+                shifted_bcs
+                    .iter()
+                    .position(|bcs| bcs.get_changeset_id() == csid)
+            });
+            match bookmark_index {
+                Some(bookmark_index) => Some(bookmark_index.max(checkpoint_index)),
+                None => {
+                    return Err(format_err!(
+                        "Cannot resume: bookmark {:?} points to {:?}, which is not part of this import",
+                        bookmark,
+                        bookmark_csid
+                    ));
+                }
+            }
+        }
+        None => None,
+    };
+    // When re-running, continue from the bookmark a previous run left behind
+    let existing_csid = match resume_index {
+        Some(index) => Some(shifted_bcs[index].get_changeset_id()),
+        None if idempotent => {
+            repo.get_bonsai_bookmark(ctx.clone(), &bookmark)
+                .compat()
+                .await?
+        }
+        None => None,
+    };
+    let remaining = match resume_index {
+        Some(index) => &shifted_bcs[index + 1..],
+        None => shifted_bcs,
+    };
+    let mut old_csid = match existing_csid {
+        Some(existing_csid) => {
+            info!(
+                ctx.logger(),
+                "Adopting bookmark {:?} pointing to {}", bookmark, existing_csid
+            );
+            existing_csid
+        }
+        None => {
+            let first_csid = first_bcs.get_changeset_id();
+            check_derived(ctx, repo, vec![first_csid]).await?;
+            set_bookmark(ctx, repo, &bookmark, first_csid, None, bookmark_retries).await?;
+            info!(
+                ctx.logger(),
+                "Created bookmark {:?} pointing to {}", bookmark, first_csid
+            );
+            if let Some(checkpoint_path) = checkpoint_path {
+                write_checkpoint(checkpoint_path, first_csid, 0).await?;
+            }
+            first_csid
+        }
+    };
+    // Without checks to wait for in between, the bookmark can be moved through several
+    // batches in a single transaction. Each batch still gets its own bookmark log entry.
+    let checks_disabled = checker_flags.phab_check_disabled
+        && checker_flags.x_repo_check_disabled
+        && checker_flags.hg_sync_check_disabled;
+    let steps_per_transaction = if checks_disabled {
+        steps_per_transaction
+    } else {
+        1
+    };
+    let mut index = shifted_bcs.len() - remaining.len();
+    for chunk in remaining.chunks(batch_size * steps_per_transaction) {
+        // Don't make changesets visible before their derived data is there
+        let chunk_csids = chunk.iter().map(|bcs| bcs.get_changeset_id()).collect();
+        check_derived(ctx, repo, chunk_csids).await?;
+
+        let steps: Vec<_> = chunk
+            .chunks(batch_size)
+            .filter_map(|batch| batch.last())
+            .map(|bcs| bcs.get_changeset_id())
+            .collect();
+        let curr_csid = match steps.last() {
+            Some(csid) => *csid,
             None => {
                 return Err(format_err!("There is no bonsai changeset present"));
             }
         };
-        transaction.update(
+        set_bookmark_steps(
+            ctx,
+            repo,
             &bookmark,
-            curr_csid,
-            old_csid,
-            BookmarkUpdateReason::ManualMove,
-            None,
-        )?;
-
-        if !transaction.commit().await? {
-            return Err(format_err!("Logical failure while setting {:?}", bookmark));
-        }
+            &steps,
+            Some(old_csid),
+            bookmark_retries,
+        )
+        .await?;
         info!(
             ctx.logger(),
             "Set bookmark {:?} to point to {:?}", bookmark, curr_csid
         );
+        // Checkpoint as soon as the bookmark moved, so a resume never tries to move it again
+        index += chunk.len();
+        if let Some(checkpoint_path) = checkpoint_path {
+            write_checkpoint(checkpoint_path, curr_csid, index - 1).await?;
+        }
 
         // if a check is disabled, we have already passed the check
-        let mut passed_phab_check = checker_flags.phab_check_disabled;
+        let passed_phab_check = checker_flags.phab_check_disabled;
         let mut _passed_x_repo_check = checker_flags.x_repo_check_disabled;
         let mut _passed_hg_sync_check = checker_flags.hg_sync_check_disabled;
         let hg_csid = repo
             .get_hg_from_bonsai_changeset(ctx.clone(), curr_csid)
             .compat()
             .await?;
-        while !passed_phab_check {
-            let call_sign = checker_flags.call_sign.as_ref().unwrap();
-            passed_phab_check = phabricator_commit_check(&call_sign, &hg_csid).await?;
-            if !passed_phab_check {
-                info!(
+        if !passed_phab_check {
+            let call_sign = checker_flags.call_sign.unwrap();
+            let hg_csid = &hg_csid;
+            wait_for_check(
+                ctx,
+                "Phabricator",
+                &checker_flags.timeout_policy,
+                sleep_time,
+                move || async move {
+                    let passed = phabricator_commit_check(call_sign, hg_csid).await?;
+                    if !passed {
+                        info!(
+                            ctx.logger(),
+                            "Phabricator hasn't parsed commit: {:?}", hg_csid
+                        );
+                    }
+                    Ok::<_, Error>(passed)
+                },
+            )
+            .await?;
+        }
+        old_csid = curr_csid;
+    }
+    Ok(())
+}
+
+/// Run `check` every `sleep_time` seconds until it passes, retrying or giving up according
+/// to `policy` if that takes longer than its timeout.
+async fn wait_for_check<F, Fut>(
+    ctx: &CoreContext,
+    name: &str,
+    policy: &CheckTimeoutPolicy,
+    sleep_time: u64,
+    mut check: F,
+) -> Result<(), Error>
+where
+    F: FnMut() -> Fut,
+    Fut: Future<Output = Result<bool, Error>>,
+{
+    let mut backoff = policy.backoff;
+    for attempt in 0..=policy.retries {
+        let poll = async {
+            while !check().await? {
+                time::delay_for(time::Duration::from_secs(sleep_time)).await;
+            }
+            Ok::<_, Error>(())
+        };
+        let timeout = match policy.timeout {
+            Some(timeout) => timeout,
+            None => return poll.await,
+        };
+        match time::timeout(timeout, poll).await {
+            Ok(res) => return res,
+            Err(_) if attempt < policy.retries => {
+                warn!(
                     ctx.logger(),
-                    "Phabricator hasn't parsed commit: {:?}", hg_csid
+                    "{} check timed out after {:?}, retrying in {:?}", name, timeout, backoff
                 );
-                time::delay_for(time::Duration::from_secs(sleep_time)).await;
+                time::delay_for(backoff).await;
+                backoff *= 2;
             }
+            Err(_) => {}
         }
-        old_csid = curr_csid;
     }
-    Ok(())
+    if policy.proceed_on_timeout {
+        warn!(
+            ctx.logger(),
+            "{} check timed out {} times, proceeding without it",
+            name,
+            policy.retries + 1
+        );
+        Ok(())
+    } else {
+        Err(format_err!(
+            "{} check timed out {} times",
+            name,
+            policy.retries + 1
+        ))
+    }
+}
+
+/// Move `bookmark` from `old_csid` to `new_csid`, or create it if `old_csid` is None.
+/// If the transaction fails logically, e.g. because the bookmark was moved concurrently,
+/// re-read its current value and retry, at most `retries` times.
+async fn set_bookmark(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    bookmark: &BookmarkName,
+    new_csid: ChangesetId,
+    old_csid: Option<ChangesetId>,
+    retries: usize,
+) -> Result<(), Error> {
+    set_bookmark_steps(ctx, repo, bookmark, &[new_csid], old_csid, retries).await
+}
+
+/// Like `set_bookmark`, but move `bookmark` through each of `steps` in a single transaction,
+/// logging each step. A bookmark that doesn't exist yet is created at the last step.
+async fn set_bookmark_steps(
+    ctx: &CoreContext,
+    repo: &BlobRepo,
+    bookmark: &BookmarkName,
+    steps: &[ChangesetId],
+    old_csid: Option<ChangesetId>,
+    retries: usize,
+) -> Result<(), Error> {
+    let new_csid = match steps.last() {
+        Some(new_csid) => *new_csid,
+        None => return Err(format_err!("No changesets to move {:?} to", bookmark)),
+    };
+    let action = if old_csid.is_some() {
+        "setting"
+    } else {
+        "creating"
+    };
+    let mut old_csid = old_csid;
+    for attempt in 0..=retries {
+        let mut transaction = repo.update_bookmark_transaction(ctx.clone());
+        match old_csid {
+            Some(old_csid) => transaction.update_steps(
+                bookmark,
+                steps,
+                old_csid,
+                BookmarkUpdateReason::ManualMove,
+            )?,
+            None => {
+                transaction.create(bookmark, new_csid, BookmarkUpdateReason::ManualMove, None)?
+            }
+        }
+        if transaction.commit().await? {
+            return Ok(());
+        }
+        if attempt < retries {
+            let current_csid = repo
+                .get_bonsai_bookmark(ctx.clone(), bookmark)
+                .compat()
+                .await?;
+            if old_csid.is_none() {
+                match current_csid {
+                    None => {}
+                    // The bookmark got created where we wanted it after all
+                    Some(current_csid) if current_csid == new_csid => return Ok(()),
+                    // Someone else created it, so don't move it away from under them
+                    Some(current_csid) => {
+                        return Err(format_err!(
+                            "Logical failure while {} {:?}: it already points to {}",
+                            action,
+                            bookmark,
+                            current_csid
+                        ));
+                    }
+                }
+            }
+            old_csid = current_csid;
+            warn!(
+                ctx.logger(),
+                "Logical failure while {} {:?}, retrying from {:?}", action, bookmark, old_csid
+            );
+        }
+    }
+    Err(format_err!(
+        "Logical failure while {} {:?}",
+        action,
+        bookmark
+    ))
 }
 
 async fn phabricator_commit_check(call_sign: &str, hg_csid: &HgChangesetId) -> Result<bool, Error> {
@@ -278,10 +1051,15 @@ fn is_valid_bookmark_suffix(bookmark_suffix: &str) -> bool {
 }
 
 fn sort_bcs(shifted_bcs: &[BonsaiChangeset]) -> Result<Vec<BonsaiChangeset>, Error> {
+    let ids: HashSet<_> = shifted_bcs
+        .iter()
+        .map(|bcs| bcs.get_changeset_id())
+        .collect();
     let mut bcs_parents = HashMap::new();
     let mut id_bcs = HashMap::new();
     for bcs in shifted_bcs {
-        let parents: Vec<_> = bcs.parents().collect();
+        // Parents outside of the import, e.g. the commit it is grafted onto, need no sorting
+        let parents: Vec<_> = bcs.parents().filter(|p| ids.contains(p)).collect();
         let bcs_id = bcs.get_changeset_id();
         bcs_parents.insert(bcs_id, parents);
         id_bcs.insert(bcs_id, bcs);
@@ -303,9 +1081,8 @@ fn sort_bcs(shifted_bcs: &[BonsaiChangeset]) -> Result<Vec<BonsaiChangeset>, Err
     Ok(sorted_bcs)
 }
 
-#[fbinit::main]
-fn main(fb: FacebookInit) -> Result<(), Error> {
-    let app = args::MononokeApp::new("Import Repository")
+fn setup_app<'a, 'b>() -> App<'a, 'b> {
+    args::MononokeApp::new("Import Repository")
         .with_advanced_args_hidden()
         .build()
         .version("0.0.0")
@@ -368,11 +1145,184 @@ fn main(fb: FacebookInit) -> Result<(), Error> {
                 .help(
                     "Sleep time, if we fail dependent system (phabricator, hg_sync ...) checkers",
                 ),
-        );
-
-    let matches = app.get_matches();
-
-    let path = Path::new(matches.value_of(ARG_GIT_REPOSITORY_PATH).unwrap());
+        )
+        .arg(
+            Arg::with_name(ARG_CHECK_TIMEOUT)
+                .long(ARG_CHECK_TIMEOUT)
+                .takes_value(true)
+                .value_name("SECONDS")
+                .help(
+                    "How long to wait for a dependent system check to pass. Waits indefinitely if not set",
+                ),
+        )
+        .arg(
+            Arg::with_name(ARG_CHECK_RETRIES)
+                .long(ARG_CHECK_RETRIES)
+                .takes_value(true)
+                .requires(ARG_CHECK_TIMEOUT)
+                .help(
+                    "How often to retry a check that timed out, backing off from the sleep time",
+                ),
+        )
+        .arg(
+            Arg::with_name(ARG_PROCEED_ON_CHECK_TIMEOUT)
+                .long(ARG_PROCEED_ON_CHECK_TIMEOUT)
+                .takes_value(false)
+                .requires(ARG_CHECK_TIMEOUT)
+                .help(
+                    "Log a warning and continue the import if a check still times out after all retries",
+                ),
+        )
+        .arg(
+            Arg::with_name(ARG_CHECKPOINT_PATH)
+                .long(ARG_CHECKPOINT_PATH)
+                .takes_value(true)
+                .help(
+                    "Record progress in this file after each bookmark move and, if it exists, resume from it. It is removed once the import is done. With --idempotent, the import resumes from the bookmark instead, which is at least as far as the checkpoint",
+                ),
+        )
+        .arg(
+            Arg::with_name(ARG_DRY_RUN)
+                .long(ARG_DRY_RUN)
+                .takes_value(false)
+                .conflicts_with_all(&[ARG_CHECKPOINT_PATH, ARG_MAPPING_OUTPUT, ARG_VERIFY])
+                .help(
+                    "Rewrite the git commits and log the result, without writing to the repo: everything the import stores is only kept in memory, and no data is derived and the bookmark isn't moved",
+                ),
+        )
+        .arg(
+            Arg::with_name(ARG_MAPPING_OUTPUT)
+                .long(ARG_MAPPING_OUTPUT)
+                .takes_value(true)
+                .help(
+                    "Write the git commit => bonsai => hg changeset mapping to this file as JSON",
+                ),
+        )
+        .arg(
+            Arg::with_name(ARG_BOOKMARK_RETRIES)
+                .long(ARG_BOOKMARK_RETRIES)
+                .takes_value(true)
+                .default_value("0")
+                .help("Number of times to retry moving the bookmark if it was moved concurrently"),
+        )
+        .arg(
+            Arg::with_name(ARG_NORMALIZE_EOL)
+                .long(ARG_NORMALIZE_EOL)
+                .help("Convert CRLF line endings to LF in text files"),
+        )
+        .arg(
+            Arg::with_name(ARG_REJECT_NON_UTF8_PATHS)
+                .long(ARG_REJECT_NON_UTF8_PATHS)
+                .help("Fail the import if any file path is not valid UTF-8"),
+        )
+        .arg(
+            Arg::with_name(ARG_REQUIRE_EMPTY_DEST)
+                .long(ARG_REQUIRE_EMPTY_DEST)
+                .takes_value(true)
+                .value_name("BOOKMARK")
+                .help("Abort if the destination path already has content in this bookmark"),
+        )
+        .arg(
+            Arg::with_name(ARG_PROVENANCE_EXTRA)
+                .long(ARG_PROVENANCE_EXTRA)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("KEY=VALUE")
+                .help("Add this extra to every imported commit, e.g. imported_source=<git-url>"),
+        )
+        .arg(
+            Arg::with_name(ARG_STEPS_PER_TRANSACTION)
+                .long(ARG_STEPS_PER_TRANSACTION)
+                .takes_value(true)
+                .default_value("1")
+                .help(
+                    "Number of batches to make visible with each bookmark move, if all checks are disabled",
+                ),
+        )
+        .arg(
+            Arg::with_name(ARG_DERIVE_CONCURRENCY)
+                .long(ARG_DERIVE_CONCURRENCY)
+                .takes_value(true)
+                .help(
+                    "Maximum number of derived data types to derive at once. Default: all configured types",
+                ),
+        )
+        .arg(
+            Arg::with_name(ARG_IDEMPOTENT)
+                .long(ARG_IDEMPOTENT)
+                .help(
+                    "Skip changesets that were already imported and continue from an existing bookmark, so that the import can be re-run",
+                ),
+        )
+        .arg(
+            Arg::with_name(ARG_VERIFY)
+                .long(ARG_VERIFY)
+                .help("Check that derived data exists for all imported changesets once the import is done"),
+        )
+        .arg(
+            Arg::with_name(ARG_VERIFY_BACKEND)
+                .long(ARG_VERIFY_BACKEND)
+                .takes_value(true)
+                .possible_values(&["unodes-v1", "unodes-v2"])
+                .requires(ARG_VERIFY)
+                .help(
+                    "Derived data backend to read from when verifying. Default: the one configured for the repo",
+                ),
+        )
+        .arg(
+            Arg::with_name(ARG_STRICT_REWRITE)
+                .long(ARG_STRICT_REWRITE)
+                .help(
+                    "Fail the import if rewriting drops a commit, e.g. because none of its file changes are moved into the destination",
+                ),
+        )
+        .arg(
+            Arg::with_name(ARG_EXPECT_DROPPED)
+                .long(ARG_EXPECT_DROPPED)
+                .takes_value(true)
+                .multiple(true)
+                .number_of_values(1)
+                .value_name("GIT_COMMIT")
+                .requires(ARG_STRICT_REWRITE)
+                .help("Git commit that --strict-rewrite allows to be dropped"),
+        )
+        .arg(
+            Arg::with_name(ARG_GRAFT_ONTO)
+                .long(ARG_GRAFT_ONTO)
+                .takes_value(true)
+                .value_name("CHANGESET_ID")
+                .help(
+                    "Bonsai changeset that the root commits of the import are grafted onto, instead of creating a new root",
+                ),
+        )
+}
+
+fn parse_check_timeout_policy(
+    matches: &ArgMatches<'_>,
+    sleep_time: u64,
+) -> Result<CheckTimeoutPolicy, Error> {
+    Ok(CheckTimeoutPolicy {
+        timeout: matches
+            .value_of(ARG_CHECK_TIMEOUT)
+            .map(|timeout| timeout.parse::<u64>())
+            .transpose()?
+            .map(time::Duration::from_secs),
+        // Not a default value, as clap would then consider it present and require the timeout
+        retries: matches
+            .value_of(ARG_CHECK_RETRIES)
+            .unwrap_or("0")
+            .parse::<usize>()?,
+        backoff: time::Duration::from_secs(sleep_time),
+        proceed_on_timeout: matches.is_present(ARG_PROCEED_ON_CHECK_TIMEOUT),
+    })
+}
+
+#[fbinit::main]
+fn main(fb: FacebookInit) -> Result<(), Error> {
+    let matches = setup_app().get_matches();
+
+    let path = Path::new(matches.value_of(ARG_GIT_REPOSITORY_PATH).unwrap());
     let prefix = matches.value_of(ARG_DEST_PATH).unwrap();
     let bookmark_suffix = matches.value_of(ARG_BOOKMARK_SUFFIX).unwrap();
     let batch_size = matches.value_of(ARG_BATCH_SIZE).unwrap();
@@ -391,14 +1341,64 @@ fn main(fb: FacebookInit) -> Result<(), Error> {
     if !phab_check_disabled && call_sign.is_none() {
         return Err(format_err!("Call sign was not specified"));
     }
+    let sleep_time = matches.value_of(ARG_SLEEP_TIME).unwrap();
+    let sleep_time = sleep_time.parse::<u64>()?;
+    let timeout_policy = parse_check_timeout_policy(&matches, sleep_time)?;
     let checker_flags = CheckerFlags {
         phab_check_disabled,
         x_repo_check_disabled,
         hg_sync_check_disabled,
         call_sign,
+        timeout_policy,
     };
-    let sleep_time = matches.value_of(ARG_SLEEP_TIME).unwrap();
-    let sleep_time = sleep_time.parse::<u64>()?;
+    let mapping_output = matches.value_of(ARG_MAPPING_OUTPUT).map(Path::new);
+    let checkpoint_path = matches.value_of(ARG_CHECKPOINT_PATH).map(Path::new);
+    let bookmark_retries = matches.value_of(ARG_BOOKMARK_RETRIES).unwrap();
+    let bookmark_retries = bookmark_retries.parse::<usize>()?;
+    let steps_per_transaction = matches.value_of(ARG_STEPS_PER_TRANSACTION).unwrap();
+    let steps_per_transaction = steps_per_transaction.parse::<NonZeroUsize>()?.get();
+    let derive_concurrency = matches
+        .value_of(ARG_DERIVE_CONCURRENCY)
+        .map(|concurrency| concurrency.parse::<NonZeroUsize>())
+        .transpose()?
+        .map(NonZeroUsize::get);
+    let idempotent = matches.is_present(ARG_IDEMPOTENT);
+    let dry_run = matches.is_present(ARG_DRY_RUN);
+    let verify = matches.is_present(ARG_VERIFY);
+    let verify_backend = matches
+        .value_of(ARG_VERIFY_BACKEND)
+        .map(parse_verify_backend)
+        .transpose()?;
+    let strict_rewrite = if matches.is_present(ARG_STRICT_REWRITE) {
+        Some(
+            matches
+                .values_of(ARG_EXPECT_DROPPED)
+                .into_iter()
+                .flatten()
+                .map(String::from)
+                .collect::<HashSet<_>>(),
+        )
+    } else {
+        None
+    };
+    let graft_onto = matches
+        .value_of(ARG_GRAFT_ONTO)
+        .map(ChangesetId::from_str)
+        .transpose()?;
+    let normalization = NormalizationOptions {
+        normalize_eol: matches.is_present(ARG_NORMALIZE_EOL),
+        reject_non_utf8_paths: matches.is_present(ARG_REJECT_NON_UTF8_PATHS),
+    };
+    let require_empty_dest = matches
+        .value_of(ARG_REQUIRE_EMPTY_DEST)
+        .map(BookmarkName::new)
+        .transpose()?;
+    let provenance_extras = matches
+        .values_of(ARG_PROVENANCE_EXTRA)
+        .into_iter()
+        .flatten()
+        .map(parse_provenance_extra)
+        .collect::<Result<Vec<_>, Error>>()?;
 
     args::init_cachelib(fb, &matches, None);
 
@@ -408,9 +1408,56 @@ fn main(fb: FacebookInit) -> Result<(), Error> {
     block_execute(
         async {
             let repo = repo.compat().await?;
-            let mut shifted_bcs = rewrite_file_paths(&ctx, &repo, &path, &prefix).await?;
-            shifted_bcs = sort_bcs(&shifted_bcs)?;
-            derive_bonsais(&ctx, &repo, &shifted_bcs).await?;
+            let repo = if dry_run { dry_run_repo(&repo) } else { repo };
+            if let Some(bookmark) = require_empty_dest {
+                check_dest_empty(&ctx, &repo, &bookmark, &prefix).await?;
+            }
+            if let Some(graft_onto) = graft_onto {
+                if !repo
+                    .changeset_exists_by_bonsai(ctx.clone(), graft_onto)
+                    .compat()
+                    .await?
+                {
+                    return Err(format_err!(
+                        "Changeset {} to graft onto does not exist",
+                        graft_onto
+                    ));
+                }
+            }
+            let (shifted_bcs, git_commits) = changesets_to_import(
+                &ctx,
+                &repo,
+                &path,
+                &prefix,
+                &normalization,
+                &provenance_extras,
+                strict_rewrite.as_ref(),
+                graft_onto,
+                idempotent,
+                &bookmark_suffix,
+            )
+            .await?;
+            if dry_run {
+                return log_dry_run(&ctx, &shifted_bcs, &git_commits, &bookmark_suffix);
+            }
+            // The checkpoint is only written once the bookmark moved, so with --idempotent
+            // `skip_imported` already left out everything up to it
+            let resume_index = match checkpoint_path {
+                Some(checkpoint_path) if !idempotent => {
+                    read_checkpoint(&ctx, checkpoint_path, &shifted_bcs).await?
+                }
+                _ => None,
+            };
+            // Everything up to the checkpoint was saved and derived before the bookmark moved
+            let unsaved_bcs = &shifted_bcs[resume_index.map_or(0, |index| index + 1)..];
+            save_bonsai_changesets(unsaved_bcs.to_vec(), ctx.clone(), repo.clone())
+                .compat()
+                .await?;
+            derive_bonsais(&ctx, &repo, unsaved_bcs, derive_concurrency).await?;
+            if let Some(mapping_output) = mapping_output {
+                write_mapping_output(&ctx, &repo, &shifted_bcs, &git_commits, mapping_output)
+                    .await?;
+            }
             move_bookmark(
                 &ctx,
                 &repo,
@@ -419,8 +1466,20 @@ fn main(fb: FacebookInit) -> Result<(), Error> {
                 &bookmark_suffix,
                 &checker_flags,
                 sleep_time,
+                bookmark_retries,
+                steps_per_transaction,
+                idempotent,
+                checkpoint_path,
+                resume_index,
             )
-            .await
+            .await?;
+            if verify {
+                verify_import(&ctx, &repo, &shifted_bcs, verify_backend).await?;
+            }
+            if let Some(checkpoint_path) = checkpoint_path {
+                remove_checkpoint(checkpoint_path).await?;
+            }
+            Ok(())
         },
         fb,
         "repo_import",
@@ -432,15 +1491,34 @@ fn main(fb: FacebookInit) -> Result<(), Error> {
 
 #[cfg(test)]
 mod tests {
-    use crate::{move_bookmark, sort_bcs, CheckerFlags};
+    use crate::{
+        add_provenance_extras, changesets_to_import, check_derived, check_dest_empty,
+        derive_bonsais, dry_run_repo, log_dry_run, move_bookmark, normalize_file_changes,
+        parse_check_timeout_policy, parse_provenance_extra, parse_verify_backend, read_checkpoint,
+        remove_checkpoint, rewrite_changesets, set_bookmark, setup_app, skip_imported, sort_bcs,
+        verify_import, wait_for_check, CheckTimeoutPolicy, CheckerFlags, Checkpoint,
+        NormalizationOptions, CHECKPOINT_VERSION,
+    };
 
     use anyhow::Result;
+    use blobrepo::save_bonsai_changesets;
+    use blobrepo_override::DangerousOverride;
     use blobstore::Loadable;
     use bookmarks::{BookmarkName, BookmarkUpdateLog, BookmarkUpdateReason, Freshness};
+    use bytes::Bytes;
     use context::CoreContext;
     use fbinit::FacebookInit;
-    use futures::stream::TryStreamExt;
-    use tests_utils::drawdag::create_from_dag;
+    use filestore::FetchKey;
+    use futures::{compat::Future01CompatExt, future, stream::TryStreamExt};
+    use metaconfig_types::DerivedDataConfig;
+    use mononoke_types::{FileChange, FileContents, MPath};
+    use movers::Mover;
+    use std::collections::HashSet;
+    use std::path::Path;
+    use std::sync::Arc;
+    use std::time::Duration;
+    use tempdir::TempDir;
+    use tests_utils::{bookmark, drawdag::create_from_dag, CreateCommitContext};
 
     #[fbinit::compat_test]
     async fn move_bookmark_test(fb: FacebookInit) -> Result<()> {
@@ -453,6 +1531,7 @@ mod tests {
             x_repo_check_disabled: true,
             hg_sync_check_disabled: true,
             call_sign,
+            timeout_policy: Default::default(),
         };
         let sleep_time = 1;
         let changesets = create_from_dag(
@@ -468,6 +1547,7 @@ mod tests {
             bonsais.push(csid.load(ctx.clone(), &blob_repo.get_blobstore()).await?);
         }
         bonsais = sort_bcs(&bonsais)?;
+        derive_bonsais(&ctx, &blob_repo, &bonsais, None).await?;
         move_bookmark(
             &ctx,
             &blob_repo,
@@ -476,6 +1556,11 @@ mod tests {
             "test_repo",
             &checker_flags,
             sleep_time,
+            0,
+            1,
+            false,
+            None,
+            None,
         )
         .await?;
         // Check the bookmark moves created BookmarkLogUpdate entries
@@ -504,4 +1589,1147 @@ mod tests {
         );
         Ok(())
     }
+    #[fbinit::compat_test]
+    async fn move_bookmark_steps_per_transaction_test(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let blob_repo = blobrepo_factory::new_memblob_empty(None)?;
+        let checker_flags = CheckerFlags {
+            phab_check_disabled: true,
+            x_repo_check_disabled: true,
+            hg_sync_check_disabled: true,
+            call_sign: None,
+            timeout_policy: Default::default(),
+        };
+        let changesets = create_from_dag(
+            &ctx,
+            &blob_repo,
+            r##"
+                A-B-C-D-E-F-G
+            "##,
+        )
+        .await?;
+        let mut bonsais = vec![];
+        for (_, csid) in &changesets {
+            bonsais.push(csid.load(ctx.clone(), &blob_repo.get_blobstore()).await?);
+        }
+        bonsais = sort_bcs(&bonsais)?;
+        derive_bonsais(&ctx, &blob_repo, &bonsais, None).await?;
+        // Batches of 2, made visible 2 at a time, and logged one at a time
+        move_bookmark(
+            &ctx,
+            &blob_repo,
+            &bonsais,
+            2,
+            "test_repo",
+            &checker_flags,
+            1,
+            0,
+            2,
+            false,
+            None,
+            None,
+        )
+        .await?;
+        let entries = blob_repo
+            .attribute_expected::<dyn BookmarkUpdateLog>()
+            .list_bookmark_log_entries(
+                ctx.clone(),
+                BookmarkName::new("repo_import_test_repo")?,
+                5,
+                None,
+                Freshness::MostRecent,
+            )
+            .map_ok(|(cs, rs, _ts)| (cs, rs)) // dropping timestamps
+            .try_collect::<Vec<_>>()
+            .await?;
+
+        assert_eq!(
+            entries,
+            vec![
+                (Some(changesets["G"]), BookmarkUpdateReason::ManualMove),
+                (Some(changesets["F"]), BookmarkUpdateReason::ManualMove),
+                (Some(changesets["D"]), BookmarkUpdateReason::ManualMove),
+                (Some(changesets["B"]), BookmarkUpdateReason::ManualMove),
+                (Some(changesets["A"]), BookmarkUpdateReason::ManualMove),
+            ]
+        );
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn move_bookmark_underived_test(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let blob_repo = blobrepo_factory::new_memblob_empty(None)?;
+        let checker_flags = CheckerFlags {
+            phab_check_disabled: true,
+            x_repo_check_disabled: true,
+            hg_sync_check_disabled: true,
+            call_sign: None,
+            timeout_policy: Default::default(),
+        };
+        let changesets = create_from_dag(
+            &ctx,
+            &blob_repo,
+            r##"
+                A-B-C
+            "##,
+        )
+        .await?;
+        let mut bonsais = vec![];
+        for (_, csid) in &changesets {
+            bonsais.push(csid.load(ctx.clone(), &blob_repo.get_blobstore()).await?);
+        }
+        bonsais = sort_bcs(&bonsais)?;
+        // Only derive the first changeset, so that the first batch can't be exposed
+        derive_bonsais(&ctx, &blob_repo, &bonsais[..1], None).await?;
+
+        let err = move_bookmark(
+            &ctx,
+            &blob_repo,
+            &bonsais,
+            2,
+            "test_repo",
+            &checker_flags,
+            1,
+            0,
+            1,
+            false,
+            None,
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("is not derived for changesets"));
+
+        // The bookmark was created, but never moved past the derived changeset
+        let bookmark_value = blob_repo
+            .get_bonsai_bookmark(ctx.clone(), &BookmarkName::new("repo_import_test_repo")?)
+            .compat()
+            .await?;
+        assert_eq!(bookmark_value, Some(changesets["A"]));
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn idempotent_test(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let blob_repo = blobrepo_factory::new_memblob_empty(None)?;
+        let checker_flags = CheckerFlags {
+            phab_check_disabled: true,
+            x_repo_check_disabled: true,
+            hg_sync_check_disabled: true,
+            call_sign: None,
+            timeout_policy: Default::default(),
+        };
+        let changesets = create_from_dag(
+            &ctx,
+            &blob_repo,
+            r##"
+                A-B-C-D
+            "##,
+        )
+        .await?;
+        let mut bonsais = vec![];
+        for (_, csid) in &changesets {
+            bonsais.push(csid.load(ctx.clone(), &blob_repo.get_blobstore()).await?);
+        }
+        bonsais = sort_bcs(&bonsais)?;
+        derive_bonsais(&ctx, &blob_repo, &bonsais, None).await?;
+        let bookmark = BookmarkName::new("repo_import_test_repo")?;
+
+        // A first run that only got as far as B
+        move_bookmark(
+            &ctx,
+            &blob_repo,
+            &bonsais[..2],
+            2,
+            "test_repo",
+            &checker_flags,
+            1,
+            0,
+            1,
+            true,
+            None,
+            None,
+        )
+        .await?;
+        // Re-running adopts the bookmark rather than failing to create it
+        move_bookmark(
+            &ctx,
+            &blob_repo,
+            &bonsais[2..],
+            2,
+            "test_repo",
+            &checker_flags,
+            1,
+            0,
+            1,
+            true,
+            None,
+            None,
+        )
+        .await?;
+        assert_eq!(
+            blob_repo
+                .get_bonsai_bookmark(ctx.clone(), &bookmark)
+                .compat()
+                .await?,
+            Some(changesets["D"])
+        );
+
+        // The bookmark has been moved over all changesets, so a second run has nothing left to
+        // import
+        let new_bonsais = skip_imported(&ctx, &blob_repo, bonsais, "test_repo").await?;
+        assert!(new_bonsais.is_empty());
+        move_bookmark(
+            &ctx,
+            &blob_repo,
+            &new_bonsais,
+            1,
+            "test_repo",
+            &checker_flags,
+            1,
+            0,
+            1,
+            true,
+            None,
+            None,
+        )
+        .await?;
+        let entries = blob_repo
+            .attribute_expected::<dyn BookmarkUpdateLog>()
+            .list_bookmark_log_entries(ctx.clone(), bookmark, 10, None, Freshness::MostRecent)
+            .try_collect::<Vec<_>>()
+            .await?;
+        // Created at A and moved to B by the first run, then moved to D by the second one
+        assert_eq!(entries.len(), 3);
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn idempotent_after_save_test(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let blob_repo = blobrepo_factory::new_memblob_empty(None)?;
+        let checker_flags = CheckerFlags {
+            phab_check_disabled: true,
+            x_repo_check_disabled: true,
+            hg_sync_check_disabled: true,
+            call_sign: None,
+            timeout_policy: Default::default(),
+        };
+        // A first run that saved all changesets, then died before moving the bookmark
+        let changesets = create_from_dag(
+            &ctx,
+            &blob_repo,
+            r##"
+                A-B-C
+            "##,
+        )
+        .await?;
+        let mut bonsais = vec![];
+        for (_, csid) in &changesets {
+            bonsais.push(csid.load(ctx.clone(), &blob_repo.get_blobstore()).await?);
+        }
+        bonsais = sort_bcs(&bonsais)?;
+
+        // The changesets exist, but none were made visible, so none are skipped
+        let new_bonsais = skip_imported(&ctx, &blob_repo, bonsais.clone(), "test_repo").await?;
+        assert_eq!(new_bonsais.len(), 3);
+        derive_bonsais(&ctx, &blob_repo, &new_bonsais, None).await?;
+        move_bookmark(
+            &ctx,
+            &blob_repo,
+            &new_bonsais,
+            2,
+            "test_repo",
+            &checker_flags,
+            1,
+            0,
+            1,
+            true,
+            None,
+            None,
+        )
+        .await?;
+        assert_eq!(
+            blob_repo
+                .get_bonsai_bookmark(ctx.clone(), &BookmarkName::new("repo_import_test_repo")?)
+                .compat()
+                .await?,
+            Some(changesets["C"])
+        );
+
+        // A bookmark outside of the import can't be continued from
+        bookmark(&ctx, &blob_repo, "repo_import_other")
+            .set_to(changesets["A"])
+            .await?;
+        assert!(
+            skip_imported(&ctx, &blob_repo, bonsais[1..].to_vec(), "other")
+                .await
+                .is_err()
+        );
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn checkpoint_test(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let blob_repo = blobrepo_factory::new_memblob_empty(None)?;
+        let checker_flags = CheckerFlags {
+            phab_check_disabled: true,
+            x_repo_check_disabled: true,
+            hg_sync_check_disabled: true,
+            call_sign: None,
+            timeout_policy: Default::default(),
+        };
+        let changesets = create_from_dag(
+            &ctx,
+            &blob_repo,
+            r##"
+                A-B-C-D-E
+            "##,
+        )
+        .await?;
+        let mut bonsais = vec![];
+        for (_, csid) in &changesets {
+            bonsais.push(csid.load(ctx.clone(), &blob_repo.get_blobstore()).await?);
+        }
+        bonsais = sort_bcs(&bonsais)?;
+        derive_bonsais(&ctx, &blob_repo, &bonsais, None).await?;
+        let bookmark = BookmarkName::new("repo_import_test_repo")?;
+        let dir = TempDir::new("repo_import_checkpoint")?;
+        let checkpoint_path = dir.path().join("checkpoint.json");
+
+        // Nothing to resume from before the first run
+        assert_eq!(
+            read_checkpoint(&ctx, &checkpoint_path, &bonsais).await?,
+            None
+        );
+
+        // A first run that died after moving the bookmark to C
+        move_bookmark(
+            &ctx,
+            &blob_repo,
+            &bonsais[..3],
+            2,
+            "test_repo",
+            &checker_flags,
+            1,
+            0,
+            1,
+            false,
+            Some(checkpoint_path.as_path()),
+            None,
+        )
+        .await?;
+        let resume_index = read_checkpoint(&ctx, &checkpoint_path, &bonsais).await?;
+        assert_eq!(resume_index, Some(2));
+
+        // Resuming continues the bookmark walk from C
+        move_bookmark(
+            &ctx,
+            &blob_repo,
+            &bonsais,
+            2,
+            "test_repo",
+            &checker_flags,
+            1,
+            0,
+            1,
+            false,
+            Some(checkpoint_path.as_path()),
+            resume_index,
+        )
+        .await?;
+        assert_eq!(
+            blob_repo
+                .get_bonsai_bookmark(ctx.clone(), &bookmark)
+                .compat()
+                .await?,
+            Some(changesets["E"])
+        );
+        let entries = blob_repo
+            .attribute_expected::<dyn BookmarkUpdateLog>()
+            .list_bookmark_log_entries(ctx.clone(), bookmark, 10, None, Freshness::MostRecent)
+            .map_ok(|(cs, _rs, _ts)| cs)
+            .try_collect::<Vec<_>>()
+            .await?;
+        assert_eq!(
+            entries,
+            vec![
+                Some(changesets["E"]),
+                Some(changesets["C"]),
+                Some(changesets["B"]),
+                Some(changesets["A"]),
+            ]
+        );
+        assert_eq!(
+            read_checkpoint(&ctx, &checkpoint_path, &bonsais).await?,
+            Some(4)
+        );
+
+        // A checkpoint from a different import is rejected
+        assert!(read_checkpoint(&ctx, &checkpoint_path, &bonsais[..4])
+            .await
+            .is_err());
+
+        // So is one in an unknown format
+        std::fs::write(
+            &checkpoint_path,
+            serde_json::to_vec(&Checkpoint {
+                version: CHECKPOINT_VERSION + 1,
+                last_csid: changesets["E"].to_string(),
+                index: 4,
+            })?,
+        )?;
+        assert!(read_checkpoint(&ctx, &checkpoint_path, &bonsais)
+            .await
+            .is_err());
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn derive_concurrency_test(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let blob_repo = blobrepo_factory::new_memblob_empty(None)?;
+        assert!(blob_repo.get_derived_data_config().derived_data_types.len() > 1);
+        let changesets = create_from_dag(
+            &ctx,
+            &blob_repo,
+            r##"
+                A-B-C
+            "##,
+        )
+        .await?;
+        let mut bonsais = vec![];
+        for (_, csid) in &changesets {
+            bonsais.push(csid.load(ctx.clone(), &blob_repo.get_blobstore()).await?);
+        }
+        bonsais = sort_bcs(&bonsais)?;
+
+        // Derive one type at a time, fewer than the number of configured types
+        derive_bonsais(&ctx, &blob_repo, &bonsais, Some(1)).await?;
+        check_derived(&ctx, &blob_repo, changesets.values().cloned().collect()).await?;
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn set_bookmark_retry_test(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let blob_repo = blobrepo_factory::new_memblob_empty(None)?;
+        let changesets = create_from_dag(
+            &ctx,
+            &blob_repo,
+            r##"
+                A-B-C
+            "##,
+        )
+        .await?;
+        let bookmark = BookmarkName::new("repo_import_test_repo")?;
+        let get_bookmark = || {
+            blob_repo
+                .get_bonsai_bookmark(ctx.clone(), &bookmark)
+                .compat()
+        };
+
+        set_bookmark(&ctx, &blob_repo, &bookmark, changesets["A"], None, 0).await?;
+        // Someone else moves the bookmark behind our back
+        set_bookmark(
+            &ctx,
+            &blob_repo,
+            &bookmark,
+            changesets["B"],
+            Some(changesets["A"]),
+            0,
+        )
+        .await?;
+
+        // Without retries the stale move fails
+        let err = set_bookmark(
+            &ctx,
+            &blob_repo,
+            &bookmark,
+            changesets["C"],
+            Some(changesets["A"]),
+            0,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains("Logical failure while setting"));
+        assert_eq!(get_bookmark().await?, Some(changesets["B"]));
+
+        // With a retry, the move is reattempted from the bookmark's current value
+        set_bookmark(
+            &ctx,
+            &blob_repo,
+            &bookmark,
+            changesets["C"],
+            Some(changesets["A"]),
+            1,
+        )
+        .await?;
+        assert_eq!(get_bookmark().await?, Some(changesets["C"]));
+
+        // Creating a bookmark that already exists elsewhere fails even with retries, rather
+        // than rewinding it
+        let err = set_bookmark(&ctx, &blob_repo, &bookmark, changesets["A"], None, 1)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("Logical failure while creating"));
+        assert_eq!(get_bookmark().await?, Some(changesets["C"]));
+
+        // But one that already points where it should be created is fine on retry
+        set_bookmark(&ctx, &blob_repo, &bookmark, changesets["C"], None, 1).await?;
+        assert_eq!(get_bookmark().await?, Some(changesets["C"]));
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn normalize_file_changes_test(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let blob_repo = blobrepo_factory::new_memblob_empty(None)?;
+        let csid = CreateCommitContext::new_root(&ctx, &blob_repo)
+            .add_file("crlf", "a\r\nb\r\n")
+            .add_file("lf", "a\nb\n")
+            .commit()
+            .await?;
+        let bcs = csid.load(ctx.clone(), &blob_repo.get_blobstore()).await?;
+        let fetch = |file_change: &Option<FileChange>| {
+            filestore::fetch_concat(
+                &blob_repo.get_blobstore(),
+                ctx.clone(),
+                file_change.as_ref().unwrap().content_id(),
+            )
+            .compat()
+        };
+
+        // Nothing changes without the flag
+        let mut bcs_mut = bcs.clone().into_mut();
+        normalize_file_changes(&ctx, &blob_repo, &mut bcs_mut, &Default::default()).await?;
+        assert_eq!(bcs_mut.file_changes, bcs.clone().into_mut().file_changes);
+
+        let mut bcs_mut = bcs.clone().into_mut();
+        let normalization = NormalizationOptions {
+            normalize_eol: true,
+            reject_non_utf8_paths: true,
+        };
+        normalize_file_changes(&ctx, &blob_repo, &mut bcs_mut, &normalization).await?;
+        let crlf = &bcs_mut.file_changes[&MPath::new("crlf")?];
+        assert_eq!(fetch(crlf).await?, &b"a\nb\n"[..]);
+        assert_eq!(crlf.as_ref().map(|fc| fc.size()), Some(4));
+        let lf = &bcs_mut.file_changes[&MPath::new("lf")?];
+        assert_eq!(fetch(lf).await?, &b"a\nb\n"[..]);
+
+        // Non-UTF-8 paths are rejected, naming the path
+        let file_change = bcs_mut.file_changes[&MPath::new("lf")?].clone();
+        bcs_mut
+            .file_changes
+            .insert(MPath::new(b"bad\xff")?, file_change);
+        let err = normalize_file_changes(&ctx, &blob_repo, &mut bcs_mut, &normalization)
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("bad"));
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn check_dest_empty_test(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let blob_repo = blobrepo_factory::new_memblob_empty(None)?;
+        let csid = CreateCommitContext::new_root(&ctx, &blob_repo)
+            .add_file("dest/file", "content")
+            .commit()
+            .await?;
+        let master = bookmark(&ctx, &blob_repo, "master").set_to(csid).await?;
+
+        assert!(check_dest_empty(&ctx, &blob_repo, &master, "dest")
+            .await
+            .is_err());
+        assert!(check_dest_empty(&ctx, &blob_repo, &master, "dest/file")
+            .await
+            .is_err());
+        check_dest_empty(&ctx, &blob_repo, &master, "other").await?;
+        check_dest_empty(&ctx, &blob_repo, &master, "dest/other").await?;
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn provenance_extras_test(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let blob_repo = blobrepo_factory::new_memblob_empty(None)?;
+        let csid = CreateCommitContext::new_root(&ctx, &blob_repo)
+            .add_file("file", "content")
+            .commit()
+            .await?;
+        let bcs = csid.load(ctx.clone(), &blob_repo.get_blobstore()).await?;
+
+        let provenance_extras = vec![
+            parse_provenance_extra("imported_source=https://example.com/repo.git")?,
+            parse_provenance_extra("import_job_id=42")?,
+        ];
+        assert!(parse_provenance_extra("no_value").is_err());
+        assert!(parse_provenance_extra("=value").is_err());
+
+        let mut bcs_mut = bcs.into_mut();
+        add_provenance_extras(&mut bcs_mut, &provenance_extras);
+        let imported_bcs = bcs_mut.freeze()?;
+        save_bonsai_changesets(vec![imported_bcs.clone()], ctx.clone(), blob_repo.clone())
+            .compat()
+            .await?;
+        derive_bonsais(&ctx, &blob_repo, &[imported_bcs.clone()], None).await?;
+
+        let saved_bcs = imported_bcs
+            .get_changeset_id()
+            .load(ctx.clone(), &blob_repo.get_blobstore())
+            .await?;
+        let extra = saved_bcs.extra().collect::<Vec<_>>();
+        assert!(extra.contains(&("imported_source", &b"https://example.com/repo.git"[..])));
+        assert!(extra.contains(&("import_job_id", &b"42"[..])));
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn verify_backend_test(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        // Test repos derive unodes v2
+        let blob_repo = blobrepo_factory::new_memblob_empty(None)?;
+        let changesets = create_from_dag(
+            &ctx,
+            &blob_repo,
+            r##"
+                A-B-C
+            "##,
+        )
+        .await?;
+        let mut bonsais = vec![];
+        for (_, csid) in &changesets {
+            bonsais.push(csid.load(ctx.clone(), &blob_repo.get_blobstore()).await?);
+        }
+        bonsais = sort_bcs(&bonsais)?;
+        derive_bonsais(&ctx, &blob_repo, &bonsais, None).await?;
+
+        let unodes_v1 = parse_verify_backend("unodes-v1")?;
+        let unodes_v2 = parse_verify_backend("unodes-v2")?;
+        assert!(parse_verify_backend("unodes-v3").is_err());
+
+        verify_import(&ctx, &blob_repo, &bonsais, None).await?;
+        verify_import(&ctx, &blob_repo, &bonsais, Some(unodes_v2)).await?;
+        let err = verify_import(&ctx, &blob_repo, &bonsais, Some(unodes_v1))
+            .await
+            .unwrap_err();
+        assert!(err.to_string().contains("unodes"));
+
+        // Once the second backend has been populated, verifying against it succeeds
+        let v1_repo = blob_repo.dangerous_override(|mut derived_data_config: DerivedDataConfig| {
+            derived_data_config.unode_version = unodes_v1;
+            derived_data_config
+        });
+        derive_bonsais(&ctx, &v1_repo, &bonsais, None).await?;
+        verify_import(&ctx, &blob_repo, &bonsais, Some(unodes_v1)).await?;
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn strict_rewrite_test(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let blob_repo = blobrepo_factory::new_memblob_empty(None)?;
+        let changesets = create_from_dag(
+            &ctx,
+            &blob_repo,
+            r##"
+                A-B
+            "##,
+        )
+        .await?;
+        let mut source = vec![];
+        for name in vec!["A", "B"] {
+            let bcs_id = changesets[name];
+            let bcs = bcs_id.load(ctx.clone(), &blob_repo.get_blobstore()).await?;
+            source.push((name.to_string(), bcs_id, bcs));
+        }
+        // Drops file B, and with it commit B, which changes nothing else
+        let mover: Mover = Arc::new(|path: &MPath| -> Result<Option<MPath>> {
+            if path == &MPath::new("B")? {
+                Ok(None)
+            } else {
+                Ok(Some(MPath::new("dest")?.join(path)))
+            }
+        });
+
+        // Lenient mode silently skips the commit
+        let (rewritten, source_commits) = rewrite_changesets(
+            &ctx,
+            &blob_repo,
+            source.clone(),
+            mover.clone(),
+            &Default::default(),
+            &[],
+            None,
+            None,
+        )
+        .await?;
+        assert_eq!(rewritten.len(), 1);
+        assert_eq!(
+            source_commits.get(&rewritten[0].get_changeset_id()),
+            Some(&"A".to_string())
+        );
+
+        // Strict mode fails, naming the commit
+        let err = rewrite_changesets(
+            &ctx,
+            &blob_repo,
+            source.clone(),
+            mover.clone(),
+            &Default::default(),
+            &[],
+            Some(&HashSet::new()),
+            None,
+        )
+        .await
+        .unwrap_err();
+        assert!(err.to_string().contains(&changesets["B"].to_string()));
+
+        // Unless the commit is expected to be dropped
+        let expected_dropped = vec!["B".to_string()].into_iter().collect();
+        let (rewritten, _) = rewrite_changesets(
+            &ctx,
+            &blob_repo,
+            source,
+            mover,
+            &Default::default(),
+            &[],
+            Some(&expected_dropped),
+            None,
+        )
+        .await?;
+        assert_eq!(rewritten.len(), 1);
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn graft_onto_test(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let blob_repo = blobrepo_factory::new_memblob_empty(None)?;
+        let changesets = create_from_dag(
+            &ctx,
+            &blob_repo,
+            r##"
+                A-B
+                X
+            "##,
+        )
+        .await?;
+        let mut source = vec![];
+        for name in vec!["A", "B"] {
+            let bcs_id = changesets[name];
+            let bcs = bcs_id.load(ctx.clone(), &blob_repo.get_blobstore()).await?;
+            source.push((name.to_string(), bcs_id, bcs));
+        }
+        let mover: Mover = Arc::new(|path: &MPath| -> Result<Option<MPath>> {
+            Ok(Some(MPath::new("dest")?.join(path)))
+        });
+
+        let (rewritten, _) = rewrite_changesets(
+            &ctx,
+            &blob_repo,
+            source,
+            mover,
+            &Default::default(),
+            &[],
+            None,
+            Some(changesets["X"]),
+        )
+        .await?;
+        assert_eq!(rewritten.len(), 2);
+        assert_eq!(
+            rewritten[0].parents().collect::<Vec<_>>(),
+            vec![changesets["X"]]
+        );
+        assert_eq!(
+            rewritten[1].parents().collect::<Vec<_>>(),
+            vec![rewritten[0].get_changeset_id()]
+        );
+
+        // The graft is outside of the import, so sorting leaves it out
+        let sorted = sort_bcs(&rewritten)?;
+        assert_eq!(sorted.len(), 2);
+        assert_eq!(
+            sorted[0].get_changeset_id(),
+            rewritten[0].get_changeset_id()
+        );
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn dry_run_test(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let blob_repo = blobrepo_factory::new_memblob_empty(None)?;
+        let git_dir = TempDir::new("repo_import_dry_run")?;
+        let git_repo = git2::Repository::init(git_dir.path())?;
+        std::fs::write(git_dir.path().join("file"), "content\r\n")?;
+        let mut index = git_repo.index()?;
+        index.add_path(Path::new("file"))?;
+        let tree = git_repo.find_tree(index.write_tree()?)?;
+        let signature = git2::Signature::now("test", "test@example.com")?;
+        git_repo.commit(Some("HEAD"), &signature, &signature, "Add file", &tree, &[])?;
+
+        let dry_run_repo = dry_run_repo(&blob_repo);
+        let normalization = NormalizationOptions {
+            normalize_eol: true,
+            reject_non_utf8_paths: false,
+        };
+        let (shifted_bcs, git_commits) = changesets_to_import(
+            &ctx,
+            &dry_run_repo,
+            git_dir.path(),
+            "dest",
+            &normalization,
+            &[],
+            None,
+            None,
+            true,
+            "test_repo",
+        )
+        .await?;
+        assert_eq!(shifted_bcs.len(), 1);
+        log_dry_run(&ctx, &shifted_bcs, &git_commits, "test_repo")?;
+
+        // The contents stored by the git import and the normalization are only in memory
+        for content in vec!["content\r\n", "content\n"] {
+            let key =
+                FetchKey::Canonical(FileContents::content_id_for_bytes(&Bytes::from(content)));
+            assert!(
+                filestore::exists(dry_run_repo.blobstore(), ctx.clone(), &key)
+                    .compat()
+                    .await?
+            );
+            assert!(
+                !filestore::exists(blob_repo.blobstore(), ctx.clone(), &key)
+                    .compat()
+                    .await?
+            );
+        }
+        // Nothing was saved and the bookmark wasn't created
+        assert!(
+            !blob_repo
+                .changeset_exists_by_bonsai(ctx.clone(), shifted_bcs[0].get_changeset_id())
+                .compat()
+                .await?
+        );
+        assert_eq!(
+            blob_repo
+                .get_bonsai_bookmark(ctx.clone(), &BookmarkName::new("repo_import_test_repo")?)
+                .compat()
+                .await?,
+            None
+        );
+
+        // Re-running with everything already imported is not an error
+        log_dry_run(&ctx, &[], &git_commits, "test_repo")?;
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn check_timeout_test(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let policy = CheckTimeoutPolicy {
+            timeout: Some(Duration::from_millis(10)),
+            retries: 2,
+            backoff: Duration::from_millis(1),
+            proceed_on_timeout: false,
+        };
+        // Each attempt calls the check once, which then never finishes
+        let mut calls = 0;
+        let res = wait_for_check(&ctx, "test", &policy, 0, || {
+            calls += 1;
+            future::pending()
+        })
+        .await;
+        assert!(res.is_err());
+        assert_eq!(calls, 3);
+
+        let mut calls = 0;
+        wait_for_check(
+            &ctx,
+            "test",
+            &CheckTimeoutPolicy {
+                proceed_on_timeout: true,
+                ..policy
+            },
+            0,
+            || {
+                calls += 1;
+                future::pending()
+            },
+        )
+        .await?;
+        assert_eq!(calls, 3);
+
+        // A retry can still pass the check
+        let mut calls = 0;
+        wait_for_check(&ctx, "test", &policy, 0, || {
+            calls += 1;
+            let passes = calls > 1;
+            async move {
+                if passes {
+                    Ok(true)
+                } else {
+                    future::pending::<Result<bool>>().await
+                }
+            }
+        })
+        .await?;
+        assert_eq!(calls, 2);
+        Ok(())
+    }
+
+    #[test]
+    fn check_timeout_args_test() -> Result<()> {
+        let required = vec![
+            "repo_import",
+            "/path/to/git",
+            "--dest-path",
+            "dest",
+            "--bookmark-suffix",
+            "test",
+        ];
+
+        // Without a timeout, checks wait indefinitely and are never retried
+        let matches = setup_app().get_matches_from_safe(required.clone())?;
+        let policy = parse_check_timeout_policy(&matches, 1)?;
+        assert_eq!(policy.timeout, None);
+        assert_eq!(policy.retries, 0);
+
+        let mut args = required.clone();
+        args.extend(vec!["--check-timeout", "30", "--check-retries", "2"]);
+        let matches = setup_app().get_matches_from_safe(args)?;
+        let policy = parse_check_timeout_policy(&matches, 1)?;
+        assert_eq!(policy.timeout, Some(Duration::from_secs(30)));
+        assert_eq!(policy.retries, 2);
+
+        // Retrying needs a timeout to retry after
+        let mut args = required;
+        args.extend(vec!["--check-retries", "2"]);
+        assert!(setup_app().get_matches_from_safe(args).is_err());
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn checkpoint_idempotent_test(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let blob_repo = blobrepo_factory::new_memblob_empty(None)?;
+        let checker_flags = CheckerFlags {
+            phab_check_disabled: true,
+            x_repo_check_disabled: true,
+            hg_sync_check_disabled: true,
+            call_sign: None,
+            timeout_policy: Default::default(),
+        };
+        let changesets = create_from_dag(
+            &ctx,
+            &blob_repo,
+            r##"
+                A-B-C-D-E
+            "##,
+        )
+        .await?;
+        let mut bonsais = vec![];
+        for (_, csid) in &changesets {
+            bonsais.push(csid.load(ctx.clone(), &blob_repo.get_blobstore()).await?);
+        }
+        bonsais = sort_bcs(&bonsais)?;
+        derive_bonsais(&ctx, &blob_repo, &bonsais, None).await?;
+        let bookmark = BookmarkName::new("repo_import_test_repo")?;
+        let dir = TempDir::new("repo_import_checkpoint")?;
+        let checkpoint_path = dir.path().join("checkpoint.json");
+
+        let matches = setup_app().get_matches_from_safe(vec![
+            "repo_import",
+            "/path/to/git",
+            "--dest-path",
+            "dest",
+            "--bookmark-suffix",
+            "test_repo",
+            "--idempotent",
+            "--checkpoint-path",
+            checkpoint_path.to_str().unwrap(),
+        ]);
+        assert!(matches.is_ok());
+
+        // A first run that died after moving the bookmark to C
+        move_bookmark(
+            &ctx,
+            &blob_repo,
+            &bonsais[..3],
+            2,
+            "test_repo",
+            &checker_flags,
+            1,
+            0,
+            1,
+            false,
+            Some(checkpoint_path.as_path()),
+            None,
+        )
+        .await?;
+
+        // An idempotent run picks up from the bookmark, and keeps the checkpoint up to date
+        let remaining = skip_imported(&ctx, &blob_repo, bonsais.clone(), "test_repo").await?;
+        assert_eq!(remaining.len(), 2);
+        move_bookmark(
+            &ctx,
+            &blob_repo,
+            &remaining,
+            2,
+            "test_repo",
+            &checker_flags,
+            1,
+            0,
+            1,
+            true,
+            Some(checkpoint_path.as_path()),
+            None,
+        )
+        .await?;
+        assert_eq!(
+            blob_repo
+                .get_bonsai_bookmark(ctx.clone(), &bookmark)
+                .compat()
+                .await?,
+            Some(changesets["E"])
+        );
+
+        // The checkpoint it wrote still works for a run without --idempotent
+        assert_eq!(
+            read_checkpoint(&ctx, &checkpoint_path, &bonsais).await?,
+            Some(4)
+        );
+        remove_checkpoint(&checkpoint_path).await?;
+
+        // Re-running once everything is imported finds nothing new and writes no checkpoint,
+        // which is fine to clean up after
+        let remaining = skip_imported(&ctx, &blob_repo, bonsais.clone(), "test_repo").await?;
+        assert!(remaining.is_empty());
+        move_bookmark(
+            &ctx,
+            &blob_repo,
+            &remaining,
+            2,
+            "test_repo",
+            &checker_flags,
+            1,
+            0,
+            1,
+            true,
+            Some(checkpoint_path.as_path()),
+            None,
+        )
+        .await?;
+        assert!(!checkpoint_path.exists());
+        remove_checkpoint(&checkpoint_path).await?;
+        Ok(())
+    }
+
+    #[fbinit::compat_test]
+    async fn checkpoint_behind_bookmark_test(fb: FacebookInit) -> Result<()> {
+        let ctx = CoreContext::test_mock(fb);
+        let blob_repo = blobrepo_factory::new_memblob_empty(None)?;
+        let checker_flags = CheckerFlags {
+            phab_check_disabled: true,
+            x_repo_check_disabled: true,
+            hg_sync_check_disabled: true,
+            call_sign: None,
+            timeout_policy: Default::default(),
+        };
+        let changesets = create_from_dag(
+            &ctx,
+            &blob_repo,
+            r##"
+                A-B-C-D-E
+            "##,
+        )
+        .await?;
+        let mut bonsais = vec![];
+        for (_, csid) in &changesets {
+            bonsais.push(csid.load(ctx.clone(), &blob_repo.get_blobstore()).await?);
+        }
+        bonsais = sort_bcs(&bonsais)?;
+        derive_bonsais(&ctx, &blob_repo, &bonsais, None).await?;
+        let bookmark = BookmarkName::new("repo_import_test_repo")?;
+        let dir = TempDir::new("repo_import_checkpoint")?;
+        let checkpoint_path = dir.path().join("checkpoint.json");
+
+        move_bookmark(
+            &ctx,
+            &blob_repo,
+            &bonsais[..3],
+            2,
+            "test_repo",
+            &checker_flags,
+            1,
+            0,
+            1,
+            false,
+            Some(checkpoint_path.as_path()),
+            None,
+        )
+        .await?;
+        let resume_index = read_checkpoint(&ctx, &checkpoint_path, &bonsais).await?;
+        assert_eq!(resume_index, Some(2));
+
+        // The bookmark moved on to D, but the run died before checkpointing it
+        bookmark(&ctx, &blob_repo, "repo_import_test_repo")
+            .set_to(changesets["D"])
+            .await?;
+
+        // Resuming continues from the bookmark instead of trying to move it from C again
+        move_bookmark(
+            &ctx,
+            &blob_repo,
+            &bonsais,
+            2,
+            "test_repo",
+            &checker_flags,
+            1,
+            0,
+            1,
+            false,
+            Some(checkpoint_path.as_path()),
+            resume_index,
+        )
+        .await?;
+        assert_eq!(
+            blob_repo
+                .get_bonsai_bookmark(ctx.clone(), &bookmark)
+                .compat()
+                .await?,
+            Some(changesets["E"])
+        );
+        assert_eq!(
+            read_checkpoint(&ctx, &checkpoint_path, &bonsais).await?,
+            Some(4)
+        );
+
+        // A bookmark that was moved somewhere else entirely can't be resumed from
+        bookmark(&ctx, &blob_repo, "repo_import_test_repo")
+            .delete()
+            .await?;
+        assert!(move_bookmark(
+            &ctx,
+            &blob_repo,
+            &bonsais,
+            2,
+            "test_repo",
+            &checker_flags,
+            1,
+            0,
+            1,
+            false,
+            Some(checkpoint_path.as_path()),
+            Some(2),
+        )
+        .await
+        .is_err());
+        Ok(())
+    }
 }
@@ -52,6 +52,7 @@ define_perf_counters! {
         BlobGetsShardAccessWait,
         BlobGetsMaxLatency,
         BlobGetsDeduplicated,
+        BlobGetsWinner,
         BlobPresenceChecks,
         BlobPresenceChecksMaxLatency,
         BlobPuts,
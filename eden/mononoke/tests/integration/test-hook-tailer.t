@@ -17,6 +17,7 @@ Run the hook tailer
   Poll time: *us (glob)
   Changesets accepted: 3
   Changesets rejected: 0
+  Changesets skipped: 0
 
 Test the CSV output
   $ quiet hook_tailer --bookmark master_bookmark --stats-file "$TESTTMP/stats.csv"
@@ -37,6 +38,7 @@ Test various combinations of exclusions
   Poll time: *us (glob)
   Changesets accepted: 2
   Changesets rejected: 0
+  Changesets skipped: 0
 
   $ hook_tailer --bookmark master_bookmark --exclude master_bookmark 2>&1 | strip_glog
   Hook tailer is starting
@@ -47,6 +49,7 @@ Test various combinations of exclusions
   Poll time: *us (glob)
   Changesets accepted: 2
   Changesets rejected: 0
+  Changesets skipped: 0
 
   $ echo "master_bookmark" > "$TESTTMP/excluded"
   $ hook_tailer --bookmark master_bookmark --exclude_file "$TESTTMP/excluded" 2>&1 | strip_glog
@@ -58,6 +61,7 @@ Test various combinations of exclusions
   Poll time: *us (glob)
   Changesets accepted: 2
   Changesets rejected: 0
+  Changesets skipped: 0
 
 Test excluding multiple commits
 
@@ -71,6 +75,7 @@ Test excluding multiple commits
   Poll time: *us (glob)
   Changesets accepted: 1
   Changesets rejected: 0
+  Changesets skipped: 0
 
 Test explicit commits
 
@@ -83,6 +88,7 @@ Test explicit commits
   Poll time: *us (glob)
   Changesets accepted: 1
   Changesets rejected: 0
+  Changesets skipped: 0
 
   $ hook_tailer --bookmark master_bookmark --changeset 459f16ae564c501cb408c1e5b60fc98a1e8b8e97b9409c7520658bfa1577fb66 --changeset 9feb8ddd3e8eddcfa3a4913b57df7842bedf84b8ea3b7b3fcb14c6424aa81fec 2>&1 | strip_glog
   Hook tailer is starting
@@ -94,6 +100,7 @@ Test explicit commits
   Poll time: *us (glob)
   Changesets accepted: 2
   Changesets rejected: 0
+  Changesets skipped: 0
 
   $ echo "459f16ae564c501cb408c1e5b60fc98a1e8b8e97b9409c7520658bfa1577fb66" >> "$TESTTMP/included"
   $ echo "9feb8ddd3e8eddcfa3a4913b57df7842bedf84b8ea3b7b3fcb14c6424aa81fec" >> "$TESTTMP/included"
@@ -107,3 +114,19 @@ Test explicit commits
   Poll time: *us (glob)
   Changesets accepted: 2
   Changesets rejected: 0
+  Changesets skipped: 0
+
+Test slow changeset warnings
+
+  $ hook_tailer --bookmark master_bookmark --slow-threshold-ms 0 2>&1 | strip_glog
+  Hook tailer is starting
+  ==== Hooks results ====
+  Slow hooks for changeset *: *us (glob)
+  Slow hooks for changeset *: *us (glob)
+  Slow hooks for changeset *: *us (glob)
+  ==== Hooks stats ====
+  Completion time: *us (glob)
+  Poll time: *us (glob)
+  Changesets accepted: 3
+  Changesets rejected: 0
+  Changesets skipped: 0
@@ -27,11 +27,17 @@
 # Get the config
   $ curl -fs "${lfs_root}/config" | jq -S .
   {
+    "always_log_bytes_threshold": null,
     "disable_hostname_logging": false,
     "enable_consistent_routing": false,
     "enforce_acl_check": false,
+    "hostname_resolution_timeout_ms": null,
+    "method_timeouts_ms": null,
+    "no_log_client_identities": null,
     "object_popularity_category": null,
     "object_popularity_threshold": null,
+    "slow_request_threshold_ms": null,
+    "slow_request_thresholds_ms": null,
     "throttle_limits": [],
     "track_bytes_sent": true
   }
@@ -55,11 +61,17 @@
 # Get the updated config
   $ curl -fs "${lfs_root}/config" | jq -S .
   {
+    "always_log_bytes_threshold": null,
     "disable_hostname_logging": false,
     "enable_consistent_routing": false,
     "enforce_acl_check": false,
+    "hostname_resolution_timeout_ms": null,
+    "method_timeouts_ms": null,
+    "no_log_client_identities": null,
     "object_popularity_category": null,
     "object_popularity_threshold": null,
+    "slow_request_threshold_ms": null,
+    "slow_request_thresholds_ms": null,
     "throttle_limits": [],
     "track_bytes_sent": false
   }
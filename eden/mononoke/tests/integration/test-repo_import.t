@@ -33,9 +33,9 @@
    1 file changed, 1 insertion(+)
    create mode 100644 file3_repo/file3
 
-# Import it into Mononoke
+# Dry run, which writes nothing to the repo
   $ cd "$TESTTMP"
-  $ repo_import "$GIT_REPO" --dest-path "new_dir/new_repo" --batch-size 3 --bookmark-suffix "new_repo" --disable-phabricator-check
+  $ repo_import "$GIT_REPO" --dest-path "new_dir/new_repo" --batch-size 3 --bookmark-suffix "new_repo" --disable-phabricator-check --dry-run
   * using repo "repo" repoid RepositoryId(0) (glob)
   * Created ce435b03d4ef526648f8654c61e26ae5cc1069cc => ChangesetId(Blake2(f7cbf75d9c08ff96896ed2cebd0327aa514e58b1dd9901d50129b9e08f4aa062)) (glob)
   * Created 2c01e4a5658421e2bfcd08e31d9b69399319bcd3 => ChangesetId(Blake2(f7708ed066b1c23591f862148e0386ec704a450e572154cc52f87ca0e394a0fb)) (glob)
@@ -43,9 +43,39 @@
   * Ref: Some("refs/heads/master"): Some(ChangesetId(Blake2(f7708ed066b1c23591f862148e0386ec704a450e572154cc52f87ca0e394a0fb))) (glob)
   * Remapped ChangesetId(Blake2(f7cbf75d9c08ff96896ed2cebd0327aa514e58b1dd9901d50129b9e08f4aa062)) => ChangesetId(Blake2(a159bc614d2dbd07a5ecc6476156fa464b69e884d819bbc2e854ade3e4c353b9)) (glob)
   * Remapped ChangesetId(Blake2(f7708ed066b1c23591f862148e0386ec704a450e572154cc52f87ca0e394a0fb)) => ChangesetId(Blake2(a2e6329ed60e3dd304f53efd0f92c28b849404a47979fcf48bb43b6fe3a0cad5)) (glob)
+  * Dry run: ChangesetId(Blake2(a2e6329ed60e3dd304f53efd0f92c28b849404a47979fcf48bb43b6fe3a0cad5)) (from 2c01e4a5658421e2bfcd08e31d9b69399319bcd3) has parent ChangesetId(Blake2(a159bc614d2dbd07a5ecc6476156fa464b69e884d819bbc2e854ade3e4c353b9)) (glob)
+  * Dry run: would import 2 changesets and set bookmark BookmarkName { bookmark: "repo_import_new_repo" } to point to ChangesetId(Blake2(a2e6329ed60e3dd304f53efd0f92c28b849404a47979fcf48bb43b6fe3a0cad5)) (glob)
+  $ ls "$TESTTMP/blobstore/blobs" 2> /dev/null | wc -l
+  0
+
+# Import it into Mononoke
+  $ repo_import "$GIT_REPO" --dest-path "new_dir/new_repo" --batch-size 3 --bookmark-suffix "new_repo" --disable-phabricator-check --mapping-output "$TESTTMP/mapping.json"
+  * using repo "repo" repoid RepositoryId(0) (glob)
+  * Created ce435b03d4ef526648f8654c61e26ae5cc1069cc => ChangesetId(Blake2(f7cbf75d9c08ff96896ed2cebd0327aa514e58b1dd9901d50129b9e08f4aa062)) (glob)
+  * Created 2c01e4a5658421e2bfcd08e31d9b69399319bcd3 => ChangesetId(Blake2(f7708ed066b1c23591f862148e0386ec704a450e572154cc52f87ca0e394a0fb)) (glob)
+  * 2 bonsai changesets have been committed (glob)
+  * Ref: Some("refs/heads/master"): Some(ChangesetId(Blake2(f7708ed066b1c23591f862148e0386ec704a450e572154cc52f87ca0e394a0fb))) (glob)
+  * Remapped ChangesetId(Blake2(f7cbf75d9c08ff96896ed2cebd0327aa514e58b1dd9901d50129b9e08f4aa062)) => ChangesetId(Blake2(a159bc614d2dbd07a5ecc6476156fa464b69e884d819bbc2e854ade3e4c353b9)) (glob)
+  * Remapped ChangesetId(Blake2(f7708ed066b1c23591f862148e0386ec704a450e572154cc52f87ca0e394a0fb)) => ChangesetId(Blake2(a2e6329ed60e3dd304f53efd0f92c28b849404a47979fcf48bb43b6fe3a0cad5)) (glob)
+  * Wrote mapping of 2 commits to $TESTTMP/mapping.json (glob)
   * Created bookmark BookmarkName { bookmark: "repo_import_new_repo" } pointing to * (glob)
   * Set bookmark BookmarkName { bookmark: "repo_import_new_repo" } to * (glob)
 
+# Check the commit mapping
+  $ cat "$TESTTMP/mapping.json"
+  [
+    {
+      "git_commit": "ce435b03d4ef526648f8654c61e26ae5cc1069cc",
+      "bonsai_changeset": "a159bc614d2dbd07a5ecc6476156fa464b69e884d819bbc2e854ade3e4c353b9",
+      "hg_changeset": "*" (glob)
+    },
+    {
+      "git_commit": "2c01e4a5658421e2bfcd08e31d9b69399319bcd3",
+      "bonsai_changeset": "a2e6329ed60e3dd304f53efd0f92c28b849404a47979fcf48bb43b6fe3a0cad5",
+      "hg_changeset": "*" (glob)
+    }
+  ] (no-eol)
+
 # Check if we derived all the types
   $ BOOKMARK_NAME="repo_import_new_repo"
   $ mononoke_admin derived-data exists changeset_info $BOOKMARK_NAME 2> /dev/null
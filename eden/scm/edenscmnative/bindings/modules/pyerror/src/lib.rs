@@ -5,11 +5,21 @@
  * GNU General Public License version 2.
  */
 
+use std::sync::atomic::{AtomicUsize, Ordering};
+
 use cpython::*;
-use cpython_ext::{error, ResultPyErrExt};
+use cpython_ext::{error, PyNone, ResultPyErrExt};
 
 use taggederror::{intentional_bail, intentional_error, CommonMetadata, Fault, FilteredAnyhow};
 
+/// Messages of errors converted by the fallback handler are truncated to
+/// this many characters, so that an error embedding e.g. a huge path list
+/// doesn't turn into an equally huge Python string.
+const DEFAULT_MAX_MESSAGE_LENGTH: usize = 64 * 1024;
+const TRUNCATION_MARKER: &str = "... (truncated)";
+
+static MAX_MESSAGE_LENGTH: AtomicUsize = AtomicUsize::new(DEFAULT_MAX_MESSAGE_LENGTH);
+
 py_exception!(error, IndexedLogError);
 py_exception!(error, MetaLogError);
 py_exception!(error, RustError);
@@ -73,6 +83,17 @@ pub fn init_module(py: Python, package: &str) -> PyResult<PyModule> {
     )?;
     m.add(py, "throwrustexception", py_fn!(py, py_intentional_error()))?;
     m.add(py, "throwrustbail", py_fn!(py, py_intentional_bail()))?;
+    m.add(py, "list_handlers", py_fn!(py, py_list_handlers()))?;
+    m.add(
+        py,
+        "max_message_length",
+        py_fn!(py, py_max_message_length()),
+    )?;
+    m.add(
+        py,
+        "set_max_message_length",
+        py_fn!(py, py_set_max_message_length(length: usize)),
+    )?;
 
     register_error_handlers();
 
@@ -107,7 +128,11 @@ fn register_error_handlers() {
     }
 
     fn fallback_error_handler(py: Python, e: &error::Error, m: CommonMetadata) -> Option<PyErr> {
-        TaggedExceptionData::create_instance(py, m, format!("{:?}", FilteredAnyhow::new(e)))
+        let message = truncate_message(
+            format!("{:?}", FilteredAnyhow::new(e)),
+            MAX_MESSAGE_LENGTH.load(Ordering::Relaxed),
+        );
+        TaggedExceptionData::create_instance(py, m, message)
             .map(|data| PyErr::new::<RustError, _>(py, data))
             .ok()
     }
@@ -116,6 +141,14 @@ fn register_error_handlers() {
     error::register("999-fallback", fallback_error_handler);
 }
 
+fn truncate_message(mut message: String, max_length: usize) -> String {
+    if let Some((index, _)) = message.char_indices().nth(max_length) {
+        message.truncate(index);
+        message.push_str(TRUNCATION_MARKER);
+    }
+    message
+}
+
 fn py_intentional_error(py: Python) -> PyResult<PyInt> {
     Ok(intentional_error()
         .map(|r| r.to_py_object(py))
@@ -127,3 +160,19 @@ fn py_intentional_bail(py: Python) -> PyResult<PyInt> {
         .map(|r| r.to_py_object(py))
         .map_pyerr(py)?)
 }
+
+fn py_list_handlers(_py: Python) -> PyResult<Vec<String>> {
+    Ok(error::registered_names()
+        .into_iter()
+        .map(String::from)
+        .collect())
+}
+
+fn py_max_message_length(_py: Python) -> PyResult<usize> {
+    Ok(MAX_MESSAGE_LENGTH.load(Ordering::Relaxed))
+}
+
+fn py_set_max_message_length(_py: Python, length: usize) -> PyResult<PyNone> {
+    MAX_MESSAGE_LENGTH.store(length, Ordering::Relaxed);
+    Ok(PyNone)
+}
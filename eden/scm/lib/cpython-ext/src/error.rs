@@ -91,6 +91,11 @@ pub fn register(name: &'static str, func: AnyhowErrorIntoPyErrFunc) {
     list.insert(name, func);
 }
 
+/// Names of the functions registered via [`register`], in the order they are executed.
+pub fn registered_names() -> Vec<&'static str> {
+    INTO_PYERR_FUNC_LIST.lock().keys().cloned().collect()
+}
+
 impl<T, E: Into<Error>> ResultPyErrExt<T> for Result<T, E> {
     fn map_pyerr(self, py: Python<'_>) -> PyResult<T> {
         self.map_err(|e| {
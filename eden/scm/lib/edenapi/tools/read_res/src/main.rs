@@ -8,28 +8,36 @@
 //! read_res -- Read the content of EdenAPI responses
 //!
 //! This program allows querying the contents of
-//! EdenAPI CBOR data and history responses.
+//! EdenAPI CBOR data, history and bookmark responses,
+//! or dumping any CBOR response as JSON.
 
 #![deny(warnings)]
 
 use std::collections::{BTreeMap, BTreeSet};
+use std::convert::TryFrom;
 use std::fs::File;
-use std::io::{prelude::*, stdin, stdout};
+use std::io::{prelude::*, stdin, stdout, BufReader};
 use std::path::PathBuf;
+use std::str::FromStr;
 
 use anyhow::{anyhow, Result};
+use flate2::read::MultiGzDecoder;
+use rayon::{prelude::*, ThreadPool, ThreadPoolBuilder};
 use serde::de::DeserializeOwned;
-use serde_cbor::Deserializer;
+use serde_cbor::{Deserializer, Value as CborValue};
+use serde_json::{Map, Number, Value as JsonValue};
 use structopt::StructOpt;
 
-use edenapi_types::{DataEntry, DataError, HistoryResponseChunk, WireHistoryEntry};
-use types::{Key, Parents, RepoPathBuf};
+use edenapi_types::{BookmarkEntry, DataEntry, DataError, HistoryResponseChunk, WireHistoryEntry};
+use types::{HgId, Key, Parents, RepoPathBuf};
 
 #[derive(Debug, StructOpt)]
 #[structopt(name = "read_res", about = "Read the content of EdenAPI responses")]
 enum Args {
     Data(DataArgs),
     History(HistoryArgs),
+    Bookmarks(BookmarksArgs),
+    ToJson(ToJsonArgs),
 }
 
 #[derive(Debug, StructOpt)]
@@ -38,6 +46,8 @@ enum DataArgs {
     Ls(DataLsArgs),
     Cat(DataCatArgs),
     Check(DataCheckArgs),
+    Summary(DataSummaryArgs),
+    Diff(DataDiffArgs),
 }
 
 #[derive(Debug, StructOpt)]
@@ -62,6 +72,8 @@ struct DataCatArgs {
     hgid: String,
     #[structopt(long, short, help = "Only look at the first N entries")]
     limit: Option<usize>,
+    #[structopt(long, help = "Format content as a hexdump when writing to stdout")]
+    hexdump: bool,
 }
 
 #[derive(Debug, StructOpt)]
@@ -71,6 +83,40 @@ struct DataCheckArgs {
     input: Option<PathBuf>,
     #[structopt(long, short, help = "Only look at the first N entries")]
     limit: Option<usize>,
+    #[structopt(long, help = "Check entries in parallel")]
+    parallel: bool,
+    #[structopt(
+        long,
+        requires = "parallel",
+        help = "Number of threads to check entries with (defaults to one per CPU)"
+    )]
+    threads: Option<usize>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Show the number and total size of the data entries")]
+struct DataSummaryArgs {
+    #[structopt(help = "Input CBOR file (stdin is used if omitted)")]
+    input: Option<PathBuf>,
+    #[structopt(long, short, help = "Only look at the first N entries")]
+    limit: Option<usize>,
+    #[structopt(long, help = "Also show how many entries fall into each size range")]
+    histogram: bool,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Compare the data entries of two responses")]
+struct DataDiffArgs {
+    #[structopt(help = "CBOR file with the old response")]
+    old: PathBuf,
+    #[structopt(help = "CBOR file with the new response")]
+    new: PathBuf,
+    #[structopt(
+        long,
+        short,
+        help = "Only look at the first N entries of each response"
+    )]
+    limit: Option<usize>,
 }
 
 #[derive(Debug, StructOpt)]
@@ -78,6 +124,7 @@ struct DataCheckArgs {
 enum HistoryArgs {
     Ls(HistLsArgs),
     Show(HistShowArgs),
+    ByLinknode(HistByLinknodeArgs),
 }
 
 #[derive(Debug, StructOpt)]
@@ -87,6 +134,13 @@ struct HistLsArgs {
     input: Option<PathBuf>,
     #[structopt(long, short, help = "Only look at the first N entries")]
     limit: Option<usize>,
+    #[structopt(
+        long,
+        default_value = "path",
+        possible_values = &["path", "count"],
+        help = "Order files by path, or by number of entries (most first)"
+    )]
+    order_by: OrderBy,
 }
 
 #[derive(Debug, StructOpt)]
@@ -100,12 +154,77 @@ struct HistShowArgs {
     count: bool,
     #[structopt(long, short, help = "Only look at the first N entries")]
     limit: Option<usize>,
+    #[structopt(
+        long,
+        default_value = "path",
+        possible_values = &["path", "count"],
+        help = "Order files by path, or by number of entries (most first)"
+    )]
+    order_by: OrderBy,
+}
+
+/// Order in which the files of a history response are listed.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum OrderBy {
+    Path,
+    Count,
+}
+
+impl FromStr for OrderBy {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        match s {
+            "path" => Ok(OrderBy::Path),
+            "count" => Ok(OrderBy::Count),
+            _ => Err(anyhow!("Invalid order: {}", s)),
+        }
+    }
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "List files whose history entries have the given linknode")]
+struct HistByLinknodeArgs {
+    #[structopt(help = "Input CBOR file (stdin is used if omitted)")]
+    input: Option<PathBuf>,
+    #[structopt(long, help = "Linknode (commit hash) to look up")]
+    linknode: HgId,
+    #[structopt(long, short, help = "Only look at the first N entries")]
+    limit: Option<usize>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Read the content of a CBOR bookmark response")]
+enum BookmarksArgs {
+    Ls(BookmarksLsArgs),
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "List the bookmarks and their nodes in this response")]
+struct BookmarksLsArgs {
+    #[structopt(help = "Input CBOR file (stdin is used if omitted)")]
+    input: Option<PathBuf>,
+    #[structopt(long, short, help = "Only look at the first N entries")]
+    limit: Option<usize>,
+}
+
+#[derive(Debug, StructOpt)]
+#[structopt(about = "Convert any CBOR response to JSON")]
+struct ToJsonArgs {
+    #[structopt(help = "Input CBOR file (stdin is used if omitted)")]
+    input: Option<PathBuf>,
+    #[structopt(long, short, help = "Output file (stdout used if omitted)")]
+    output: Option<PathBuf>,
+    #[structopt(long, short, help = "Only look at the first N entries")]
+    limit: Option<usize>,
 }
 
 fn main() -> Result<()> {
     match Args::from_args() {
         Args::Data(args) => cmd_data(args),
         Args::History(args) => cmd_history(args),
+        Args::Bookmarks(args) => cmd_bookmarks(args),
+        Args::ToJson(args) => cmd_to_json(args),
     }
 }
 
@@ -114,13 +233,14 @@ fn cmd_data(args: DataArgs) -> Result<()> {
         DataArgs::Ls(args) => cmd_data_ls(args),
         DataArgs::Cat(args) => cmd_data_cat(args),
         DataArgs::Check(args) => cmd_data_check(args),
+        DataArgs::Summary(args) => cmd_data_summary(args),
+        DataArgs::Diff(args) => cmd_data_diff(args),
     }
 }
 
 fn cmd_data_ls(args: DataLsArgs) -> Result<()> {
-    let entries: Vec<DataEntry> = read_input(args.input, args.limit)?;
-    for entry in entries {
-        println!("{}", entry.key());
+    for entry in stream_input::<DataEntry>(args.input, args.limit)? {
+        println!("{}", entry?.key());
     }
     Ok(())
 }
@@ -130,49 +250,216 @@ fn cmd_data_cat(args: DataCatArgs) -> Result<()> {
     let hgid = args.hgid.parse()?;
     let key = Key::new(path, hgid);
 
-    let entries: Vec<DataEntry> = read_input(args.input, args.limit)?;
-    let entry = entries
-        .into_iter()
-        .find(|entry| entry.key() == &key)
-        .ok_or_else(|| anyhow!("Key not found"))?;
-
-    write_output(args.output, &entry.data()?)
+    // Stop reading as soon as the entry was found
+    for entry in stream_input::<DataEntry>(args.input, args.limit)? {
+        let entry = entry?;
+        if entry.key() == &key {
+            return write_output(args.output, &entry.data()?, args.hexdump);
+        }
+    }
+    Err(anyhow!("Key not found"))
 }
 
+/// Number of entries `data check` holds in memory at once, to check them in parallel.
+const CHECK_CHUNK_SIZE: usize = 10_000;
+
 fn cmd_data_check(args: DataCheckArgs) -> Result<()> {
-    let entries: Vec<DataEntry> = read_input(args.input, args.limit)?;
-    for entry in entries {
-        match entry.data() {
-            Ok(_) => {}
-            Err(DataError::Redacted(..)) => {
-                println!("{} [Contents redacted]", entry.key());
-            }
-            Err(DataError::MaybeHybridManifest(e)) => {
-                println!("{} [Possible flat manifest hash] {}", entry.key(), e);
-            }
-            Err(DataError::Corrupt(e)) => {
-                println!("{} [Invalid hash] {}", entry.key(), e);
-            }
+    let pool = check_pool(args.parallel, args.threads)?;
+    let mut entries = stream_input::<DataEntry>(args.input, args.limit)?;
+    loop {
+        let chunk = entries
+            .by_ref()
+            .take(CHECK_CHUNK_SIZE)
+            .collect::<Result<Vec<_>>>()?;
+        if chunk.is_empty() {
+            return Ok(());
+        }
+        for problem in check_entries(&chunk, pool.as_ref()) {
+            println!("{}", problem);
         }
     }
+}
+
+/// Build the thread pool to check entries with, if they should be checked in parallel,
+/// optionally using the given number of threads.
+fn check_pool(parallel: bool, threads: Option<usize>) -> Result<Option<ThreadPool>> {
+    if !parallel {
+        return Ok(None);
+    }
+    let mut pool = ThreadPoolBuilder::new();
+    if let Some(threads) = threads {
+        pool = pool.num_threads(threads);
+    }
+    Ok(Some(pool.build()?))
+}
+
+/// Validate the hashes of the given entries, returning a description of each problem
+/// found in the same order as the entries, regardless of whether the check ran in parallel.
+fn check_entries(entries: &[DataEntry], pool: Option<&ThreadPool>) -> Vec<String> {
+    match pool {
+        Some(pool) => pool.install(|| entries.par_iter().filter_map(check_entry).collect()),
+        None => entries.iter().filter_map(check_entry).collect(),
+    }
+}
+
+fn check_entry(entry: &DataEntry) -> Option<String> {
+    match entry.data() {
+        Ok(_) => None,
+        Err(DataError::Redacted(..)) => Some(format!("{} [Contents redacted]", entry.key())),
+        Err(DataError::MaybeHybridManifest(e)) => Some(format!(
+            "{} [Possible flat manifest hash] {}",
+            entry.key(),
+            e
+        )),
+        Err(DataError::Corrupt(e)) => Some(format!("{} [Invalid hash] {}", entry.key(), e)),
+    }
+}
+
+/// Upper bounds (exclusive) of the size ranges in a histogram, and their labels. Sizes
+/// beyond the last bound fall into a final open-ended range.
+const SIZE_BUCKETS: &[(u64, &str)] = &[
+    (1 << 10, "<1KB"),
+    (10 << 10, "1-10KB"),
+    (100 << 10, "10-100KB"),
+    (1 << 20, "100KB-1MB"),
+    (10 << 20, "1-10MB"),
+];
+const SIZE_BUCKET_MAX_LABEL: &str = ">=10MB";
+const HISTOGRAM_WIDTH: usize = 40;
+
+fn cmd_data_summary(args: DataSummaryArgs) -> Result<()> {
+    let mut count = 0;
+    let mut total_size = 0;
+    let mut histogram = size_histogram(&[]);
+    for entry in stream_input::<DataEntry>(args.input, args.limit)? {
+        // Hashes aren't checked, as only the sizes matter here
+        let size = entry?.data_unchecked().len() as u64;
+        count += 1;
+        total_size += size;
+        histogram[size_bucket(size)].1 += 1;
+    }
+    println!("Entries: {}", count);
+    println!("Total size: {} bytes", total_size);
+    if args.histogram {
+        print!("{}", format_histogram(&histogram));
+    }
     Ok(())
 }
 
+/// Count how many of `sizes` fall into each of the `SIZE_BUCKETS`, plus the open-ended one.
+fn size_histogram(sizes: &[u64]) -> Vec<(&'static str, usize)> {
+    let mut buckets: Vec<(&'static str, usize)> = SIZE_BUCKETS
+        .iter()
+        .map(|(_, label)| (*label, 0))
+        .chain(std::iter::once((SIZE_BUCKET_MAX_LABEL, 0)))
+        .collect();
+    for size in sizes {
+        buckets[size_bucket(*size)].1 += 1;
+    }
+    buckets
+}
+
+/// Index of the histogram bucket `size` falls into.
+fn size_bucket(size: u64) -> usize {
+    SIZE_BUCKETS
+        .iter()
+        .position(|(bound, _)| size < *bound)
+        .unwrap_or(SIZE_BUCKETS.len())
+}
+
+/// Render a histogram with one line per bucket, with bars scaled to the largest bucket.
+fn format_histogram(buckets: &[(&str, usize)]) -> String {
+    // Empty buckets have no bar, so a histogram without entries has no bars at all
+    let max = buckets
+        .iter()
+        .map(|(_, count)| *count)
+        .max()
+        .unwrap_or(0)
+        .max(1);
+    let mut out = String::new();
+    for (label, count) in buckets {
+        let width = (count * HISTOGRAM_WIDTH).div_ceil(max);
+        out.push_str(&format!(
+            "{:>10} {:>8} {}\n",
+            label,
+            count,
+            "#".repeat(width)
+        ));
+    }
+    out
+}
+
+fn cmd_data_diff(args: DataDiffArgs) -> Result<()> {
+    let old: Vec<DataEntry> = read_input(Some(args.old), args.limit)?;
+    let new: Vec<DataEntry> = read_input(Some(args.new), args.limit)?;
+    let diff = diff_data(old, new);
+    for key in &diff.removed {
+        println!("- {}", key);
+    }
+    for key in &diff.added {
+        println!("+ {}", key);
+    }
+    for key in &diff.changed {
+        println!("~ {}", key);
+    }
+    println!(
+        "Added: {}, removed: {}, changed: {}",
+        diff.added.len(),
+        diff.removed.len(),
+        diff.changed.len()
+    );
+    Ok(())
+}
+
+/// Keys that differ between two data responses, each in key order.
+#[derive(Debug, Default, PartialEq, Eq)]
+struct DataDiff {
+    /// Only in the new response.
+    added: Vec<Key>,
+    /// Only in the old response.
+    removed: Vec<Key>,
+    /// In both responses, with different content.
+    changed: Vec<Key>,
+}
+
+/// Compare two data responses by key. Content is compared without checking hashes, so
+/// that entries with invalid hashes are still compared by what they contain.
+fn diff_data(old: Vec<DataEntry>, new: Vec<DataEntry>) -> DataDiff {
+    let mut old: BTreeMap<Key, DataEntry> = old
+        .into_iter()
+        .map(|entry| (entry.key().clone(), entry))
+        .collect();
+    let new: BTreeMap<Key, DataEntry> = new
+        .into_iter()
+        .map(|entry| (entry.key().clone(), entry))
+        .collect();
+    let mut diff = DataDiff::default();
+    for (key, entry) in new {
+        match old.remove(&key) {
+            Some(old_entry) => {
+                if old_entry.data_unchecked() != entry.data_unchecked() {
+                    diff.changed.push(key);
+                }
+            }
+            None => diff.added.push(key),
+        }
+    }
+    diff.removed = old.into_keys().collect();
+    diff
+}
+
 fn cmd_history(args: HistoryArgs) -> Result<()> {
     match args {
         HistoryArgs::Ls(args) => cmd_history_ls(args),
         HistoryArgs::Show(args) => cmd_history_show(args),
+        HistoryArgs::ByLinknode(args) => cmd_history_by_linknode(args),
     }
 }
 
 fn cmd_history_ls(args: HistLsArgs) -> Result<()> {
     let chunks: Vec<HistoryResponseChunk> = read_input(args.input, args.limit)?;
-    // Deduplicate and sort paths.
-    let mut paths = BTreeSet::new();
-    for chunk in chunks {
-        paths.insert(chunk.path.into_string());
-    }
-    for path in paths {
+    let map = make_history_map(chunks);
+    for (path, _) in order_history(&map, args.order_by) {
         println!("{}", path);
     }
     Ok(())
@@ -187,7 +474,7 @@ fn cmd_history_show(args: HistShowArgs) -> Result<()> {
             None => println!("Path not found in input: {}", path),
         },
         None => {
-            for (path, entries) in &map {
+            for (path, entries) in order_history(&map, args.order_by) {
                 print_history(path, entries, args.count);
             }
         }
@@ -195,6 +482,17 @@ fn cmd_history_show(args: HistShowArgs) -> Result<()> {
     Ok(())
 }
 
+fn cmd_history_by_linknode(args: HistByLinknodeArgs) -> Result<()> {
+    let chunks: Vec<HistoryResponseChunk> = read_input(args.input, args.limit)?;
+    let map = make_linknode_map(&make_history_map(chunks));
+    if let Some(paths) = map.get(&args.linknode) {
+        for path in paths {
+            println!("{}", path);
+        }
+    }
+    Ok(())
+}
+
 fn make_history_map(
     chunks: impl IntoIterator<Item = HistoryResponseChunk>,
 ) -> BTreeMap<String, Vec<WireHistoryEntry>> {
@@ -207,6 +505,34 @@ fn make_history_map(
     map
 }
 
+/// List the paths of a history map with their entries in the given order. Paths with
+/// the same number of entries stay in path order.
+fn order_history(
+    history: &BTreeMap<String, Vec<WireHistoryEntry>>,
+    order_by: OrderBy,
+) -> Vec<(&String, &Vec<WireHistoryEntry>)> {
+    let mut ordered: Vec<_> = history.iter().collect();
+    if order_by == OrderBy::Count {
+        ordered.sort_by_key(|(_, entries)| std::cmp::Reverse(entries.len()));
+    }
+    ordered
+}
+
+/// Invert a history map, to find the paths that have history entries for each linknode.
+fn make_linknode_map(
+    history: &BTreeMap<String, Vec<WireHistoryEntry>>,
+) -> BTreeMap<HgId, BTreeSet<String>> {
+    let mut map = BTreeMap::new();
+    for (path, entries) in history {
+        for entry in entries {
+            map.entry(entry.linknode)
+                .or_insert_with(BTreeSet::new)
+                .insert(path.clone());
+        }
+    }
+    map
+}
+
 fn print_history(path: &str, entries: &[WireHistoryEntry], counts_only: bool) {
     if counts_only {
         println!("{}: {}", path, entries.len());
@@ -230,36 +556,579 @@ fn print_history(path: &str, entries: &[WireHistoryEntry], counts_only: bool) {
     }
 }
 
-fn read_input<T: DeserializeOwned>(path: Option<PathBuf>, limit: Option<usize>) -> Result<Vec<T>> {
-    Ok(match path {
+fn cmd_bookmarks(args: BookmarksArgs) -> Result<()> {
+    match args {
+        BookmarksArgs::Ls(args) => cmd_bookmarks_ls(args),
+    }
+}
+
+fn cmd_bookmarks_ls(args: BookmarksLsArgs) -> Result<()> {
+    let entries: Vec<BookmarkEntry> = read_input(args.input, args.limit)?;
+    for entry in entries {
+        println!("{}", format_bookmark(&entry));
+    }
+    Ok(())
+}
+
+fn format_bookmark(entry: &BookmarkEntry) -> String {
+    match &entry.hgid {
+        Some(hgid) => format!("{}: {}", entry.bookmark, hgid),
+        None => format!("{}: None", entry.bookmark),
+    }
+}
+
+fn cmd_to_json(args: ToJsonArgs) -> Result<()> {
+    let entries: Vec<CborValue> = read_input(args.input, args.limit)?;
+    let mut json = entries_to_json(entries)?;
+    json.push('\n');
+    write_output(args.output, json.as_bytes(), false)
+}
+
+/// Render a sequence of CBOR values as a pretty-printed JSON array.
+fn entries_to_json(entries: Vec<CborValue>) -> Result<String> {
+    let json = JsonValue::Array(entries.into_iter().map(cbor_to_json).collect());
+    Ok(serde_json::to_string_pretty(&json)?)
+}
+
+/// Convert a generic CBOR value to JSON. Byte strings are base64 encoded,
+/// tags are dropped, and map keys that are not strings are replaced by
+/// their JSON encoding. Integers and floats that JSON cannot represent
+/// become strings and nulls respectively.
+fn cbor_to_json(value: CborValue) -> JsonValue {
+    match value {
+        CborValue::Null => JsonValue::Null,
+        CborValue::Bool(b) => JsonValue::Bool(b),
+        CborValue::Integer(i) => {
+            if let Ok(i) = i64::try_from(i) {
+                JsonValue::Number(i.into())
+            } else if let Ok(i) = u64::try_from(i) {
+                JsonValue::Number(i.into())
+            } else {
+                JsonValue::String(i.to_string())
+            }
+        }
+        CborValue::Float(f) => Number::from_f64(f).map_or(JsonValue::Null, JsonValue::Number),
+        CborValue::Bytes(bytes) => JsonValue::String(base64_encode(&bytes)),
+        CborValue::Text(text) => JsonValue::String(text),
+        CborValue::Array(values) => {
+            JsonValue::Array(values.into_iter().map(cbor_to_json).collect())
+        }
+        CborValue::Map(entries) => JsonValue::Object(
+            entries
+                .into_iter()
+                .map(|(k, v)| {
+                    let k = match cbor_to_json(k) {
+                        JsonValue::String(k) => k,
+                        k => k.to_string(),
+                    };
+                    (k, cbor_to_json(v))
+                })
+                .collect::<Map<_, _>>(),
+        ),
+        CborValue::Tag(_, value) => cbor_to_json(*value),
+        _ => JsonValue::Null,
+    }
+}
+
+/// Standard (RFC 4648) base64 encoding with padding.
+fn base64_encode(bytes: &[u8]) -> String {
+    const ALPHABET: &[u8; 64] = b"ABCDEFGHIJKLMNOPQRSTUVWXYZabcdefghijklmnopqrstuvwxyz0123456789+/";
+    let mut out = String::new();
+    for chunk in bytes.chunks(3) {
+        let n = chunk
+            .iter()
+            .enumerate()
+            .fold(0u32, |n, (i, &b)| n | (b as u32) << (16 - 8 * i));
+        for i in 0..4 {
+            if i <= chunk.len() {
+                out.push(ALPHABET[(n >> (18 - 6 * i) & 0x3f) as usize] as char);
+            } else {
+                out.push('=');
+            }
+        }
+    }
+    out
+}
+
+/// Lazily deserialized entries. Errors end the iteration, as the input can't be resynchronized.
+type Entries<'a, T> = Box<dyn Iterator<Item = Result<T>> + 'a>;
+
+fn read_input<T: DeserializeOwned + 'static>(
+    path: Option<PathBuf>,
+    limit: Option<usize>,
+) -> Result<Vec<T>> {
+    stream_input(path, limit)?.collect()
+}
+
+/// Like `read_input`, but deserializes each entry only once it is needed, so that commands
+/// which look at one entry at a time use bounded memory on arbitrarily large inputs.
+fn stream_input<T: DeserializeOwned + 'static>(
+    path: Option<PathBuf>,
+    limit: Option<usize>,
+) -> Result<Entries<'static, T>> {
+    match path {
         Some(path) => {
             eprintln!("Reading from file: {:?}", &path);
-            let file = File::open(&path)?;
-            Deserializer::from_reader(file)
-                .into_iter()
-                .take(limit.unwrap_or(usize::MAX))
-                .collect::<Result<Vec<_>, _>>()?
+            stream_entries(File::open(&path)?, limit)
         }
         None => {
             eprintln!("Reading from stdin");
-            Deserializer::from_reader(stdin())
-                .into_iter()
-                .take(limit.unwrap_or(usize::MAX))
-                .collect::<Result<Vec<_>, _>>()?
+            stream_entries(stdin(), limit)
         }
-    })
+    }
+}
+
+/// The first bytes of a gzip stream. Valid CBOR never starts with these, as 0x1f is a reserved
+/// additional information value for major type 0.
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+
+/// Read CBOR entries, decompressing them first if they are gzipped.
+#[cfg(test)]
+fn read_entries<T: DeserializeOwned, R: Read>(reader: R, limit: Option<usize>) -> Result<Vec<T>> {
+    stream_entries(reader, limit)?.collect()
 }
 
-fn write_output(path: Option<PathBuf>, content: &[u8]) -> Result<()> {
+/// Lazily read CBOR entries, decompressing them first if they are gzipped.
+fn stream_entries<'a, T: DeserializeOwned + 'a, R: Read + 'a>(
+    reader: R,
+    limit: Option<usize>,
+) -> Result<Entries<'a, T>> {
+    let mut reader = BufReader::new(reader);
+    if reader.fill_buf()?.starts_with(GZIP_MAGIC) {
+        Ok(deserialize_entries(MultiGzDecoder::new(reader), limit))
+    } else {
+        Ok(deserialize_entries(reader, limit))
+    }
+}
+
+fn deserialize_entries<'a, T: DeserializeOwned + 'a, R: Read + 'a>(
+    reader: R,
+    limit: Option<usize>,
+) -> Entries<'a, T> {
+    Box::new(
+        Deserializer::from_reader(reader)
+            .into_iter()
+            .take(limit.unwrap_or(usize::MAX))
+            .map(|entry| Ok(entry?)),
+    )
+}
+
+fn write_output(path: Option<PathBuf>, content: &[u8], hexdump: bool) -> Result<()> {
     match path {
         Some(path) => {
             eprintln!("Writing to file: {:?}", &path);
             let mut file = File::create(&path)?;
             file.write_all(content)?;
         }
+        None if hexdump => {
+            stdout().write_all(format_hexdump(content).as_bytes())?;
+        }
         None => {
             stdout().write_all(content)?;
         }
     }
     Ok(())
 }
+
+/// Format the given bytes in the style of `xxd`: an offset column,
+/// 16 bytes per line in groups of 2, followed by the printable ASCII.
+fn format_hexdump(content: &[u8]) -> String {
+    let mut out = String::new();
+    for (i, line) in content.chunks(16).enumerate() {
+        out.push_str(&format!("{:08x}:", i * 16));
+        for group in 0..8 {
+            out.push(' ');
+            for j in 0..2 {
+                match line.get(group * 2 + j) {
+                    Some(byte) => out.push_str(&format!("{:02x}", byte)),
+                    None => out.push_str("  "),
+                }
+            }
+        }
+        out.push_str("  ");
+        for &byte in line {
+            out.push(if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            });
+        }
+        out.push('\n');
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    #[test]
+    fn test_format_hexdump() {
+        let dump = format_hexdump(b"hello, world!\n\x00\xffabc");
+        let lines: Vec<&str> = dump.lines().collect();
+        assert_eq!(lines.len(), 2);
+        assert_eq!(
+            lines[0],
+            "00000000: 6865 6c6c 6f2c 2077 6f72 6c64 210a 00ff  hello, world!..."
+        );
+        assert_eq!(
+            lines[1],
+            "00000010: 6162 63                                  abc"
+        );
+    }
+
+    #[test]
+    fn test_base64_encode() {
+        assert_eq!(base64_encode(b""), "");
+        assert_eq!(base64_encode(b"f"), "Zg==");
+        assert_eq!(base64_encode(b"fo"), "Zm8=");
+        assert_eq!(base64_encode(b"foo"), "Zm9v");
+        assert_eq!(base64_encode(b"foobar"), "Zm9vYmFy");
+        assert_eq!(base64_encode(b"\xff\x00\xfe"), "/wD+");
+    }
+
+    #[test]
+    fn test_to_json() -> Result<()> {
+        let hgid = "1111111111111111111111111111111111111111".parse()?;
+        let mut response = Vec::new();
+        for entry in &[
+            BookmarkEntry::new("master".to_string(), Some(hgid)),
+            BookmarkEntry::new("missing".to_string(), None),
+        ] {
+            response.extend(serde_cbor::to_vec(entry)?);
+        }
+
+        let entries: Vec<CborValue> = read_entries(&response[..], None)?;
+        let json: JsonValue = serde_json::from_str(&entries_to_json(entries)?)?;
+        assert_eq!(
+            json,
+            serde_json::json!([
+                {"bookmark": "master", "hgid": vec![17; 20]},
+                {"bookmark": "missing", "hgid": null},
+            ])
+        );
+
+        let mut map = BTreeMap::new();
+        map.insert(CborValue::Integer(1), CborValue::Bytes(b"foo".to_vec()));
+        map.insert(
+            CborValue::Text("big".to_string()),
+            CborValue::Integer(u64::MAX as i128 + 1),
+        );
+        let json: JsonValue = serde_json::from_str(&entries_to_json(vec![CborValue::Map(map)])?)?;
+        assert_eq!(
+            json,
+            serde_json::json!([{"1": "Zm9v", "big": "18446744073709551616"}])
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_check_parallel() -> Result<()> {
+        // Entries with a null hgid and non-empty content all fail validation.
+        let template = serde_cbor::value::to_value(DataEntry::default())?;
+        let entries = (0..100u8)
+            .map(|i| {
+                let mut value = template.clone();
+                if let CborValue::Map(ref mut map) = value {
+                    map.insert(
+                        CborValue::Text("data".to_string()),
+                        CborValue::Bytes(vec![i; i as usize + 1]),
+                    );
+                }
+                Ok(serde_cbor::value::from_value(value)?)
+            })
+            .collect::<Result<Vec<DataEntry>>>()?;
+
+        let sequential = check_entries(&entries, None);
+        assert_eq!(sequential.len(), entries.len());
+        let pool = check_pool(true, None)?;
+        assert_eq!(check_entries(&entries, pool.as_ref()), sequential);
+        let pool = check_pool(true, Some(4))?;
+        assert_eq!(check_entries(&entries, pool.as_ref()), sequential);
+        Ok(())
+    }
+
+    #[test]
+    fn test_data_check_args() -> Result<()> {
+        let check_args = |args: &[&str]| -> Result<DataCheckArgs> {
+            match Args::from_iter_safe(args)? {
+                Args::Data(DataArgs::Check(args)) => Ok(args),
+                args => Err(anyhow!("Unexpected arguments: {:?}", args)),
+            }
+        };
+
+        // --parallel doesn't take the input file as its value
+        let args = check_args(&["read_res", "data", "check", "--parallel", "resp.cbor"])?;
+        assert!(args.parallel);
+        assert_eq!(args.threads, None);
+        assert_eq!(args.input, Some(PathBuf::from("resp.cbor")));
+
+        let args = check_args(&[
+            "read_res",
+            "data",
+            "check",
+            "--parallel",
+            "--threads",
+            "4",
+            "resp.cbor",
+        ])?;
+        assert_eq!(args.threads, Some(4));
+        assert_eq!(args.input, Some(PathBuf::from("resp.cbor")));
+
+        // --threads only makes sense when checking in parallel
+        assert!(check_args(&["read_res", "data", "check", "--threads", "4"]).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_bookmarks_ls() -> Result<()> {
+        let hgid = "1111111111111111111111111111111111111111".parse()?;
+        let mut response = Vec::new();
+        for entry in &[
+            BookmarkEntry::new("master".to_string(), Some(hgid)),
+            BookmarkEntry::new("missing".to_string(), None),
+        ] {
+            response.extend(serde_cbor::to_vec(entry)?);
+        }
+
+        let entries: Vec<BookmarkEntry> = read_entries(&response[..], None)?;
+        let listing: Vec<String> = entries.iter().map(format_bookmark).collect();
+        assert_eq!(
+            listing,
+            vec![
+                "master: 1111111111111111111111111111111111111111",
+                "missing: None",
+            ]
+        );
+        Ok(())
+    }
+
+    #[test]
+    fn test_size_histogram() {
+        let sizes = [0, 10, 1023, 1024, 5000, 200 << 10, 20 << 20];
+        assert_eq!(
+            size_histogram(&sizes),
+            vec![
+                ("<1KB", 3),
+                ("1-10KB", 2),
+                ("10-100KB", 0),
+                ("100KB-1MB", 1),
+                ("1-10MB", 0),
+                (">=10MB", 1),
+            ]
+        );
+
+        let lines: Vec<String> = format_histogram(&size_histogram(&sizes))
+            .lines()
+            .map(|line| line.trim_end().to_string())
+            .collect();
+        assert_eq!(lines.len(), 6);
+        assert_eq!(
+            lines[0],
+            format!("{:>10} {:>8} {}", "<1KB", 3, "#".repeat(40))
+        );
+        assert_eq!(lines[2], format!("{:>10} {:>8}", "10-100KB", 0));
+        assert_eq!(
+            lines[5],
+            format!("{:>10} {:>8} {}", ">=10MB", 1, "#".repeat(14))
+        );
+    }
+
+    #[test]
+    fn test_history_by_linknode() -> Result<()> {
+        let linknode1: HgId = "1111111111111111111111111111111111111111".parse()?;
+        let linknode2: HgId = "2222222222222222222222222222222222222222".parse()?;
+        let entry = |node: &str, linknode| -> Result<WireHistoryEntry> {
+            Ok(WireHistoryEntry {
+                node: node.parse()?,
+                parents: Parents::None,
+                linknode,
+                copyfrom: None,
+            })
+        };
+        let chunk = |path: &str, entries| -> Result<HistoryResponseChunk> {
+            Ok(HistoryResponseChunk::new(
+                RepoPathBuf::from_string(path.to_string())?,
+                entries,
+            ))
+        };
+        let chunks = vec![
+            chunk(
+                "a",
+                vec![
+                    entry("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa", linknode1)?,
+                    entry("abababababababababababababababababababab", linknode2)?,
+                ],
+            )?,
+            chunk(
+                "b",
+                vec![entry(
+                    "bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb",
+                    linknode2,
+                )?],
+            )?,
+            chunk(
+                "c",
+                vec![entry(
+                    "cccccccccccccccccccccccccccccccccccccccc",
+                    linknode1,
+                )?],
+            )?,
+            // A later chunk for a path that was already seen
+            chunk(
+                "b",
+                vec![entry(
+                    "bcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbc",
+                    linknode1,
+                )?],
+            )?,
+        ];
+
+        let map = make_linknode_map(&make_history_map(chunks));
+        let paths: Vec<&str> = map[&linknode1].iter().map(String::as_str).collect();
+        assert_eq!(paths, vec!["a", "b", "c"]);
+        let paths: Vec<&str> = map[&linknode2].iter().map(String::as_str).collect();
+        assert_eq!(paths, vec!["a", "b"]);
+        assert_eq!(map.len(), 2);
+        Ok(())
+    }
+
+    #[test]
+    fn test_order_history() -> Result<()> {
+        let entry = |node: &str| -> Result<WireHistoryEntry> {
+            Ok(WireHistoryEntry {
+                node: node.parse()?,
+                parents: Parents::None,
+                linknode: node.parse()?,
+                copyfrom: None,
+            })
+        };
+        let mut map = BTreeMap::new();
+        map.insert(
+            "a".to_string(),
+            vec![entry("aaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaaa")?],
+        );
+        map.insert(
+            "b".to_string(),
+            vec![
+                entry("bbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbbb")?,
+                entry("bcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbcbc")?,
+            ],
+        );
+        map.insert(
+            "c".to_string(),
+            vec![entry("cccccccccccccccccccccccccccccccccccccccc")?],
+        );
+
+        let paths = |order_by| -> Vec<String> {
+            order_history(&map, order_by)
+                .into_iter()
+                .map(|(path, _)| path.clone())
+                .collect()
+        };
+        assert_eq!(paths(OrderBy::Path), vec!["a", "b", "c"]);
+        // The busiest path comes first, ties stay in path order
+        assert_eq!(paths(OrderBy::Count), vec!["b", "a", "c"]);
+        assert_eq!("count".parse::<OrderBy>()?, OrderBy::Count);
+        assert!("size".parse::<OrderBy>().is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_diff_data() -> Result<()> {
+        let entry = |path: &str, content: &[u8]| -> Result<DataEntry> {
+            let path = RepoPathBuf::from_string(path.to_string())?;
+            let key = Key::new(path, "1111111111111111111111111111111111111111".parse()?);
+            Ok(DataEntry::new(
+                key,
+                content.to_vec().into(),
+                Default::default(),
+                Default::default(),
+            ))
+        };
+        let old = vec![
+            entry("same", b"same")?,
+            entry("changed", b"before")?,
+            entry("removed", b"removed")?,
+        ];
+        let new = vec![
+            entry("added", b"added")?,
+            entry("changed", b"after")?,
+            entry("same", b"same")?,
+        ];
+
+        assert_eq!(
+            diff_data(old.clone(), new.clone()),
+            DataDiff {
+                added: vec![new[0].key().clone()],
+                removed: vec![old[2].key().clone()],
+                changed: vec![old[1].key().clone()],
+            }
+        );
+        assert_eq!(diff_data(old.clone(), old), DataDiff::default());
+        Ok(())
+    }
+
+    /// Reader that fails every read, to make sure nothing reads past the data before it.
+    struct FailingReader;
+
+    impl Read for FailingReader {
+        fn read(&mut self, _buf: &mut [u8]) -> std::io::Result<usize> {
+            Err(std::io::Error::other("read past the end"))
+        }
+    }
+
+    #[test]
+    fn test_stream_entries() -> Result<()> {
+        let hgid = "1111111111111111111111111111111111111111".parse()?;
+        let entries = vec![
+            BookmarkEntry::new("master".to_string(), Some(hgid)),
+            BookmarkEntry::new("stable".to_string(), None),
+        ];
+        let mut response = Vec::new();
+        for entry in &entries {
+            response.extend(serde_cbor::to_vec(entry)?);
+        }
+
+        // Entries are available before the rest of the input was read
+        let reader = (&response[..]).chain(FailingReader);
+        let mut stream = stream_entries::<BookmarkEntry, _>(reader, None)?;
+        assert_eq!(stream.next().transpose()?.as_ref(), Some(&entries[0]));
+        assert_eq!(stream.next().transpose()?.as_ref(), Some(&entries[1]));
+        assert!(stream.next().unwrap().is_err());
+
+        // Reading everything at once fails instead
+        let reader = (&response[..]).chain(FailingReader);
+        assert!(read_entries::<BookmarkEntry, _>(reader, None).is_err());
+        Ok(())
+    }
+
+    #[test]
+    fn test_read_gzipped_entries() -> Result<()> {
+        let entries = (0..10u8)
+            .map(|i| {
+                let path = RepoPathBuf::from_string(format!("file{}", i))?;
+                let key = Key::new(path, "1111111111111111111111111111111111111111".parse()?);
+                Ok(DataEntry::new(
+                    key,
+                    vec![i; 10].into(),
+                    Default::default(),
+                    Default::default(),
+                ))
+            })
+            .collect::<Result<Vec<_>>>()?;
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        for entry in &entries {
+            serde_cbor::to_writer(&mut encoder, entry)?;
+        }
+        let response = encoder.finish()?;
+
+        let read: Vec<DataEntry> = read_entries(&response[..], None)?;
+        assert_eq!(read, entries);
+        let read: Vec<DataEntry> = read_entries(&response[..], Some(3))?;
+        assert_eq!(read, &entries[..3]);
+        Ok(())
+    }
+}
@@ -0,0 +1,37 @@
+/*
+ * Copyright (c) Facebook, Inc. and its affiliates.
+ *
+ * This software may be used and distributed according to the terms of the
+ * GNU General Public License version 2.
+ */
+
+use serde_derive::{Deserialize, Serialize};
+
+use types::hgid::HgId;
+
+/// A single entry in a bookmark response. The `hgid` is `None` if the
+/// requested bookmark does not exist.
+#[derive(Clone, Debug, Serialize, Deserialize, Eq, PartialEq)]
+pub struct BookmarkEntry {
+    pub bookmark: String,
+    pub hgid: Option<HgId>,
+}
+
+impl BookmarkEntry {
+    pub fn new(bookmark: String, hgid: Option<HgId>) -> Self {
+        Self { bookmark, hgid }
+    }
+}
+
+#[cfg(any(test, feature = "for-tests"))]
+use quickcheck::Arbitrary;
+
+#[cfg(any(test, feature = "for-tests"))]
+impl Arbitrary for BookmarkEntry {
+    fn arbitrary<G: quickcheck::Gen>(g: &mut G) -> Self {
+        Self {
+            bookmark: Arbitrary::arbitrary(g),
+            hgid: Arbitrary::arbitrary(g),
+        }
+    }
+}
@@ -25,11 +25,13 @@
 
 #![deny(warnings)]
 
+pub mod bookmark;
 pub mod data;
 pub mod history;
 pub mod json;
 pub mod tree;
 
+pub use crate::bookmark::BookmarkEntry;
 pub use crate::data::{DataEntry, DataError, DataRequest, DataResponse};
 pub use crate::history::{
     HistoryEntry, HistoryRequest, HistoryResponse, HistoryResponseChunk, WireHistoryEntry,
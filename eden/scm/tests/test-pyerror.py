@@ -0,0 +1,32 @@
+from __future__ import absolute_import
+
+import unittest
+
+import silenttestrunner
+from bindings import error
+
+
+class pyerrortests(unittest.TestCase):
+    def testListHandlers(self):
+        handlers = error.list_handlers()
+        self.assertIn("010-specific", handlers)
+        self.assertIn("999-fallback", handlers)
+        self.assertEqual(handlers, sorted(handlers))
+
+    def testMessageTruncated(self):
+        oldlength = error.max_message_length()
+        error.set_max_message_length(20)
+        try:
+            with self.assertRaises(error.RustError) as cm:
+                error.throwrustbail()
+        finally:
+            error.set_max_message_length(oldlength)
+
+        data = cm.exception.args[0]
+        self.assertEqual(data.message(), "intentional bail wit... (truncated)")
+        # Metadata is kept even though the message was cut short
+        self.assertEqual(data.fault(), "request")
+
+
+if __name__ == "__main__":
+    silenttestrunner.main(__name__)